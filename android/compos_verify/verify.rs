@@ -21,7 +21,7 @@ use android_logger::LogId;
 use anyhow::{anyhow, bail, Context, Result};
 use binder::ProcessState;
 use clap::{Parser, ValueEnum};
-use compos_common::compos_client::{ComposClient, VmCpuTopology, VmParameters};
+use compos_common::compos_client::{ComposClient, VmCpuTopology, VmDebugLevel, VmParameters};
 use compos_common::odrefresh::{
     CURRENT_ARTIFACTS_SUBDIR, ODREFRESH_OUTPUT_ROOT_DIR, PENDING_ARTIFACTS_SUBDIR,
     TEST_ARTIFACTS_SUBDIR,
@@ -125,7 +125,7 @@ fn try_main() -> Result<()> {
         &VmParameters {
             name: String::from("ComposVerify"),
             cpu_topology: VmCpuTopology::OneCpu, // This VM runs very little work at boot
-            debug_mode: args.debug,
+            debug_level: if args.debug { VmDebugLevel::Full } else { VmDebugLevel::None },
             ..Default::default()
         },
     )?;
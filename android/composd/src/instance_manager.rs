@@ -21,7 +21,7 @@ use crate::instance_starter::{CompOsInstance, InstanceStarter};
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice;
 use anyhow::{anyhow, bail, Context, Result};
 use binder::Strong;
-use compos_common::compos_client::{VmCpuTopology, VmParameters};
+use compos_common::compos_client::{VmCpuTopology, VmDebugLevel, VmParameters};
 use compos_common::{CURRENT_INSTANCE_DIR, TEST_INSTANCE_DIR};
 use log::info;
 use rustutils::system_properties;
@@ -49,7 +49,7 @@ impl InstanceManager {
     pub fn start_test_instance(&self, prefer_staged: bool) -> Result<CompOsInstance> {
         let mut vm_parameters = new_vm_parameters()?;
         vm_parameters.name = String::from("ComposdTest");
-        vm_parameters.debug_mode = true;
+        vm_parameters.debug_level = VmDebugLevel::Full;
         vm_parameters.prefer_staged = prefer_staged;
         self.start_instance(TEST_INSTANCE_DIR, vm_parameters)
     }
@@ -83,7 +83,9 @@ fn new_vm_parameters() -> Result<VmParameters> {
     // number of dex2oat threads.
     let cpu_topology = VmCpuTopology::MatchHost;
     let memory_mib = Some(compos_memory_mib()?);
-    Ok(VmParameters { cpu_topology, memory_mib, ..Default::default() })
+    // Compilation can take a long time, so survive transient failures of the service connection.
+    let auto_reconnect = true;
+    Ok(VmParameters { cpu_topology, memory_mib, auto_reconnect, ..Default::default() })
 }
 
 fn compos_memory_mib() -> Result<i32> {
@@ -48,6 +48,12 @@ impl CompOsInstance {
         self.service.clone()
     }
 
+    /// Checks that the VM is still alive and the service in it is responsive, reconnecting to the
+    /// service if necessary and allowed by the VM parameters.
+    pub fn ping(&mut self) -> Result<()> {
+        self.vm_instance.ping(&mut self.service)
+    }
+
     /// Returns an Arc that this instance holds a strong reference to as long as it exists. This
     /// can be used to determine when the instance has been dropped.
     pub fn get_instance_tracker(&self) -> &Arc<()> {
@@ -175,7 +181,12 @@ impl InstanceStarter {
         // TODO: Where does this number come from?
         let size = 10 * 1024 * 1024;
         virtualization_service
-            .initializeWritablePartition(&instance_image, size, PartitionType::ANDROID_VM_INSTANCE)
+            .initializeWritablePartition(
+                &instance_image,
+                size,
+                PartitionType::ANDROID_VM_INSTANCE,
+                /* force= */ false,
+            )
             .context("Writing instance image file")?;
         Ok(())
     }
@@ -77,11 +77,13 @@ impl OdrefreshTask {
     }
 
     pub fn start(
-        comp_os: CompOsInstance,
+        mut comp_os: CompOsInstance,
         compilation_mode: CompilationMode,
         target_dir_name: String,
         callback: &Strong<dyn ICompilationTaskCallback>,
     ) -> Result<OdrefreshTask> {
+        // Compilation takes a long time; make sure the VM is still usable before committing to it.
+        comp_os.ping().context("CompOS VM is not responsive")?;
         let service = comp_os.get_service();
         let task = RunningTask { comp_os, callback: callback.clone() };
         let task = OdrefreshTask { running_task: Arc::new(Mutex::new(Some(task))) };
@@ -16,12 +16,18 @@
 
 use crate::{get_calling_pid, get_calling_uid, get_this_pid};
 use crate::atom::{write_vm_booted_stats, write_vm_creation_stats};
-use crate::composite::make_composite_image;
-use crate::crosvm::{AudioConfig, CrosvmConfig, DiskFile, DisplayConfig, GpuConfig, InputDeviceOption, PayloadState, UsbConfig, VmContext, VmInstance, VmState};
+use crate::composite::{describe_composite_image, make_composite_image};
+use crate::console_history::{tee_into_history, ConsoleHistory};
+use crate::crosvm::{check_platform_version, AudioConfig, CROSVM_PLATFORM_VERSION, CrosvmConfig, DiskFile, DisplayConfig, GpuConfig, InputDeviceOption, PayloadState, UsbConfig, VmContext, VmInstance, VmState};
 use crate::debug_config::DebugConfig;
+use crate::digest_cache::APK_DIGEST_CACHE;
 use crate::dt_overlay::{create_device_tree_overlay, VM_DT_OVERLAY_MAX_SIZE, VM_DT_OVERLAY_PATH};
+use crate::label_allowlist::label_allowlist;
 use crate::payload::{add_microdroid_payload_images, add_microdroid_system_images, add_microdroid_vendor_image};
+use crate::ramdump::capture_ramdump;
+use crate::retry::retry_while;
 use crate::selinux::{getfilecon, SeContext};
+use crate::vsock_ports::ReservedVsockPorts;
 use android_os_permissions_aidl::aidl::android::os::IPermissionController;
 use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::{
     Certificate::Certificate,
@@ -30,22 +36,30 @@ use android_system_virtualizationcommon::aidl::android::system::virtualizationco
 };
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
     AssignableDevice::AssignableDevice,
+    BootMetrics::BootMetrics,
     CpuTopology::CpuTopology,
     DiskImage::DiskImage,
+    DiskLayout::DiskLayout,
     InputDevice::InputDevice,
     IVirtualMachine::{BnVirtualMachine, IVirtualMachine},
     IVirtualMachineCallback::IVirtualMachineCallback,
     IVirtualizationService::IVirtualizationService,
     Partition::Partition,
     PartitionType::PartitionType,
+    PayloadError::PayloadError,
     VirtualMachineAppConfig::{DebugLevel::DebugLevel, Payload::Payload, VirtualMachineAppConfig},
     VirtualMachineConfig::VirtualMachineConfig,
     VirtualMachineDebugInfo::VirtualMachineDebugInfo,
+    VirtualMachineInfo::VirtualMachineInfo,
     VirtualMachinePayloadConfig::VirtualMachinePayloadConfig,
     VirtualMachineRawConfig::VirtualMachineRawConfig,
     VirtualMachineState::VirtualMachineState,
+    VmStats::VmStats,
 };
 use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IGlobalVmContext::IGlobalVmContext;
+use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IGuestFileCallback::{
+        BnGuestFileCallback, IGuestFileCallback,
+};
 use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IVirtualizationServiceInternal::IVirtualizationServiceInternal;
 use android_system_virtualmachineservice::aidl::android::system::virtualmachineservice::IVirtualMachineService::{
         BnVirtualMachineService, IVirtualMachineService,
@@ -59,7 +73,7 @@ use android_hardware_security_authgraph::aidl::android::hardware::security::auth
     SessionInitiationInfo::SessionInitiationInfo,
 };
 use anyhow::{anyhow, bail, Context, Result};
-use apkverify::{HashAlgorithm, V4Signature};
+use apkverify::{get_apk_digest, HashAlgorithm, V4Signature};
 use avflog::LogResult;
 use binder::{
     self, wait_for_interface, BinderFeatures, ExceptionCode, Interface, ParcelFileDescriptor,
@@ -70,6 +84,7 @@ use cstr::cstr;
 use glob::glob;
 use log::{debug, error, info, warn};
 use microdroid_payload_config::{ApkConfig, Task, TaskType, VmPayloadConfig};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
 use nix::unistd::pipe;
 use rpcbinder::RpcServer;
 use rustutils::system_properties;
@@ -80,7 +95,7 @@ use std::convert::TryInto;
 use std::fs;
 use std::ffi::CStr;
 use std::fs::{canonicalize, create_dir_all, read_dir, remove_dir_all, remove_file, File, OpenOptions};
-use std::io::{BufRead, BufReader, Error, ErrorKind, Seek, SeekFrom, Write};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::iter;
 use std::num::{NonZeroU16, NonZeroU32};
 use std::ops::Range;
@@ -88,9 +103,11 @@ use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
 use std::os::unix::raw::pid_t;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, Weak, LazyLock};
+use std::thread::JoinHandle;
+use std::time::Duration;
 use vbmeta::VbMetaImage;
 use vmconfig::{VmConfig, get_debug_level};
-use vsock::VsockStream;
+use vsock::{VsockStream, VMADDR_CID_HOST};
 use zip::ZipArchive;
 
 /// The unique ID of a VM used (together with a port number) for vsock communication.
@@ -108,6 +125,9 @@ const ANDROID_VM_INSTANCE_MAGIC: &str = "Android-VM-instance";
 /// Version of the instance image format
 const ANDROID_VM_INSTANCE_VERSION: u16 = 1;
 
+/// Magic number at the start of QCOW2 images
+const QCOW2_MAGIC: &[u8] = b"QFI\xfb";
+
 const MICRODROID_OS_NAME: &str = "microdroid";
 
 const SECRETKEEPER_IDENTIFIER: &str =
@@ -120,6 +140,10 @@ const PARTITION_GRANULARITY_BYTES: u64 = 4096;
 
 const VM_REFERENCE_DT_ON_HOST_PATH: &str = "/proc/device-tree/avf/reference";
 
+/// The longest a client can have `connectVsockWithTimeout` retry for, so that it can't hold a
+/// binder thread indefinitely.
+const MAX_CONNECT_VSOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub static GLOBAL_SERVICE: LazyLock<Strong<dyn IVirtualizationServiceInternal>> =
     LazyLock::new(|| {
         if cfg!(early) {
@@ -141,9 +165,14 @@ fn create_or_update_idsig_file(
     if !metadata.is_file() {
         bail!("input is not a regular file");
     }
-    let mut sig =
-        V4Signature::create(&mut input, get_current_sdk()?, 4096, &[], HashAlgorithm::SHA256)
-            .context("failed to create idsig")?;
+    let current_sdk = get_current_sdk()?;
+    let start = input.stream_position().context("failed to get input position")?;
+    // The digest is only read again from the APK if it has changed since it was last read.
+    let apk_digest = APK_DIGEST_CACHE
+        .get_or_compute(&mut input, |input| {
+            Ok(get_apk_digest(input, current_sdk, /*verify=*/ false)?.1)
+        })
+        .context("failed to create idsig")?;
 
     let mut output = clone_file(idsig_fd)?;
 
@@ -151,7 +180,7 @@ fn create_or_update_idsig_file(
     // if the idsig file already has the same APK digest.
     if output.metadata()?.len() > 0 {
         if let Ok(out_sig) = V4Signature::from_idsig(&mut output) {
-            if out_sig.signing_info.apk_digest == sig.signing_info.apk_digest {
+            if out_sig.signing_info.apk_digest == apk_digest {
                 debug!("idsig {:?} is up-to-date with apk {:?}.", output, input);
                 return Ok(());
             }
@@ -160,11 +189,122 @@ fn create_or_update_idsig_file(
         // We will anyway overwrite the file to the v4signature generated from input_fd.
     }
 
-    output
+    input.seek(SeekFrom::Start(start)).context("failed to move cursor back on the input")?;
+    match idsig_path(&output) {
+        Some(path) => match replace_idsig_at(&output, &path, &mut input, current_sdk) {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                warn!("Failed to replace idsig {path:?}, overwriting it in place: {e:?}");
+                input.seek(SeekFrom::Start(start)).context("failed to move cursor back")?;
+            }
+        },
+        None => {
+            warn!("idsig {output:?} has no path it can be replaced at, overwriting it in place")
+        }
+    }
+    overwrite_idsig(&mut output, &mut input, current_sdk)
+}
+
+/// Returns the path of the idsig file, if it is still linked at the path the fd was opened with.
+fn idsig_path(idsig: &File) -> Option<PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+
+    let path = fs::read_link(format!("/proc/self/fd/{}", idsig.as_raw_fd())).ok()?;
+    let linked = fs::metadata(&path).ok()?;
+    let opened = idsig.metadata().ok()?;
+    (path.is_absolute() && linked.dev() == opened.dev() && linked.ino() == opened.ino())
+        .then_some(path)
+}
+
+/// Streams the idsig of `input` into a temporary file next to `path`, the path of `output`, then
+/// renames it over `path`, so that readers of `path` see either the old or the new idsig, never a
+/// partially written one.
+fn replace_idsig_at(output: &File, path: &Path, input: &mut File, current_sdk: u32) -> Result<()> {
+    let dir = path.parent().context("idsig has no parent directory")?;
+    let name = path.file_name().context("idsig has no file name")?.to_string_lossy();
+    // The fd is only open once in this process at a time, so concurrent updates don't collide.
+    let tmp_path = dir.join(format!(".{name}.{}.{}.tmp", std::process::id(), output.as_raw_fd()));
+    let permissions = fs::metadata(path)?.permissions();
+    let mut tmp = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp_path)
+        .with_context(|| format!("failed to create {tmp_path:?}"))?;
+    let result = (|| {
+        V4Signature::create_streaming(
+            input,
+            current_sdk,
+            4096,
+            &[],
+            HashAlgorithm::SHA256,
+            &mut tmp,
+        )
+        .context("failed to create idsig")?;
+        tmp.set_permissions(permissions).context("failed to set idsig permissions")?;
+        tmp.sync_all().context("failed to sync idsig")?;
+        fs::rename(&tmp_path, path).with_context(|| format!("failed to rename over {path:?}"))
+    })();
+    if result.is_err() {
+        let _ = remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Streams the idsig of `input` into an anonymous file, then copies it over `output` in a single
+/// pass before truncating it. Unlike `replace_idsig_at`, readers may see the copy in progress, so
+/// this is only used when the idsig can't be replaced through its path.
+fn overwrite_idsig(output: &mut File, input: &mut File, current_sdk: u32) -> Result<()> {
+    let mut idsig: File = memfd_create(cstr!("idsig"), MemFdCreateFlag::MFD_CLOEXEC)
+        .context("failed to create memfd for idsig")?
+        .into();
+    V4Signature::create_streaming(input, current_sdk, 4096, &[], HashAlgorithm::SHA256, &mut idsig)
+        .context("failed to create idsig")?;
+    idsig.rewind().context("failed to rewind idsig")?;
+    output.rewind().context("failed to rewind idsig output")?;
+    let len = std::io::copy(&mut idsig, output).context("failed to write idsig")?;
+    output.set_len(len).context("failed to set_len on the idsig output")?;
+    Ok(())
+}
+
+fn initialize_writable_partition(
+    image: &mut File,
+    size_bytes: u64,
+    partition_type: PartitionType,
+    force: bool,
+) -> binder::Result<()> {
+    let size_bytes = round_up(size_bytes, PARTITION_GRANULARITY_BYTES);
+    // Refuse to erase what looks like the data of an existing partition, in case the wrong file
+    // was passed.
+    if !force
+        && has_partition_header(image)
+            .context("Failed to read the file")
+            .or_service_specific_exception(-1)?
+    {
+        return Err(anyhow!("The file is already a partition image; use force to erase it"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+    }
+    // initialize the file. Any data in the file will be erased.
+    image
         .seek(SeekFrom::Start(0))
-        .context("failed to move cursor to start on the idsig output")?;
-    output.set_len(0).context("failed to set_len on the idsig output")?;
-    sig.write_into(&mut output).context("failed to write idsig")?;
+        .context("failed to move cursor to start")
+        .or_service_specific_exception(-1)?;
+    image.set_len(0).context("Failed to reset a file").or_service_specific_exception(-1)?;
+    // Set the file length. In most filesystems, this will not allocate any physical disk
+    // space, it will only change the logical size.
+    image.set_len(size_bytes).context("Failed to extend file").or_service_specific_exception(-1)?;
+
+    match partition_type {
+        PartitionType::RAW => Ok(()),
+        PartitionType::ANDROID_VM_INSTANCE => format_as_android_vm_instance(image),
+        PartitionType::ENCRYPTEDSTORE => format_as_encryptedstore(image),
+        _ => Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("Unsupported partition type {:?}", partition_type),
+        )),
+    }
+    .with_context(|| format!("Failed to initialize partition as {:?}", partition_type))
+    .or_service_specific_exception(-1)?;
+
     Ok(())
 }
 
@@ -246,40 +386,15 @@ impl IVirtualizationService for VirtualizationService {
         image_fd: &ParcelFileDescriptor,
         size_bytes: i64,
         partition_type: PartitionType,
+        force: bool,
     ) -> binder::Result<()> {
         check_manage_access()?;
         let size_bytes = size_bytes
             .try_into()
             .with_context(|| format!("Invalid size: {}", size_bytes))
             .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
-        let size_bytes = round_up(size_bytes, PARTITION_GRANULARITY_BYTES);
         let mut image = clone_file(image_fd)?;
-        // initialize the file. Any data in the file will be erased.
-        image
-            .seek(SeekFrom::Start(0))
-            .context("failed to move cursor to start")
-            .or_service_specific_exception(-1)?;
-        image.set_len(0).context("Failed to reset a file").or_service_specific_exception(-1)?;
-        // Set the file length. In most filesystems, this will not allocate any physical disk
-        // space, it will only change the logical size.
-        image
-            .set_len(size_bytes)
-            .context("Failed to extend file")
-            .or_service_specific_exception(-1)?;
-
-        match partition_type {
-            PartitionType::RAW => Ok(()),
-            PartitionType::ANDROID_VM_INSTANCE => format_as_android_vm_instance(&mut image),
-            PartitionType::ENCRYPTEDSTORE => format_as_encryptedstore(&mut image),
-            _ => Err(Error::new(
-                ErrorKind::Unsupported,
-                format!("Unsupported partition type {:?}", partition_type),
-            )),
-        }
-        .with_context(|| format!("Failed to initialize partition as {:?}", partition_type))
-        .or_service_specific_exception(-1)?;
-
-        Ok(())
+        initialize_writable_partition(&mut image, size_bytes, partition_type, force)
     }
 
     /// Creates or update the idsig file by digesting the input APK file.
@@ -301,6 +416,50 @@ impl IVirtualizationService for VirtualizationService {
         GLOBAL_SERVICE.debugListVms()
     }
 
+    /// Get a list of the VMs created by the calling UID which are still alive. This instance only
+    /// knows about the VMs of its own client process, so the global service is asked instead.
+    fn listMyVms(&self) -> binder::Result<Vec<VirtualMachineInfo>> {
+        // Delegate to the global service, including checking the manage permission.
+        GLOBAL_SERVICE.listMyVms()
+    }
+
+    /// Check whether the given CID is assigned to a VM. This method is only intended for debug
+    /// purposes, and as such is only permitted from the shell user.
+    fn isCidInUse(&self, cid: i32) -> binder::Result<bool> {
+        check_debug_access()?;
+
+        is_cid_in_use(&self.state, cid as Cid, cfg!(early), || GLOBAL_SERVICE.isCidInUse(cid))
+    }
+
+    /// Get the most recent console output of a VM created by this service.
+    fn debugGetConsoleHistory(&self, cid: i32) -> binder::Result<Vec<u8>> {
+        check_debug_access()?;
+
+        let vm = self
+            .state
+            .lock()
+            .unwrap()
+            .get_vm(cid as Cid)
+            .ok_or_else(|| anyhow!("No VM with CID {cid}"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        let history = vm
+            .console_history
+            .as_ref()
+            .ok_or_else(|| anyhow!("Console output isn't enabled for VM with CID {cid}"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_STATE)?;
+        Ok(history.snapshot())
+    }
+
+    /// Get the layout of the disks of a VM with the given config, without creating the VM.
+    fn debugDescribeDisks(
+        &self,
+        config: &VirtualMachineRawConfig,
+    ) -> binder::Result<Vec<DiskLayout>> {
+        check_debug_access()?;
+
+        config.disks.iter().map(describe_disk_image).collect()
+    }
+
     /// Get a list of assignable device types.
     fn getAssignableDevices(&self) -> binder::Result<Vec<AssignableDevice>> {
         // Delegate to the global service, including checking the permission.
@@ -370,6 +529,10 @@ impl Interface for EarlyVmContext {}
 
 impl Drop for EarlyVmContext {
     fn drop(&mut self) {
+        // The directory of an ephemeral VM is already gone.
+        if !self.temp_dir.exists() {
+            return;
+        }
         if let Err(e) = remove_dir_all(&self.temp_dir) {
             error!("Cannot remove {} upon dropping: {e}", self.temp_dir.display());
         }
@@ -391,6 +554,32 @@ impl IGlobalVmContext for EarlyVmContext {
             Some("Early VM doesn't support setting host console name"),
         ))
     }
+
+    fn setState(&self, _state: VirtualMachineState) -> binder::Result<()> {
+        // Early VMs are only known to this service, so their state isn't reported anywhere.
+        Ok(())
+    }
+
+    fn disableTombstones(&self) -> binder::Result<()> {
+        Err(Status::new_exception_str(
+            ExceptionCode::UNSUPPORTED_OPERATION,
+            Some("Early VM doesn't support disabling tombstones"),
+        ))
+    }
+
+    fn setGuestFileCallback(
+        &self,
+        _callback: &Strong<dyn IGuestFileCallback>,
+    ) -> binder::Result<()> {
+        // Nothing listens for the files pushed by early VMs, so the callback is never called.
+        Ok(())
+    }
+
+    fn removeTemporaryDirectory(&self) -> binder::Result<()> {
+        remove_dir_all(&self.temp_dir)
+            .with_context(|| format!("can't remove '{}'", self.temp_dir.display()))
+            .or_service_specific_exception(-1)
+    }
 }
 
 fn find_partition(path: &Path) -> binder::Result<String> {
@@ -490,11 +679,19 @@ impl VirtualizationService {
         let requester_debug_pid = get_calling_pid();
 
         check_config_features(config)?;
+        check_extra_kernel_cmdline_params_are_allowed(config)?;
 
         if cfg!(early) {
             check_config_allowed_for_early_vms(config)?;
         }
 
+        // Fail fast with a meaningful error message in case device doesn't support pVMs, before
+        // creating the temporary directory and files for the VM.
+        check_protected_vm_is_supported_if_requested(
+            config,
+            hypervisor_props::is_protected_vm_supported,
+        )?;
+
         // Allocating VM context checks the MANAGE_VIRTUAL_MACHINE permission.
         let (vm_context, cid, temporary_directory) = if cfg!(early) {
             self.create_early_vm_context(config)?
@@ -516,15 +713,27 @@ impl VirtualizationService {
         let device_tree_overlay = maybe_create_device_tree_overlay(config, &temporary_directory)?;
 
         let debug_config = DebugConfig::new(config);
-        let ramdump = if !uses_gki_kernel(config) && debug_config.is_ramdump_needed() {
-            Some(prepare_ramdump_file(&temporary_directory)?)
-        } else {
-            None
-        };
+        let ramdump_needed = !uses_gki_kernel(config) && debug_config.is_ramdump_needed();
 
         let state = &mut *self.state.lock().unwrap();
         let console_out_fd =
             clone_or_prepare_logger_fd(console_out_fd, format!("Console({})", cid))?;
+        let console_history = debug_config
+            .should_prepare_console_output()
+            .then(|| Arc::new(ConsoleHistory::default()));
+        let callbacks = VirtualMachineCallbacks::default();
+        let console_out_fd = match (console_out_fd, &console_history) {
+            (Some(fd), Some(history)) => {
+                let callbacks = callbacks.clone();
+                let on_closed = move || callbacks.notify_console_closed(cid);
+                Some(
+                    tee_into_history(fd, history.clone(), on_closed)
+                        .context("Failed to record console history")
+                        .or_service_specific_exception(-1)?,
+                )
+            }
+            (fd, _) => fd,
+        };
         let console_in_fd = console_in_fd.map(clone_file).transpose()?;
         let log_fd = clone_or_prepare_logger_fd(log_fd, format!("Log({})", cid))?;
 
@@ -561,12 +770,12 @@ impl VirtualizationService {
             .flat_map(|disk| disk.partitions.iter())
             .filter(|partition| {
                 if is_app_config {
-                    !is_safe_app_partition(&partition.label)
+                    !label_allowlist().is_safe_app_partition(&partition.label)
                 } else {
-                    !is_safe_raw_partition(&partition.label)
+                    !label_allowlist().is_safe_raw_partition(&partition.label)
                 }
             })
-            .try_for_each(check_label_for_partition)
+            .try_for_each(|partition| check_label_for_partition(partition, getfilecon))
             .or_service_specific_exception(-1)?;
 
         // Check if files for payloads and bases are NOT coming from /vendor and /odm, as they may
@@ -581,8 +790,6 @@ impl VirtualizationService {
             // In a protected VM, we require custom kernels to come from a trusted source
             // (b/237054515).
             check_label_for_kernel_files(&kernel, &initrd).or_service_specific_exception(-1)?;
-            // Fail fast with a meaningful error message in case device doesn't support pVMs.
-            check_protected_vm_is_supported()?;
         }
 
         let zero_filler_path = temporary_directory.join("zero.img");
@@ -704,6 +911,32 @@ impl VirtualizationService {
             .unwrap_or(Ok(UsbConfig { controller: false }))
             .or_binder_exception(ExceptionCode::BAD_PARCELABLE)?;
 
+        let platform_version = parse_platform_version_req(&config.platformVersion)?;
+        check_platform_version_is_satisfied(&platform_version, CROSVM_PLATFORM_VERSION)?;
+
+        if config.disableTombstones {
+            vm_context.global_context.disableTombstones()?;
+        }
+        vm_context
+            .global_context
+            .setGuestFileCallback(&GuestFileForwarder::new_binder(cid, callbacks.clone()))?;
+
+        let memory_mib = config
+            .memoryMib
+            .try_into()
+            .ok()
+            .and_then(NonZeroU32::new)
+            .unwrap_or(NonZeroU32::new(256).unwrap());
+        let (ramdump, ramdump_capture) = if ramdump_needed {
+            let max_size = max_ramdump_size(config, memory_mib)?;
+            let (ramdump, capture) = prepare_ramdump_file(&temporary_directory, max_size, cid)?;
+            (Some(ramdump), Some(capture))
+        } else {
+            (None, None)
+        };
+
+        let reserved_vsock_ports = bind_reserved_vsock_ports(cid, config, &callbacks)?;
+
         // Actually start the VM.
         let crosvm_config = CrosvmConfig {
             cid,
@@ -715,20 +948,16 @@ impl VirtualizationService {
             params: config.params.to_owned(),
             protected: *is_protected,
             debug_config,
-            memory_mib: config
-                .memoryMib
-                .try_into()
-                .ok()
-                .and_then(NonZeroU32::new)
-                .unwrap_or(NonZeroU32::new(256).unwrap()),
+            memory_mib,
             cpus,
             host_cpu_topology,
             console_out_fd,
             console_in_fd,
             log_fd,
             ramdump,
+            ramdump_capture,
             indirect_files,
-            platform_version: parse_platform_version_req(&config.platformVersion)?,
+            platform_version,
             detect_hangup: is_app_config,
             gdb_port,
             vfio_devices,
@@ -744,6 +973,7 @@ impl VirtualizationService {
             audio_config,
             no_balloon: config.noBalloon,
             usb_config,
+            ephemeral: config.ephemeral,
         };
         let instance = Arc::new(
             VmInstance::new(
@@ -752,16 +982,45 @@ impl VirtualizationService {
                 requester_uid,
                 requester_debug_pid,
                 vm_context,
+                callbacks,
+                console_history,
             )
             .with_context(|| format!("Failed to create VM with config {:?}", config))
             .with_log()
             .or_service_specific_exception(-1)?,
         );
         state.add_vm(Arc::downgrade(&instance));
-        Ok(VirtualMachine::create(instance))
+        Ok(VirtualMachine::create(instance, reserved_vsock_ports))
     }
 }
 
+/// Listens on the vsock ports reserved in `config` for connections from the VM with `cid`, which
+/// are passed to `callbacks`.
+fn bind_reserved_vsock_ports(
+    cid: Cid,
+    config: &VirtualMachineRawConfig,
+    callbacks: &VirtualMachineCallbacks,
+) -> binder::Result<ReservedVsockPorts> {
+    if config.reservedVsockPorts.is_empty() {
+        return Ok(ReservedVsockPorts::default());
+    }
+    let ports = config
+        .reservedVsockPorts
+        .iter()
+        .map(|&port| match u32::try_from(port) {
+            Ok(port) if port >= 1024 => Ok(port),
+            _ => Err(anyhow!("Can't reserve privileged or invalid vsock port {port}")),
+        })
+        .collect::<Result<Vec<_>>>()
+        .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+    let callbacks = callbacks.clone();
+    ReservedVsockPorts::bind(VMADDR_CID_HOST, &ports, cid, move |port, stream| {
+        callbacks.notify_vsock_connection(cid, port, stream)
+    })
+    .with_log()
+    .or_service_specific_exception(-1)
+}
+
 /// Returns whether a VM config represents a "custom" virtual machine, which requires the
 /// USE_CUSTOM_VIRTUAL_MACHINE.
 fn is_custom_config(config: &VirtualMachineConfig) -> bool {
@@ -890,6 +1149,15 @@ fn write_zero_filler(zero_filler_path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Returns whether `part` starts with the header of a QCOW2 image or of an instance partition.
+fn has_partition_header(part: &mut File) -> std::io::Result<bool> {
+    let mut header = Vec::new();
+    part.seek(SeekFrom::Start(0))?;
+    part.take(ANDROID_VM_INSTANCE_MAGIC.len().max(QCOW2_MAGIC.len()) as u64)
+        .read_to_end(&mut header)?;
+    Ok(header.starts_with(QCOW2_MAGIC) || header.starts_with(ANDROID_VM_INSTANCE_MAGIC.as_bytes()))
+}
+
 fn format_as_android_vm_instance(part: &mut dyn Write) -> std::io::Result<()> {
     part.write_all(ANDROID_VM_INSTANCE_MAGIC.as_bytes())?;
     part.write_all(&ANDROID_VM_INSTANCE_VERSION.to_le_bytes())?;
@@ -994,6 +1262,23 @@ fn assemble_disk_image(
     Ok(DiskFile { image, writable: disk.writable })
 }
 
+/// Given the configuration for a disk image, returns the layout of the composite disk image that
+/// `assemble_disk_image` would assemble for it, if any.
+fn describe_disk_image(disk: &DiskImage) -> binder::Result<DiskLayout> {
+    if disk.partitions.is_empty() {
+        return Ok(DiskLayout { partitions: vec![] });
+    }
+    if disk.image.is_some() {
+        return Err(anyhow!("DiskImage contains both image and partitions"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+    }
+    let partitions = describe_composite_image(&disk.partitions)
+        .with_context(|| format!("Failed to plan composite disk image with config {:?}", disk))
+        .with_log()
+        .or_service_specific_exception(-1)?;
+    Ok(DiskLayout { partitions })
+}
+
 fn append_kernel_param(param: &str, vm_config: &mut VirtualMachineRawConfig) {
     if let Some(ref mut params) = vm_config.params {
         params.push(' ');
@@ -1127,6 +1412,8 @@ fn load_app_config(
     vm_config.cpuTopology = config.cpuTopology;
     vm_config.hugePages = config.hugePages || vm_payload_config.hugepages;
     vm_config.boostUclamp = config.boostUclamp;
+    vm_config.disableTombstones = config.disableTombstones;
+    vm_config.ephemeral = config.ephemeral;
 
     // Microdroid takes additional init ramdisk & (optionally) storage image
     add_microdroid_system_images(config, instance_file, storage_image, os_name, &mut vm_config)?;
@@ -1253,6 +1540,11 @@ fn check_permission(perm: &str) -> binder::Result<()> {
     }
 }
 
+/// Check whether the caller of the current Binder method is allowed to debug VMs
+fn check_debug_access() -> binder::Result<()> {
+    check_permission("android.permission.DEBUG_VIRTUAL_MACHINE")
+}
+
 /// Check whether the caller of the current Binder method is allowed to manage VMs
 fn check_manage_access() -> binder::Result<()> {
     check_permission("android.permission.MANAGE_VIRTUAL_MACHINE")
@@ -1263,48 +1555,19 @@ fn check_use_custom_virtual_machine() -> binder::Result<()> {
     check_permission("android.permission.USE_CUSTOM_VIRTUAL_MACHINE")
 }
 
-/// Return whether a partition is exempt from selinux label checks, because we know that it does
-/// not contain code and is likely to be generated in an app-writable directory.
-fn is_safe_app_partition(label: &str) -> bool {
-    // See add_microdroid_system_images & add_microdroid_payload_images in payload.rs.
-    label == "vm-instance"
-        || label == "encryptedstore"
-        || label == "microdroid-apk-idsig"
-        || label == "payload-metadata"
-        || label.starts_with("extra-idsig-")
-}
-
-/// Returns whether a partition with the given label is safe for a raw config VM.
-fn is_safe_raw_partition(label: &str) -> bool {
-    label == "vm-instance"
-}
-
-/// Check that a file SELinux label is acceptable.
-///
-/// We only want to allow code in a VM to be sourced from places that apps, and the
-/// system or vendor, do not have write access to.
-///
-/// Note that sepolicy must also grant read access for these types to both virtualization
-/// service and crosvm.
-///
-/// App private data files are deliberately excluded, to avoid arbitrary payloads being run on
-/// user devices (W^X).
+/// Check that a file SELinux label is acceptable, according to the label allowlist of the device.
 fn check_label_is_allowed(context: &SeContext) -> Result<()> {
-    match context.selinux_type()? {
-        | "apk_data_file" // APKs of an installed app
-        | "shell_data_file" // test files created via adb shell
-        | "staging_data_file" // updated/staged APEX images
-        | "system_file" // immutable dm-verity protected partition
-        | "virtualizationservice_data_file" // files created by VS / VirtMgr
-        | "vendor_microdroid_file" // immutable dm-verity protected partition (/vendor/etc/avf/microdroid/.*)
-         => Ok(()),
-        _ => bail!("Label {} is not allowed", context),
-    }
+    label_allowlist().check_label_is_allowed(context)
 }
 
-fn check_label_for_partition(partition: &Partition) -> Result<()> {
+/// Check that the SELinux label of a partition image is allowed, where `get_context` returns the
+/// context of the image file, i.e. `getfilecon` outside of tests.
+fn check_label_for_partition(
+    partition: &Partition,
+    get_context: impl FnOnce(&File) -> Result<SeContext>,
+) -> Result<()> {
     let file = partition.image.as_ref().unwrap().as_ref();
-    check_label_is_allowed(&getfilecon(file)?)
+    check_label_is_allowed(&get_context(file)?)
         .with_context(|| format!("Partition {} invalid", &partition.label))
 }
 
@@ -1325,11 +1588,33 @@ fn check_label_for_file(file: &File, name: &str) -> Result<()> {
 #[derive(Debug)]
 struct VirtualMachine {
     instance: Arc<VmInstance>,
+    /// Listeners for the vsock ports reserved in the config, closed along with the VM.
+    _reserved_vsock_ports: ReservedVsockPorts,
 }
 
 impl VirtualMachine {
-    fn create(instance: Arc<VmInstance>) -> Strong<dyn IVirtualMachine> {
-        BnVirtualMachine::new_binder(VirtualMachine { instance }, BinderFeatures::default())
+    fn create(
+        instance: Arc<VmInstance>,
+        reserved_vsock_ports: ReservedVsockPorts,
+    ) -> Strong<dyn IVirtualMachine> {
+        BnVirtualMachine::new_binder(
+            VirtualMachine { instance, _reserved_vsock_ports: reserved_vsock_ports },
+            BinderFeatures::default(),
+        )
+    }
+
+    /// Returns the vsock address of `port` of the VM, which must be running. Privileged ports
+    /// aren't allowed.
+    fn vsock_address(&self, port: i32) -> binder::Result<(Cid, u32)> {
+        if !matches!(&*self.instance.vm_state.lock().unwrap(), VmState::Running { .. }) {
+            return Err(anyhow!("VM is not running")).or_service_specific_exception(-1);
+        }
+        let port = port as u32;
+        if port < 1024 {
+            return Err(anyhow!("Can't connect to privileged port {port}"))
+                .or_service_specific_exception(-1);
+        }
+        Ok((self.instance.cid, port))
     }
 }
 
@@ -1368,6 +1653,15 @@ impl IVirtualMachine for VirtualMachine {
             .or_service_specific_exception(-1)
     }
 
+    fn cancelStart(&self) -> binder::Result<()> {
+        self.instance
+            .cancel_start()
+            .with_context(|| format!("Error cancelling start of VM with CID {}", self.instance.cid))
+            .with_log()
+            .or_service_specific_exception(-1)?;
+        Ok(())
+    }
+
     fn stop(&self) -> binder::Result<()> {
         self.instance
             .kill()
@@ -1376,6 +1670,17 @@ impl IVirtualMachine for VirtualMachine {
             .or_service_specific_exception(-1)
     }
 
+    fn shutdown(&self, timeout_ms: i64) -> binder::Result<()> {
+        let timeout_ms = u64::try_from(timeout_ms)
+            .map_err(|_| anyhow!("Invalid timeout {timeout_ms}ms"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        self.instance
+            .shutdown(Duration::from_millis(timeout_ms))
+            .with_context(|| format!("Error shutting down VM with CID {}", self.instance.cid))
+            .with_log()
+            .or_service_specific_exception(-1)
+    }
+
     fn getMemoryBalloon(&self) -> binder::Result<i64> {
         let balloon = self
             .instance
@@ -1394,21 +1699,41 @@ impl IVirtualMachine for VirtualMachine {
             .or_service_specific_exception(-1)
     }
 
+    fn setMemoryBalloonFloor(&self, num_bytes: i64) -> binder::Result<()> {
+        let num_bytes = u64::try_from(num_bytes)
+            .map_err(|_| anyhow!("Invalid balloon floor {num_bytes}"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        self.instance.set_memory_balloon_floor(num_bytes);
+        Ok(())
+    }
+
     fn connectVsock(&self, port: i32) -> binder::Result<ParcelFileDescriptor> {
-        if !matches!(&*self.instance.vm_state.lock().unwrap(), VmState::Running { .. }) {
-            return Err(anyhow!("VM is not running")).or_service_specific_exception(-1);
-        }
-        let port = port as u32;
-        if port < 1024 {
-            return Err(anyhow!("Can't connect to privileged port {port}"))
-                .or_service_specific_exception(-1);
-        }
-        let stream = VsockStream::connect_with_cid_port(self.instance.cid, port)
+        let (cid, port) = self.vsock_address(port)?;
+        let stream = VsockStream::connect_with_cid_port(cid, port)
             .context("Failed to connect")
             .or_service_specific_exception(-1)?;
         Ok(vsock_stream_to_pfd(stream))
     }
 
+    fn connectVsockWithTimeout(
+        &self,
+        port: i32,
+        timeout_ms: i64,
+    ) -> binder::Result<ParcelFileDescriptor> {
+        let (cid, port) = self.vsock_address(port)?;
+        let timeout_ms = u64::try_from(timeout_ms)
+            .map_err(|_| anyhow!("Invalid timeout {timeout_ms}ms"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        let timeout = Duration::from_millis(timeout_ms).min(MAX_CONNECT_VSOCK_TIMEOUT);
+        let is_running =
+            || matches!(&*self.instance.vm_state.lock().unwrap(), VmState::Running { .. });
+        let stream =
+            retry_while(timeout, is_running, || VsockStream::connect_with_cid_port(cid, port))
+                .with_context(|| format!("Failed to connect within {timeout:?}"))
+                .or_service_specific_exception(-1)?;
+        Ok(vsock_stream_to_pfd(stream))
+    }
+
     fn setHostConsoleName(&self, ptsname: &str) -> binder::Result<()> {
         self.instance.vm_context.global_context.setHostConsoleName(ptsname)
     }
@@ -1428,6 +1753,41 @@ impl IVirtualMachine for VirtualMachine {
             .with_log()
             .or_service_specific_exception(-1)
     }
+
+    fn getBootMetrics(&self) -> binder::Result<BootMetrics> {
+        let boot_times = self
+            .instance
+            .vm_metric
+            .lock()
+            .unwrap()
+            .boot_times()
+            .with_context(|| {
+                format!("Error getting boot metrics of VM with CID {}", self.instance.cid)
+            })
+            .or_binder_exception(ExceptionCode::ILLEGAL_STATE)?;
+        Ok(BootMetrics {
+            bootTimeMillis: boot_times.boot_time.as_millis() as i64,
+            payloadReadyLatencyMillis: boot_times.payload_ready_latency.as_millis() as i64,
+        })
+    }
+
+    fn getLastError(&self) -> binder::Result<Option<PayloadError>> {
+        let last_error = self.instance.last_payload_error.get();
+        Ok(last_error.map(|(error_code, message)| PayloadError { errorCode: error_code, message }))
+    }
+
+    fn getVmStats(&self) -> binder::Result<VmStats> {
+        check_debug_access()?;
+        let usage = self
+            .instance
+            .resource_usage()
+            .with_context(|| format!("Error getting stats of VM with CID {}", self.instance.cid))
+            .or_binder_exception(ExceptionCode::ILLEGAL_STATE)?;
+        Ok(VmStats {
+            rssBytes: usage.rss_bytes.try_into().unwrap_or(i64::MAX),
+            cpuTimeMillis: usage.cpu_time.as_millis().try_into().unwrap_or(i64::MAX),
+        })
+    }
 }
 
 impl Drop for VirtualMachine {
@@ -1440,9 +1800,9 @@ impl Drop for VirtualMachine {
 }
 
 /// A set of Binders to be called back in response to various events on the VM, such as when it
-/// dies.
-#[derive(Debug, Default)]
-pub struct VirtualMachineCallbacks(Mutex<Vec<Strong<dyn IVirtualMachineCallback>>>);
+/// dies. Clones share the same set.
+#[derive(Clone, Debug, Default)]
+pub struct VirtualMachineCallbacks(Arc<Mutex<Vec<Strong<dyn IVirtualMachineCallback>>>>);
 
 impl VirtualMachineCallbacks {
     /// Call all registered callbacks to notify that the payload has started.
@@ -1485,6 +1845,66 @@ impl VirtualMachineCallbacks {
         }
     }
 
+    /// Call all registered callbacks to say that the VM is approaching or has exceeded a resource
+    /// limit.
+    pub fn notify_resource_limit(&self, cid: Cid, resource: &str, limit: u64, observed: u64) {
+        let callbacks = &*self.0.lock().unwrap();
+        for callback in callbacks {
+            if let Err(e) = callback.onResourceLimit(
+                cid as i32,
+                resource,
+                limit.try_into().unwrap_or(i64::MAX),
+                observed.try_into().unwrap_or(i64::MAX),
+            ) {
+                error!("Error notifying resource limit event from VM CID {}: {:?}", cid, e);
+            }
+        }
+    }
+
+    /// Call all registered callbacks to say that the console output of the VM has ended.
+    pub fn notify_console_closed(&self, cid: Cid) {
+        let callbacks = &*self.0.lock().unwrap();
+        for callback in callbacks {
+            if let Err(e) = callback.onConsoleClosed(cid as i32) {
+                error!("Error notifying console close event from VM CID {}: {:?}", cid, e);
+            }
+        }
+    }
+
+    /// Call all registered callbacks to say that the console window of the VM was resized.
+    pub fn notify_console_resize(&self, cid: Cid, rows: u16, cols: u16) {
+        let callbacks = &*self.0.lock().unwrap();
+        for callback in callbacks {
+            if let Err(e) = callback.onConsoleResize(cid as i32, rows.into(), cols.into()) {
+                error!("Error notifying console resize event from VM CID {}: {:?}", cid, e);
+            }
+        }
+    }
+
+    /// Pass a connection from the VM to a reserved vsock port to a registered callback. As the
+    /// stream should only have one owner, it goes to the first callback it can be sent to.
+    pub fn notify_vsock_connection(&self, cid: Cid, port: u32, stream: VsockStream) {
+        let stream = vsock_stream_to_pfd(stream);
+        let callbacks = &*self.0.lock().unwrap();
+        for callback in callbacks {
+            match callback.onVsockConnection(cid as i32, port as i32, &stream) {
+                Ok(()) => return,
+                Err(e) => error!("Error passing vsock connection from VM CID {}: {:?}", cid, e),
+            }
+        }
+        warn!("No callback took the connection from VM CID {} to vsock port {}", cid, port);
+    }
+
+    /// Call all registered callbacks to pass them a file pushed by the VM.
+    pub fn notify_guest_file_received(&self, cid: Cid, file: &ParcelFileDescriptor) {
+        let callbacks = &*self.0.lock().unwrap();
+        for callback in callbacks {
+            if let Err(e) = callback.onGuestFileReceived(cid as i32, file) {
+                error!("Error passing file from VM CID {}: {:?}", cid, e);
+            }
+        }
+    }
+
     /// Call all registered callbacks to say that the VM has died.
     pub fn callback_on_died(&self, cid: Cid, reason: DeathReason) {
         let callbacks = &*self.0.lock().unwrap();
@@ -1534,8 +1954,29 @@ impl State {
     }
 }
 
+/// Checks whether the given CID is assigned to one of the VMs in `state` or, unless `early`, to a VM
+/// known to the global service, as reported by `is_cid_in_use_globally`.
+///
+/// Early VMs are only known to the early virtmgr which created them, and early virtmgr can't
+/// connect to the global service. The other VMs are known to the global service, which also knows
+/// about the CIDs reserved for VMs which are still being created.
+fn is_cid_in_use(
+    state: &Mutex<State>,
+    cid: Cid,
+    early: bool,
+    is_cid_in_use_globally: impl FnOnce() -> binder::Result<bool>,
+) -> binder::Result<bool> {
+    if state.lock().unwrap().get_vm(cid).is_some() {
+        return Ok(true);
+    }
+    if early {
+        return Ok(false);
+    }
+    is_cid_in_use_globally()
+}
+
 /// Gets the `VirtualMachineState` of the given `VmInstance`.
-fn get_state(instance: &VmInstance) -> VirtualMachineState {
+pub(crate) fn get_state(instance: &VmInstance) -> VirtualMachineState {
     match &*instance.vm_state.lock().unwrap() {
         VmState::NotStarted { .. } => VirtualMachineState::NOT_STARTED,
         VmState::Running { .. } => match instance.payload_state() {
@@ -1571,6 +2012,28 @@ fn vsock_stream_to_pfd(stream: VsockStream) -> ParcelFileDescriptor {
     ParcelFileDescriptor::new(f)
 }
 
+/// Passes the files which the VM pushes to the host, as received by virtualizationservice, to the
+/// callbacks of the VM.
+struct GuestFileForwarder {
+    cid: Cid,
+    callbacks: VirtualMachineCallbacks,
+}
+
+impl GuestFileForwarder {
+    fn new_binder(cid: Cid, callbacks: VirtualMachineCallbacks) -> Strong<dyn IGuestFileCallback> {
+        BnGuestFileCallback::new_binder(Self { cid, callbacks }, BinderFeatures::default())
+    }
+}
+
+impl Interface for GuestFileForwarder {}
+
+impl IGuestFileCallback for GuestFileForwarder {
+    fn onGuestFileReceived(&self, file: &ParcelFileDescriptor) -> binder::Result<()> {
+        self.callbacks.notify_guest_file_received(self.cid, file);
+        Ok(())
+    }
+}
+
 /// Parses the platform version requirement string.
 fn parse_platform_version_req(s: &str) -> binder::Result<VersionReq> {
     VersionReq::parse(s)
@@ -1578,17 +2041,48 @@ fn parse_platform_version_req(s: &str) -> binder::Result<VersionReq> {
         .or_binder_exception(ExceptionCode::BAD_PARCELABLE)
 }
 
-/// Create the empty ramdump file
-fn prepare_ramdump_file(temporary_directory: &Path) -> binder::Result<File> {
-    // `ramdump_write` is sent to crosvm and will be the backing store for the /dev/hvc1 where
-    // VM will emit ramdump to. `ramdump_read` will be sent back to the client (i.e. the VM
-    // owner) for readout.
+/// Checks that `platform_version`, which is the version of the platform implemented by the host,
+/// satisfies the requirement of the VM, as `validate_config` will when the VM is started.
+fn check_platform_version_is_satisfied(
+    requirement: &VersionReq,
+    platform_version: &str,
+) -> binder::Result<()> {
+    check_platform_version(requirement, platform_version)
+        .with_log()
+        .or_binder_exception(ExceptionCode::UNSUPPORTED_OPERATION)
+}
+
+/// Returns the maximum size in bytes of the ramdump of a VM with `memory_mib` of memory.
+fn max_ramdump_size(
+    config: &VirtualMachineRawConfig,
+    memory_mib: NonZeroU32,
+) -> binder::Result<u64> {
+    match config.maxRamdumpSizeBytes {
+        0 => Ok((u64::from(memory_mib.get()) + 1) * (1 << 20)),
+        size => u64::try_from(size)
+            .with_context(|| format!("Invalid maximum ramdump size {size}"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT),
+    }
+}
+
+/// Create the empty ramdump file, and the pipe through which it is written, up to `max_size` bytes
+fn prepare_ramdump_file(
+    temporary_directory: &Path,
+    max_size: u64,
+    cid: Cid,
+) -> binder::Result<(File, JoinHandle<()>)> {
+    // The write end of the pipe is sent to crosvm and will be the backing store for the /dev/hvc1
+    // where VM will emit ramdump to. What goes through it is written to the ramdump file, which
+    // will be sent to tombstoned once the VM is gone.
     let ramdump_path = temporary_directory.join("ramdump");
     let ramdump = File::create(ramdump_path)
         .context("Failed to prepare ramdump file")
         .with_log()
         .or_service_specific_exception(-1)?;
-    Ok(ramdump)
+    capture_ramdump(ramdump, max_size, cid)
+        .context("Failed to capture ramdump")
+        .with_log()
+        .or_service_specific_exception(-1)
 }
 
 fn is_protected(config: &VirtualMachineConfig) -> bool {
@@ -1681,9 +2175,39 @@ fn check_no_extra_kernel_cmdline_params(config: &VirtualMachineConfig) -> binder
     Ok(())
 }
 
-fn check_protected_vm_is_supported() -> binder::Result<()> {
-    let is_pvm_supported =
-        hypervisor_props::is_protected_vm_supported().or_service_specific_exception(-1)?;
+/// Names of the kernel command line parameters which app VMs may set with
+/// `extraKernelCmdlineParams`. They only control where and how much the kernel logs, so they can't
+/// change what runs in the VM, e.g. as `init=` would. Raw configs, which only privileged callers can
+/// use, may set any parameter.
+const ALLOWED_EXTRA_KERNEL_CMDLINE_PARAMS: &[&str] =
+    &["console", "earlycon", "ignore_loglevel", "loglevel", "printk.devkmsg"];
+
+/// Checks that the extra kernel command line parameters of an app config, each of which may hold
+/// several space-separated parameters, are all in `ALLOWED_EXTRA_KERNEL_CMDLINE_PARAMS`.
+fn check_extra_kernel_cmdline_params_are_allowed(
+    config: &VirtualMachineConfig,
+) -> binder::Result<()> {
+    let VirtualMachineConfig::AppConfig(config) = config else { return Ok(()) };
+    let Some(custom_config) = &config.customConfig else { return Ok(()) };
+    let params = custom_config.extraKernelCmdlineParams.iter().flat_map(|p| p.split_whitespace());
+    for param in params {
+        let name = param.split_once('=').map_or(param, |(name, _)| name);
+        if !ALLOWED_EXTRA_KERNEL_CMDLINE_PARAMS.contains(&name) {
+            return Err(anyhow!(
+                "Kernel command line parameter {param:?} isn't allowed for app VMs"
+            ))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the device supports protected VMs, using `is_protected_vm_supported` to probe the
+/// hypervisor capabilities.
+fn check_protected_vm_is_supported(
+    is_protected_vm_supported: impl FnOnce() -> Result<bool>,
+) -> binder::Result<()> {
+    let is_pvm_supported = is_protected_vm_supported().or_service_specific_exception(-1)?;
     if is_pvm_supported {
         Ok(())
     } else {
@@ -1692,6 +2216,16 @@ fn check_protected_vm_is_supported() -> binder::Result<()> {
     }
 }
 
+fn check_protected_vm_is_supported_if_requested(
+    config: &VirtualMachineConfig,
+    is_protected_vm_supported: impl FnOnce() -> Result<bool>,
+) -> binder::Result<()> {
+    if is_protected(config) {
+        check_protected_vm_is_supported(is_protected_vm_supported)?;
+    }
+    Ok(())
+}
+
 fn check_config_features(config: &VirtualMachineConfig) -> binder::Result<()> {
     if !cfg!(vendor_modules) {
         check_no_vendor_modules(config)?;
@@ -1829,6 +2363,7 @@ impl IVirtualMachineService for VirtualMachineService {
             info!("VM with CID {} encountered an error", cid);
             vm.update_payload_state(PayloadState::Finished)
                 .or_binder_exception(ExceptionCode::ILLEGAL_STATE)?;
+            vm.last_payload_error.record(error_code, message);
             vm.callbacks.notify_error(cid, error_code, message);
             Ok(())
         } else {
@@ -1837,6 +2372,25 @@ impl IVirtualMachineService for VirtualMachineService {
         }
     }
 
+    fn notifyConsoleResize(&self, rows: i32, cols: i32) -> binder::Result<()> {
+        let cid = self.cid;
+        let (rows, cols) = match (u16::try_from(rows), u16::try_from(cols)) {
+            (Ok(rows), Ok(cols)) if rows > 0 && cols > 0 => (rows, cols),
+            _ => {
+                return Err(anyhow!("Invalid console size {rows}x{cols}"))
+                    .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)
+            }
+        };
+        if let Some(vm) = self.state.lock().unwrap().get_vm(cid) {
+            debug!("VM with CID {} resized its console to {}x{}", cid, rows, cols);
+            vm.callbacks.notify_console_resize(cid, rows, cols);
+            Ok(())
+        } else {
+            error!("notifyConsoleResize is called from an unknown CID {}", cid);
+            Err(anyhow!("cannot find a VM with CID {}", cid)).or_service_specific_exception(-1)
+        }
+    }
+
     fn getSecretkeeper(&self) -> binder::Result<Strong<dyn ISecretkeeper>> {
         if !is_secretkeeper_supported() {
             return Err(StatusCode::NAME_NOT_FOUND)?;
@@ -1848,6 +2402,24 @@ impl IVirtualMachineService for VirtualMachineService {
     fn requestAttestation(&self, csr: &[u8], test_mode: bool) -> binder::Result<Vec<Certificate>> {
         GLOBAL_SERVICE.requestAttestation(csr, get_calling_uid() as i32, test_mode)
     }
+
+    fn requestMemory(&self, mib: i32) -> binder::Result<i32> {
+        let cid = self.cid;
+        let mib = u32::try_from(mib)
+            .map_err(|_| anyhow!("Invalid memory request: {mib} MiB"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        if let Some(vm) = self.state.lock().unwrap().get_vm(cid) {
+            let granted = vm
+                .request_memory(mib)
+                .with_context(|| format!("Error granting memory to VM with CID {}", cid))
+                .with_log()
+                .or_service_specific_exception(-1)?;
+            Ok(granted.try_into().unwrap())
+        } else {
+            error!("requestMemory is called from an unknown CID {}", cid);
+            Err(anyhow!("cannot find a VM with CID {}", cid)).or_service_specific_exception(-1)
+        }
+    }
 }
 
 fn is_secretkeeper_supported() -> bool {
@@ -1999,6 +2571,8 @@ fn find_early_vm_for_partition(partition: &str, name: &str) -> Result<EarlyVm> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use android_system_virtualizationservice::aidl::android::system::virtualizationservice::IVirtualMachineCallback::BnVirtualMachineCallback;
+    use android_system_virtualizationservice::aidl::android::system::virtualizationservice::VirtualMachineAppConfig::CustomConfig::CustomConfig;
 
     #[test]
     fn test_is_allowed_label_for_partition() -> Result<()> {
@@ -2025,6 +2599,95 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_check_label_for_partition_uses_context_of_image() -> Result<()> {
+        let image = tempfile::tempfile()?;
+        let image_fd = image.as_raw_fd();
+        let partition = Partition {
+            label: "payload".to_owned(),
+            image: Some(ParcelFileDescriptor::new(image)),
+            writable: false,
+            guid: None,
+        };
+        let context_of_image = |label: &'static str| {
+            move |file: &File| {
+                assert_eq!(image_fd, file.as_raw_fd());
+                SeContext::new(label)
+            }
+        };
+
+        check_label_for_partition(&partition, context_of_image("u:object_r:system_file:s0"))?;
+
+        let error =
+            check_label_for_partition(&partition, context_of_image("u:object_r:app_data_file:s0"))
+                .expect_err("label of app data should be disallowed");
+        assert_eq!("Partition payload invalid", error.to_string());
+
+        let error = check_label_for_partition(&partition, |_| Err(anyhow!("no context")))
+            .expect_err("failure to get the context should be reported");
+        assert_eq!("no context", error.to_string());
+        Ok(())
+    }
+
+    fn raw_config(protected_vm: bool) -> VirtualMachineConfig {
+        VirtualMachineConfig::RawConfig(VirtualMachineRawConfig {
+            protectedVm: protected_vm,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_protected_vm_fails_early_if_unsupported() {
+        let result = check_protected_vm_is_supported_if_requested(&raw_config(true), || Ok(false));
+
+        let status = result.expect_err("should fail");
+        assert_eq!(ExceptionCode::UNSUPPORTED_OPERATION, status.exception_code());
+    }
+
+    #[test]
+    fn test_protected_vm_passes_if_supported() {
+        let result = check_protected_vm_is_supported_if_requested(&raw_config(true), || Ok(true));
+
+        assert!(result.is_ok(), "should pass, got {:?}", result);
+    }
+
+    #[test]
+    fn test_non_protected_vm_does_not_probe_pvm_support() {
+        let result = check_protected_vm_is_supported_if_requested(&raw_config(false), || {
+            panic!("pVM support should not be probed for a non-protected VM")
+        });
+
+        assert!(result.is_ok(), "should pass, got {:?}", result);
+    }
+
+    #[test]
+    fn empty_file_is_initialized_as_partition() -> Result<()> {
+        let mut image = tempfile::tempfile()?;
+
+        initialize_writable_partition(&mut image, 1, PartitionType::ANDROID_VM_INSTANCE, false)?;
+
+        assert_eq!(PARTITION_GRANULARITY_BYTES, image.metadata()?.len());
+        assert!(has_partition_header(&mut image)?);
+        Ok(())
+    }
+
+    #[test]
+    fn formatted_partition_is_only_reinitialized_with_force() -> Result<()> {
+        for header in [ANDROID_VM_INSTANCE_MAGIC.as_bytes(), QCOW2_MAGIC] {
+            let mut image = tempfile::tempfile()?;
+            image.write_all(header)?;
+            image.write_all(b"data to keep")?;
+
+            let ret = initialize_writable_partition(&mut image, 4096, PartitionType::RAW, false);
+            assert_eq!(ExceptionCode::ILLEGAL_ARGUMENT, ret.unwrap_err().exception_code());
+            assert!(has_partition_header(&mut image)?);
+
+            initialize_writable_partition(&mut image, 4096, PartitionType::RAW, true)?;
+            assert!(!has_partition_header(&mut image)?);
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_create_or_update_idsig_file_empty_apk() -> Result<()> {
         let apk = tempfile::tempfile().unwrap();
@@ -2131,6 +2794,60 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_create_or_update_idsig_is_never_seen_partially_written() -> Result<()> {
+        use std::os::unix::fs::FileExt;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let mut apk = File::open("/system/priv-app/Shell/Shell.apk").unwrap();
+        let mut expected_idsig = tempfile::tempfile().unwrap();
+        create_or_update_idsig_file(
+            &ParcelFileDescriptor::new(apk.try_clone()?),
+            &ParcelFileDescriptor::new(expected_idsig.try_clone()?),
+        )?;
+        let mut expected_idsig_bytes = Vec::new();
+        expected_idsig.rewind()?;
+        expected_idsig.read_to_end(&mut expected_idsig_bytes)?;
+
+        // Start from stale contents longer than the new idsig, so that anything else read back
+        // comes from the idsig being truncated or partially written.
+        let idsig = tempfile::NamedTempFile::new()?;
+        let stale_idsig_bytes = vec![0xff; expected_idsig_bytes.len() * 2];
+        idsig.as_file().write_all_at(&stale_idsig_bytes, 0)?;
+
+        let idsig_path = idsig.path().to_owned();
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = std::thread::spawn({
+            let done = done.clone();
+            let expected_idsig_bytes = expected_idsig_bytes.clone();
+            move || {
+                let mut snapshots = 0;
+                while !done.load(Ordering::Relaxed) {
+                    let contents = std::fs::read(&idsig_path).unwrap();
+                    assert!(
+                        contents == stale_idsig_bytes || contents == expected_idsig_bytes,
+                        "read {} bytes of idsig mid-update",
+                        contents.len()
+                    );
+                    snapshots += 1;
+                }
+                snapshots
+            }
+        });
+
+        apk.rewind()?;
+        create_or_update_idsig_file(
+            &ParcelFileDescriptor::new(apk),
+            &ParcelFileDescriptor::new(idsig.reopen()?),
+        )?;
+        done.store(true, Ordering::Relaxed);
+
+        assert!(reader.join().unwrap() > 0);
+        assert_eq!(std::fs::read(idsig.path())?, expected_idsig_bytes);
+        Ok(())
+    }
+
     #[test]
     fn test_append_kernel_param_first_param() {
         let mut vm_config = VirtualMachineRawConfig { ..Default::default() };
@@ -2146,6 +2863,144 @@ mod tests {
         assert_eq!(vm_config.params, Some("foo=5 bar=42".to_owned()))
     }
 
+    #[test]
+    fn test_unsatisfied_platform_version_is_rejected() {
+        let requirement = parse_platform_version_req("~2.0.0").unwrap();
+
+        let result = check_platform_version_is_satisfied(&requirement, "1.0.0");
+
+        let status = result.expect_err("should fail");
+        assert_eq!(ExceptionCode::UNSUPPORTED_OPERATION, status.exception_code());
+    }
+
+    #[test]
+    fn test_satisfied_platform_version_is_accepted() {
+        let requirement = parse_platform_version_req("~1.0.0").unwrap();
+
+        let result = check_platform_version_is_satisfied(&requirement, "1.0.3");
+
+        assert!(result.is_ok(), "should pass, got {:?}", result);
+    }
+
+    /// Records the console resize events it is called back with.
+    #[derive(Clone, Default)]
+    struct ConsoleResizeRecorder(Arc<Mutex<Vec<(i32, i32, i32)>>>);
+
+    impl Interface for ConsoleResizeRecorder {}
+
+    impl IVirtualMachineCallback for ConsoleResizeRecorder {
+        fn onPayloadStarted(&self, _cid: i32) -> binder::Result<()> {
+            Ok(())
+        }
+        fn onPayloadReady(&self, _cid: i32) -> binder::Result<()> {
+            Ok(())
+        }
+        fn onPayloadFinished(&self, _cid: i32, _exit_code: i32) -> binder::Result<()> {
+            Ok(())
+        }
+        fn onError(&self, _cid: i32, _error_code: ErrorCode, _message: &str) -> binder::Result<()> {
+            Ok(())
+        }
+        fn onResourceLimit(&self, _: i32, _: &str, _: i64, _: i64) -> binder::Result<()> {
+            Ok(())
+        }
+        fn onConsoleClosed(&self, _cid: i32) -> binder::Result<()> {
+            Ok(())
+        }
+        fn onConsoleResize(&self, cid: i32, rows: i32, cols: i32) -> binder::Result<()> {
+            self.0.lock().unwrap().push((cid, rows, cols));
+            Ok(())
+        }
+        fn onVsockConnection(
+            &self,
+            _: i32,
+            _: i32,
+            _: &ParcelFileDescriptor,
+        ) -> binder::Result<()> {
+            Ok(())
+        }
+        fn onGuestFileReceived(&self, _: i32, _: &ParcelFileDescriptor) -> binder::Result<()> {
+            Ok(())
+        }
+        fn onDied(&self, _cid: i32, _reason: DeathReason) -> binder::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_console_resize_is_notified_to_all_callbacks() {
+        let callbacks = VirtualMachineCallbacks::default();
+        let recorders = [ConsoleResizeRecorder::default(), ConsoleResizeRecorder::default()];
+        for recorder in &recorders {
+            callbacks.add(BnVirtualMachineCallback::new_binder(
+                recorder.clone(),
+                BinderFeatures::default(),
+            ));
+        }
+
+        callbacks.notify_console_resize(2049, 24, 80);
+        callbacks.notify_console_resize(2049, 50, 132);
+
+        for recorder in &recorders {
+            assert_eq!(vec![(2049, 24, 80), (2049, 50, 132)], *recorder.0.lock().unwrap());
+        }
+    }
+
+    fn app_config_with_kernel_params(params: &[&str]) -> VirtualMachineConfig {
+        VirtualMachineConfig::AppConfig(VirtualMachineAppConfig {
+            customConfig: Some(CustomConfig {
+                extraKernelCmdlineParams: params.iter().map(|p| p.to_string()).collect(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_allowed_extra_kernel_cmdline_params_are_accepted() {
+        let config =
+            app_config_with_kernel_params(&["earlycon=uart8250,mmio,0x3f8", "loglevel=8 console"]);
+
+        let result = check_extra_kernel_cmdline_params_are_allowed(&config);
+
+        assert!(result.is_ok(), "should pass, got {:?}", result);
+    }
+
+    #[test]
+    fn test_disallowed_extra_kernel_cmdline_params_are_rejected() {
+        for params in [&["init=/bin/sh"][..], &["loglevel=8 init=/bin/sh"], &["loglevel=8", "--"]] {
+            let config = app_config_with_kernel_params(params);
+
+            let result = check_extra_kernel_cmdline_params_are_allowed(&config);
+
+            let status = result.expect_err("should fail");
+            assert_eq!(ExceptionCode::ILLEGAL_ARGUMENT, status.exception_code(), "{params:?}");
+        }
+    }
+
+    #[test]
+    fn test_raw_config_kernel_params_are_not_restricted() {
+        let config = VirtualMachineConfig::RawConfig(VirtualMachineRawConfig {
+            params: Some("init=/bin/sh".to_owned()),
+            ..Default::default()
+        });
+
+        assert!(check_extra_kernel_cmdline_params_are_allowed(&config).is_ok());
+    }
+
+    #[test]
+    fn test_max_ramdump_size() {
+        let memory_mib = NonZeroU32::new(256).unwrap();
+        let default_config = VirtualMachineRawConfig::default();
+        assert_eq!(max_ramdump_size(&default_config, memory_mib).unwrap(), 257 << 20);
+
+        let config = VirtualMachineRawConfig { maxRamdumpSizeBytes: 4096, ..Default::default() };
+        assert_eq!(max_ramdump_size(&config, memory_mib).unwrap(), 4096);
+
+        let config = VirtualMachineRawConfig { maxRamdumpSizeBytes: -1, ..Default::default() };
+        assert!(max_ramdump_size(&config, memory_mib).is_err());
+    }
+
     fn test_extract_os_name_from_config_path(
         path: &Path,
         expected_result: Option<&str>,
@@ -2212,6 +3067,21 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_ephemeral_early_vm_temporary_directory_is_removed() -> Result<()> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let temp_dir = tmp_dir.path().join("2");
+        let context = EarlyVmContext::new(2, temp_dir.clone())?;
+        std::fs::write(temp_dir.join("composite-0.img"), b"")?;
+        std::fs::create_dir(temp_dir.join("apexes"))?;
+
+        context.removeTemporaryDirectory()?;
+        assert!(!temp_dir.exists());
+        // Dropping the context afterwards is fine.
+        drop(context);
+        Ok(())
+    }
+
     #[test]
     fn test_find_early_vms_from_xml() -> Result<()> {
         let tmp_dir = tempfile::TempDir::new()?;
@@ -2276,4 +3146,22 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_early_virtmgr_does_not_ask_global_service_about_unused_cid() {
+        let state = Mutex::new(State::default());
+
+        let result = is_cid_in_use(&state, 123, /* early= */ true, || {
+            panic!("Early virtmgr must not connect to the global service")
+        });
+        assert!(matches!(result, Ok(false)), "{result:?}");
+    }
+
+    #[test]
+    fn test_unused_cid_is_checked_with_global_service() {
+        let state = Mutex::new(State::default());
+
+        let result = is_cid_in_use(&state, 123, /* early= */ false, || Ok(true));
+        assert!(matches!(result, Ok(true)), "{result:?}");
+    }
 }
@@ -14,11 +14,17 @@
 
 //! Functions for creating a composite disk image.
 
-use android_system_virtualizationservice::aidl::android::system::virtualizationservice::Partition::Partition;
-use anyhow::{bail, Context, Error};
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
+    Partition::Partition, PartitionLayout::PartitionLayout,
+};
+use anyhow::{bail, ensure, Context, Error};
+use cstr::cstr;
 use disk::{create_composite_disk, ImagePartitionType, PartitionInfo};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use std::ffi::CStr;
 use std::fs::{File, OpenOptions};
 use std::io::ErrorKind;
+use std::mem::size_of;
 use std::os::unix::fs::FileExt;
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
@@ -81,6 +87,117 @@ pub fn make_composite_image(
     Ok((composite_image, files))
 }
 
+/// Plans the composite disk image for the given list of partitions as `make_composite_image` does,
+/// without keeping it, and returns where each partition is in it, in order.
+pub fn describe_composite_image(partitions: &[Partition]) -> Result<Vec<PartitionLayout>, Error> {
+    let (partitions, _files) = convert_partitions(partitions)?;
+
+    // Nothing reads the planned image, so it only lives in memory.
+    let zero_filler_file = create_memfd(cstr!("zero_filler"))?;
+    let mut header_file = create_memfd(cstr!("composite_header"))?;
+    let mut footer_file = create_memfd(cstr!("composite_footer"))?;
+    let mut composite_image = create_memfd(cstr!("composite"))?;
+    create_composite_disk(
+        &partitions,
+        &fd_path_for_file(&zero_filler_file),
+        &fd_path_for_file(&header_file),
+        &mut header_file,
+        &fd_path_for_file(&footer_file),
+        &mut footer_file,
+        &mut composite_image,
+    )?;
+
+    let entries = read_gpt_partition_entries(&header_file)?;
+    ensure!(
+        entries.len() == partitions.len(),
+        "The GPT has {} partitions instead of {}",
+        entries.len(),
+        partitions.len()
+    );
+    partitions
+        .iter()
+        .zip(entries)
+        .map(|(partition, entry)| {
+            let (first_lba, last_lba) = (entry.first_lba, entry.last_lba);
+            let padding_size = ((last_lba + 1 - first_lba) * SECTOR_SIZE)
+                .checked_sub(partition.size)
+                .with_context(|| format!("Partition {} doesn't fit in the GPT", partition.label))?;
+            Ok(PartitionLayout {
+                label: partition.label.clone(),
+                offset: (first_lba * SECTOR_SIZE).try_into()?,
+                size: partition.size.try_into()?,
+                paddingSize: padding_size.try_into()?,
+            })
+        })
+        .collect()
+}
+
+fn create_memfd(name: &CStr) -> Result<File, Error> {
+    let fd = memfd_create(name, MemFdCreateFlag::MFD_CLOEXEC)
+        .with_context(|| format!("Failed to create {name:?}"))?;
+    Ok(fd.into())
+}
+
+/// Size of the sectors in which the GPT expresses the partition boundaries.
+const SECTOR_SIZE: u64 = 512;
+
+/// The GUID partition table header, as defined in the UEFI specification.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, AsBytes, FromZeroes, FromBytes)]
+struct GptHeader {
+    signature: [u8; 8],
+    revision: u32,
+    header_size: u32,
+    header_crc32: u32,
+    reserved: u32,
+    current_lba: u64,
+    backup_lba: u64,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    disk_guid: [u8; 16],
+    partition_entries_lba: u64,
+    num_partition_entries: u32,
+    partition_entry_size: u32,
+    partition_entries_crc32: u32,
+}
+
+/// An entry of the GUID partition table, as defined in the UEFI specification.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, AsBytes, FromZeroes, FromBytes)]
+struct GptPartitionEntry {
+    partition_type_guid: [u8; 16],
+    unique_partition_guid: [u8; 16],
+    first_lba: u64,
+    last_lba: u64,
+    attributes: u64,
+    partition_name: [u16; 36],
+}
+
+/// Reads the used entries of the primary GPT in the given composite image header.
+fn read_gpt_partition_entries(header_file: &File) -> Result<Vec<GptPartitionEntry>, Error> {
+    const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+    // The primary GPT header follows the protective MBR.
+    let mut header = GptHeader::new_zeroed();
+    header_file.read_exact_at(header.as_bytes_mut(), SECTOR_SIZE).context("failed to read GPT")?;
+    ensure!(&header.signature == GPT_SIGNATURE, "invalid GPT signature");
+    let entry_size = u64::from(header.partition_entry_size);
+    ensure!(entry_size >= size_of::<GptPartitionEntry>() as u64, "GPT entries are too small");
+
+    let mut entries = vec![];
+    for i in 0..u64::from(header.num_partition_entries) {
+        let mut entry = GptPartitionEntry::new_zeroed();
+        let offset = header.partition_entries_lba * SECTOR_SIZE + i * entry_size;
+        header_file.read_exact_at(entry.as_bytes_mut(), offset).context("failed to read GPT")?;
+        // Unused entries have a zero partition type GUID, and follow the used ones.
+        if entry.partition_type_guid == [0; 16] {
+            break;
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
 /// Given the AIDL config containing a list of partitions, with a [`ParcelFileDescriptor`] for each
 /// partition, returns the corresponding list of PartitionInfo and the list of files whose file
 /// descriptors must be passed to any process using the composite image.
@@ -196,3 +313,42 @@ fn detect_image_type(file: &File) -> std::io::Result<ImageType> {
 
     Ok(ImageType::Raw)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use binder::ParcelFileDescriptor;
+
+    fn partition(label: &str, size: u64) -> Partition {
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(size).unwrap();
+        Partition {
+            label: label.to_owned(),
+            image: Some(ParcelFileDescriptor::new(file)),
+            writable: false,
+            guid: None,
+        }
+    }
+
+    #[test]
+    fn zero_filler_pads_partitions_to_alignment() -> Result<(), Error> {
+        const ALIGNMENT: i64 = 4096;
+        let partitions = [partition("aligned", 2 * 4096), partition("unaligned", 5000)];
+
+        let layout = describe_composite_image(&partitions)?;
+
+        assert_eq!(2, layout.len());
+        let (aligned, unaligned) = (&layout[0], &layout[1]);
+        assert_eq!("aligned", aligned.label);
+        assert_eq!(0, aligned.offset % ALIGNMENT);
+        assert_eq!(2 * 4096, aligned.size);
+        assert_eq!(0, aligned.paddingSize);
+        // The zero filler goes right after the image of the unaligned partition, up to the next
+        // aligned offset.
+        assert_eq!("unaligned", unaligned.label);
+        assert_eq!(aligned.offset + aligned.size, unaligned.offset);
+        assert_eq!(5000, unaligned.size);
+        assert_eq!(2 * ALIGNMENT - 5000, unaligned.paddingSize);
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bounded history of the console output of a VM, so that a VM which is already running can be
+//! diagnosed without having captured its console from the start.
+
+use log::error;
+use nix::unistd::pipe;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// The maximum number of bytes of console output kept per VM.
+pub const CONSOLE_HISTORY_SIZE: usize = 64 * 1024;
+
+/// The most recent console output of a VM.
+#[derive(Debug, Default)]
+pub struct ConsoleHistory(Mutex<VecDeque<u8>>);
+
+impl ConsoleHistory {
+    /// Appends `bytes` to the history, dropping the oldest bytes if it gets too large.
+    fn append(&self, bytes: &[u8]) {
+        let bytes = &bytes[bytes.len().saturating_sub(CONSOLE_HISTORY_SIZE)..];
+        let history = &mut *self.0.lock().unwrap();
+        let overflow = (history.len() + bytes.len()).saturating_sub(CONSOLE_HISTORY_SIZE);
+        history.drain(..overflow);
+        history.extend(bytes);
+    }
+
+    /// Returns a copy of the history, oldest bytes first.
+    pub fn snapshot(&self) -> Vec<u8> {
+        self.0.lock().unwrap().iter().copied().collect()
+    }
+}
+
+/// Returns the write end of a pipe, everything written to which is recorded in `history` and
+/// forwarded to `output`. `on_closed` is called once the pipe is closed and everything written to
+/// it has been forwarded, after `output` is closed.
+///
+/// Recording continues even if forwarding fails, e.g. because the reader of `output` went away.
+pub fn tee_into_history(
+    output: File,
+    history: Arc<ConsoleHistory>,
+    on_closed: impl FnOnce() + Send + 'static,
+) -> io::Result<File> {
+    let (read_fd, write_fd) = pipe()?;
+    let mut reader = File::from(read_fd);
+
+    thread::spawn(move || {
+        forward_console(&mut reader, Some(output), &history);
+        on_closed();
+    });
+
+    Ok(File::from(write_fd))
+}
+
+fn forward_console(reader: &mut File, mut output: Option<File>, history: &ConsoleHistory) {
+    let mut buf = [0; 4096];
+    loop {
+        let size = match reader.read(&mut buf) {
+            Ok(0) => return, // EOF
+            Ok(size) => size,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                error!("Could not read console pipe: {:?}", e);
+                return;
+            }
+        };
+        history.append(&buf[..size]);
+        if let Some(out) = &mut output {
+            if let Err(e) = out.write_all(&buf[..size]) {
+                error!("Could not forward console output, only recording it: {:?}", e);
+                output = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn history_contains_recent_console_lines() -> io::Result<()> {
+        let (output_read_fd, output_write_fd) = pipe()?;
+        let history = Arc::new(ConsoleHistory::default());
+        let mut console = tee_into_history(File::from(output_write_fd), history.clone(), || {})?;
+
+        console.write_all(b"first line\nsecond line\n")?;
+        drop(console);
+
+        // The output is only closed once everything written to the console has been recorded.
+        let mut forwarded = vec![];
+        File::from(output_read_fd).read_to_end(&mut forwarded)?;
+
+        assert_eq!(b"first line\nsecond line\n".as_slice(), forwarded);
+        assert_eq!(forwarded, history.snapshot());
+        Ok(())
+    }
+
+    #[test]
+    fn closing_console_is_notified() -> io::Result<()> {
+        let (output_read_fd, output_write_fd) = pipe()?;
+        let (closed_sender, closed_receiver) = mpsc::channel();
+        let mut console =
+            tee_into_history(File::from(output_write_fd), Arc::default(), move || {
+                closed_sender.send(()).unwrap()
+            })?;
+
+        console.write_all(b"still open\n")?;
+        let mut output = File::from(output_read_fd);
+        let mut forwarded = [0; 11];
+        output.read_exact(&mut forwarded)?;
+        assert_eq!(b"still open\n", &forwarded);
+        assert!(closed_receiver.try_recv().is_err());
+
+        drop(console);
+        closed_receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        // The output is closed before the notification, so the client can close its side.
+        assert_eq!(0, output.read(&mut forwarded)?);
+        Ok(())
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let history = ConsoleHistory::default();
+
+        history.append(&[b'a'; CONSOLE_HISTORY_SIZE]);
+        history.append(b"recent");
+
+        let snapshot = history.snapshot();
+        assert_eq!(CONSOLE_HISTORY_SIZE, snapshot.len());
+        assert!(snapshot.ends_with(b"arecent"));
+
+        history.append(&[b'b'; CONSOLE_HISTORY_SIZE + 1]);
+        assert_eq!(vec![b'b'; CONSOLE_HISTORY_SIZE], history.snapshot());
+    }
+}
@@ -14,14 +14,16 @@
 
 //! Functions for running instances of `crosvm`.
 
-use crate::aidl::{remove_temporary_files, Cid, GLOBAL_SERVICE, VirtualMachineCallbacks};
+use crate::aidl::{get_state, remove_temporary_files, Cid, GLOBAL_SERVICE, VirtualMachineCallbacks};
 use crate::atom::{get_num_cpus, write_vm_exited_stats_sync};
+use crate::console_history::ConsoleHistory;
 use crate::debug_config::DebugConfig;
-use anyhow::{anyhow, bail, Context, Error, Result};
+use crate::retry::retry_until_timeout;
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
 use binder::ParcelFileDescriptor;
 use command_fds::CommandFdExt;
 use libc::{sysconf, _SC_CLK_TCK};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use semver::{Version, VersionReq};
 use nix::{fcntl::OFlag, unistd::pipe2, unistd::Uid, unistd::User};
 use regex::{Captures, Regex};
@@ -41,7 +43,9 @@ use std::process::{Command, ExitStatus};
 use std::sync::{Arc, Condvar, Mutex, LazyLock};
 use std::time::{Duration, SystemTime};
 use std::thread::{self, JoinHandle};
-use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::DeathReason::DeathReason;
+use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::{
+    DeathReason::DeathReason, ErrorCode::ErrorCode,
+};
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
     VirtualMachineAppConfig::DebugLevel::DebugLevel,
     AudioConfig::AudioConfig as AudioConfigParcelable,
@@ -65,7 +69,7 @@ const CROSVM_PATH: &str = "/apex/com.android.virt/bin/crosvm";
 /// should be updated when there is a platform change in the crosvm side. Having this value here is
 /// fine because virtualizationservice and crosvm are supposed to be updated together in the virt
 /// APEX.
-const CROSVM_PLATFORM_VERSION: &str = "1.0.0";
+pub const CROSVM_PLATFORM_VERSION: &str = "1.0.0";
 
 /// The exit status which crosvm returns when it has an error starting a VM.
 const CROSVM_START_ERROR_STATUS: i32 = 1;
@@ -80,6 +84,8 @@ const RAMDUMP_RESERVED_MIB: u32 = 17;
 
 const MILLIS_PER_SEC: i64 = 1000;
 
+const BYTES_PER_MIB: u64 = 1 << 20;
+
 const SYSPROP_CUSTOM_PVMFW_PATH: &str = "hypervisor.pvmfw.path";
 
 /// Serial device for VM console input.
@@ -118,6 +124,8 @@ pub struct CrosvmConfig {
     pub console_in_fd: Option<File>,
     pub log_fd: Option<File>,
     pub ramdump: Option<File>,
+    /// The thread writing what goes through `ramdump` to the ramdump file.
+    pub ramdump_capture: Option<JoinHandle<()>>,
     pub indirect_files: Vec<File>,
     pub platform_version: VersionReq,
     pub detect_hangup: bool,
@@ -135,6 +143,8 @@ pub struct CrosvmConfig {
     pub audio_config: Option<AudioConfig>,
     pub no_balloon: bool,
     pub usb_config: UsbConfig,
+    /// Whether the temporary directory is removed as soon as the VM is dead.
+    pub ephemeral: bool,
 }
 
 #[derive(Debug)]
@@ -251,6 +261,23 @@ pub enum PayloadState {
     Hangup, // Hasn't reached to Ready before timeout expires
 }
 
+/// The last error which the payload in the VM reported, if any, for the clients which weren't
+/// registered to be called back when it was reported.
+#[derive(Debug, Default)]
+pub struct LastPayloadError(Mutex<Option<(ErrorCode, String)>>);
+
+impl LastPayloadError {
+    /// Records an error reported by the payload, replacing the previous one.
+    pub fn record(&self, error_code: ErrorCode, message: &str) {
+        *self.0.lock().unwrap() = Some((error_code, message.to_owned()));
+    }
+
+    /// Returns the last error reported by the payload.
+    pub fn get(&self) -> Option<(ErrorCode, String)> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
 /// The current state of the VM itself.
 #[derive(Debug)]
 pub enum VmState {
@@ -268,10 +295,21 @@ pub enum VmState {
     },
     /// The VM died or was killed.
     Dead,
-    /// The VM failed to start.
+    /// The VM failed to start, or its start was cancelled.
     Failed,
 }
 
+/// What [`VmState::cancel_start`] did.
+#[derive(Debug)]
+enum StartCancellation {
+    /// Nothing, as the VM had already booted or stopped.
+    TooLate,
+    /// The VM was prevented from starting.
+    NeverStarted,
+    /// crosvm was killed. The thread waiting for it to finish is given, unless already taken.
+    Killed(Option<JoinHandle<()>>),
+}
+
 /// RSS values of VM and CrosVM process itself.
 #[derive(Copy, Clone, Debug, Default)]
 pub struct Rss {
@@ -279,11 +317,151 @@ pub struct Rss {
     pub crosvm: i64,
 }
 
+/// Name of the guest memory resource, as reported to `IVirtualMachineCallback.onResourceLimit`.
+const RESOURCE_MEMORY: &str = "memory";
+
+/// Percentage of a configured limit at which a VM is considered to be approaching it.
+const RESOURCE_LIMIT_THRESHOLD_PERCENT: u64 = 90;
+
+/// A source of resource usage readings for a running VM.
+trait VmStatsSource {
+    /// Returns the current RSS of the VM and of the crosvm process, in KiB.
+    fn rss(&self) -> Result<Rss>;
+    /// Returns the CPU time spent by the crosvm process, in user and kernel mode.
+    fn cpu_time(&self) -> Result<Duration>;
+}
+
+/// Reads the resource usage of a VM from its crosvm process.
+struct CrosvmStats {
+    pid: u32,
+}
+
+impl VmStatsSource for CrosvmStats {
+    fn rss(&self) -> Result<Rss> {
+        get_rss(self.pid)
+    }
+
+    fn cpu_time(&self) -> Result<Duration> {
+        get_cpu_time(self.pid)
+    }
+}
+
+/// Resource usage of the crosvm process running a VM.
+#[derive(Debug, Eq, PartialEq)]
+pub struct ResourceUsage {
+    /// Resident set size of the crosvm process, including guest memory, in bytes.
+    pub rss_bytes: u64,
+    /// CPU time spent by the crosvm process, in user and kernel mode.
+    pub cpu_time: Duration,
+}
+
+fn read_resource_usage(stats: &dyn VmStatsSource) -> Result<ResourceUsage> {
+    let rss = stats.rss()?;
+    let rss_bytes = u64::try_from(rss.crosvm).context("Negative RSS")?.saturating_mul(1024);
+    Ok(ResourceUsage { rss_bytes, cpu_time: stats.cpu_time()? })
+}
+
+/// Compares the resource usage of a VM against its configured limits.
+///
+/// A limit is reported once when usage crosses its threshold, and is only reported again after
+/// usage has dropped back below the threshold.
+#[derive(Debug)]
+struct ResourceLimitMonitor {
+    /// Guest memory limit, in bytes.
+    memory_limit: u64,
+    /// Whether guest memory is currently above the threshold and has been reported.
+    memory_reported: bool,
+}
+
+impl ResourceLimitMonitor {
+    fn new(memory_mib: NonZeroU32) -> Self {
+        Self { memory_limit: u64::from(memory_mib.get()) * BYTES_PER_MIB, memory_reported: false }
+    }
+
+    /// Reads the resource usage from `stats` and calls `notify` with the resource, limit and
+    /// observed usage of any limit that has newly been crossed. Returns the usage that was read.
+    fn poll(
+        &mut self,
+        stats: &dyn VmStatsSource,
+        mut notify: impl FnMut(&str, u64, u64),
+    ) -> Result<Rss> {
+        let rss = stats.rss()?;
+        let observed = u64::try_from(rss.vm).unwrap_or(0).saturating_mul(1024);
+        let threshold = self.memory_limit / 100 * RESOURCE_LIMIT_THRESHOLD_PERCENT;
+        if observed >= threshold {
+            if !self.memory_reported {
+                self.memory_reported = true;
+                notify(RESOURCE_MEMORY, self.memory_limit, observed);
+            }
+        } else {
+            self.memory_reported = false;
+        }
+        Ok(rss)
+    }
+}
+
+/// The controls of a running VM needed to shut it down gracefully.
+trait VmPowerControl {
+    /// Asks the guest to shut down, as if the power button of the VM was pressed.
+    fn press_power_button(&self) -> Result<()>;
+    /// Returns whether the VM has died.
+    fn is_dead(&self) -> bool;
+}
+
+/// Asks the guest of `vm` to shut down and waits up to `timeout` for the VM to die. Returns whether
+/// it did.
+fn shut_down_gracefully(vm: &dyn VmPowerControl, timeout: Duration) -> bool {
+    if let Err(e) = vm.press_power_button() {
+        warn!("Failed to ask the guest to shut down: {e:?}");
+        return false;
+    }
+    retry_until_timeout(timeout, || if vm.is_dead() { Ok(()) } else { Err(()) }).is_ok()
+}
+
+/// The controls of a running VM needed to adjust its memory balloon.
+trait MemoryBalloonControl {
+    /// Returns the amount of guest memory currently held by the balloon, in bytes.
+    fn balloon_size(&self) -> Result<u64>;
+    /// Asks the balloon to hold `num_bytes` of guest memory.
+    fn resize_balloon(&self, num_bytes: u64) -> Result<()>;
+}
+
+/// Gives `mib` MiB of memory back to the guest of `vm` by deflating its balloon, and returns the
+/// amount granted.
+///
+/// `floor` is the size set by the host below which the balloon may not be deflated for the guest,
+/// if any; without one, nothing is granted. It is kept locked while the balloon is adjusted, so
+/// that concurrent requests and adjustments from the host don't lose each other's updates.
+fn grant_memory_request(
+    vm: &dyn MemoryBalloonControl,
+    floor: &Mutex<Option<u64>>,
+    mib: u32,
+) -> Result<u32> {
+    let floor = floor.lock().unwrap();
+    let Some(floor) = *floor else {
+        bail!("The host doesn't allow the guest to request memory");
+    };
+    let balloon = vm.balloon_size()?;
+    let requested_bytes = u64::from(mib) * BYTES_PER_MIB;
+    let available_bytes = balloon.saturating_sub(floor);
+    ensure!(
+        requested_bytes <= available_bytes,
+        "Requested {mib} MiB of memory, but at most {} MiB can be granted",
+        available_bytes / BYTES_PER_MIB
+    );
+    vm.resize_balloon(balloon - requested_bytes)?;
+    Ok(mib)
+}
+
 /// Metrics regarding the VM.
 #[derive(Debug, Default)]
 pub struct VmMetric {
     /// Recorded timestamp when the VM is started.
     pub start_timestamp: Option<SystemTime>,
+    /// Recorded timestamp when the payload reported it started.
+    pub payload_started_timestamp: Option<SystemTime>,
+    /// Recorded timestamp when the payload reported it is ready.
+    pub payload_ready_timestamp: Option<SystemTime>,
     /// Update most recent guest_time periodically from /proc/[crosvm pid]/stat while VM is
     /// running.
     pub cpu_guest_time: Option<i64>,
@@ -291,6 +469,37 @@ pub struct VmMetric {
     pub rss: Option<Rss>,
 }
 
+/// How long the boot of a VM took, as measured from `VmMetric`.
+#[derive(Debug, Eq, PartialEq)]
+pub struct BootTimes {
+    /// From the VM being started to its payload reporting it started.
+    pub boot_time: Duration,
+    /// From the payload reporting it started to it reporting it is ready.
+    pub payload_ready_latency: Duration,
+}
+
+impl VmMetric {
+    /// Records that the payload reached `state` at `timestamp`.
+    pub fn record_payload_state(&mut self, state: PayloadState, timestamp: SystemTime) {
+        match state {
+            PayloadState::Started => self.payload_started_timestamp = Some(timestamp),
+            PayloadState::Ready => self.payload_ready_timestamp = Some(timestamp),
+            _ => {}
+        }
+    }
+
+    /// Returns how long the VM took to boot. Fails if the payload hasn't reported being ready.
+    pub fn boot_times(&self) -> Result<BootTimes, Error> {
+        let ready = self.payload_ready_timestamp.context("Payload isn't ready")?;
+        let started = self.payload_started_timestamp.context("Payload never reported starting")?;
+        let start = self.start_timestamp.context("VM was never started")?;
+        Ok(BootTimes {
+            boot_time: started.duration_since(start)?,
+            payload_ready_latency: ready.duration_since(started)?,
+        })
+    }
+}
+
 impl VmState {
     /// Tries to start the VM, if it is in the `NotStarted` state.
     ///
@@ -298,10 +507,11 @@ impl VmState {
     fn start(&mut self, instance: Arc<VmInstance>) -> Result<(), Error> {
         let state = mem::replace(self, VmState::Failed);
         if let VmState::NotStarted { config } = state {
-            let config = *config;
+            let mut config = *config;
             let detect_hangup = config.detect_hangup;
             let (failure_pipe_read, failure_pipe_write) = create_pipe()?;
             let vfio_devices = config.vfio_devices.clone();
+            let ramdump_capture = config.ramdump_capture.take();
             let tap =
                 if let Some(tap_file) = &config.tap { Some(tap_file.try_clone()?) } else { None };
 
@@ -318,7 +528,13 @@ impl VmState {
             let child_clone = child.clone();
             let instance_clone = instance.clone();
             let monitor_vm_exit_thread = Some(thread::spawn(move || {
-                instance_clone.monitor_vm_exit(child_clone, failure_pipe_read, vfio_devices, tap);
+                instance_clone.monitor_vm_exit(
+                    child_clone,
+                    failure_pipe_read,
+                    vfio_devices,
+                    tap,
+                    ramdump_capture,
+                );
             }));
 
             if detect_hangup {
@@ -336,6 +552,29 @@ impl VmState {
             bail!("VM already started or failed")
         }
     }
+
+    /// Cancels the start of the VM if it is still booting, i.e. if it hasn't been started yet, or
+    /// if the payload, which is in `payload_state`, hasn't started. `self` is then left in the
+    /// `Failed` state.
+    fn cancel_start(&mut self, payload_state: PayloadState) -> Result<StartCancellation, Error> {
+        match self {
+            VmState::NotStarted { .. } => {
+                *self = VmState::Failed;
+                Ok(StartCancellation::NeverStarted)
+            }
+            VmState::Running { child, monitor_vm_exit_thread }
+                if payload_state == PayloadState::Starting =>
+            {
+                let id = child.id();
+                debug!("Killing crosvm({}) to cancel the start of the VM", id);
+                child.kill().with_context(|| format!("Error killing crosvm({id}) instance"))?;
+                let monitor_vm_exit_thread = monitor_vm_exit_thread.take();
+                *self = VmState::Failed;
+                Ok(StartCancellation::Killed(monitor_vm_exit_thread))
+            }
+            _ => Ok(StartCancellation::TooLate),
+        }
+    }
 }
 
 /// Internal struct that holds the handles to globally unique resources of a VM.
@@ -372,6 +611,8 @@ pub struct VmInstance {
     pub protected: bool,
     /// Directory of temporary files used by the VM while it is running.
     pub temporary_directory: PathBuf,
+    /// Whether `temporary_directory` is removed as soon as the VM is dead.
+    ephemeral: bool,
     /// The UID of the process which requested the VM.
     pub requester_uid: u32,
     /// The PID of the process which requested the VM. Note that this process may no longer exist
@@ -388,8 +629,17 @@ pub struct VmInstance {
     payload_state: Mutex<PayloadState>,
     /// Represents the condition that payload_state was updated
     payload_state_updated: Condvar,
+    /// The last error reported by the payload.
+    pub last_payload_error: LastPayloadError,
     /// The human readable name of requester_uid
     requester_uid_name: String,
+    /// Guest memory the VM was configured with, used as its memory limit.
+    memory_mib: NonZeroU32,
+    /// The size below which the memory balloon may not be deflated when the guest requests memory,
+    /// as set by the host. Also held while the balloon is adjusted.
+    memory_balloon_floor: Mutex<Option<u64>>,
+    /// The most recent console output of the VM, if console output is enabled.
+    pub console_history: Option<Arc<ConsoleHistory>>,
 }
 
 impl fmt::Display for VmInstance {
@@ -411,11 +661,15 @@ impl VmInstance {
         requester_uid: u32,
         requester_debug_pid: i32,
         vm_context: VmContext,
+        callbacks: VirtualMachineCallbacks,
+        console_history: Option<Arc<ConsoleHistory>>,
     ) -> Result<VmInstance, Error> {
         validate_config(&config)?;
         let cid = config.cid;
         let name = config.name.clone();
         let protected = config.protected;
+        let memory_mib = config.memory_mib;
+        let ephemeral = config.ephemeral;
         let requester_uid_name = User::from_uid(Uid::from_raw(requester_uid))
             .ok()
             .flatten()
@@ -428,14 +682,19 @@ impl VmInstance {
             name,
             protected,
             temporary_directory,
+            ephemeral,
             requester_uid,
             requester_debug_pid,
-            callbacks: Default::default(),
+            callbacks,
             vm_service: Mutex::new(None),
             vm_metric: Mutex::new(Default::default()),
             payload_state: Mutex::new(PayloadState::Starting),
             payload_state_updated: Condvar::new(),
+            last_payload_error: Default::default(),
             requester_uid_name,
+            memory_mib,
+            memory_balloon_floor: Mutex::new(None),
+            console_history,
         };
         info!("{} created", &instance);
         Ok(instance)
@@ -449,6 +708,7 @@ impl VmInstance {
         let ret = self.vm_state.lock().unwrap().start(self.clone());
         if ret.is_ok() {
             info!("{} started", &self);
+            self.report_state();
         }
         ret.with_context(|| format!("{} failed to start", &self))
     }
@@ -462,6 +722,7 @@ impl VmInstance {
         mut failure_pipe_read: File,
         vfio_devices: Vec<VfioDevice>,
         tap: Option<File>,
+        ramdump_capture: Option<JoinHandle<()>>,
     ) {
         let result = child.wait();
         match &result {
@@ -477,10 +738,14 @@ impl VmInstance {
         }
 
         let mut vm_state = self.vm_state.lock().unwrap();
-        *vm_state = VmState::Dead;
+        // A VM whose start was cancelled stays in the `Failed` state.
+        if !matches!(*vm_state, VmState::Failed) {
+            *vm_state = VmState::Dead;
+        }
         // Ensure that the mutex is released before calling the callbacks.
         drop(vm_state);
         info!("{} exited", &self);
+        self.report_state();
 
         // Read the pipe to see if any failure reason is written
         let mut failure_reason = String::new();
@@ -500,6 +765,10 @@ impl VmInstance {
                 Cow::from(failure_reason)
             };
 
+        // The ramdump is complete once crosvm, which held the only write end of its pipe, is gone.
+        if let Some(Err(e)) = ramdump_capture.map(JoinHandle::join) {
+            error!("Error capturing ramdump: {:?}", e);
+        }
         self.handle_ramdump().unwrap_or_else(|e| error!("Error handling ramdump: {}", e));
 
         let death_reason = death_reason(&result, &failure_reason);
@@ -516,10 +785,18 @@ impl VmInstance {
             &vm_metric,
         );
 
-        // Delete temporary files. The folder itself is removed by VirtualizationServiceInternal.
-        remove_temporary_files(&self.temporary_directory).unwrap_or_else(|e| {
-            error!("Error removing temporary files from {:?}: {}", self.temporary_directory, e);
-        });
+        // Delete temporary files. crosvm is gone, and the ramdump was handled above, so none of
+        // them is in use any more. The folder itself is removed by VirtualizationServiceInternal,
+        // right away for ephemeral VMs and otherwise once the VM context is released.
+        if self.ephemeral {
+            self.vm_context.global_context.removeTemporaryDirectory().unwrap_or_else(|e| {
+                error!("Error removing temporary directory {:?}: {}", self.temporary_directory, e);
+            });
+        } else {
+            remove_temporary_files(&self.temporary_directory).unwrap_or_else(|e| {
+                error!("Error removing temporary files from {:?}: {}", self.temporary_directory, e);
+            });
+        }
 
         if let Some(tap_file) = tap {
             GLOBAL_SERVICE
@@ -559,12 +836,14 @@ impl VmInstance {
 
     fn monitor_vm_status(&self, child: Arc<SharedChild>) {
         let pid = child.id();
+        let stats = CrosvmStats { pid };
+        let mut limit_monitor = ResourceLimitMonitor::new(self.memory_mib);
 
         loop {
             {
                 // Check VM state
                 let vm_state = &*self.vm_state.lock().unwrap();
-                if let VmState::Dead = vm_state {
+                if let VmState::Dead | VmState::Failed = vm_state {
                     break;
                 }
 
@@ -576,8 +855,12 @@ impl VmInstance {
                     Err(e) => error!("Failed to get guest CPU time: {e:?}"),
                 }
 
-                // Get Memory Information
-                match get_rss(pid) {
+                // Get Memory Information, and check it against the configured limit
+                let rss = limit_monitor.poll(&stats, |resource, limit, observed| {
+                    info!("{} reached {resource} limit: {observed}/{limit}", &self);
+                    self.callbacks.notify_resource_limit(self.cid, resource, limit, observed);
+                });
+                match rss {
                     Ok(rss) => {
                         vm_metric.rss = match &vm_metric.rss {
                             Some(x) => Some(Rss::extract_max(x, &rss)),
@@ -592,6 +875,15 @@ impl VmInstance {
         }
     }
 
+    /// Reports the current state of the VM to VirtualizationServiceInternal, which lists it to the
+    /// other processes of the same app.
+    fn report_state(&self) {
+        let state = get_state(self);
+        if let Err(e) = self.vm_context.global_context.setState(state) {
+            warn!("Error reporting the state {:?} of {}: {:?}", state, &self, e);
+        }
+    }
+
     /// Returns the last reported state of the VM payload.
     pub fn payload_state(&self) -> PayloadState {
         *self.payload_state.lock().unwrap()
@@ -604,7 +896,11 @@ impl VmInstance {
         // the other direction.
         if new_state > *state_locked {
             *state_locked = new_state;
+            self.vm_metric.lock().unwrap().record_payload_state(new_state, SystemTime::now());
             self.payload_state_updated.notify_all();
+            // get_state() takes the lock again.
+            drop(state_locked);
+            self.report_state();
             Ok(())
         } else {
             bail!("Invalid payload state transition from {:?} to {:?}", *state_locked, new_state)
@@ -637,39 +933,86 @@ impl VmInstance {
         Ok(())
     }
 
+    /// Cancels the start of the VM if it is still booting, killing crosvm if it was already
+    /// started. The VM is then left in the `Failed` state, and clients are told that it died.
+    /// Returns whether the start was cancelled; it is a no-op once the payload has started, or if
+    /// the VM isn't running.
+    pub fn cancel_start(&self) -> Result<bool, Error> {
+        let cancellation = {
+            let vm_state = &mut *self.vm_state.lock().unwrap();
+            vm_state.cancel_start(self.payload_state())?
+        };
+        match cancellation {
+            StartCancellation::TooLate => return Ok(false),
+            StartCancellation::NeverStarted => {
+                self.report_state();
+                self.callbacks.callback_on_died(self.cid, DeathReason::KILLED);
+            }
+            StartCancellation::Killed(monitor_vm_exit_thread) => {
+                // monitor_vm_exit() calls the clients back once crosvm is gone. Like in `kill`,
+                // wait for it now that vm_state lock is released, and free up the server threads.
+                monitor_vm_exit_thread.map(JoinHandle::join);
+                self.vm_context.vm_server.shutdown()?;
+            }
+        }
+        info!("{} start cancelled", &self);
+        Ok(true)
+    }
+
+    /// Asks the guest to shut down, giving it the chance to flush its state, and kills the crosvm
+    /// instance if the VM is still running after `timeout`.
+    pub fn shutdown(&self, timeout: Duration) -> Result<(), Error> {
+        if !matches!(&*self.vm_state.lock().unwrap(), VmState::Running { .. }) {
+            bail!("VM is not running")
+        }
+        if !shut_down_gracefully(self, timeout) {
+            info!("{} didn't shut down within {:?}, killing it", &self, timeout);
+            return self.kill();
+        }
+
+        // Like `kill`, eagerly free up the server threads of the VirtualMachineService.
+        self.vm_context.vm_server.shutdown()?;
+        Ok(())
+    }
+
+    /// Returns the current resource usage of the crosvm process, if the VM is running.
+    pub fn resource_usage(&self) -> Result<ResourceUsage, Error> {
+        let pid = match &*self.vm_state.lock().unwrap() {
+            VmState::Running { child, .. } => child.id(),
+            _ => bail!("VM is not running"),
+        };
+        read_resource_usage(&CrosvmStats { pid })
+    }
+
     /// Responds to memory-trimming notifications by inflating the virtio
     /// balloon to reclaim guest memory.
     pub fn get_memory_balloon(&self) -> Result<u64, Error> {
-        let request = VmRequest::BalloonCommand(BalloonControlCommand::Stats {});
-        let result =
-            match vm_control::client::handle_request(&request, &self.crosvm_control_socket_path) {
-                Ok(VmResponse::BalloonStats { stats: _, balloon_actual }) => balloon_actual,
-                Ok(VmResponse::Err(e)) => {
-                    // ENOTSUP is returned when the balloon protocol is not initialized. This
-                    // can occur for numerous reasons: Guest is still booting, guest doesn't
-                    // support ballooning, host doesn't support ballooning. We don't log or
-                    // raise an error in this case: trim is just a hint and we can ignore it.
-                    if e.errno() != libc::ENOTSUP {
-                        bail!("Errno return when requesting balloon stats: {}", e.errno())
-                    }
-                    0
-                }
-                e => bail!("Error requesting balloon stats: {:?}", e),
-            };
-        Ok(result)
+        self.balloon_size()
     }
 
     /// Responds to memory-trimming notifications by inflating the virtio
     /// balloon to reclaim guest memory.
     pub fn set_memory_balloon(&self, num_bytes: u64) -> Result<(), Error> {
-        let command = BalloonControlCommand::Adjust { num_bytes, wait_for_success: false };
-        if let Err(e) = vm_control::client::handle_request(
-            &VmRequest::BalloonCommand(command),
-            &self.crosvm_control_socket_path,
-        ) {
-            bail!("Error sending balloon adjustment: {:?}", e);
-        }
-        Ok(())
+        let _floor = self.memory_balloon_floor.lock().unwrap();
+        self.resize_balloon(num_bytes)
+    }
+
+    /// Sets the size below which the memory balloon may not be deflated when the guest requests
+    /// memory with `request_memory`.
+    pub fn set_memory_balloon_floor(&self, num_bytes: u64) {
+        *self.memory_balloon_floor.lock().unwrap() = Some(num_bytes);
+    }
+
+    /// Gives the guest `mib` MiB of additional memory, in response to a request from the guest.
+    ///
+    /// crosvm can't add memory to a running VM, so this deflates the memory balloon instead, down
+    /// to the floor set by the host with `set_memory_balloon_floor`. The memory the balloon holds
+    /// above that floor is therefore the cap on what can be granted, and the VM never grows beyond
+    /// the memory it was started with.
+    pub fn request_memory(&self, mib: u32) -> Result<u32, Error> {
+        let granted = grant_memory_request(self, &self.memory_balloon_floor, mib)?;
+        info!("{} was granted {} MiB of memory", &self, granted);
+        Ok(granted)
     }
 
     /// Checks if ramdump has been created. If so, send it to tombstoned.
@@ -726,6 +1069,55 @@ impl VmInstance {
     }
 }
 
+impl MemoryBalloonControl for VmInstance {
+    fn balloon_size(&self) -> Result<u64> {
+        let request = VmRequest::BalloonCommand(BalloonControlCommand::Stats {});
+        let result =
+            match vm_control::client::handle_request(&request, &self.crosvm_control_socket_path) {
+                Ok(VmResponse::BalloonStats { stats: _, balloon_actual }) => balloon_actual,
+                Ok(VmResponse::Err(e)) => {
+                    // ENOTSUP is returned when the balloon protocol is not initialized. This
+                    // can occur for numerous reasons: Guest is still booting, guest doesn't
+                    // support ballooning, host doesn't support ballooning. We don't log or
+                    // raise an error in this case: trim is just a hint and we can ignore it.
+                    if e.errno() != libc::ENOTSUP {
+                        bail!("Errno return when requesting balloon stats: {}", e.errno())
+                    }
+                    0
+                }
+                e => bail!("Error requesting balloon stats: {:?}", e),
+            };
+        Ok(result)
+    }
+
+    fn resize_balloon(&self, num_bytes: u64) -> Result<()> {
+        let command = BalloonControlCommand::Adjust { num_bytes, wait_for_success: false };
+        if let Err(e) = vm_control::client::handle_request(
+            &VmRequest::BalloonCommand(command),
+            &self.crosvm_control_socket_path,
+        ) {
+            bail!("Error sending balloon adjustment: {:?}", e);
+        }
+        Ok(())
+    }
+}
+
+impl VmPowerControl for VmInstance {
+    fn press_power_button(&self) -> Result<()> {
+        match vm_control::client::handle_request(
+            &VmRequest::Powerbtn,
+            &self.crosvm_control_socket_path,
+        ) {
+            Ok(VmResponse::Ok) => Ok(()),
+            e => bail!("Failed to press the power button: {e:?}"),
+        }
+    }
+
+    fn is_dead(&self) -> bool {
+        matches!(&*self.vm_state.lock().unwrap(), VmState::Dead | VmState::Failed)
+    }
+}
+
 impl Rss {
     fn extract_max(x: &Rss, y: &Rss) -> Rss {
         Rss { vm: max(x.vm, y.vm), crosvm: max(x.crosvm, y.crosvm) }
@@ -780,6 +1172,22 @@ fn get_guest_time(pid: u32) -> Result<i64> {
     Ok(guest_time_ticks * MILLIS_PER_SEC / ticks_per_sec)
 }
 
+// Get user and system CPU time from /proc/[crosvm pid]/stat
+fn get_cpu_time(pid: u32) -> Result<Duration> {
+    let file = read_to_string(format!("/proc/{}/stat", pid))?;
+    let data_list: Vec<_> = file.split_whitespace().collect();
+
+    // utime and stime are at the 14th and 15th places of the file split with the whitespace.
+    if data_list.len() < 15 {
+        bail!("Failed to parse command result for getting CPU time : {}", file);
+    }
+
+    let cpu_time_ticks = data_list[13].parse::<u64>()? + data_list[14].parse::<u64>()?;
+    // SAFETY: It just returns an integer about CPU tick information.
+    let ticks_per_sec = u64::try_from(unsafe { sysconf(_SC_CLK_TCK) })?;
+    Ok(Duration::from_millis(cpu_time_ticks * MILLIS_PER_SEC as u64 / ticks_per_sec))
+}
+
 // Get rss from /proc/[crosvm pid]/smaps
 fn get_rss(pid: u32) -> Result<Rss> {
     let file = read_to_string(format!("/proc/{}/smaps", pid))?;
@@ -1209,12 +1617,21 @@ fn validate_config(config: &CrosvmConfig) -> Result<(), Error> {
     if config.bootloader.is_some() && (config.kernel.is_some() || config.initrd.is_some()) {
         bail!("Can't have both bootloader and kernel/initrd image.");
     }
-    let version = Version::parse(CROSVM_PLATFORM_VERSION).unwrap();
-    if !config.platform_version.matches(&version) {
+    check_platform_version(&config.platform_version, CROSVM_PLATFORM_VERSION)?;
+
+    Ok(())
+}
+
+/// Checks that `platform_version`, which is the version of the platform implemented by the host,
+/// satisfies `requirement`, the platform version(s) the config of a VM is compatible with.
+pub fn check_platform_version(requirement: &VersionReq, platform_version: &str) -> Result<()> {
+    let version = Version::parse(platform_version)
+        .with_context(|| format!("Invalid platform version {platform_version}"))?;
+    if !requirement.matches(&version) {
         bail!(
             "Incompatible platform version. The config is compatible with platform version(s) \
               {}, but the actual platform version is {}",
-            config.platform_version,
+            requirement,
             version
         );
     }
@@ -1290,3 +1707,240 @@ fn create_crosvm_control_listener(crosvm_control_socket_path: &Path) -> Result<O
     socket::listen(&fd, socket::Backlog::new(127).unwrap()).context("listen failed")?;
     Ok(fd)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_payload_error_is_kept() {
+        let last_error = LastPayloadError::default();
+        assert_eq!(None, last_error.get());
+
+        last_error.record(ErrorCode::PAYLOAD_CHANGED, "first");
+        last_error.record(ErrorCode::PAYLOAD_INVALID_CONFIG, "second");
+        assert_eq!(
+            Some((ErrorCode::PAYLOAD_INVALID_CONFIG, "second".to_owned())),
+            last_error.get()
+        );
+    }
+
+    #[test]
+    fn boot_times_are_measured_from_payload_states() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let mut metric = VmMetric { start_timestamp: Some(start), ..Default::default() };
+        assert!(metric.boot_times().is_err());
+
+        metric.record_payload_state(PayloadState::Started, start + Duration::from_millis(1500));
+        assert!(metric.boot_times().is_err());
+
+        metric.record_payload_state(PayloadState::Ready, start + Duration::from_millis(1750));
+        metric.record_payload_state(PayloadState::Finished, start + Duration::from_secs(10));
+        assert_eq!(
+            BootTimes {
+                boot_time: Duration::from_millis(1500),
+                payload_ready_latency: Duration::from_millis(250)
+            },
+            metric.boot_times().unwrap()
+        );
+    }
+
+    /// A memory balloon which yields to other threads between being read and resized, so that
+    /// unsynchronized requests would lose updates.
+    struct FakeBalloon {
+        size: Mutex<u64>,
+    }
+
+    impl MemoryBalloonControl for FakeBalloon {
+        fn balloon_size(&self) -> Result<u64> {
+            let size = *self.size.lock().unwrap();
+            thread::sleep(Duration::from_millis(1));
+            Ok(size)
+        }
+
+        fn resize_balloon(&self, num_bytes: u64) -> Result<()> {
+            *self.size.lock().unwrap() = num_bytes;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn memory_request_within_cap_deflates_balloon() {
+        let balloon = FakeBalloon { size: Mutex::new(100 * BYTES_PER_MIB) };
+        let floor = Mutex::new(Some(0));
+        assert_eq!(grant_memory_request(&balloon, &floor, 30).unwrap(), 30);
+        assert_eq!(*balloon.size.lock().unwrap(), 70 * BYTES_PER_MIB);
+        assert_eq!(grant_memory_request(&balloon, &floor, 70).unwrap(), 70);
+        assert_eq!(*balloon.size.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn memory_request_over_cap_is_denied() {
+        let balloon = FakeBalloon { size: Mutex::new(100 * BYTES_PER_MIB) };
+        let floor = Mutex::new(Some(0));
+        assert!(grant_memory_request(&balloon, &floor, 101).is_err());
+        assert_eq!(*balloon.size.lock().unwrap(), 100 * BYTES_PER_MIB);
+    }
+
+    #[test]
+    fn memory_request_cannot_deflate_balloon_below_floor() {
+        let balloon = FakeBalloon { size: Mutex::new(100 * BYTES_PER_MIB) };
+        let floor = Mutex::new(Some(60 * BYTES_PER_MIB));
+        assert!(grant_memory_request(&balloon, &floor, 41).is_err());
+        assert_eq!(grant_memory_request(&balloon, &floor, 40).unwrap(), 40);
+        assert_eq!(*balloon.size.lock().unwrap(), 60 * BYTES_PER_MIB);
+        assert!(grant_memory_request(&balloon, &floor, 1).is_err());
+    }
+
+    #[test]
+    fn memory_request_is_denied_without_floor() {
+        let balloon = FakeBalloon { size: Mutex::new(100 * BYTES_PER_MIB) };
+        assert!(grant_memory_request(&balloon, &Mutex::new(None), 1).is_err());
+        assert_eq!(*balloon.size.lock().unwrap(), 100 * BYTES_PER_MIB);
+    }
+
+    #[test]
+    fn concurrent_memory_requests_are_all_accounted_for() {
+        let balloon = FakeBalloon { size: Mutex::new(100 * BYTES_PER_MIB) };
+        let floor = Mutex::new(Some(0));
+        thread::scope(|s| {
+            for _ in 0..10 {
+                s.spawn(|| grant_memory_request(&balloon, &floor, 10).unwrap());
+            }
+        });
+        assert_eq!(*balloon.size.lock().unwrap(), 0);
+    }
+
+    /// A VM whose control socket acknowledges the power button if `acknowledges`, and which then
+    /// dies after being polled `polls_until_dead` times.
+    struct FakeVm {
+        acknowledges: bool,
+        polls_until_dead: std::cell::Cell<Option<u32>>,
+    }
+
+    impl VmPowerControl for FakeVm {
+        fn press_power_button(&self) -> Result<()> {
+            if self.acknowledges {
+                Ok(())
+            } else {
+                bail!("Not acknowledged")
+            }
+        }
+
+        fn is_dead(&self) -> bool {
+            match self.polls_until_dead.get() {
+                Some(0) => true,
+                Some(n) => {
+                    self.polls_until_dead.set(Some(n - 1));
+                    false
+                }
+                None => false,
+            }
+        }
+    }
+
+    #[test]
+    fn acknowledged_shutdown_is_waited_for() {
+        let vm = FakeVm { acknowledges: true, polls_until_dead: Some(2).into() };
+        assert!(shut_down_gracefully(&vm, Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn shutdown_times_out_if_vm_keeps_running() {
+        let vm = FakeVm { acknowledges: true, polls_until_dead: None.into() };
+        assert!(!shut_down_gracefully(&vm, Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn unacknowledged_shutdown_is_not_waited_for() {
+        let vm = FakeVm { acknowledges: false, polls_until_dead: Some(0).into() };
+        assert!(!shut_down_gracefully(&vm, Duration::from_secs(10)));
+    }
+
+    struct FakeStats(std::cell::Cell<i64>);
+
+    impl VmStatsSource for FakeStats {
+        fn rss(&self) -> Result<Rss> {
+            Ok(Rss { vm: self.0.get(), crosvm: self.0.get() + 2048 })
+        }
+
+        fn cpu_time(&self) -> Result<Duration> {
+            Ok(Duration::from_millis(1500))
+        }
+    }
+
+    #[test]
+    fn resource_usage_is_read_from_crosvm_process() {
+        let stats = FakeStats(std::cell::Cell::new(50 * 1024));
+
+        assert_eq!(
+            ResourceUsage { rss_bytes: 52 * BYTES_PER_MIB, cpu_time: Duration::from_millis(1500) },
+            read_resource_usage(&stats).unwrap()
+        );
+    }
+
+    #[test]
+    fn crossing_memory_threshold_notifies_once() {
+        let mut monitor = ResourceLimitMonitor::new(NonZeroU32::new(100).unwrap());
+        let stats = FakeStats(std::cell::Cell::new(50 * 1024));
+        let mut events = Vec::new();
+        let mut poll = |kib| {
+            stats.0.set(kib);
+            monitor.poll(&stats, |r, l, o| events.push((r.to_owned(), l, o))).unwrap();
+        };
+
+        poll(50 * 1024);
+        poll(95 * 1024);
+        poll(99 * 1024);
+        poll(10 * 1024);
+        poll(100 * 1024);
+
+        let limit = 100 * BYTES_PER_MIB;
+        assert_eq!(
+            events,
+            vec![
+                (RESOURCE_MEMORY.to_owned(), limit, 95 * BYTES_PER_MIB),
+                (RESOURCE_MEMORY.to_owned(), limit, 100 * BYTES_PER_MIB),
+            ]
+        );
+    }
+
+    fn spawn_fake_crosvm() -> Arc<SharedChild> {
+        Arc::new(SharedChild::spawn(Command::new("sleep").arg("1000")).unwrap())
+    }
+
+    #[test]
+    fn cancelling_start_while_booting_kills_crosvm() {
+        let child = spawn_fake_crosvm();
+        let mut state = VmState::Running { child: child.clone(), monitor_vm_exit_thread: None };
+
+        let cancellation = state.cancel_start(PayloadState::Starting).unwrap();
+
+        assert!(matches!(cancellation, StartCancellation::Killed(None)), "{cancellation:?}");
+        assert!(matches!(state, VmState::Failed), "{state:?}");
+        assert_eq!(Some(libc::SIGKILL), child.wait().unwrap().signal());
+    }
+
+    #[test]
+    fn cancelling_start_of_stopped_vm_is_noop() {
+        for mut state in [VmState::Dead, VmState::Failed] {
+            let before = format!("{state:?}");
+            let cancellation = state.cancel_start(PayloadState::Starting).unwrap();
+            assert!(matches!(cancellation, StartCancellation::TooLate), "{cancellation:?}");
+            assert_eq!(before, format!("{state:?}"));
+        }
+    }
+
+    #[test]
+    fn cancelling_start_once_payload_started_is_noop() {
+        let child = spawn_fake_crosvm();
+        let mut state = VmState::Running { child: child.clone(), monitor_vm_exit_thread: None };
+
+        let cancellation = state.cancel_start(PayloadState::Started).unwrap();
+
+        assert!(matches!(cancellation, StartCancellation::TooLate), "{cancellation:?}");
+        assert!(matches!(state, VmState::Running { .. }), "{state:?}");
+        assert_eq!(None, child.try_wait().unwrap());
+        child.kill().unwrap();
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cache of the digests of APKs, so that an idsig file can be found up-to-date with an APK which
+//! hasn't changed without reading the APK again.
+
+use anyhow::{Context, Result};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::os::unix::fs::MetadataExt;
+use std::sync::{LazyLock, Mutex};
+
+/// The maximum number of APKs whose digest is cached.
+const APK_DIGEST_CACHE_CAPACITY: usize = 64;
+
+/// The digests of the APKs for which an idsig file was requested, shared by all the clients.
+pub static APK_DIGEST_CACHE: LazyLock<ApkDigestCache> =
+    LazyLock::new(|| ApkDigestCache::new(APK_DIGEST_CACHE_CAPACITY));
+
+/// Identifies a file, regardless of the path it is opened from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct FileId {
+    dev: u64,
+    ino: u64,
+}
+
+/// Identifies the contents of a file, assuming that they don't change without its modification
+/// time or size changing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct FileVersion {
+    mtime: i64,
+    mtime_nsec: i64,
+    size: u64,
+}
+
+/// Bounded cache of APK digests. The least recently added entries are evicted first.
+pub struct ApkDigestCache {
+    capacity: usize,
+    entries: Mutex<VecDeque<(FileId, FileVersion, Box<[u8]>)>>,
+}
+
+impl ApkDigestCache {
+    fn new(capacity: usize) -> Self {
+        Self { capacity, entries: Mutex::new(VecDeque::with_capacity(capacity)) }
+    }
+
+    /// Returns the digest of `apk`, as cached for its current version or else as computed by
+    /// `compute`. The digest cached for a previous version of `apk` is replaced.
+    pub fn get_or_compute(
+        &self,
+        apk: &mut File,
+        compute: impl FnOnce(&mut File) -> Result<Box<[u8]>>,
+    ) -> Result<Box<[u8]>> {
+        let metadata = apk.metadata().context("failed to get APK metadata")?;
+        let id = FileId { dev: metadata.dev(), ino: metadata.ino() };
+        let version = FileVersion {
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            size: metadata.size(),
+        };
+
+        {
+            let mut entries = self.entries.lock().unwrap();
+            if let Some(i) = entries.iter().position(|(entry_id, _, _)| *entry_id == id) {
+                if entries[i].1 == version {
+                    return Ok(entries[i].2.clone());
+                }
+                entries.remove(i);
+            }
+        }
+
+        // Don't hold the lock while reading the APK.
+        let digest = compute(apk)?;
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|(entry_id, _, _)| *entry_id != id);
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back((id, version, digest.clone()));
+        Ok(digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn unchanged_apk_is_served_from_cache() -> Result<()> {
+        let cache = ApkDigestCache::new(2);
+        let mut apk = tempfile::tempfile()?;
+        apk.write_all(b"apk")?;
+        let mut computed = 0;
+        let mut compute = |_: &mut File| {
+            computed += 1;
+            Ok(Box::from([computed]))
+        };
+
+        assert_eq!([1], *cache.get_or_compute(&mut apk, &mut compute)?);
+        assert_eq!([1], *cache.get_or_compute(&mut apk, &mut compute)?);
+        assert_eq!(1, computed);
+        Ok(())
+    }
+
+    #[test]
+    fn modified_apk_bypasses_cache() -> Result<()> {
+        let cache = ApkDigestCache::new(2);
+        let mut apk = tempfile::tempfile()?;
+        apk.write_all(b"apk")?;
+        let mut computed = 0;
+        let mut compute = |_: &mut File| {
+            computed += 1;
+            Ok(Box::from([computed]))
+        };
+
+        assert_eq!([1], *cache.get_or_compute(&mut apk, &mut compute)?);
+        apk.write_all(b" v2")?;
+        assert_eq!([2], *cache.get_or_compute(&mut apk, &mut compute)?);
+        assert_eq!([2], *cache.get_or_compute(&mut apk, &mut compute)?);
+        assert_eq!(2, computed);
+        Ok(())
+    }
+
+    #[test]
+    fn oldest_entries_are_evicted() -> Result<()> {
+        let cache = ApkDigestCache::new(2);
+        let mut apks = [tempfile::tempfile()?, tempfile::tempfile()?, tempfile::tempfile()?];
+        let mut computed = 0;
+        let mut compute = |_: &mut File| {
+            computed += 1;
+            Ok(Box::from([computed]))
+        };
+
+        for apk in &mut apks {
+            cache.get_or_compute(apk, &mut compute)?;
+        }
+        assert_eq!([3], *cache.get_or_compute(&mut apks[2], &mut compute)?);
+        assert_eq!([4], *cache.get_or_compute(&mut apks[0], &mut compute)?);
+        assert_eq!(4, computed);
+        Ok(())
+    }
+}
@@ -0,0 +1,324 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Allowlists of the SELinux labels of the files a VM may be sourced from, and of the partitions
+//! which are exempt from that check.
+//!
+//! The built-in allowlists can be extended by the device, without a code change, in
+//! `/vendor/etc/avf/label_allowlist.json`. virtmgr can't read the vendor partition, so the file is
+//! read by VirtualizationServiceInternal and passed to `init`.
+
+use crate::selinux::SeContext;
+use anyhow::{bail, ensure, Context, Result};
+use log::{error, info};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+/// SELinux types of the files a VM may be sourced from, unless the device allows more.
+///
+/// We only want to allow code in a VM to be sourced from places that apps, and the system or
+/// vendor, do not have write access to.
+///
+/// Note that sepolicy must also grant read access for these types to both virtualization service
+/// and crosvm.
+const DEFAULT_SELINUX_TYPES: &[&str] = &[
+    "apk_data_file",                   // APKs of an installed app
+    "shell_data_file",                 // test files created via adb shell
+    "staging_data_file",               // updated/staged APEX images
+    "system_file",                     // immutable dm-verity protected partition
+    "virtualizationservice_data_file", // files created by VS / VirtMgr
+    "vendor_microdroid_file", // immutable dm-verity protected partition (/vendor/etc/avf/microdroid/.*)
+];
+
+/// SELinux types which the device can't allow, along with any type ending in `app_data_file`.
+///
+/// Files which apps can write are deliberately excluded, to avoid arbitrary payloads being run on
+/// user devices (W^X).
+const FORBIDDEN_SELINUX_TYPES: &[&str] = &[
+    "app_data_file",         // app private data
+    "privapp_data_file",     // privileged app private data
+    "sdk_sandbox_data_file", // SDK sandbox private data
+    "media_rw_data_file",    // shared storage
+    "fuse",                  // shared storage, as mounted for apps
+    "sdcardfs",              // shared storage, on older devices
+    "vfat",                  // removable storage
+    "exfat",                 // removable storage
+    "app_fuse_file",         // files served by apps through appfuse
+    "mnt_user_file",         // per-user views of shared storage
+    "storage_file",          // shared storage mount points
+];
+
+/// Partitions of app config VMs which contain code, and so are never exempt from label checks. See
+/// add_microdroid_system_images & add_microdroid_payload_images in payload.rs.
+const CODE_APP_PARTITIONS: &[&str] = &["microdroid-apk", "microdroid-vendor"];
+
+/// Prefixes of the labels of the other partitions of app config VMs which contain code.
+const CODE_APP_PARTITION_PREFIXES: &[&str] = &["microdroid-apex-", "extra-apk-"];
+
+/// Partitions of app config VMs which don't contain code and are likely to be generated in an
+/// app-writable directory. See add_microdroid_system_images & add_microdroid_payload_images in
+/// payload.rs.
+const DEFAULT_SAFE_APP_PARTITIONS: &[&str] =
+    &["vm-instance", "encryptedstore", "microdroid-apk-idsig", "payload-metadata"];
+
+/// Prefixes of the labels of the other partitions of app config VMs which don't contain code,
+/// e.g. because there may be any number of them.
+const DEFAULT_SAFE_APP_PARTITION_PREFIXES: &[&str] = &["extra-idsig-"];
+
+/// Partitions of raw config VMs which don't contain code.
+const DEFAULT_SAFE_RAW_PARTITIONS: &[&str] = &["vm-instance"];
+
+/// The allowlists in effect. They are the built-in ones unless `init` was called first.
+static LABEL_ALLOWLIST: OnceLock<LabelAllowlist> = OnceLock::new();
+
+/// Sets the allowlists in effect to the built-in ones, extended with `extensions` if there are any.
+/// Does nothing if they were already set.
+pub fn init(extensions: Option<&str>) {
+    LABEL_ALLOWLIST
+        .get_or_init(|| extensions.map_or_else(LabelAllowlist::default, LabelAllowlist::load));
+}
+
+/// Returns the allowlists in effect.
+pub fn label_allowlist() -> &'static LabelAllowlist {
+    LABEL_ALLOWLIST.get_or_init(LabelAllowlist::default)
+}
+
+/// Additions to the built-in allowlists, as read from `/vendor/etc/avf/label_allowlist.json`.
+///
+/// Partitions of raw config VMs can't be added, as their contents are up to the client, so none of
+/// them can be known not to contain code.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Extensions {
+    selinux_types: Vec<String>,
+    safe_app_partitions: Vec<String>,
+    safe_app_partition_prefixes: Vec<String>,
+}
+
+/// Which files a VM may be sourced from, and which partitions are exempt from that check.
+#[derive(Debug)]
+pub struct LabelAllowlist {
+    selinux_types: HashSet<String>,
+    safe_app_partitions: HashSet<String>,
+    safe_app_partition_prefixes: Vec<String>,
+    safe_raw_partitions: HashSet<String>,
+}
+
+impl Default for LabelAllowlist {
+    fn default() -> Self {
+        let to_set = |labels: &[&str]| labels.iter().map(|label| label.to_string()).collect();
+        Self {
+            selinux_types: to_set(DEFAULT_SELINUX_TYPES),
+            safe_app_partitions: to_set(DEFAULT_SAFE_APP_PARTITIONS),
+            safe_app_partition_prefixes: DEFAULT_SAFE_APP_PARTITION_PREFIXES
+                .iter()
+                .map(|prefix| prefix.to_string())
+                .collect(),
+            safe_raw_partitions: to_set(DEFAULT_SAFE_RAW_PARTITIONS),
+        }
+    }
+}
+
+impl LabelAllowlist {
+    /// Returns the built-in allowlists, extended with the ones in the JSON `extensions`. Falls back
+    /// to the built-in allowlists if `extensions` is invalid.
+    fn load(extensions: &str) -> Self {
+        let mut allowlist = Self::default();
+        match parse_extensions(extensions).and_then(|extensions| allowlist.extend(extensions)) {
+            Ok(()) => info!("Extended the label allowlists of the device"),
+            Err(e) => {
+                error!("Ignoring invalid label allowlist of the device: {e:?}");
+                allowlist = Self::default();
+            }
+        }
+        allowlist
+    }
+
+    fn extend(&mut self, extensions: Extensions) -> Result<()> {
+        for selinux_type in &extensions.selinux_types {
+            ensure!(!is_app_writable(selinux_type), "{selinux_type} can't be allowed");
+        }
+        for label in &extensions.safe_app_partitions {
+            ensure!(!is_code_app_partition(label), "{label} contains code");
+        }
+        // Prefixes may only exempt new families of partitions, so that a short one, e.g. "m" or
+        // "extra-", doesn't exempt the partitions which contain code along with them.
+        for prefix in &extensions.safe_app_partition_prefixes {
+            ensure!(
+                !matches_built_in_app_partition(prefix),
+                "Prefix {prefix:?} matches built-in partitions"
+            );
+        }
+        self.selinux_types.extend(extensions.selinux_types);
+        self.safe_app_partitions.extend(extensions.safe_app_partitions);
+        self.safe_app_partition_prefixes.extend(extensions.safe_app_partition_prefixes);
+        Ok(())
+    }
+
+    /// Returns whether a partition is exempt from selinux label checks, because we know that it
+    /// does not contain code and is likely to be generated in an app-writable directory.
+    pub fn is_safe_app_partition(&self, label: &str) -> bool {
+        self.safe_app_partitions.contains(label)
+            || self.safe_app_partition_prefixes.iter().any(|prefix| label.starts_with(prefix))
+    }
+
+    /// Returns whether a partition with the given label is safe for a raw config VM.
+    pub fn is_safe_raw_partition(&self, label: &str) -> bool {
+        self.safe_raw_partitions.contains(label)
+    }
+
+    /// Checks that a file SELinux label is acceptable.
+    pub fn check_label_is_allowed(&self, context: &SeContext) -> Result<()> {
+        if self.selinux_types.contains(context.selinux_type()?) {
+            Ok(())
+        } else {
+            bail!("Label {} is not allowed", context)
+        }
+    }
+}
+
+fn parse_extensions(json: &str) -> Result<Extensions> {
+    serde_json::from_str(json).context("Can't parse label allowlist")
+}
+
+/// Returns whether files of the given SELinux type may be written by apps.
+fn is_app_writable(selinux_type: &str) -> bool {
+    FORBIDDEN_SELINUX_TYPES.contains(&selinux_type) || selinux_type.ends_with("app_data_file")
+}
+
+/// Returns whether `prefix` matches the label of any partition which virtmgr generates for app
+/// config VMs, e.g. because it is a prefix of `microdroid-apk` or of `extra-apk-`, or an
+/// `extra-apk-` label itself.
+fn matches_built_in_app_partition(prefix: &str) -> bool {
+    let mut labels = CODE_APP_PARTITIONS.iter().chain(DEFAULT_SAFE_APP_PARTITIONS);
+    let mut families =
+        CODE_APP_PARTITION_PREFIXES.iter().chain(DEFAULT_SAFE_APP_PARTITION_PREFIXES);
+    labels.any(|label| label.starts_with(prefix))
+        || families.any(|family| family.starts_with(prefix) || prefix.starts_with(family))
+}
+
+/// Returns whether the partition of an app config VM with the given label contains code.
+fn is_code_app_partition(label: &str) -> bool {
+    CODE_APP_PARTITIONS.contains(&label)
+        || CODE_APP_PARTITION_PREFIXES.iter().any(|prefix| label.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_allowed(allowlist: &LabelAllowlist, label: &str) -> bool {
+        allowlist.check_label_is_allowed(&SeContext::new(label).unwrap()).is_ok()
+    }
+
+    #[test]
+    fn defaults_keep_app_data_files_out() {
+        let allowlist = LabelAllowlist::default();
+
+        assert!(is_allowed(&allowlist, "u:object_r:apk_data_file:s0"));
+        assert!(is_allowed(&allowlist, "u:object_r:system_file:s0"));
+        assert!(!is_allowed(&allowlist, "u:object_r:app_data_file:s0"));
+        assert!(!is_allowed(&allowlist, "u:object_r:privapp_data_file:s0:c512,c768"));
+        assert!(allowlist.is_safe_app_partition("extra-idsig-0"));
+        assert!(!allowlist.is_safe_app_partition("microdroid-apk"));
+        assert!(!allowlist.is_safe_raw_partition("encryptedstore"));
+    }
+
+    #[test]
+    fn allowlist_is_extended() {
+        let allowlist = LabelAllowlist::load(
+            r#"{"selinux_types": ["vendor_payload_file"], "safe_app_partitions": ["scratch"]}"#,
+        );
+
+        assert!(is_allowed(&allowlist, "u:object_r:vendor_payload_file:s0"));
+        assert!(is_allowed(&allowlist, "u:object_r:apk_data_file:s0"));
+        assert!(allowlist.is_safe_app_partition("scratch"));
+        assert!(allowlist.is_safe_app_partition("vm-instance"));
+        assert!(!allowlist.is_safe_raw_partition("scratch"));
+    }
+
+    #[test]
+    fn app_partition_prefixes_are_extended() {
+        let allowlist =
+            LabelAllowlist::load(r#"{"safe_app_partition_prefixes": ["extra-hashtree-"]}"#);
+
+        assert!(allowlist.is_safe_app_partition("extra-hashtree-0"));
+        assert!(allowlist.is_safe_app_partition("extra-idsig-1"));
+        assert!(allowlist.is_safe_app_partition("payload-metadata"));
+        assert!(!allowlist.is_safe_app_partition("extra-apk-0"));
+        assert!(!allowlist.is_safe_app_partition("extra-hashtree"));
+        assert!(!allowlist.is_safe_raw_partition("extra-hashtree-0"));
+    }
+
+    #[test]
+    fn empty_app_partition_prefix_cant_be_allowed() {
+        let allowlist =
+            LabelAllowlist::load(r#"{"safe_app_partition_prefixes": ["extra-hashtree-", ""]}"#);
+
+        assert!(!allowlist.is_safe_app_partition("microdroid-apk"));
+        // The whole allowlist is ignored.
+        assert!(!allowlist.is_safe_app_partition("extra-hashtree-0"));
+        assert!(allowlist.is_safe_app_partition("extra-idsig-0"));
+    }
+
+    #[test]
+    fn app_partition_prefixes_matching_built_in_partitions_cant_be_allowed() {
+        for prefix in ["m", "microdroid-apk", "extra-", "extra-apk-1", "payload", "extra-idsig-"] {
+            let allowlist = LabelAllowlist::load(&format!(
+                r#"{{"safe_app_partition_prefixes": ["extra-hashtree-", "{prefix}"]}}"#
+            ));
+
+            assert!(!allowlist.is_safe_app_partition("microdroid-apk"));
+            assert!(!allowlist.is_safe_app_partition("extra-apk-1"));
+            // The whole allowlist is ignored.
+            assert!(!allowlist.is_safe_app_partition("extra-hashtree-0"));
+        }
+    }
+
+    #[test]
+    fn app_writable_types_cant_be_allowed() {
+        for selinux_type in ["app_data_file", "media_rw_data_file", "system_app_data_file"] {
+            let allowlist = LabelAllowlist::load(&format!(
+                r#"{{"selinux_types": ["vendor_payload_file", "{selinux_type}"]}}"#
+            ));
+
+            assert!(!is_allowed(&allowlist, &format!("u:object_r:{selinux_type}:s0")));
+            // The whole allowlist is ignored.
+            assert!(!is_allowed(&allowlist, "u:object_r:vendor_payload_file:s0"));
+            assert!(is_allowed(&allowlist, "u:object_r:apk_data_file:s0"));
+        }
+    }
+
+    #[test]
+    fn code_partitions_cant_be_exempted() {
+        for label in ["microdroid-apk", "microdroid-vendor", "microdroid-apex-0", "extra-apk-1"] {
+            let allowlist = LabelAllowlist::load(&format!(
+                r#"{{"safe_app_partitions": ["scratch", "{label}"]}}"#
+            ));
+
+            assert!(!allowlist.is_safe_app_partition(label));
+            // The whole allowlist is ignored.
+            assert!(!allowlist.is_safe_app_partition("scratch"));
+        }
+    }
+
+    #[test]
+    fn raw_partitions_cant_be_exempted() {
+        let allowlist = LabelAllowlist::load(r#"{"safe_raw_partitions": ["scratch"]}"#);
+
+        assert!(!allowlist.is_safe_raw_partition("scratch"));
+        assert!(allowlist.is_safe_raw_partition("vm-instance"));
+    }
+}
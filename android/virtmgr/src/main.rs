@@ -17,17 +17,23 @@
 mod aidl;
 mod atom;
 mod composite;
+mod console_history;
 mod crosvm;
 mod debug_config;
+mod digest_cache;
 mod dt_overlay;
+mod label_allowlist;
 mod payload;
+mod ramdump;
+mod retry;
 mod selinux;
+mod vsock_ports;
 
 use crate::aidl::{GLOBAL_SERVICE, VirtualizationService};
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::IVirtualizationService::BnVirtualizationService;
 use anyhow::{bail, Result};
 use binder::{BinderFeatures, ProcessState};
-use log::{info, LevelFilter};
+use log::{error, info, LevelFilter};
 use rpcbinder::{FileDescriptorTransportMode, RpcServer};
 use std::os::unix::io::{AsFd, RawFd};
 use std::sync::LazyLock;
@@ -120,6 +126,11 @@ fn main() {
         }
     } else {
         GLOBAL_SERVICE.removeMemlockRlimit().expect("Failed to remove memlock rlimit");
+        // Early VMs only use the built-in label allowlist.
+        match GLOBAL_SERVICE.getLabelAllowlistExtensions() {
+            Ok(extensions) => label_allowlist::init(extensions.as_deref()),
+            Err(e) => error!("Failed to get the label allowlist of the device: {e:?}"),
+        }
     }
 
     let service = VirtualizationService::init();
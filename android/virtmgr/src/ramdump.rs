@@ -0,0 +1,104 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Capture of the ramdump which a VM emits to /dev/hvc1, bounded in size so that a guest which
+//! keeps emitting data can't fill up the host storage.
+
+use crate::aidl::Cid;
+use log::{error, warn};
+use nix::fcntl::OFlag;
+use nix::unistd::pipe2;
+use std::fs::File;
+use std::io::{self, Read};
+use std::thread::{self, JoinHandle};
+
+/// Returns the write end of a pipe, everything written to which is written to `output`, up to
+/// `max_size` bytes. Anything beyond that is discarded. The returned thread finishes once the pipe
+/// is closed, at which point the ramdump in `output` is complete.
+pub fn capture_ramdump(
+    output: File,
+    max_size: u64,
+    cid: Cid,
+) -> io::Result<(File, JoinHandle<()>)> {
+    let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC)?;
+    let mut reader = File::from(read_fd);
+
+    let capture_thread = thread::spawn(move || {
+        if let Err(e) = copy_capped(&mut reader, output, max_size, cid) {
+            error!("Could not capture the ramdump of VM cid={cid}: {e:?}");
+        }
+    });
+
+    Ok((File::from(write_fd), capture_thread))
+}
+
+fn copy_capped(
+    reader: &mut impl Read,
+    mut output: File,
+    max_size: u64,
+    cid: Cid,
+) -> io::Result<()> {
+    io::copy(&mut reader.by_ref().take(max_size), &mut output)?;
+
+    // Keep reading until the pipe is closed, so that the guest isn't blocked, but drop the rest.
+    let mut buf = [0; 1];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(_) => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    warn!("Ramdump of VM cid={cid} exceeds {max_size} bytes, discarding the rest");
+    let discarded = 1 + io::copy(reader, &mut io::sink())?;
+    warn!("Discarded {discarded} bytes of the ramdump of VM cid={cid}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+
+    const CID: Cid = 42;
+
+    fn capture(ramdump: &[u8], max_size: u64) -> io::Result<Vec<u8>> {
+        let test_dir = tempfile::TempDir::new()?;
+        let ramdump_path = test_dir.path().join("ramdump");
+        let (mut pipe, capture_thread) =
+            capture_ramdump(File::create(&ramdump_path)?, max_size, CID)?;
+
+        pipe.write_all(ramdump)?;
+        drop(pipe);
+        capture_thread.join().unwrap();
+
+        fs::read(ramdump_path)
+    }
+
+    #[test]
+    fn ramdump_within_cap_is_kept_whole() -> io::Result<()> {
+        let ramdump: Vec<u8> = (0..=255).cycle().take(100_000).collect();
+        assert_eq!(ramdump, capture(&ramdump, 100_000)?);
+        Ok(())
+    }
+
+    #[test]
+    fn ramdump_exceeding_cap_is_truncated() -> io::Result<()> {
+        let ramdump: Vec<u8> = (0..=255).cycle().take(300_000).collect();
+        assert_eq!(&ramdump[..100_000], capture(&ramdump, 100_000)?);
+        Ok(())
+    }
+}
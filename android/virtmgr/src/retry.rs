@@ -0,0 +1,122 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Retrying of operations which may only succeed once the VM has made some progress, e.g.
+//! connecting to a port that the payload has yet to listen on.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The delay before the first retry. It doubles with each retry, up to `MAX_RETRY_DELAY`.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(10);
+/// The longest delay between two attempts.
+const MAX_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Calls `attempt` until it succeeds or `timeout` has elapsed, backing off exponentially between
+/// attempts. `attempt` is called at least once, and the error of the last attempt is returned if
+/// none succeeds.
+pub fn retry_until_timeout<T, E>(
+    timeout: Duration,
+    attempt: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    retry_while(timeout, || true, attempt)
+}
+
+/// Same as `retry_until_timeout`, but also stops retrying as soon as `keep_trying` returns false
+/// after a failed attempt, e.g. because the VM the attempts depend on has died.
+pub fn retry_while<T, E>(
+    timeout: Duration,
+    mut keep_trying: impl FnMut() -> bool,
+    mut attempt: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let deadline = Instant::now() + timeout;
+    let mut delay = INITIAL_RETRY_DELAY;
+    loop {
+        let e = match attempt() {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        let now = Instant::now();
+        if now >= deadline || !keep_trying() {
+            return Err(e);
+        }
+        thread::sleep(delay.min(deadline - now));
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn retries_until_success() {
+        let mut attempts = 0;
+        let result = retry_until_timeout(Duration::from_secs(10), || {
+            attempts += 1;
+            if attempts < 4 {
+                Err(attempts)
+            } else {
+                Ok(attempts)
+            }
+        });
+
+        assert_eq!(Ok(4), result);
+    }
+
+    #[test]
+    fn returns_last_error_on_timeout() {
+        let timeout = Duration::from_millis(100);
+        let start = Instant::now();
+        let mut attempts = 0;
+        let result: Result<(), _> = retry_until_timeout(timeout, || {
+            attempts += 1;
+            Err(attempts)
+        });
+
+        assert!(start.elapsed() >= timeout);
+        assert!(attempts > 1);
+        assert_eq!(Err(attempts), result);
+    }
+
+    #[test]
+    fn stops_retrying_when_told() {
+        let start = Instant::now();
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = retry_while(
+            Duration::from_secs(10),
+            || attempts.get() < 3,
+            || {
+                attempts.set(attempts.get() + 1);
+                Err(attempts.get())
+            },
+        );
+
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(Err(3), result);
+    }
+
+    #[test]
+    fn zero_timeout_attempts_once() {
+        let mut attempts = 0;
+        let result: Result<(), _> = retry_until_timeout(Duration::ZERO, || {
+            attempts += 1;
+            Err(())
+        });
+
+        assert_eq!(Err(()), result);
+        assert_eq!(1, attempts);
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Host-side vsock listeners for the ports reserved in the config of a VM, through which the guest
+//! can connect to the client of the VM.
+
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use vsock::{VsockListener, VsockStream};
+
+/// How often a listener with no pending connection checks whether it should stop.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Listeners for the reserved vsock ports of a VM. They are closed when this is dropped.
+#[derive(Debug, Default)]
+pub struct ReservedVsockPorts {
+    stopped: Arc<AtomicBool>,
+}
+
+impl ReservedVsockPorts {
+    /// Binds a listener to each of `ports` of `bind_cid`, and calls `on_connection` with the port
+    /// and the stream of each connection accepted from `guest_cid`. Connections from other CIDs
+    /// are refused.
+    ///
+    /// Fails without listening on any port if any of them can't be bound, e.g. because it is
+    /// already in use.
+    pub fn bind(
+        bind_cid: u32,
+        ports: &[u32],
+        guest_cid: u32,
+        on_connection: impl Fn(u32, VsockStream) + Send + Sync + 'static,
+    ) -> Result<Self> {
+        let listeners = ports
+            .iter()
+            .map(|&port| {
+                let listener = VsockListener::bind_with_cid_port(bind_cid, port)
+                    .with_context(|| format!("Failed to listen on vsock port {port}"))?;
+                listener.set_nonblocking(true)?;
+                Ok((port, listener))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let on_connection = Arc::new(on_connection);
+        for (port, listener) in listeners {
+            let stopped = stopped.clone();
+            let on_connection = on_connection.clone();
+            thread::spawn(move || {
+                accept_connections(&listener, port, guest_cid, &stopped, &*on_connection)
+            });
+        }
+        Ok(Self { stopped })
+    }
+}
+
+impl Drop for ReservedVsockPorts {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+fn accept_connections(
+    listener: &VsockListener,
+    port: u32,
+    guest_cid: u32,
+    stopped: &AtomicBool,
+    on_connection: &dyn Fn(u32, VsockStream),
+) {
+    while !stopped.load(Ordering::Relaxed) {
+        let (stream, addr) = match listener.accept() {
+            Ok(connection) => connection,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                error!("Failed to accept connection on vsock port {port}: {e:?}");
+                return;
+            }
+        };
+        if addr.cid() != guest_cid {
+            warn!("Refusing connection to vsock port {port} from cid={}", addr.cid());
+            continue;
+        }
+        if let Err(e) = stream.set_nonblocking(false) {
+            error!("Failed to set up connection to vsock port {port}: {e:?}");
+            continue;
+        }
+        info!("Accepted connection to vsock port {port} from cid={guest_cid}");
+        on_connection(port, stream);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc::VMADDR_CID_LOCAL;
+    use std::io::{Read, Write};
+    use std::sync::mpsc;
+
+    #[test]
+    fn connection_to_reserved_port_is_delivered() -> Result<()> {
+        let port = 51835;
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let _ports = ReservedVsockPorts::bind(VMADDR_CID_LOCAL, &[port], VMADDR_CID_LOCAL, {
+            move |port, stream| sender.send((port, stream)).unwrap()
+        })?;
+
+        let mut client = VsockStream::connect_with_cid_port(VMADDR_CID_LOCAL, port)?;
+        client.write_all(b"hello")?;
+        let (accepted_port, mut stream) = receiver.recv_timeout(Duration::from_secs(10))?;
+
+        assert_eq!(port, accepted_port);
+        let mut message = [0; 5];
+        stream.read_exact(&mut message)?;
+        assert_eq!(b"hello", &message);
+        Ok(())
+    }
+
+    #[test]
+    fn connection_from_other_cid_is_refused() -> Result<()> {
+        let port = 51836;
+        let guest_cid = 1234;
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let _ports = ReservedVsockPorts::bind(VMADDR_CID_LOCAL, &[port], guest_cid, {
+            move |port, stream| sender.send((port, stream)).unwrap()
+        })?;
+
+        let mut client = VsockStream::connect_with_cid_port(VMADDR_CID_LOCAL, port)?;
+        // The connection is closed by the host without being delivered.
+        assert_eq!(0, client.read(&mut [0; 1])?);
+        assert!(receiver.try_recv().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn bind_fails_if_port_is_in_use() -> Result<()> {
+        let port = 51837;
+        let _listener = VsockListener::bind_with_cid_port(VMADDR_CID_LOCAL, port)?;
+
+        assert!(ReservedVsockPorts::bind(VMADDR_CID_LOCAL, &[port], VMADDR_CID_LOCAL, |_, _| {})
+            .is_err());
+        Ok(())
+    }
+}
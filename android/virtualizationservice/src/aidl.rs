@@ -15,6 +15,7 @@
 //! Implementation of the AIDL interface of the VirtualizationService.
 
 use crate::atom::{forward_vm_booted_atom, forward_vm_creation_atom, forward_vm_exited_atom};
+use crate::guest_file::{receive_guest_file, ConnectionSlots, GuestFileLimits, VmQuota};
 use crate::maintenance;
 use crate::remote_provisioning;
 use crate::rkpvm::{generate_ecdsa_p256_key_pair, request_attestation};
@@ -33,7 +34,7 @@ use binder::{
     self, wait_for_interface, BinderFeatures, ExceptionCode, Interface, IntoBinderResult,
     LazyServiceGuard, ParcelFileDescriptor, Status, Strong,
 };
-use libc::{VMADDR_CID_HOST, VMADDR_CID_HYPERVISOR, VMADDR_CID_LOCAL};
+use libc::{VMADDR_CID_ANY, VMADDR_CID_HOST, VMADDR_CID_HYPERVISOR, VMADDR_CID_LOCAL};
 use log::{error, info, warn};
 use nix::unistd::{chown, Uid};
 use openssl::x509::X509;
@@ -46,8 +47,11 @@ use rustutils::{
 use serde::Deserialize;
 use service_vm_comm::Response;
 use std::collections::{HashMap, HashSet};
-use std::fs::{self, create_dir, remove_dir_all, remove_file, set_permissions, File, Permissions};
+use std::fs::{
+    self, create_dir, read_dir, remove_dir_all, remove_file, set_permissions, File, Permissions,
+};
 use std::io::{Read, Write};
+use std::ops::RangeInclusive;
 use std::os::unix::fs::PermissionsExt;
 use std::os::unix::raw::{pid_t, uid_t};
 use std::path::{Path, PathBuf};
@@ -60,6 +64,7 @@ use virtualizationmaintenance::{
 };
 use virtualizationservice::{
     AssignableDevice::AssignableDevice, VirtualMachineDebugInfo::VirtualMachineDebugInfo,
+    VirtualMachineInfo::VirtualMachineInfo, VirtualMachineState::VirtualMachineState,
 };
 use virtualizationservice_internal::{
     AtomVmBooted::AtomVmBooted,
@@ -67,12 +72,15 @@ use virtualizationservice_internal::{
     AtomVmExited::AtomVmExited,
     IBoundDevice::IBoundDevice,
     IGlobalVmContext::{BnGlobalVmContext, IGlobalVmContext},
+    IGuestFileCallback::IGuestFileCallback,
     IVfioHandler::VfioDev::VfioDev,
     IVfioHandler::{BpVfioHandler, IVfioHandler},
     IVirtualizationServiceInternal::IVirtualizationServiceInternal,
     IVmnic::{BpVmnic, IVmnic},
 };
-use virtualmachineservice::IVirtualMachineService::VM_TOMBSTONES_SERVICE_PORT;
+use virtualmachineservice::IVirtualMachineService::{
+    VM_FILE_TRANSFER_SERVICE_PORT, VM_TOMBSTONES_SERVICE_PORT,
+};
 use vmtethering::IVmTethering::{BpVmTethering, IVmTethering};
 use vsock::{VsockListener, VsockStream};
 
@@ -88,9 +96,15 @@ const GUEST_CID_MIN: Cid = 2048;
 const GUEST_CID_MAX: Cid = 65535;
 
 const SYSPROP_LAST_CID: &str = "virtualizationservice.state.last_cid";
+/// The range of CIDs to assign to guest VMs, as "<first>" or "<first>-<last>", for hosts which
+/// reserve CIDs from the default range for other usage.
+const SYSPROP_CID_RANGE: &str = "virtualizationservice.cid_range";
 
 const CHUNK_RECV_MAX_LEN: usize = 1024;
 
+/// Path of the file in which the device extends the label allowlists of virtmgr.
+const LABEL_ALLOWLIST_PATH: &str = "/vendor/etc/avf/label_allowlist.json";
+
 /// The fake certificate is used for testing only when a client VM requests attestation in test
 /// mode, it is a single certificate extracted on an unregistered device for testing.
 /// Here is the snapshot of the certificate:
@@ -169,8 +183,34 @@ static TETHERING_SERVICE: LazyLock<Strong<dyn IVmTethering>> = LazyLock::new(||
         .expect("Could not connect to VmTethering")
 });
 
-fn is_valid_guest_cid(cid: Cid) -> bool {
-    (GUEST_CID_MIN..=GUEST_CID_MAX).contains(&cid)
+/// Parses a range of guest CIDs, given as "<first>" or "<first>-<last>". The last CID defaults to
+/// `GUEST_CID_MAX`.
+fn parse_cid_range(value: &str) -> Result<RangeInclusive<Cid>> {
+    let (first, last): (Cid, Cid) = match value.split_once('-') {
+        Some((first, last)) => (first.parse()?, last.parse()?),
+        None => (value.parse()?, GUEST_CID_MAX),
+    };
+    ensure!(first >= GUEST_CID_MIN, "CIDs lower than {GUEST_CID_MIN} are reserved");
+    ensure!(last < VMADDR_CID_ANY, "CID {VMADDR_CID_ANY} is reserved");
+    ensure!(first <= last, "Empty range");
+    Ok(first..=last)
+}
+
+/// Returns the range of CIDs to assign to guest VMs, which can be configured with
+/// `SYSPROP_CID_RANGE`.
+fn read_cid_range() -> RangeInclusive<Cid> {
+    let default = GUEST_CID_MIN..=GUEST_CID_MAX;
+    match system_properties::read(SYSPROP_CID_RANGE) {
+        Ok(Some(value)) => parse_cid_range(&value).unwrap_or_else(|e| {
+            error!("Invalid value '{value}' of property '{SYSPROP_CID_RANGE}': {e:?}");
+            default
+        }),
+        Ok(None) => default,
+        Err(e) => {
+            error!("Failed to read property '{SYSPROP_CID_RANGE}': {e:?}");
+            default
+        }
+    }
 }
 
 /// Singleton service for allocating globally-unique VM resources, such as the CID, and running
@@ -184,16 +224,25 @@ pub struct VirtualizationServiceInternal {
 impl VirtualizationServiceInternal {
     pub fn init() -> VirtualizationServiceInternal {
         let service = VirtualizationServiceInternal {
-            state: Arc::new(Mutex::new(GlobalState::new())),
+            state: Arc::new(Mutex::new(GlobalState::new(read_cid_range()))),
             display_service_set: Arc::new(Condvar::new()),
         };
+        service.state.lock().unwrap().remove_stale_temporary_dirs(Path::new(TEMPORARY_DIRECTORY));
 
-        std::thread::spawn(|| {
-            if let Err(e) = handle_stream_connection_tombstoned() {
+        let state = service.state.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = handle_stream_connection_tombstoned(&state) {
                 warn!("Error receiving tombstone from guest or writing them. Error: {:?}", e);
             }
         });
 
+        let state = service.state.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = handle_stream_connection_guest_files(&state, GuestFileLimits::read()) {
+                warn!("Error receiving files from guests. Error: {:?}", e);
+            }
+        });
+
         service
     }
 }
@@ -300,6 +349,22 @@ impl IVirtualizationServiceInternal for VirtualizationServiceInternal {
         Ok(cids)
     }
 
+    fn listMyVms(&self) -> binder::Result<Vec<VirtualMachineInfo>> {
+        check_manage_access()?;
+
+        // Each client process has its own virtmgr, running as the client's UID, so the VMs of the
+        // same app are only all known here.
+        let state = self.state.lock().unwrap();
+        Ok(state.vms_of_uid(get_calling_uid()))
+    }
+
+    fn isCidInUse(&self, cid: i32) -> binder::Result<bool> {
+        check_debug_access()?;
+
+        let state = self.state.lock().unwrap();
+        Ok(Cid::try_from(cid).is_ok_and(|cid| state.is_cid_in_use(cid)))
+    }
+
     fn enableTestAttestation(&self) -> binder::Result<()> {
         check_manage_access()?;
         check_use_custom_virtual_machine()?;
@@ -456,6 +521,19 @@ impl IVirtualizationServiceInternal for VirtualizationServiceInternal {
         Ok(ParcelFileDescriptor::new(file))
     }
 
+    fn getLabelAllowlistExtensions(&self) -> binder::Result<Option<String>> {
+        // The allowlists are no secret, as virtmgr enforces them for every client.
+        let path = Path::new(LABEL_ALLOWLIST_PATH);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let json = fs::read_to_string(path)
+            .context("Failed to read label_allowlist.json")
+            .with_log()
+            .or_service_specific_exception(-1)?;
+        Ok(Some(json))
+    }
+
     fn allocateInstanceId(&self) -> binder::Result<[u8; 64]> {
         let mut id = [0u8; 64];
         id.try_fill(&mut rand::thread_rng())
@@ -666,6 +744,14 @@ struct GlobalVmInstance {
     requester_debug_pid: pid_t,
     /// Name of the host console.
     host_console_name: Option<String>,
+    /// Last state of the VM reported by virtmgr.
+    state: VirtualMachineState,
+    /// Whether the tombstones sent by the VM are refused.
+    tombstones_disabled: bool,
+    /// Where the files pushed by the VM are passed, if anywhere.
+    guest_file_callback: Option<Strong<dyn IGuestFileCallback>>,
+    /// What the VM may still push, created along with its first transfer.
+    guest_file_quota: Option<Arc<VmQuota>>,
 }
 
 impl GlobalVmInstance {
@@ -689,22 +775,30 @@ struct GlobalState {
     sk_state: Option<maintenance::State>,
 
     display_service: Option<binder::SpIBinder>,
+
+    /// The range of CIDs assigned to guest VMs.
+    cid_range: RangeInclusive<Cid>,
 }
 
 impl GlobalState {
-    fn new() -> Self {
+    fn new(cid_range: RangeInclusive<Cid>) -> Self {
+        info!("Assigning CIDs {cid_range:?} to guest VMs");
         Self {
             held_contexts: HashMap::new(),
             dtbo_file: Mutex::new(None),
             sk_state: maintenance::State::new(),
             display_service: None,
+            cid_range,
         }
     }
 
-    /// Get the next available CID, or an error if we have run out. The last CID used is stored in
-    /// a system property so that restart of virtualizationservice doesn't reuse CID while the host
-    /// Android is up.
-    fn get_next_available_cid(&mut self) -> Result<Cid> {
+    /// Reserves the next available CID for `instance`, or returns an error if we have run out. The
+    /// last CID used is stored in a system property so that restart of virtualizationservice
+    /// doesn't reuse CID while the host Android is up.
+    fn reserve_next_available_cid(
+        &mut self,
+        instance: &Arc<Mutex<GlobalVmInstance>>,
+    ) -> Result<Cid> {
         // Start trying to find a CID from the last used CID + 1. This ensures
         // that we do not eagerly recycle CIDs. It makes debugging easier but
         // also means that retrying to allocate a CID, eg. because it is
@@ -712,7 +806,7 @@ impl GlobalState {
         let last_cid_prop =
             system_properties::read(SYSPROP_LAST_CID)?.and_then(|val| match val.parse::<Cid>() {
                 Ok(num) => {
-                    if is_valid_guest_cid(num) {
+                    if self.cid_range.contains(&num) {
                         Some(num)
                     } else {
                         error!("Invalid value '{}' of property '{}'", num, SYSPROP_LAST_CID);
@@ -725,25 +819,39 @@ impl GlobalState {
                 }
             });
 
-        let first_cid = if let Some(last_cid) = last_cid_prop {
-            if last_cid == GUEST_CID_MAX {
-                GUEST_CID_MIN
-            } else {
-                last_cid + 1
-            }
-        } else {
-            GUEST_CID_MIN
-        };
+        let cid = self.reserve_cid(last_cid_prop, instance)?;
+
+        system_properties::write(SYSPROP_LAST_CID, &format!("{}", cid))?;
+        Ok(cid)
+    }
 
+    /// Assigns the first CID which isn't in use after `last_cid` to `instance`, and marks it as in
+    /// use for as long as `instance` is alive. Both happen together, so that the CID can't be
+    /// handed out twice, even while the rest of the VM context is still being set up.
+    fn reserve_cid(
+        &mut self,
+        last_cid: Option<Cid>,
+        instance: &Arc<Mutex<GlobalVmInstance>>,
+    ) -> Result<Cid> {
         let cid = self
-            .find_available_cid(first_cid..=GUEST_CID_MAX)
-            .or_else(|| self.find_available_cid(GUEST_CID_MIN..first_cid))
+            .find_next_available_cid(last_cid)
             .ok_or_else(|| anyhow!("Could not find an available CID."))?;
-
-        system_properties::write(SYSPROP_LAST_CID, &format!("{}", cid))?;
+        instance.lock().unwrap().cid = cid;
+        self.held_contexts.insert(cid, Arc::downgrade(instance));
         Ok(cid)
     }
 
+    /// Returns the first CID which isn't in use after `last_cid`, wrapping around to the start of
+    /// the CID range.
+    fn find_next_available_cid(&self, last_cid: Option<Cid>) -> Option<Cid> {
+        let (min, max) = (*self.cid_range.start(), *self.cid_range.end());
+        let first_cid = match last_cid {
+            Some(last_cid) if (min..max).contains(&last_cid) => last_cid + 1,
+            _ => min,
+        };
+        self.find_available_cid(first_cid..=max).or_else(|| self.find_available_cid(min..first_cid))
+    }
+
     fn find_available_cid<I>(&self, mut range: I) -> Option<Cid>
     where
         I: Iterator<Item = Cid>,
@@ -759,20 +867,90 @@ impl GlobalState {
         // Garbage collect unused VM contexts.
         self.held_contexts.retain(|_, instance| instance.strong_count() > 0);
 
-        let cid = self.get_next_available_cid()?;
         let instance = Arc::new(Mutex::new(GlobalVmInstance {
-            cid,
             requester_uid,
             requester_debug_pid,
             ..Default::default()
         }));
+        // If anything below fails, `instance` is dropped and the CID is freed again.
+        self.reserve_next_available_cid(&instance)?;
         create_temporary_directory(&instance.lock().unwrap().get_temp_dir(), Some(requester_uid))?;
 
-        self.held_contexts.insert(cid, Arc::downgrade(&instance));
         let binder = GlobalVmContext { instance, ..Default::default() };
         Ok(BnGlobalVmContext::new_binder(binder, BinderFeatures::default()))
     }
 
+    /// Removes the per-CID directories in `dir` of the VMs which are no longer running, e.g. those
+    /// leaked by a previous instance of the service which crashed.
+    fn remove_stale_temporary_dirs(&self, dir: &Path) {
+        let entries = match read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Could not list temporary directories in {:?}: {}", dir, e);
+                return;
+            }
+        };
+        for entry in entries.flatten() {
+            // Other entries, such as the common directory, aren't tied to a VM.
+            let Some(cid) = entry.file_name().to_str().and_then(|name| name.parse::<Cid>().ok())
+            else {
+                continue;
+            };
+            if self.held_contexts.contains_key(&cid) {
+                continue;
+            }
+            let path = entry.path();
+            match remove_temporary_dir(&path) {
+                Ok(()) => info!("Removed stale temporary directory {:?}", path),
+                Err(e) => warn!("Could not delete stale temporary directory {:?}: {}", path, e),
+            }
+        }
+    }
+
+    /// Returns whether `cid` is held by a VM context which is still alive. CIDs are reserved as
+    /// soon as the context is allocated, so this covers the VMs which are still being set up.
+    fn is_cid_in_use(&self, cid: Cid) -> bool {
+        self.held_contexts.get(&cid).is_some_and(|instance| instance.strong_count() > 0)
+    }
+
+    /// Returns the VMs requested by `uid` whose VM context is still alive, in CID order.
+    fn vms_of_uid(&self, uid: uid_t) -> Vec<VirtualMachineInfo> {
+        let mut vms: Vec<_> = self
+            .held_contexts
+            .values()
+            .filter_map(Weak::upgrade)
+            .filter_map(|instance| {
+                let instance = instance.lock().unwrap();
+                (instance.requester_uid == uid)
+                    .then(|| VirtualMachineInfo { cid: instance.cid as i32, state: instance.state })
+            })
+            .collect();
+        vms.sort_by_key(|vm| vm.cid);
+        vms
+    }
+
+    /// Returns whether the tombstones sent by the VM with the given CID are to be forwarded to
+    /// tombstoned. They are unless the VM disabled them.
+    fn accepts_tombstones_from(&self, cid: Cid) -> bool {
+        let instance = self.held_contexts.get(&cid).and_then(Weak::upgrade);
+        !instance.is_some_and(|instance| instance.lock().unwrap().tombstones_disabled)
+    }
+
+    /// Returns the directory in which to write the files sent by the VM with the given CID, the
+    /// callback to pass them to and the quota of the VM, or `None` if the VM doesn't accept files.
+    fn guest_file_receiver(
+        &self,
+        cid: Cid,
+        limits: &GuestFileLimits,
+    ) -> Option<(PathBuf, Strong<dyn IGuestFileCallback>, Arc<VmQuota>)> {
+        let instance = self.held_contexts.get(&cid).and_then(Weak::upgrade)?;
+        let mut instance = instance.lock().unwrap();
+        let callback = instance.guest_file_callback.clone()?;
+        let quota =
+            instance.guest_file_quota.get_or_insert_with(|| Arc::new(VmQuota::new(limits))).clone();
+        Some((instance.get_temp_dir(), callback, quota))
+    }
+
     fn get_dtbo_file(&mut self) -> Result<File> {
         let mut file = self.dtbo_file.lock().unwrap();
 
@@ -867,11 +1045,36 @@ impl IGlobalVmContext for GlobalVmContext {
         self.instance.lock().unwrap().host_console_name = Some(pathname.to_string());
         Ok(())
     }
+
+    fn setState(&self, state: VirtualMachineState) -> binder::Result<()> {
+        self.instance.lock().unwrap().state = state;
+        Ok(())
+    }
+
+    fn disableTombstones(&self) -> binder::Result<()> {
+        self.instance.lock().unwrap().tombstones_disabled = true;
+        Ok(())
+    }
+
+    fn setGuestFileCallback(
+        &self,
+        callback: &Strong<dyn IGuestFileCallback>,
+    ) -> binder::Result<()> {
+        self.instance.lock().unwrap().guest_file_callback = Some(callback.clone());
+        Ok(())
+    }
+
+    fn removeTemporaryDirectory(&self) -> binder::Result<()> {
+        let temp_dir = self.instance.lock().unwrap().get_temp_dir();
+        remove_temporary_dir(&temp_dir)
+            .with_context(|| format!("Could not delete temporary directory {:?}", temp_dir))
+            .or_service_specific_exception(-1)
+    }
 }
 
-fn handle_stream_connection_tombstoned() -> Result<()> {
+fn handle_stream_connection_tombstoned(state: &Mutex<GlobalState>) -> Result<()> {
     // Should not listen for tombstones on a guest VM's port.
-    assert!(!is_valid_guest_cid(VM_TOMBSTONES_SERVICE_PORT as Cid));
+    assert!((VM_TOMBSTONES_SERVICE_PORT as Cid) < GUEST_CID_MIN);
     let listener =
         VsockListener::bind_with_cid_port(VMADDR_CID_HOST, VM_TOMBSTONES_SERVICE_PORT as Cid)?;
     for incoming_stream in listener.incoming() {
@@ -889,6 +1092,10 @@ fn handle_stream_connection_tombstoned() -> Result<()> {
                     warn!("Rejecting non-guest tombstone vsock connection from cid={cid}");
                     continue;
                 }
+                _ if !state.lock().unwrap().accepts_tombstones_from(cid) => {
+                    info!("Refusing tombstones from cid={cid}, disabled for the VM");
+                    continue;
+                }
                 _ => info!("Vsock Stream connected to cid={cid} for tombstones"),
             }
         }
@@ -901,6 +1108,70 @@ fn handle_stream_connection_tombstoned() -> Result<()> {
     Ok(())
 }
 
+fn handle_stream_connection_guest_files(
+    state: &Mutex<GlobalState>,
+    limits: GuestFileLimits,
+) -> Result<()> {
+    // Should not listen for files on a guest VM's port.
+    assert!((VM_FILE_TRANSFER_SERVICE_PORT as Cid) < GUEST_CID_MIN);
+    let listener =
+        VsockListener::bind_with_cid_port(VMADDR_CID_HOST, VM_FILE_TRANSFER_SERVICE_PORT as Cid)?;
+    let slots = ConnectionSlots::new(limits.max_connections);
+    for incoming_stream in listener.incoming() {
+        let mut incoming_stream = match incoming_stream {
+            Err(e) => {
+                warn!("invalid incoming connection: {e:?}");
+                continue;
+            }
+            Ok(s) => s,
+        };
+        let cid = match incoming_stream.peer_addr() {
+            Ok(addr) => addr.cid(),
+            Err(e) => {
+                warn!("Rejecting file vsock connection with unknown peer: {e:?}");
+                continue;
+            }
+        };
+        // Only guest VMs have a context, so this also rejects the host and the hypervisor.
+        let receiver = state.lock().unwrap().guest_file_receiver(cid, &limits);
+        let Some((temp_dir, callback, quota)) = receiver else {
+            info!("Refusing file from cid={cid}, no receiver set for the VM");
+            continue;
+        };
+        // The slot of the VM is taken first, so that a VM using up its own slots doesn't take the
+        // ones of the other VMs.
+        let Some(vm_slot) = quota.try_take_slot() else {
+            warn!(
+                "Refusing file from cid={cid}, {} of its transfers in progress",
+                limits.max_connections_per_vm
+            );
+            continue;
+        };
+        let Some(slot) = slots.try_take() else {
+            warn!("Refusing file from cid={cid}, {} transfers in progress", limits.max_connections);
+            continue;
+        };
+        if let Err(e) = incoming_stream.set_read_timeout(Some(limits.read_timeout)) {
+            warn!("Refusing file from cid={cid}, failed to set read timeout: {e:?}");
+            continue;
+        }
+        info!("Vsock Stream connected to cid={cid} for a file");
+        std::thread::spawn(move || {
+            let _slots = (slot, vm_slot);
+            match receive_guest_file(&mut incoming_stream, &temp_dir, limits.max_size, &quota) {
+                Ok(file) => {
+                    let file = ParcelFileDescriptor::new(file);
+                    if let Err(e) = callback.onGuestFileReceived(&file) {
+                        error!("Error passing file from cid={cid}: {e:?}");
+                    }
+                }
+                Err(e) => error!("Failed to receive file from cid={cid}: {e:?}"),
+            }
+        });
+    }
+    Ok(())
+}
+
 fn handle_tombstone(stream: &mut VsockStream) -> Result<()> {
     let tb_connection =
         TombstonedConnection::connect(std::process::id() as i32, DebuggerdDumpType::Tombstone)
@@ -977,6 +1248,141 @@ mod tests {
 
     const TEST_RKP_CERT_CHAIN_PATH: &str = "testdata/rkp_cert_chain.der";
 
+    #[test]
+    fn parsing_cid_range_succeeds() -> Result<()> {
+        assert_eq!(3000..=GUEST_CID_MAX, parse_cid_range("3000")?);
+        assert_eq!(3000..=4000, parse_cid_range("3000-4000")?);
+        assert!(parse_cid_range("100").is_err());
+        assert!(parse_cid_range("4000-3000").is_err());
+        assert!(parse_cid_range("3000-").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn overridden_first_cid_is_honored() {
+        let mut state = GlobalState::new(3000..=3002);
+        assert_eq!(Some(3000), state.find_next_available_cid(None));
+        assert_eq!(Some(3002), state.find_next_available_cid(Some(3001)));
+        // Wraps around to the start of the range, skipping the CIDs in use.
+        let instance = Arc::new(Mutex::new(GlobalVmInstance::default()));
+        state.held_contexts.insert(3000, Arc::downgrade(&instance));
+        assert_eq!(Some(3001), state.find_next_available_cid(Some(3002)));
+        // A last CID from outside of the range is ignored.
+        assert_eq!(Some(3001), state.find_next_available_cid(Some(GUEST_CID_MIN)));
+    }
+
+    #[test]
+    fn cid_is_in_use_only_while_its_context_is_alive() {
+        let mut state = GlobalState::new(3000..=3002);
+        let instance = Arc::new(Mutex::new(GlobalVmInstance::default()));
+        let cid = state.reserve_cid(None, &instance).unwrap();
+
+        assert!(state.is_cid_in_use(cid));
+        // CIDs which were never assigned aren't in use.
+        assert!(!state.is_cid_in_use(cid + 1));
+        drop(instance);
+        assert!(!state.is_cid_in_use(cid));
+    }
+
+    #[test]
+    fn only_live_vms_of_the_uid_are_listed() {
+        let mut state = GlobalState::new(3000..=3003);
+        let instance = |requester_uid| {
+            Arc::new(Mutex::new(GlobalVmInstance { requester_uid, ..Default::default() }))
+        };
+        let instances = [instance(10001), instance(10002), instance(10001), instance(10001)];
+        for instance in &instances {
+            state.reserve_cid(None, instance).unwrap();
+        }
+        let [first, _, _, last] = instances;
+        drop(last);
+
+        let vm = |cid| VirtualMachineInfo { cid, state: VirtualMachineState::NOT_STARTED };
+        assert_eq!(vec![vm(3000), vm(3002)], state.vms_of_uid(10001));
+        assert_eq!(vec![vm(3001)], state.vms_of_uid(10002));
+        assert!(state.vms_of_uid(10003).is_empty());
+        drop(first);
+        assert_eq!(vec![vm(3002)], state.vms_of_uid(10001));
+    }
+
+    #[test]
+    fn reported_state_of_vm_is_listed() {
+        let mut state = GlobalState::new(3000..=3001);
+        let instance =
+            Arc::new(Mutex::new(GlobalVmInstance { requester_uid: 10001, ..Default::default() }));
+        let cid = state.reserve_cid(None, &instance).unwrap() as i32;
+        let context = GlobalVmContext { instance: instance.clone(), ..Default::default() };
+
+        context.setState(VirtualMachineState::READY).unwrap();
+
+        assert_eq!(
+            vec![VirtualMachineInfo { cid, state: VirtualMachineState::READY }],
+            state.vms_of_uid(10001)
+        );
+    }
+
+    #[test]
+    fn tombstones_are_refused_once_disabled() {
+        let mut state = GlobalState::new(3000..=3002);
+        let instance = Arc::new(Mutex::new(GlobalVmInstance::default()));
+        state.held_contexts.insert(3000, Arc::downgrade(&instance));
+
+        assert!(state.accepts_tombstones_from(3000));
+        instance.lock().unwrap().tombstones_disabled = true;
+        assert!(!state.accepts_tombstones_from(3000));
+        // Other VMs are unaffected.
+        assert!(state.accepts_tombstones_from(3001));
+    }
+
+    #[test]
+    fn stale_temporary_dirs_are_removed() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        for name in ["3000", "3001", "common"] {
+            create_dir(dir.path().join(name))?;
+        }
+        fs::write(dir.path().join("3001").join("composite.img"), b"")?;
+        let mut state = GlobalState::new(3000..=3002);
+        let instance = Arc::new(Mutex::new(GlobalVmInstance::default()));
+        state.held_contexts.insert(3000, Arc::downgrade(&instance));
+
+        state.remove_stale_temporary_dirs(dir.path());
+
+        assert!(dir.path().join("3000").exists());
+        assert!(!dir.path().join("3001").exists());
+        assert!(dir.path().join("common").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn concurrently_reserved_cids_are_unique() {
+        const THREADS: usize = 8;
+        const CIDS_PER_THREAD: usize = 50;
+        let state = Arc::new(Mutex::new(GlobalState::new(3000..=4000)));
+
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let state = state.clone();
+                std::thread::spawn(move || {
+                    // Keep the instances alive, so that their CIDs stay in use.
+                    (0..CIDS_PER_THREAD)
+                        .map(|_| {
+                            let instance = Arc::new(Mutex::new(GlobalVmInstance::default()));
+                            let cid = state.lock().unwrap().reserve_cid(None, &instance).unwrap();
+                            assert_eq!(cid, instance.lock().unwrap().cid);
+                            instance
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let instances: Vec<_> =
+            threads.into_iter().flat_map(|thread| thread.join().unwrap()).collect();
+
+        let cids: HashSet<Cid> =
+            instances.iter().map(|instance| instance.lock().unwrap().cid).collect();
+        assert_eq!(THREADS * CIDS_PER_THREAD, cids.len());
+    }
+
     #[test]
     fn splitting_x509_certificate_chain_succeeds() -> Result<()> {
         let bytes = fs::read(TEST_RKP_CERT_CHAIN_PATH)?;
@@ -0,0 +1,330 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reception of the files which guest VMs push to the host, e.g. crash minidumps, bounded in size,
+//! in duration and in number of concurrent transfers, overall and for each VM, so that guests
+//! can't exhaust the host resources or starve each other.
+
+use anyhow::{ensure, Context, Result};
+use log::error;
+use rustutils::system_properties;
+use std::fs::{remove_file, File, OpenOptions};
+use std::io::{self, Read, Seek};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The maximum size of a file sent by a guest, in bytes.
+const SYSPROP_MAX_SIZE: &str = "virtualizationservice.guest_file.max_size";
+/// The maximum number of files being received at the same time, from all guests.
+const SYSPROP_MAX_CONNECTIONS: &str = "virtualizationservice.guest_file.max_connections";
+/// The maximum number of files being received at the same time from a single guest.
+const SYSPROP_MAX_CONNECTIONS_PER_VM: &str =
+    "virtualizationservice.guest_file.max_connections_per_vm";
+/// The maximum number of bytes received from a single guest, over the lifetime of its VM context.
+const SYSPROP_MAX_TOTAL_SIZE_PER_VM: &str =
+    "virtualizationservice.guest_file.max_total_size_per_vm";
+/// How long to wait for more data from a guest, in milliseconds, before giving up on its file.
+const SYSPROP_READ_TIMEOUT_MS: &str = "virtualizationservice.guest_file.read_timeout_ms";
+
+/// Counter to generate unique names for the received files.
+static NEXT_FILE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Limits on the files received from the guests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GuestFileLimits {
+    /// Files larger than this, in bytes, are discarded.
+    pub max_size: u64,
+    /// Connections beyond this number of transfers in progress are refused.
+    pub max_connections: usize,
+    /// Connections from a VM beyond this number of its transfers in progress are refused.
+    pub max_connections_per_vm: usize,
+    /// Files from a VM are discarded once it sent more than this, in bytes, in total.
+    pub max_total_size_per_vm: u64,
+    /// Transfers are abandoned when the VM sends nothing for this long.
+    pub read_timeout: Duration,
+}
+
+impl Default for GuestFileLimits {
+    fn default() -> Self {
+        Self {
+            max_size: 16 * 1024 * 1024,
+            max_connections: 4,
+            max_connections_per_vm: 1,
+            max_total_size_per_vm: 64 * 1024 * 1024,
+            read_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl GuestFileLimits {
+    /// Returns the limits configured with the `SYSPROP_*` properties, or the default ones for
+    /// those not set.
+    pub fn read() -> Self {
+        let default = Self::default();
+        Self {
+            max_size: read_sysprop(SYSPROP_MAX_SIZE).unwrap_or(default.max_size),
+            max_connections: read_sysprop(SYSPROP_MAX_CONNECTIONS)
+                .unwrap_or(default.max_connections),
+            max_connections_per_vm: read_sysprop(SYSPROP_MAX_CONNECTIONS_PER_VM)
+                .unwrap_or(default.max_connections_per_vm),
+            max_total_size_per_vm: read_sysprop(SYSPROP_MAX_TOTAL_SIZE_PER_VM)
+                .unwrap_or(default.max_total_size_per_vm),
+            read_timeout: read_sysprop(SYSPROP_READ_TIMEOUT_MS)
+                .map_or(default.read_timeout, Duration::from_millis),
+        }
+    }
+}
+
+fn read_sysprop<T: std::str::FromStr>(name: &str) -> Option<T> {
+    match system_properties::read(name) {
+        Ok(Some(value)) => {
+            let parsed = value.parse().ok();
+            if parsed.is_none() {
+                error!("Invalid value '{value}' of property '{name}'");
+            }
+            parsed
+        }
+        Ok(None) => None,
+        Err(e) => {
+            error!("Failed to read property '{name}': {e:?}");
+            None
+        }
+    }
+}
+
+/// Slots for the transfers in progress, one of which must be taken for each connection.
+#[derive(Debug)]
+pub struct ConnectionSlots {
+    in_use: Arc<AtomicUsize>,
+    max: usize,
+}
+
+/// A slot taken for a transfer in progress. It is released when this is dropped.
+#[derive(Debug)]
+pub struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlots {
+    pub fn new(max: usize) -> Self {
+        Self { in_use: Arc::new(AtomicUsize::new(0)), max }
+    }
+
+    /// Takes a slot, or returns `None` if they are all in use.
+    pub fn try_take(&self) -> Option<ConnectionSlot> {
+        self.in_use
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n < self.max).then_some(n + 1))
+            .ok()
+            .map(|_| ConnectionSlot(self.in_use.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The share of a single VM of the transfers, for the lifetime of its VM context.
+#[derive(Debug)]
+pub struct VmQuota {
+    slots: ConnectionSlots,
+    bytes_left: AtomicU64,
+}
+
+impl VmQuota {
+    pub fn new(limits: &GuestFileLimits) -> Self {
+        Self {
+            slots: ConnectionSlots::new(limits.max_connections_per_vm),
+            bytes_left: AtomicU64::new(limits.max_total_size_per_vm),
+        }
+    }
+
+    /// Takes one of the slots of the VM, or returns `None` if they are all in use.
+    pub fn try_take_slot(&self) -> Option<ConnectionSlot> {
+        self.slots.try_take()
+    }
+
+    /// Sets aside up to `max` of the bytes the VM may still send, and returns how many.
+    fn reserve(&self, max: u64) -> u64 {
+        let left = self
+            .bytes_left
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| Some(left - left.min(max)))
+            .unwrap();
+        left.min(max)
+    }
+
+    /// Gives back bytes which were set aside but not used.
+    fn release(&self, bytes: u64) {
+        self.bytes_left.fetch_add(bytes, Ordering::SeqCst);
+    }
+}
+
+/// Writes everything read from `stream` to a new file in `dir`, and returns it open for reading
+/// from its start. If there is more than `max_size` bytes to read, or more than the VM may still
+/// send according to `quota`, the file is removed instead. The bytes read count against `quota`
+/// either way.
+pub fn receive_guest_file(
+    stream: &mut impl Read,
+    dir: &Path,
+    max_size: u64,
+    quota: &VmQuota,
+) -> Result<File> {
+    let max_size = quota.reserve(max_size);
+    ensure!(max_size > 0, "The VM sent all the bytes it may send");
+    let id = NEXT_FILE_ID.fetch_add(1, Ordering::Relaxed);
+    let path = dir.join(format!("guest_file_{id}"));
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .with_context(|| format!("Failed to create {path:?}"))?;
+
+    let result = copy_capped(stream, &mut file, max_size);
+    match &result {
+        Ok(size) => quota.release(max_size - size),
+        Err(_) => {
+            if let Err(e) = remove_file(&path) {
+                error!("Failed to remove {path:?}: {e:?}");
+            }
+        }
+    }
+    result?;
+    file.rewind()?;
+    Ok(file)
+}
+
+/// Copies `stream` to `file`, and returns the number of bytes copied. Fails if there are more than
+/// `max_size` bytes to copy, or if reading `stream` fails, e.g. because it timed out.
+fn copy_capped(stream: &mut impl Read, file: &mut File, max_size: u64) -> Result<u64> {
+    // Read one more byte than allowed, to tell a file of exactly `max_size` bytes from a larger one.
+    let size = io::copy(&mut stream.take(max_size.saturating_add(1)), file)
+        .context("Failed to receive file")?;
+    ensure!(size <= max_size, "File exceeds {max_size} bytes");
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::read_dir;
+
+    fn vm_quota(max_connections_per_vm: usize, max_total_size_per_vm: u64) -> VmQuota {
+        VmQuota::new(&GuestFileLimits {
+            max_connections_per_vm,
+            max_total_size_per_vm,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn guest_file_within_limit_is_received() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let contents: Vec<u8> = (0..=255).cycle().take(100_000).collect();
+
+        let quota = vm_quota(1, 1_000_000);
+        let mut file = receive_guest_file(&mut contents.as_slice(), dir.path(), 100_000, &quota)?;
+
+        let mut received = Vec::new();
+        file.read_to_end(&mut received)?;
+        assert_eq!(contents, received);
+        assert_eq!(1, read_dir(dir.path())?.count());
+        Ok(())
+    }
+
+    #[test]
+    fn guest_file_exceeding_limit_is_discarded() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let contents: Vec<u8> = (0..=255).cycle().take(100_001).collect();
+
+        let quota = vm_quota(1, 1_000_000);
+        assert!(receive_guest_file(&mut contents.as_slice(), dir.path(), 100_000, &quota).is_err());
+        assert_eq!(0, read_dir(dir.path())?.count());
+        Ok(())
+    }
+
+    #[test]
+    fn guest_files_exceeding_total_limit_of_vm_are_discarded() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let contents: Vec<u8> = (0..=255).cycle().take(60_000).collect();
+        let quota = vm_quota(1, 100_000);
+
+        receive_guest_file(&mut contents.as_slice(), dir.path(), 100_000, &quota)?;
+        // Only 40 000 bytes are left for the VM.
+        assert!(receive_guest_file(&mut contents.as_slice(), dir.path(), 100_000, &quota).is_err());
+        assert!(receive_guest_file(&mut &contents[..1], dir.path(), 100_000, &quota).is_err());
+        assert_eq!(1, read_dir(dir.path())?.count());
+        // Other VMs have their own quota.
+        receive_guest_file(&mut contents.as_slice(), dir.path(), 100_000, &vm_quota(1, 100_000))?;
+        Ok(())
+    }
+
+    /// Returns some data, then fails as a socket whose read timeout expired.
+    struct TimingOutStream(Vec<u8>);
+
+    impl Read for TimingOutStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+            let n = buf.len().min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0.drain(..n);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn guest_file_timing_out_is_discarded() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let mut stream = TimingOutStream(vec![0; 1000]);
+
+        let quota = vm_quota(1, 100_000);
+        assert!(receive_guest_file(&mut stream, dir.path(), 100_000, &quota).is_err());
+        assert_eq!(0, read_dir(dir.path())?.count());
+        Ok(())
+    }
+
+    #[test]
+    fn connection_slots_are_limited_and_released() {
+        let slots = ConnectionSlots::new(2);
+
+        let first = slots.try_take();
+        let second = slots.try_take();
+        assert!(first.is_some());
+        assert!(second.is_some());
+        assert!(slots.try_take().is_none());
+
+        drop(first);
+        assert!(slots.try_take().is_some());
+    }
+
+    #[test]
+    fn connection_slots_of_vm_are_limited() {
+        let quota = vm_quota(1, 100_000);
+        let other_quota = vm_quota(1, 100_000);
+
+        let slot = quota.try_take_slot();
+        assert!(slot.is_some());
+        assert!(quota.try_take_slot().is_none());
+        // Other VMs have their own slots.
+        assert!(other_quota.try_take_slot().is_some());
+
+        drop(slot);
+        assert!(quota.try_take_slot().is_some());
+    }
+}
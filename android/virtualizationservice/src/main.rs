@@ -16,6 +16,7 @@
 
 mod aidl;
 mod atom;
+mod guest_file;
 mod maintenance;
 mod remote_provisioning;
 mod rkpvm;
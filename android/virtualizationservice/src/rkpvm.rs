@@ -18,11 +18,15 @@
 
 use android_hardware_security_rkp::aidl::android::hardware::security::keymint::MacedPublicKey::MacedPublicKey;
 use anyhow::{bail, Context, Result};
+use log::warn;
 use service_vm_comm::{
     ClientVmAttestationParams, GenerateCertificateRequestParams, Request, Response,
 };
 use service_vm_manager::process_request;
 
+/// The maximum number of service VM events to retrieve when diagnosing a failed request.
+const MAX_EVENT_LOG_ENTRIES: u32 = 16;
+
 pub(crate) fn request_attestation(
     csr: Vec<u8>,
     remotely_provisioned_key_blob: Vec<u8>,
@@ -33,7 +37,24 @@ pub(crate) fn request_attestation(
     let request = Request::RequestClientVmAttestation(params);
     match process_request(request).context("Failed to process request")? {
         Response::RequestClientVmAttestation(cert) => Ok(cert),
-        other => bail!("Incorrect response type {other:?}"),
+        other => {
+            log_service_vm_events();
+            bail!("Incorrect response type {other:?}")
+        }
+    }
+}
+
+/// Logs the recent events of the service VM, to help diagnose a failed request.
+fn log_service_vm_events() {
+    let request = Request::GetEventLog { max_entries: MAX_EVENT_LOG_ENTRIES };
+    match process_request(request) {
+        Ok(Response::EventLog(events)) => {
+            for event in events {
+                warn!("Service VM event: {event}");
+            }
+        }
+        Ok(other) => warn!("Failed to get service VM events: incorrect response type {other:?}"),
+        Err(e) => warn!("Failed to get service VM events: {e:?}"),
     }
 }
 
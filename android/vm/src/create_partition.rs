@@ -40,6 +40,7 @@ pub fn command_create_partition(
             &ParcelFileDescriptor::new(image),
             size.try_into()?,
             partition_type,
+            /* force= */ false,
         )
         .context(format!(
             "Failed to initialize partition type: {:?}, size: {}",
@@ -170,8 +170,9 @@ Result<ScopedFileDescriptor> create_instance_image_file_if_needed(IVirtualizatio
     // If not, create a new one.
     ScopedFileDescriptor instance = OR_RETURN(open_file(path, O_CREAT | O_RDWR));
     long size = 10 * 1024 * 1024; // 10MB, but could be smaller.
-    ScopedAStatus ret =
-            service.initializeWritablePartition(instance, size, PartitionType::ANDROID_VM_INSTANCE);
+    ScopedAStatus ret = service.initializeWritablePartition(instance, size,
+                                                           PartitionType::ANDROID_VM_INSTANCE,
+                                                           /* force= */ false);
     if (!ret.isOk()) {
         return Error() << "Failed to create instance disk image: " << path;
     }
@@ -264,6 +265,26 @@ public:
         return ScopedAStatus::ok();
     }
 
+    ScopedAStatus onResourceLimit(int32_t, const std::string&, int64_t, int64_t) {
+        return ScopedAStatus::ok();
+    }
+
+    ScopedAStatus onConsoleClosed(int32_t) {
+        return ScopedAStatus::ok();
+    }
+
+    ScopedAStatus onConsoleResize(int32_t, int32_t, int32_t) {
+        return ScopedAStatus::ok();
+    }
+
+    ScopedAStatus onVsockConnection(int32_t, int32_t, const ScopedFileDescriptor&) {
+        return ScopedAStatus::ok();
+    }
+
+    ScopedAStatus onGuestFileReceived(int32_t, const ScopedFileDescriptor&) {
+        return ScopedAStatus::ok();
+    }
+
     ScopedAStatus onDied(int32_t, DeathReason) {
         std::unique_lock lock(mMutex);
         mCv.notify_all();
@@ -16,51 +16,284 @@
 
 //! `apkdmverity` is a program that protects a signed APK file using dm-verity. The APK is assumed
 //! to be signed using APK signature scheme V4. The idsig file generated by the signing scheme is
-//! also used as an input to provide the merkle tree. This program is currently intended to be used
-//! to securely mount the APK inside Microdroid. Since the APK is physically stored in the file
-//! system managed by the host Android which is assumed to be compromisable, it is important to
-//! keep the integrity of the file "inside" Microdroid.
+//! also used as an input to provide the merkle tree. For APKs which are only signed using APK
+//! signature scheme V3, the merkle tree is computed from the APK instead. This program is currently
+//! intended to be used to securely mount the APK inside Microdroid. Since the APK is physically
+//! stored in the file system managed by the host Android which is assumed to be compromisable, it
+//! is important to keep the integrity of the file "inside" Microdroid.
 
 #![cfg_attr(test, allow(unused))]
 
-use anyhow::{bail, Context, Result};
-use apkverify::{HashAlgorithm, V4Signature};
-use clap::{arg, Arg, ArgAction, Command};
+use anyhow::{bail, ensure, Context, Result};
+use apkverify::{get_apk_digest, HashAlgorithm, V4Signature};
+use clap::{arg, Arg, ArgAction, ArgMatches, Command};
 use dm::loopdevice;
+use dm::snapshot::DmSnapshotTargetBuilder;
 use dm::util;
 use dm::verity::{DmVerityHashAlgorithm, DmVerityTargetBuilder};
 use itertools::Itertools;
-use std::fmt::Debug;
-use std::fs;
-use std::os::unix::fs::FileTypeExt;
+use log::{debug, error, info, warn};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sys::statfs::{fstatfs, TMPFS_MAGIC};
+use num_traits::ToPrimitive;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fmt::{self, Debug};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::fs::{FileExt, FileTypeExt};
 use std::path::{Path, PathBuf};
+use thiserror::Error;
 
 #[cfg(not(test))]
 fn main() -> Result<()> {
+    init_logger();
+    try_main().map_err(|e| {
+        error!("Failed with {:?}.", e);
+        e
+    })
+}
+
+/// On Android, the output of apkdmverity may not be captured in the boot logs. Hence what is set
+/// up, and what fails, is logged to logcat there. Elsewhere, it is logged to stderr, as configured
+/// with `RUST_LOG`.
+#[cfg(target_os = "android")]
+fn init_logger() {
+    android_logger::init_once(
+        android_logger::Config::default()
+            .with_tag("apkdmverity")
+            .with_max_level(log::LevelFilter::Info),
+    );
+}
+
+#[cfg(not(target_os = "android"))]
+fn init_logger() {
+    let _ = env_logger::try_init();
+}
+
+#[cfg(not(test))]
+fn try_main() -> Result<()> {
     let matches = clap_command().get_matches();
 
-    let apks = matches.get_many::<String>("apk").unwrap();
-    assert!(apks.len() % 4 == 0);
+    if let Some(("inspect", matches)) = matches.subcommand() {
+        let idsig = matches.get_one::<String>("idsig").unwrap();
+        let json = matches.get_one::<String>("format").unwrap() == "json";
+        print!("{}", inspect_idsig(Path::new(idsig), json)?);
+        return Ok(());
+    }
 
     let verbose = matches.get_flag("verbose");
+    let fs_type = matches.get_one::<String>("fs-type").unwrap();
+    let apks = get_apk_args(&matches)?;
 
-    for (apk, idsig, name, roothash) in apks.tuples() {
-        let roothash = if roothash != "none" {
-            Some(hex::decode(roothash).expect("failed to parse roothash"))
-        } else {
-            None
-        };
-        let ret = enable_verity(apk, idsig, name, roothash.as_deref())?;
+    for ret in enable_all(&apks, fs_type)? {
         if verbose {
             println!(
-                "data_device: {:?}, hash_device: {:?}, mapper_device: {:?}",
-                ret.data_device, ret.hash_device, ret.mapper_device
+                "data_device: {:?}, hash_device: {:?}, mapper_device: {:?}, mount_point: {:?}, \
+                overlay_device: {:?}",
+                ret.data_device,
+                ret.hash_device,
+                ret.mapper_device,
+                ret.mount_point,
+                ret.overlay.as_ref().map(|overlay| &overlay.mapper_device)
             );
         }
     }
     Ok(())
 }
 
+// Prefix of the names of the block devices of the APKs given with --extra-apk, which are followed
+// by the index of the APK among them.
+const EXTRA_APK_NAME_PREFIX: &str = "extra-apk-";
+
+// Returns what to set up for each of the APKs given on the command line, the ones given with --apk
+// first, in order.
+fn get_apk_args(matches: &ArgMatches) -> Result<Vec<ApkArgs>> {
+    let apks = matches.get_many::<String>("apk").unwrap_or_default();
+    assert!(apks.len() % 4 == 0);
+    for name in apks.clone().skip(2).step_by(4) {
+        ensure!(
+            !name.starts_with(EXTRA_APK_NAME_PREFIX),
+            "The name {name} of an --apk is reserved for the --extra-apk block devices"
+        );
+    }
+    let extra_apks = matches.get_many::<String>("extra-apk").unwrap_or_default();
+    assert!(extra_apks.len() % 3 == 0);
+
+    let mount_points: HashMap<&String, &String> =
+        matches.get_many::<String>("mount-at").unwrap_or_default().tuples().collect();
+    let overlays: HashMap<&String, &String> =
+        matches.get_many::<String>("overlay").unwrap_or_default().tuples().collect();
+    let hash_files: HashMap<&String, &String> =
+        matches.get_many::<String>("hash-file").unwrap_or_default().tuples().collect();
+    let salts: HashMap<&String, &String> =
+        matches.get_many::<String>("salt").unwrap_or_default().tuples().collect();
+    let apk_offsets = get_sizes_by_name(matches, "apk-offset")?;
+    let apk_sizes = get_sizes_by_name(matches, "apk-size")?;
+
+    let apks =
+        apks.tuples().map(|(apk, idsig, name, roothash)| (apk, idsig, name.clone(), roothash));
+    let extra_apks = extra_apks.tuples().enumerate().map(|(i, (apk, idsig, roothash))| {
+        (apk, idsig, format!("{EXTRA_APK_NAME_PREFIX}{i}"), roothash)
+    });
+    let apks = apks
+        .chain(extra_apks)
+        .map(|(apk, idsig, name, roothash)| -> Result<ApkArgs> {
+            Ok(ApkArgs {
+                apk: Path::new(apk),
+                apk_range: ApkRange {
+                    offset: apk_offsets.get(&name).copied().unwrap_or_default(),
+                    size: apk_sizes.get(&name).copied(),
+                },
+                idsig: (idsig != "none").then(|| Path::new(idsig)),
+                hash_file: hash_files.get(&name).map(Path::new),
+                roothash: (roothash != "none")
+                    .then(|| hex::decode(roothash).expect("failed to parse roothash")),
+                salt: salts
+                    .get(&name)
+                    .map(|salt| parse_salt(salt).context(format!("Invalid --salt for {name}")))
+                    .transpose()?,
+                mount_point: mount_points.get(&name).map(Path::new),
+                overlay: overlays.get(&name).map(Path::new),
+                name,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for name in mount_points.keys() {
+        ensure!(
+            apks.iter().any(|args| &&args.name == name),
+            "--mount-at names {name}, which isn't the name of any block device"
+        );
+    }
+    Ok(apks)
+}
+
+// The maximum size in bytes of a salt, as in the idsig file.
+const MAX_SALT_SIZE: usize = 32;
+
+// Decodes a salt given in hex on the command line.
+fn parse_salt(salt: &str) -> Result<Vec<u8>> {
+    let salt = hex::decode(salt)?;
+    ensure!(salt.len() <= MAX_SALT_SIZE, "{} bytes, more than {MAX_SALT_SIZE}", salt.len());
+    Ok(salt)
+}
+
+// Returns the sizes given for each block device name to the option with the given id.
+fn get_sizes_by_name<'a>(matches: &'a ArgMatches, id: &str) -> Result<HashMap<&'a String, u64>> {
+    matches
+        .get_many::<String>(id)
+        .unwrap_or_default()
+        .tuples()
+        .map(|(name, size)| {
+            let size = size.parse().context(format!("Invalid --{id} for {name}: {size}"))?;
+            Ok((name, size))
+        })
+        .collect()
+}
+
+// What to set up for one of the APKs given on the command line.
+struct ApkArgs<'a> {
+    apk: &'a Path,
+    apk_range: ApkRange,
+    // `None` if the merkle tree is to be computed from the APK.
+    idsig: Option<&'a Path>,
+    // `None` if the merkle tree is in the idsig file.
+    hash_file: Option<&'a Path>,
+    name: String,
+    roothash: Option<Vec<u8>>,
+    // `None` if the salt is the one in the idsig file.
+    salt: Option<Vec<u8>>,
+    mount_point: Option<&'a Path>,
+    overlay: Option<&'a Path>,
+}
+
+// Sets up all of `apks`, in order. If any of them fails, the ones set up so far are torn down again
+// so that either all or none of the devices are left behind.
+fn enable_all(apks: &[ApkArgs], fs_type: &str) -> Result<Vec<VerityResult>> {
+    let mut results = Vec::new();
+    for args in apks {
+        match enable_apk(args, fs_type) {
+            Ok(ret) => results.push(ret),
+            Err(e) => {
+                for (ret, args) in results.into_iter().zip(apks).rev() {
+                    if let Err(cleanup_err) = disable_verity(ret, &args.name) {
+                        error!("Failed to remove {} after failure: {cleanup_err:?}", args.name);
+                    }
+                }
+                return Err(e.context(format!("Failed to set up {}", args.name)));
+            }
+        }
+    }
+    Ok(results)
+}
+
+// Sets up the dm-verity device of a single APK, mounting it and stacking an overlay over it as
+// requested.
+fn enable_apk(args: &ApkArgs, fs_type: &str) -> Result<VerityResult> {
+    // Keeps the decompressed APK, if any, open until it is attached to a loop device.
+    let decompressed_apk;
+    let apk = if is_compressed(args.apk) {
+        ensure!(
+            args.apk_range == ApkRange::default(),
+            "{:?} is compressed, so the APK can't be only part of it",
+            args.apk
+        );
+        decompressed_apk = decompress_apk(args.apk, max_apk_size(args.idsig)?)?;
+        PathBuf::from(fd_path(&decompressed_apk))
+    } else {
+        args.apk.to_path_buf()
+    };
+    // Keeps the idsig computed from the APK, if any, open until it is attached to a loop device.
+    let computed_idsig;
+    ensure!(
+        args.idsig.is_some() || args.hash_file.is_none(),
+        "A hash file can't be used with an idsig file computed from the APK"
+    );
+    // Nothing vouches for a merkle tree computed from the APK as it is now, so it must match a
+    // root hash which does.
+    ensure!(
+        args.idsig.is_some() || args.roothash.is_some(),
+        "A root hash must be given with an idsig file computed from the APK"
+    );
+    let idsig = match args.idsig {
+        Some(idsig) => idsig.to_path_buf(),
+        None => {
+            computed_idsig = create_idsig_from_apk(&apk, args.apk_range)?;
+            PathBuf::from(fd_path(&computed_idsig))
+        }
+    };
+    let name = &args.name;
+    let roothash = args.roothash.as_deref();
+    let salt = args.salt.as_deref();
+    let hash_file = args.hash_file.map(Path::to_path_buf);
+    let mut ret = if let Some(mount_point) = args.mount_point {
+        enable_verity_and_mount(
+            apk,
+            args.apk_range,
+            idsig,
+            hash_file,
+            name,
+            roothash,
+            salt,
+            mount_point,
+            fs_type,
+        )?
+    } else {
+        enable_verity_with_hash_file(apk, args.apk_range, idsig, hash_file, name, roothash, salt)?
+    };
+    if let Some(scratch) = args.overlay {
+        if let Err(e) = enable_overlay(&mut ret, name, scratch) {
+            if let Err(cleanup_err) = disable_verity(ret, name) {
+                error!("Failed to remove {name} after overlay failure: {cleanup_err:?}");
+            }
+            return Err(e);
+        }
+    }
+    Ok(ret)
+}
+
 fn clap_command() -> Command {
     Command::new("apkdmverity")
         .about("Creates a dm-verity block device out of APK signed with APK signature scheme V4.")
@@ -69,11 +302,107 @@ fn clap_command() -> Command {
                 "Input APK file, idsig file, name of the block device, and root hash. \
                 The APK file must be signed using the APK signature scheme 4. The \
                 block device is created at \"/dev/mapper/<name>\".' root_hash is \
-                optional; idsig file's root hash will be used if specified as \"none\"."
+                optional; idsig file's root hash will be used if specified as \"none\". \
+                If the idsig file is specified as \"none\", it is computed from the \
+                APK, which must then be signed using the APK signature scheme 3, and \
+                the root hash must be given. \
+                An APK file ending with \".zst\" is decompressed with zstd first, and \
+                the idsig file must be the one of the decompressed APK."
             )
             .action(ArgAction::Append)
             .value_names(["apk_path", "idsig_path", "name", "root_hash"]),
         )
+        .arg(
+            Arg::new("extra-apk")
+                .long("extra-apk")
+                .num_args(3)
+                .action(ArgAction::Append)
+                .value_names(["apk_path", "idsig_path", "root_hash"])
+                .help(
+                    "Extra APK file, idsig file and root hash, as for --apk. The block devices \
+                    of the extra APKs are named \"extra-apk-<index>\", after the order in \
+                    which they are given, and set up after the ones given with --apk.",
+                ),
+        )
+        .arg(
+            Arg::new("mount-at")
+                .long("mount-at")
+                .num_args(2)
+                .action(ArgAction::Append)
+                .value_names(["name", "dir"])
+                .help(
+                    "Mounts the block device with the given name read-only at the given \
+                    directory once it is created. The block device is removed again if the \
+                    mount fails.",
+                ),
+        )
+        .arg(
+            Arg::new("overlay")
+                .long("overlay")
+                .num_args(2)
+                .action(ArgAction::Append)
+                .value_names(["name", "scratch_file"])
+                .help(
+                    "Stacks a writable block device over the block device with the given name, \
+                    at \"/dev/mapper/<name>-overlay\". Writes go to the given scratch file, \
+                    leaving the APK unchanged, and are lost once the device is removed. \
+                    Meant for testing only.",
+                ),
+        )
+        .arg(
+            Arg::new("hash-file")
+                .long("hash-file")
+                .num_args(2)
+                .action(ArgAction::Append)
+                .value_names(["name", "hash_file"])
+                .help(
+                    "Reads the merkle tree of the block device with the given name from the given \
+                    file, where it starts at offset 0, instead of from the idsig file. The salt, \
+                    root hash and hash algorithm are still taken from the idsig file.",
+                ),
+        )
+        .arg(
+            Arg::new("salt")
+                .long("salt")
+                .num_args(2)
+                .action(ArgAction::Append)
+                .value_names(["name", "salt"])
+                .help(
+                    "Salt in hex of the merkle tree of the block device with the given name, \
+                    instead of the one in the idsig file, e.g. to reproduce a device built with \
+                    a custom salt. At most 32 bytes.",
+                ),
+        )
+        .arg(
+            Arg::new("apk-offset")
+                .long("apk-offset")
+                .num_args(2)
+                .action(ArgAction::Append)
+                .value_names(["name", "offset"])
+                .help(
+                    "Offset in bytes of the APK within the APK file given for the block device \
+                    with the given name, for APKs packed in a larger container. Must be a \
+                    multiple of 4096. Defaults to 0.",
+                ),
+        )
+        .arg(
+            Arg::new("apk-size")
+                .long("apk-size")
+                .num_args(2)
+                .action(ArgAction::Append)
+                .value_names(["name", "size"])
+                .help(
+                    "Size in bytes of the APK within the APK file given for the block device \
+                    with the given name. Must be a multiple of 4096. Defaults to the rest of \
+                    the file after --apk-offset.",
+                ),
+        )
+        .arg(
+            Arg::new("fs-type")
+                .long("fs-type")
+                .default_value("ext4")
+                .help("Filesystem type of the block devices mounted with --mount-at"),
+        )
         .arg(
             Arg::new("verbose")
                 .short('v')
@@ -81,78 +410,692 @@ fn clap_command() -> Command {
                 .action(ArgAction::SetTrue)
                 .help("Shows verbose output"),
         )
+        .subcommand(
+            Command::new("inspect")
+                .about(
+                    "Shows the fields of an idsig file, without setting anything up. Binary \
+                    fields are shown in hex.",
+                )
+                .arg(Arg::new("idsig").required(true).help("The idsig file to inspect"))
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_parser(["text", "json"])
+                        .default_value("text")
+                        .help("Output format"),
+                ),
+        )
+}
+
+// The fields of an idsig file, as shown by the inspect subcommand. Binary fields are in hex.
+#[derive(Debug, Serialize)]
+struct IdsigInfo {
+    version: u32,
+    hash_algorithm: String,
+    block_size: u64,
+    salt: String,
+    root_hash: String,
+    apk_digest: String,
+    merkle_tree_offset: u64,
+    merkle_tree_size: u32,
 }
 
+impl IdsigInfo {
+    fn new<R: Read + Seek>(sig: &V4Signature<R>) -> Result<Self> {
+        let log2_blocksize = sig.hashing_info.log2_blocksize;
+        Ok(Self {
+            version: sig.version.to_u32().context("Invalid version")?,
+            hash_algorithm: format!("{:?}", sig.hashing_info.hash_algorithm),
+            block_size: 1u64
+                .checked_shl(log2_blocksize.into())
+                .context(format!("Invalid log2 of the block size: {log2_blocksize}"))?,
+            salt: hex::encode(&sig.hashing_info.salt),
+            root_hash: hex::encode(&sig.hashing_info.raw_root_hash),
+            apk_digest: hex::encode(&sig.signing_info.apk_digest),
+            merkle_tree_offset: sig.merkle_tree_offset,
+            merkle_tree_size: sig.merkle_tree_size,
+        })
+    }
+}
+
+impl fmt::Display for IdsigInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "version: {}", self.version)?;
+        writeln!(f, "hash_algorithm: {}", self.hash_algorithm)?;
+        writeln!(f, "block_size: {}", self.block_size)?;
+        writeln!(f, "salt: {}", self.salt)?;
+        writeln!(f, "root_hash: {}", self.root_hash)?;
+        writeln!(f, "apk_digest: {}", self.apk_digest)?;
+        writeln!(f, "merkle_tree_offset: {}", self.merkle_tree_offset)?;
+        writeln!(f, "merkle_tree_size: {}", self.merkle_tree_size)
+    }
+}
+
+// Returns the fields of the idsig file at `idsig`, as text or as JSON.
+fn inspect_idsig(idsig: &Path, json: bool) -> Result<String> {
+    let sig =
+        V4Signature::from_idsig_path(idsig).context(format!("Failed to parse {:?}", idsig))?;
+    let info = IdsigInfo::new(&sig)?;
+    if json {
+        Ok(serde_json::to_string_pretty(&info)? + "\n")
+    } else {
+        Ok(info.to_string())
+    }
+}
+
+#[derive(Debug)]
 struct VerityResult {
     data_device: PathBuf,
+    // Whether `data_device` is a loop device attached by `enable_verity`. If not, the data device
+    // was given as a block device (e.g. an existing dm-linear device) and is owned by the caller.
+    data_device_attached: bool,
     hash_device: PathBuf,
     mapper_device: PathBuf,
+    mount_point: Option<PathBuf>,
+    overlay: Option<Overlay>,
+}
+
+// A writable dm-snapshot device stacked over a dm-verity device by `enable_overlay`.
+#[derive(Debug)]
+struct Overlay {
+    // Loop device attached to the scratch file, where the written chunks are stored.
+    cow_device: PathBuf,
+    mapper_device: PathBuf,
 }
 
 const BLOCK_SIZE: u64 = 4096;
 
-// Makes a dm-verity block device out of `apk` and its accompanying `idsig` files.
+// Where the APK is in the file (or block device) given as the APK. By default, the APK is the whole
+// file, but it can also be packed in a larger container.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct ApkRange {
+    offset: u64,
+    // `None` if the APK extends to the end of the file.
+    size: Option<u64>,
+}
+
+// The part of a file (or block device) holding an APK, which can be read as if the APK was on its
+// own.
+struct ApkSlice {
+    file: File,
+    offset: u64,
+    size: u64,
+    pos: u64,
+}
+
+impl ApkSlice {
+    fn open(apk: &Path, range: ApkRange) -> Result<Self> {
+        let file = File::open(apk).context(format!("Failed to open {:?}", apk))?;
+        let file_size = if file.metadata()?.file_type().is_block_device() {
+            util::blkgetsize64(apk)?
+        } else {
+            file.metadata()?.len()
+        };
+        Ok(Self::new(file, file_size, range, apk)?)
+    }
+
+    // Same as `open`, but for an already open `file` of `file_size` bytes, described as `apk` in
+    // errors.
+    fn new(file: File, file_size: u64, range: ApkRange, apk: &Path) -> Result<Self, VerityError> {
+        let size = match range.size {
+            Some(size) => size,
+            None => file_size.saturating_sub(range.offset),
+        };
+        if !range.offset.checked_add(size).is_some_and(|end| end <= file_size) {
+            return Err(VerityError::OutOfFile {
+                what: "APK",
+                path: apk.to_path_buf(),
+                offset: range.offset,
+                size,
+                file_size,
+            });
+        }
+        Ok(Self { file, offset: range.offset, size, pos: 0 })
+    }
+}
+
+impl Read for ApkSlice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.size.saturating_sub(self.pos);
+        let len = buf.len().min(remaining.try_into().unwrap_or(usize::MAX));
+        let read = self.file.read_at(&mut buf[..len], self.offset + self.pos)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for ApkSlice {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = pos.ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        Ok(self.pos)
+    }
+}
+
+// Why setting up the dm-verity device of an APK failed.
+#[derive(Debug, Error)]
+enum VerityError {
+    #[error("Failed to access {0:?}")]
+    Io(PathBuf, #[source] io::Error),
+    #[error("Failed to get the size of block device {0:?}")]
+    BlockDeviceSize(PathBuf, #[source] anyhow::Error),
+    #[error("Failed to parse idsig file {0:?}")]
+    InvalidIdsig(PathBuf, #[source] anyhow::Error),
+    #[error(
+        "{what} at offset {offset} with size {size} is out of {path:?}, which is {file_size} bytes"
+    )]
+    OutOfFile { what: &'static str, path: PathBuf, offset: u64, size: u64, file_size: u64 },
+    #[error("The APK at offset {offset} with size {size} in {path:?} is not aligned to {BLOCK_SIZE} bytes")]
+    NotBlockAligned { path: PathBuf, offset: u64, size: u64 },
+    #[error("The hash file {path:?} is {size} bytes, but the merkle tree is {tree_size} bytes")]
+    HashFileSizeMismatch { path: PathBuf, size: u64, tree_size: u64 },
+    #[error("Failed to get the APK digest of {0:?}")]
+    ApkDigest(PathBuf, #[source] anyhow::Error),
+    #[error("{idsig:?} is not the idsig file of {apk:?}: APK digest mismatch")]
+    IdsigMismatch { apk: PathBuf, idsig: PathBuf },
+    #[error("Failed to attach {0:?} to a loop device")]
+    LoopDevice(PathBuf, #[source] anyhow::Error),
+    #[error("Merkle tree in {0:?} is not compatible with dm-verity")]
+    IncompatibleMerkleTree(PathBuf, #[source] anyhow::Error),
+    #[error("Failed to create dm-verity device {0}")]
+    DeviceMapper(String, #[source] anyhow::Error),
+}
+
+// Returns a closure making a `VerityError::Io` for `path`, to be given to `map_err`.
+fn io_error(path: impl AsRef<Path>) -> impl FnOnce(io::Error) -> VerityError {
+    move |e| VerityError::Io(path.as_ref().to_path_buf(), e)
+}
+
+// Makes a dm-verity block device out of `apk` and its accompanying `idsig` files. `apk` can also be
+// an existing block device, including a device-mapper device, in which case it is used as the data
+// device as it is. Such a device is never detached by apkdmverity; the caller remains responsible
+// for it. If the APK is only part of `apk`, as given by `apk_range`, that part is attached to a loop
+// device to be used as the data device, even if `apk` is a block device.
 fn enable_verity<P: AsRef<Path> + Debug>(
     apk: P,
+    apk_range: ApkRange,
     idsig: P,
     name: &str,
     roothash: Option<&[u8]>,
-) -> Result<VerityResult> {
-    // Attach the apk file to a loop device if the apk file is a regular file. If not (i.e. block
-    // device), we only need to get the size and use the block device as it is.
-    let (data_device, apk_size) = if fs::metadata(&apk)?.file_type().is_block_device() {
-        (apk.as_ref().to_path_buf(), util::blkgetsize64(apk.as_ref())?)
-    } else {
-        let apk_size = fs::metadata(&apk)?.len();
-        if apk_size % BLOCK_SIZE != 0 {
-            bail!("The size of {:?} is not multiple of {}.", &apk, BLOCK_SIZE)
-        }
-        (
-            loopdevice::attach(
-                &apk, 0, apk_size, /* direct_io */ true, /* writable */ false,
-            )
-            .context("Failed to attach APK to a loop device")?,
+) -> Result<VerityResult, VerityError> {
+    enable_verity_with_hash_file(apk, apk_range, idsig, None, name, roothash, None)
+}
+
+// Same as `enable_verity`, but if `hash_file` is given, the merkle tree is read from it, starting
+// at offset 0, rather than from the idsig file. The idsig file still gives the root hash, unless
+// `roothash` is given, the salt, unless `salt` is given, and the hash algorithm.
+fn enable_verity_with_hash_file<P: AsRef<Path> + Debug>(
+    apk: P,
+    apk_range: ApkRange,
+    idsig: P,
+    hash_file: Option<P>,
+    name: &str,
+    roothash: Option<&[u8]>,
+    salt: Option<&[u8]>,
+) -> Result<VerityResult, VerityError> {
+    let apk_file = File::open(apk.as_ref()).map_err(io_error(&apk))?;
+    let apk_metadata = apk_file.metadata().map_err(io_error(&apk))?;
+    let idsig_file = File::open(idsig.as_ref()).map_err(io_error(&idsig))?;
+    let idsig_size = file_size(&idsig_file, idsig.as_ref())?;
+    let hash_file =
+        hash_file.map(|path| File::open(path.as_ref()).map_err(io_error(&path))).transpose()?;
+    if !apk_metadata.file_type().is_block_device() {
+        let apk_size = apk_metadata.len();
+        return enable_verity_fd(
+            &apk_file,
             apk_size,
-        )
+            apk_range,
+            &idsig_file,
+            idsig_size,
+            hash_file.as_ref(),
+            name,
+            roothash,
+            salt,
+        );
+    }
+    let apk_size = util::blkgetsize64(apk.as_ref())
+        .map_err(|e| VerityError::BlockDeviceSize(apk.as_ref().to_path_buf(), e))?;
+    if apk_range != ApkRange::default() {
+        return enable_verity_fd(
+            &apk_file,
+            apk_size,
+            apk_range,
+            &idsig_file,
+            idsig_size,
+            hash_file.as_ref(),
+            name,
+            roothash,
+            salt,
+        );
+    }
+
+    // Parse the idsig file to locate the merkle tree. Pairing the APK with the wrong idsig file
+    // would only be noticed when reading the dm-verity device, so check it upfront.
+    let sig = V4Signature::from_idsig(&idsig_file)
+        .map_err(|e| VerityError::InvalidIdsig(idsig.as_ref().to_path_buf(), e))?;
+    let (tree_file, tree_offset) =
+        locate_merkle_tree(&sig, &idsig_file, idsig_size, hash_file.as_ref())?;
+    let apk_slice = ApkSlice::new(apk_file, apk_size, apk_range, apk.as_ref())?;
+    check_idsig_is_for_apk(&apk, apk_slice, &idsig, &sig)?;
+
+    // The block device is used as the data device as it is.
+    let data_device = apk.as_ref().to_path_buf();
+    create_verity_device(
+        data_device,
+        false,
+        apk_size,
+        &sig,
+        tree_file,
+        tree_offset,
+        name,
+        roothash,
+        salt,
+    )
+}
+
+// Same as `enable_verity_with_hash_file`, but for an APK, an idsig file and a hash file which are
+// already open, e.g. because they were received from another process, of `apk_file_size` and
+// `idsig_size` bytes respectively. No path of theirs is probed: the APK, or the part of it given by
+// `apk_range`, is always attached to a loop device, even if `apk` is a block device. The idsig file
+// is read from its start.
+#[allow(clippy::too_many_arguments)]
+fn enable_verity_fd(
+    apk: &File,
+    apk_file_size: u64,
+    apk_range: ApkRange,
+    idsig: &File,
+    idsig_size: u64,
+    hash_file: Option<&File>,
+    name: &str,
+    roothash: Option<&[u8]>,
+    salt: Option<&[u8]>,
+) -> Result<VerityResult, VerityError> {
+    let (apk_path, idsig_path) = (PathBuf::from(fd_path(apk)), PathBuf::from(fd_path(idsig)));
+
+    // Parse the idsig file to locate the merkle tree. Pairing the APK with the wrong idsig file
+    // would only be noticed when reading the dm-verity device, so check it upfront.
+    let mut idsig_reader = idsig.try_clone().map_err(io_error(&idsig_path))?;
+    idsig_reader.rewind().map_err(io_error(&idsig_path))?;
+    let sig = V4Signature::from_idsig(idsig_reader)
+        .map_err(|e| VerityError::InvalidIdsig(idsig_path.clone(), e))?;
+    let (tree_file, tree_offset) = locate_merkle_tree(&sig, idsig, idsig_size, hash_file)?;
+    let apk_clone = apk.try_clone().map_err(io_error(&apk_path))?;
+    let apk_slice = ApkSlice::new(apk_clone, apk_file_size, apk_range, &apk_path)?;
+    let (apk_offset, apk_size) = (apk_slice.offset, apk_slice.size);
+    if apk_offset % BLOCK_SIZE != 0 || apk_size % BLOCK_SIZE != 0 {
+        return Err(VerityError::NotBlockAligned {
+            path: apk_path,
+            offset: apk_offset,
+            size: apk_size,
+        });
+    }
+    check_idsig_is_for_apk(&apk_path, apk_slice, &idsig_path, &sig)?;
+
+    // Direct IO isn't supported for files in memory, e.g. decompressed APKs.
+    let fs_type = fstatfs(apk).map_err(|e| VerityError::Io(apk_path.clone(), e.into()))?;
+    let direct_io = fs_type.filesystem_type() != TMPFS_MAGIC;
+    let data_device =
+        loopdevice::attach(&apk_path, apk_offset, apk_size, direct_io, /* writable */ false)
+            .map_err(|e| VerityError::LoopDevice(apk_path, e))?;
+
+    create_verity_device(
+        data_device,
+        true,
+        apk_size,
+        &sig,
+        tree_file,
+        tree_offset,
+        name,
+        roothash,
+        salt,
+    )
+}
+
+// Returns the path of the file holding the merkle tree described by `sig`, and the offset of the
+// tree in that file. That is `hash_file`, from its start, if given, or else the idsig file, of
+// `idsig_size` bytes, at the offset recorded in `sig`.
+fn locate_merkle_tree<R: Read + Seek>(
+    sig: &V4Signature<R>,
+    idsig: &File,
+    idsig_size: u64,
+    hash_file: Option<&File>,
+) -> Result<(PathBuf, u64), VerityError> {
+    let tree_size = u64::from(sig.merkle_tree_size);
+    let Some(hash_file) = hash_file else {
+        let idsig_path = PathBuf::from(fd_path(idsig));
+        let offset = sig.merkle_tree_offset;
+        if !offset.checked_add(tree_size).is_some_and(|end| end <= idsig_size) {
+            return Err(VerityError::OutOfFile {
+                what: "The merkle tree",
+                path: idsig_path,
+                offset,
+                size: tree_size,
+                file_size: idsig_size,
+            });
+        }
+        return Ok((idsig_path, offset));
     };
+    let hash_file_path = PathBuf::from(fd_path(hash_file));
+    let size = file_size(hash_file, &hash_file_path)?;
+    if size != tree_size {
+        return Err(VerityError::HashFileSizeMismatch { path: hash_file_path, size, tree_size });
+    }
+    Ok((hash_file_path, 0))
+}
 
-    // Parse the idsig file to locate the merkle tree in it, then attach the file to a loop device
-    // with the offset so that the start of the merkle tree becomes the beginning of the loop
-    // device.
-    let sig = V4Signature::from_idsig_path(&idsig)?;
-    let offset = sig.merkle_tree_offset;
+// Creates the dm-verity block device `name` over `data_device`, of `data_size` bytes, with the
+// merkle tree described by `sig`, which is at `tree_offset` in `tree_file`. `roothash` and `salt`
+// override the ones in `sig`.
+#[allow(clippy::too_many_arguments)]
+fn create_verity_device<P: AsRef<Path> + Debug, R: Read + Seek>(
+    data_device: PathBuf,
+    data_device_attached: bool,
+    data_size: u64,
+    sig: &V4Signature<R>,
+    tree_file: P,
+    tree_offset: u64,
+    name: &str,
+    roothash: Option<&[u8]>,
+    salt: Option<&[u8]>,
+) -> Result<VerityResult, VerityError> {
+    // Attach the file holding the merkle tree to a loop device with the offset so that the start
+    // of the merkle tree becomes the beginning of the loop device.
     let size = sig.merkle_tree_size as u64;
     // Due to unknown reason(b/191344832), we can't enable "direct IO" for the IDSIG file (backing
     // the hash). For now we don't use "direct IO" but it seems OK since the IDSIG file is very
-    // small and the benefit of direct-IO would be negliable.
-    let hash_device = loopdevice::attach(
-        &idsig, offset, size, /* direct_io */ false, /* writable */ false,
-    )
-    .context("Failed to attach idsig to a loop device")?;
+    // small and the benefit of direct-IO would be negliable. That only affects regular files
+    // though, so direct IO is used when the merkle tree is on a block device, provided that it is
+    // aligned as direct IO requires.
+    let direct_io = is_block_device(&tree_file) && tree_offset % BLOCK_SIZE == 0;
+    let hash_device =
+        loopdevice::attach(&tree_file, tree_offset, size, direct_io, /* writable */ false)
+            .map_err(|e| VerityError::LoopDevice(tree_file.as_ref().to_path_buf(), e))?;
+    debug!(
+        "Attached {tree_file:?} at offset {tree_offset} to {hash_device:?}, direct IO: {direct_io}"
+    );
 
     // Build a dm-verity target spec from the information from the idsig file. The apk and the
-    // idsig files are used as the data device and the hash device, respectively.
+    // idsig files are used as the data device and the hash device, respectively. The v4 signature
+    // has a single block size, used for both the data and the merkle tree. An out of range one is
+    // turned into 0, which the builder rejects like any other invalid block size.
+    let root_digest = roothash.unwrap_or(&sig.hashing_info.raw_root_hash);
+    let block_size = 1u64.checked_shl(sig.hashing_info.log2_blocksize.into()).unwrap_or(0);
     let target = DmVerityTargetBuilder::default()
-        .data_device(&data_device, apk_size)
+        .data_device(&data_device, data_size)
+        .data_block_size(block_size)
         .hash_device(&hash_device)
-        .root_digest(if let Some(roothash) = roothash {
-            roothash
-        } else {
-            &sig.hashing_info.raw_root_hash
-        })
+        .hash_block_size(block_size)
+        .hash_tree_size(size)
+        .root_digest(root_digest)
         .hash_algorithm(match sig.hashing_info.hash_algorithm {
             HashAlgorithm::SHA256 => DmVerityHashAlgorithm::SHA256,
         })
-        .salt(&sig.hashing_info.salt)
+        .salt(salt.unwrap_or(&sig.hashing_info.salt))
         .build()
-        .context(format!("Merkle tree in {:?} is not compatible with dm-verity", &idsig))?;
+        .map_err(|e| VerityError::IncompatibleMerkleTree(tree_file.as_ref().to_path_buf(), e))?;
 
     // Actually create a dm-verity block device using the spec.
+    let mapper_device = dm::DeviceMapper::new()
+        .and_then(|dm| dm.create_verity_device(name, &target))
+        .map_err(|e| VerityError::DeviceMapper(name.to_owned(), e))?;
+    info!(
+        "Created {mapper_device:?} for {name} over {data_device:?} ({data_size} bytes), with hash \
+        device {hash_device:?} and root hash {}",
+        hex::encode(root_digest)
+    );
+
+    Ok(VerityResult {
+        data_device,
+        data_device_attached,
+        hash_device,
+        mapper_device,
+        mount_point: None,
+        overlay: None,
+    })
+}
+
+// Returns the size of `file`, opened from `path`, which can also be a block device.
+fn file_size(file: &File, path: &Path) -> Result<u64, VerityError> {
+    let metadata = file.metadata().map_err(io_error(path))?;
+    if metadata.file_type().is_block_device() {
+        util::blkgetsize64(path).map_err(|e| VerityError::BlockDeviceSize(path.to_path_buf(), e))
+    } else {
+        Ok(metadata.len())
+    }
+}
+
+fn is_block_device(path: impl AsRef<Path>) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_block_device())
+}
+
+// Checks that `sig`, read from `idsig`, is for `apk` by comparing the APK digest it records to the
+// one in the signature block of `apk`. The contents of `apk` aren't digested, as dm-verity is what
+// protects them.
+fn check_idsig_is_for_apk<P: AsRef<Path> + Debug, R: Read + Seek>(
+    apk: P,
+    apk_slice: ApkSlice,
+    idsig: P,
+    sig: &V4Signature<R>,
+) -> Result<(), VerityError> {
+    let (_, apk_digest) = get_current_sdk()
+        .and_then(|current_sdk| get_apk_digest(apk_slice, current_sdk, /* verify= */ false))
+        .map_err(|e| VerityError::ApkDigest(apk.as_ref().to_path_buf(), e))?;
+    if apk_digest != sig.signing_info.apk_digest {
+        return Err(VerityError::IdsigMismatch {
+            apk: apk.as_ref().to_path_buf(),
+            idsig: idsig.as_ref().to_path_buf(),
+        });
+    }
+    Ok(())
+}
+
+// Creates an idsig file for `apk` out of its APK signature scheme V3 signature, for APKs that come
+// without one. The merkle tree is computed from the APK as it is now, and the V3 signature isn't
+// verified, so the caller must check the root hash against a trusted one. The idsig file only lives
+// in memory, for as long as the returned file or a loop device attached to it is open.
+fn create_idsig_from_apk<P: AsRef<Path> + Debug>(apk: P, apk_range: ApkRange) -> Result<File> {
+    let mut apk_slice = ApkSlice::open(apk.as_ref(), apk_range)?;
+    let mut sig = V4Signature::create(
+        &mut apk_slice,
+        get_current_sdk()?,
+        BLOCK_SIZE as usize,
+        &[],
+        HashAlgorithm::SHA256,
+    )
+    .context(format!("Failed to compute the merkle tree of {:?}", &apk))?;
+
+    let name = CStr::from_bytes_with_nul(b"apkdmverity_idsig\0").unwrap();
+    let mut idsig = create_memfd(name).context("Failed to create idsig file")?;
+    sig.write_into(&mut idsig).context("Failed to write idsig file")?;
+    Ok(idsig)
+}
+
+// Extension of the APK files compressed with zstd.
+const ZSTD_EXTENSION: &str = "zst";
+
+fn is_compressed(apk: &Path) -> bool {
+    apk.extension().is_some_and(|extension| extension == ZSTD_EXTENSION)
+}
+
+// The maximum size of a decompressed APK, which is kept in memory.
+const MAX_DECOMPRESSED_APK_SIZE: u64 = 1 << 30;
+
+// The size of the SHA-256 digests of the merkle tree.
+const SHA256_DIGEST_SIZE: u64 = 32;
+
+// Returns the maximum size of an APK whose merkle tree is in `idsig`, if any, or the maximum size
+// of a decompressed APK. Each digest of the lowest level of the tree covers a block of the APK, so
+// there are at most as many blocks as there are digests in the whole tree.
+fn max_apk_size(idsig: Option<&Path>) -> Result<u64> {
+    let Some(idsig) = idsig else {
+        return Ok(MAX_DECOMPRESSED_APK_SIZE);
+    };
+    let sig = V4Signature::from_idsig_path(idsig)
+        .context(format!("Failed to read idsig file {:?}", idsig))?;
+    let block_size = 1u64
+        .checked_shl(sig.hashing_info.log2_blocksize.into())
+        .context(format!("Invalid block size in {:?}", idsig))?;
+    let digests = u64::from(sig.merkle_tree_size) / SHA256_DIGEST_SIZE;
+    // Without a tree, the root hash covers a single block.
+    let size = digests.saturating_mul(block_size).max(block_size);
+    Ok(size.min(MAX_DECOMPRESSED_APK_SIZE))
+}
+
+// Decompresses `apk`, which is compressed with zstd, failing if it decompresses to more than
+// `max_size` bytes. The merkle tree in the idsig file covers the decompressed APK, padded with
+// zeros to a multiple of the block size like any APK attached to a loop device. The decompressed
+// APK only lives in memory, for as long as the returned file or a loop device attached to it is
+// open.
+fn decompress_apk(apk: &Path, max_size: u64) -> Result<File> {
+    let compressed = File::open(apk).context(format!("Failed to open {:?}", apk))?;
+    let name = CStr::from_bytes_with_nul(b"apkdmverity_apk\0").unwrap();
+    let mut decompressed = create_memfd(name).context("Failed to create decompressed APK")?;
+    let decoder = zstd::stream::read::Decoder::new(compressed)
+        .context(format!("Failed to decompress {:?}", apk))?;
+    // Read one more byte than allowed, to tell an APK of exactly `max_size` bytes from a larger one.
+    let size = io::copy(&mut decoder.take(max_size.saturating_add(1)), &mut decompressed)
+        .context(format!("Failed to decompress {:?}", apk))?;
+    ensure!(size <= max_size, "{:?} decompresses to more than {max_size} bytes", apk);
+    decompressed.set_len(size.next_multiple_of(BLOCK_SIZE))?;
+    Ok(decompressed)
+}
+
+fn create_memfd(name: &CStr) -> io::Result<File> {
+    // SAFETY: `name` is a valid C string, and the return value is checked below.
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` was just created and is owned by nothing else.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+// Returns a path through which `file` can be opened again, even if it has no name.
+fn fd_path(file: &File) -> String {
+    format!("/proc/self/fd/{}", file.as_raw_fd())
+}
+
+#[cfg(target_os = "android")]
+fn get_current_sdk() -> Result<u32> {
+    let current_sdk = rustutils::system_properties::read("ro.build.version.sdk")?;
+    let current_sdk = current_sdk.context("SDK version missing")?;
+    current_sdk.parse().context("Malformed SDK version")
+}
+
+#[cfg(not(target_os = "android"))]
+fn get_current_sdk() -> Result<u32> {
+    bail!("The SDK version is only known on Android")
+}
+
+// Same as `enable_verity_with_hash_file`, but also mounts the created block device read-only at
+// `mount_point`. If mounting fails, the block device is removed again so that nothing is left
+// behind.
+#[allow(clippy::too_many_arguments)]
+fn enable_verity_and_mount<P: AsRef<Path> + Debug>(
+    apk: P,
+    apk_range: ApkRange,
+    idsig: P,
+    hash_file: Option<P>,
+    name: &str,
+    roothash: Option<&[u8]>,
+    salt: Option<&[u8]>,
+    mount_point: &Path,
+    fs_type: &str,
+) -> Result<VerityResult> {
+    let mut ret =
+        enable_verity_with_hash_file(apk, apk_range, idsig, hash_file, name, roothash, salt)?;
+    if let Err(e) = mount_verity(&mut ret, mount_point, fs_type) {
+        if let Err(cleanup_err) = disable_verity(ret, name) {
+            error!("Failed to remove {name} after mount failure: {cleanup_err:?}");
+        }
+        return Err(e);
+    }
+    Ok(ret)
+}
+
+// Stacks a writable dm-snapshot device over the dm-verity device of `result`. The chunks written to
+// it are stored in `scratch` instead of the APK, so the integrity of the APK is kept. The snapshot
+// isn't persistent; the writes are lost once the device is removed.
+fn enable_overlay(result: &mut VerityResult, name: &str, scratch: &Path) -> Result<()> {
+    let scratch_size = fs::metadata(scratch)?.len();
+    if scratch_size % BLOCK_SIZE != 0 {
+        bail!("The size of {:?} is not multiple of {}.", scratch, BLOCK_SIZE)
+    }
+    let cow_device = loopdevice::attach(
+        scratch,
+        0,
+        scratch_size,
+        /* direct_io */ true,
+        /* writable */ true,
+    )
+    .context("Failed to attach scratch file to a loop device")?;
+    let cow_device = scopeguard::guard(cow_device, |dev| {
+        if let Err(e) = loopdevice::detach(&dev) {
+            warn!("Failed to detach {dev:?}: {e:?}");
+        }
+    });
+
+    let origin_size = util::blkgetsize64(&result.mapper_device)?;
+    let target = DmSnapshotTargetBuilder::default()
+        .origin_device(&result.mapper_device, origin_size)
+        .cow_device(&cow_device)
+        .build()
+        .context("Failed to build dm-snapshot target")?;
     let dm = dm::DeviceMapper::new()?;
-    let mapper_device =
-        dm.create_verity_device(name, &target).context("Failed to create dm-verity device")?;
+    let mapper_device = dm
+        .create_snapshot_device(&overlay_name(name), &target)
+        .context("Failed to create dm-snapshot device")?;
+
+    info!("Created {mapper_device:?} over {:?} for {name}", result.mapper_device);
+
+    let cow_device = scopeguard::ScopeGuard::into_inner(cow_device);
+    result.overlay = Some(Overlay { cow_device, mapper_device });
+    Ok(())
+}
 
-    Ok(VerityResult { data_device, hash_device, mapper_device })
+fn overlay_name(name: &str) -> String {
+    format!("{name}-overlay")
+}
+
+fn mount_verity(result: &mut VerityResult, mount_point: &Path, fs_type: &str) -> Result<()> {
+    fs::create_dir_all(mount_point).context(format!("Failed to create {:?}", mount_point))?;
+    mount(
+        Some(&result.mapper_device),
+        mount_point,
+        Some(fs_type),
+        MsFlags::MS_RDONLY | MsFlags::MS_NODEV | MsFlags::MS_NOSUID,
+        None::<&str>,
+    )
+    .context(format!("Failed to mount {:?} at {:?}", &result.mapper_device, mount_point))?;
+    info!("Mounted {:?} at {:?}", result.mapper_device, mount_point);
+    result.mount_point = Some(mount_point.to_path_buf());
+    Ok(())
+}
+
+// Tears down what `enable_verity` (and `mount_verity` or `enable_overlay`) set up: unmounts the
+// block device if it is mounted, removes the overlay if any, removes the dm-verity device and
+// detaches the hash device, as well as the data device if it was attached by `enable_verity`. A data
+// device given as a block device is left as it is.
+fn disable_verity(result: VerityResult, name: &str) -> Result<()> {
+    if let Some(mount_point) = &result.mount_point {
+        umount2(mount_point, MntFlags::MNT_DETACH)
+            .context(format!("Failed to unmount {:?}", mount_point))?;
+    }
+    let dm = dm::DeviceMapper::new()?;
+    if let Some(overlay) = &result.overlay {
+        dm.delete_device_deferred(&overlay_name(name))?;
+        loopdevice::detach(&overlay.cow_device).context("Failed to detach COW device")?;
+    }
+    dm.delete_device_deferred(name)?;
+    if result.data_device_attached {
+        loopdevice::detach(&result.data_device).context("Failed to detach data device")?;
+    }
+    loopdevice::detach(&result.hash_device).context("Failed to detach hash device")?;
+    Ok(())
 }
 
 #[cfg(test)]
@@ -161,6 +1104,7 @@ rdroidtest::test_main!();
 #[cfg(test)]
 mod tests {
     use crate::*;
+    use dm::verity::DmVerityMode;
     use rdroidtest::{ignore_if, rdroidtest};
     use std::fs::{File, OpenOptions};
     use std::io::Write;
@@ -184,6 +1128,11 @@ mod tests {
         false
     }
 
+    fn is_direct_io(loop_device: &Path) -> bool {
+        let dio = Path::new("/sys/block").join(loop_device.file_name().unwrap()).join("loop/dio");
+        "1" == fs::read_to_string(dio).unwrap().trim()
+    }
+
     fn create_block_aligned_file(path: &Path, data: &[u8]) {
         let mut f = File::create(path).unwrap();
         f.write_all(data).unwrap();
@@ -203,7 +1152,7 @@ mod tests {
     }
 
     fn run_test(apk: &[u8], idsig: &[u8], name: &str, check: fn(TestContext)) {
-        run_test_with_hash(apk, idsig, name, None, check);
+        run_test_with_hash(apk, idsig, name, None, None, check);
     }
 
     fn run_test_with_hash(
@@ -211,19 +1160,24 @@ mod tests {
         idsig: &[u8],
         name: &str,
         roothash: Option<&[u8]>,
+        salt: Option<&[u8]>,
         check: fn(TestContext),
     ) {
         let test_dir = tempfile::TempDir::new().unwrap();
         let (apk_path, idsig_path) = prepare_inputs(test_dir.path(), apk, idsig);
 
         // Run the program and register clean-ups.
-        let ret = enable_verity(&apk_path, &idsig_path, name, roothash).unwrap();
-        let ret = scopeguard::guard(ret, |ret| {
-            loopdevice::detach(ret.data_device).unwrap();
-            loopdevice::detach(ret.hash_device).unwrap();
-            let dm = dm::DeviceMapper::new().unwrap();
-            dm.delete_device_deferred(name).unwrap();
-        });
+        let ret = enable_verity_with_hash_file(
+            &apk_path,
+            ApkRange::default(),
+            &idsig_path,
+            None,
+            name,
+            roothash,
+            salt,
+        )
+        .unwrap();
+        let ret = scopeguard::guard(ret, |ret| disable_verity(ret, name).unwrap());
 
         check(TestContext {
             data_backing_file: &apk_path,
@@ -242,6 +1196,12 @@ mod tests {
             let original = fs::read(&ctx.result.data_device).unwrap();
             assert_eq!(verity.len(), original.len()); // fail fast
             assert_eq!(verity.as_slice(), original.as_slice());
+
+            let status = dm::DeviceMapper::new().unwrap().verity_status("correct").unwrap();
+            assert!(!status.corruption_detected);
+            assert_eq!(DmVerityMode::Eio, status.mode);
+            // The idsig file is a regular file, so the hash device doesn't use direct IO.
+            assert!(!is_direct_io(&ctx.result.hash_device));
         });
     }
 
@@ -260,6 +1220,9 @@ mod tests {
 
         run_test(modified_apk.as_slice(), idsig.as_ref(), "incorrect_apk", |ctx| {
             fs::read(&ctx.result.mapper_device).expect_err("Should fail");
+
+            let status = dm::DeviceMapper::new().unwrap().verity_status("incorrect_apk").unwrap();
+            assert!(status.corruption_detected);
         });
     }
 
@@ -328,6 +1291,25 @@ mod tests {
         });
     }
 
+    // The block size recorded in the idsig file is used for both the APK and the merkle tree, so
+    // a merkle tree whose size doesn't match it is rejected before creating the device.
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn idsig_with_other_block_size() {
+        let apk = include_bytes!("../testdata/test.apk");
+        let mut idsig = include_bytes!("../testdata/test.apk.idsig").to_vec();
+        // log2_blocksize follows the version, the size of hashing_info and the hash algorithm.
+        const LOG2_BLOCKSIZE_OFFSET: usize = 12;
+        assert_eq!(12, idsig[LOG2_BLOCKSIZE_OFFSET]);
+        idsig[LOG2_BLOCKSIZE_OFFSET] = 10;
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let (apk_path, idsig_path) = prepare_inputs(test_dir.path(), apk, &idsig);
+        let err = enable_verity(&apk_path, ApkRange::default(), &idsig_path, "block_size", None)
+            .expect_err("Should fail");
+        assert!(matches!(err, VerityError::IncompatibleMerkleTree(..)), "{err:?}");
+    }
+
     // test if both files are already block devices
     #[rdroidtest]
     #[ignore_if(should_skip())]
@@ -342,14 +1324,15 @@ mod tests {
         let apk_size = fs::metadata(&apk_path).unwrap().len();
         let idsig_size = fs::metadata(&idsig_path).unwrap().len();
 
-        // Note that apk_loop_device is not detatched. This is because, when the apk file is
-        // already a block device, `enable_verity` uses the block device as it is. The detatching
-        // of the data device is done in the scopeguard for the return value of `enable_verity`
-        // below. Only the idsig_loop_device needs detatching.
-        let apk_loop_device = loopdevice::attach(
-            &apk_path, 0, apk_size, /* direct_io */ true, /* writable */ false,
-        )
-        .unwrap();
+        // When the apk file is already a block device, `enable_verity` uses the block device as it
+        // is and leaves it to the caller, so both loop devices need detatching here.
+        let apk_loop_device = scopeguard::guard(
+            loopdevice::attach(
+                &apk_path, 0, apk_size, /* direct_io */ true, /* writable */ false,
+            )
+            .unwrap(),
+            |dev| loopdevice::detach(dev).unwrap(),
+        );
         let idsig_loop_device = scopeguard::guard(
             loopdevice::attach(
                 &idsig_path,
@@ -364,43 +1347,854 @@ mod tests {
 
         let name = "loop_as_input";
         // Run the program WITH the loop devices, not the regular files.
-        let ret =
-            enable_verity(apk_loop_device.deref(), idsig_loop_device.deref(), name, None).unwrap();
-        let ret = scopeguard::guard(ret, |ret| {
-            loopdevice::detach(ret.data_device).unwrap();
-            loopdevice::detach(ret.hash_device).unwrap();
-            let dm = dm::DeviceMapper::new().unwrap();
-            dm.delete_device_deferred(name).unwrap();
-        });
+        let ret = enable_verity(
+            apk_loop_device.deref(),
+            ApkRange::default(),
+            idsig_loop_device.deref(),
+            name,
+            None,
+        )
+        .unwrap();
+        let ret = scopeguard::guard(ret, |ret| disable_verity(ret, name).unwrap());
 
         let verity = fs::read(&ret.mapper_device).unwrap();
         let original = fs::read(&apk_path).unwrap();
         assert_eq!(verity.len(), original.len()); // fail fast
         assert_eq!(verity.as_slice(), original.as_slice());
+        // The merkle tree isn't aligned in the idsig file, so direct IO can't be used for it.
+        assert!(!is_direct_io(&ret.hash_device));
     }
 
-    // test with custom roothash
+    // When the merkle tree is aligned in an idsig file given as a block device, the hash device is
+    // attached with direct IO.
     #[rdroidtest]
     #[ignore_if(should_skip())]
-    fn correct_custom_roothash() {
+    fn aligned_merkle_tree_on_block_device_uses_direct_io() {
         let apk = include_bytes!("../testdata/test.apk");
-        let idsig = include_bytes!("../testdata/test.apk.idsig");
-        let roothash = V4Signature::from_idsig_path("testdata/test.apk.idsig")
-            .unwrap()
-            .hashing_info
-            .raw_root_hash;
-        run_test_with_hash(
-            apk.as_ref(),
-            idsig.as_ref(),
-            "correct_custom_roothash",
-            Some(&roothash),
-            |ctx| {
-                let verity = fs::read(&ctx.result.mapper_device).unwrap();
-                let original = fs::read(&ctx.result.data_device).unwrap();
-                assert_eq!(verity.len(), original.len()); // fail fast
-                assert_eq!(verity.as_slice(), original.as_slice());
-            },
-        );
+
+        // Pad the additional data of the idsig file, which isn't used by apkdmverity, so that the
+        // merkle tree starts at a block boundary.
+        let mut sig = V4Signature::from_idsig_path("testdata/test.apk.idsig").unwrap();
+        let padding = (BLOCK_SIZE - sig.merkle_tree_offset % BLOCK_SIZE) % BLOCK_SIZE;
+        let mut additional_data = sig.signing_info.additional_data.to_vec();
+        additional_data.resize(additional_data.len() + padding as usize, 0);
+        sig.signing_info.additional_data = additional_data.into_boxed_slice();
+        let mut idsig = io::Cursor::new(Vec::new());
+        sig.write_into(&mut idsig).unwrap();
+        let idsig = idsig.into_inner();
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let (apk_path, idsig_path) = prepare_inputs(test_dir.path(), apk, &idsig);
+        let idsig_size = fs::metadata(&idsig_path).unwrap().len();
+        let idsig_loop_device = scopeguard::guard(
+            loopdevice::attach(
+                &idsig_path,
+                0,
+                idsig_size,
+                /* direct_io */ false,
+                /* writable */ false,
+            )
+            .unwrap(),
+            |dev| loopdevice::detach(dev).unwrap(),
+        );
+
+        let name = "aligned_tree_on_block_device";
+        let ret =
+            enable_verity(&apk_path, ApkRange::default(), idsig_loop_device.deref(), name, None)
+                .unwrap();
+        let ret = scopeguard::guard(ret, |ret| disable_verity(ret, name).unwrap());
+
+        assert!(is_direct_io(&ret.hash_device));
+        let verity = fs::read(&ret.mapper_device).unwrap();
+        let original = fs::read(&apk_path).unwrap();
+        assert_eq!(verity.len(), original.len()); // fail fast
+        assert_eq!(verity.as_slice(), original.as_slice());
+    }
+
+    // test if the data device is an existing device-mapper device, e.g. for layered storage
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn input_is_existing_dm_device() {
+        let apk = include_bytes!("../testdata/test.apk");
+        let idsig = include_bytes!("../testdata/test.apk.idsig");
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let (apk_path, idsig_path) = prepare_inputs(test_dir.path(), apk, idsig);
+
+        // Use a dm-verity device over the APK as the pre-existing dm device.
+        let lower_name = "existing_dm_lower";
+        let lower =
+            enable_verity(&apk_path, ApkRange::default(), &idsig_path, lower_name, None).unwrap();
+        let lower = scopeguard::guard(lower, |lower| disable_verity(lower, lower_name).unwrap());
+
+        let name = "existing_dm_upper";
+        let ret = enable_verity(&lower.mapper_device, ApkRange::default(), &idsig_path, name, None)
+            .unwrap();
+        assert!(!ret.data_device_attached);
+        assert_eq!(lower.mapper_device, ret.data_device);
+
+        let verity = fs::read(&ret.mapper_device).unwrap();
+        let original = fs::read(&apk_path).unwrap();
+        assert_eq!(verity.len(), original.len()); // fail fast
+        assert_eq!(verity.as_slice(), original.as_slice());
+
+        // Tearing down the upper device must leave the existing dm device alone.
+        disable_verity(ret, name).unwrap();
+        assert_eq!(fs::read(&lower.mapper_device).unwrap().as_slice(), original.as_slice());
+    }
+
+    // test with custom roothash
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn correct_custom_roothash() {
+        let apk = include_bytes!("../testdata/test.apk");
+        let idsig = include_bytes!("../testdata/test.apk.idsig");
+        let roothash = V4Signature::from_idsig_path("testdata/test.apk.idsig")
+            .unwrap()
+            .hashing_info
+            .raw_root_hash;
+        run_test_with_hash(
+            apk.as_ref(),
+            idsig.as_ref(),
+            "correct_custom_roothash",
+            Some(&roothash),
+            None,
+            |ctx| {
+                let verity = fs::read(&ctx.result.mapper_device).unwrap();
+                let original = fs::read(&ctx.result.data_device).unwrap();
+                assert_eq!(verity.len(), original.len()); // fail fast
+                assert_eq!(verity.as_slice(), original.as_slice());
+            },
+        );
+    }
+
+    // test with custom salt, the same as the one in the idsig file
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn correct_custom_salt() {
+        let apk = include_bytes!("../testdata/test.apk");
+        let idsig = include_bytes!("../testdata/test.apk.idsig");
+        let salt =
+            V4Signature::from_idsig_path("testdata/test.apk.idsig").unwrap().hashing_info.salt;
+        run_test_with_hash(
+            apk.as_ref(),
+            idsig.as_ref(),
+            "correct_custom_salt",
+            None,
+            Some(&salt),
+            |ctx| {
+                let verity = fs::read(&ctx.result.mapper_device).unwrap();
+                let original = fs::read(&ctx.result.data_device).unwrap();
+                assert_eq!(verity.len(), original.len()); // fail fast
+                assert_eq!(verity.as_slice(), original.as_slice());
+            },
+        );
+    }
+
+    // The merkle tree doesn't match a salt other than the one it was built with, so reading fails.
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn incorrect_custom_salt() {
+        let apk = include_bytes!("../testdata/test.apk");
+        let idsig = include_bytes!("../testdata/test.apk.idsig");
+        let sig = V4Signature::from_idsig_path("testdata/test.apk.idsig").unwrap();
+        let salt = vec![0x5a; MAX_SALT_SIZE];
+        assert_ne!(sig.hashing_info.salt.as_ref(), salt.as_slice());
+        run_test_with_hash(
+            apk.as_ref(),
+            idsig.as_ref(),
+            "incorrect_custom_salt",
+            None,
+            Some(&salt),
+            |ctx| {
+                fs::read(&ctx.result.mapper_device).expect_err("Should fail");
+            },
+        );
+    }
+
+    #[rdroidtest]
+    fn salt_is_parsed_from_hex_up_to_max_size() {
+        assert_eq!(vec![0xab; MAX_SALT_SIZE], parse_salt(&"ab".repeat(MAX_SALT_SIZE)).unwrap());
+        assert_eq!(Vec::<u8>::new(), parse_salt("").unwrap());
+        parse_salt(&"ab".repeat(MAX_SALT_SIZE + 1)).expect_err("Should fail");
+        parse_salt("not hex").expect_err("Should fail");
+    }
+
+    // Mounting fails because the test APK doesn't contain an ext4 filesystem. The block device must
+    // be removed again, so that the same name can be reused.
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn failed_mount_is_rolled_back() {
+        let apk = include_bytes!("../testdata/test.apk");
+        let idsig = include_bytes!("../testdata/test.apk.idsig");
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let (apk_path, idsig_path) = prepare_inputs(test_dir.path(), apk, idsig);
+        let mount_point = test_dir.path().join("mnt");
+
+        let name = "failed_mount";
+        enable_verity_and_mount(
+            &apk_path,
+            ApkRange::default(),
+            &idsig_path,
+            None,
+            name,
+            None,
+            None,
+            &mount_point,
+            "ext4",
+        )
+        .expect_err("Should fail");
+
+        let ret = enable_verity(&apk_path, ApkRange::default(), &idsig_path, name, None).unwrap();
+        assert!(ret.mount_point.is_none());
+        disable_verity(ret, name).unwrap();
+    }
+
+    // When the APK is given as a block device, it belongs to the caller, so rolling back a failed
+    // mount must leave it attached.
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn failed_mount_leaves_block_device_of_caller() {
+        let apk = include_bytes!("../testdata/test.apk");
+        let idsig = include_bytes!("../testdata/test.apk.idsig");
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let (apk_path, idsig_path) = prepare_inputs(test_dir.path(), apk, idsig);
+        let apk_size = fs::metadata(&apk_path).unwrap().len();
+        let apk_loop_device = scopeguard::guard(
+            loopdevice::attach(
+                &apk_path, 0, apk_size, /* direct_io */ true, /* writable */ false,
+            )
+            .unwrap(),
+            |dev| loopdevice::detach(dev).unwrap(),
+        );
+        let mount_point = test_dir.path().join("mnt");
+
+        let name = "failed_mount_of_block_device";
+        enable_verity_and_mount(
+            apk_loop_device.deref(),
+            ApkRange::default(),
+            &idsig_path,
+            None,
+            name,
+            None,
+            None,
+            &mount_point,
+            "ext4",
+        )
+        .expect_err("Should fail");
+
+        assert!(!Path::new("/dev/mapper").join(name).exists());
+        assert_eq!(fs::read(apk_loop_device.deref()).unwrap(), fs::read(&apk_path).unwrap());
+    }
+
+    // Offset of the central directory offset field in the ZIP End of Central Directory record.
+    const EOCD_CENTRAL_DIRECTORY_OFFSET_FIELD_OFFSET: usize = 16;
+
+    // Contents of the only file in testdata/test.ext4.
+    const TEST_EXT4_FILE_CONTENTS: &[u8] = b"Hello from a verified filesystem!\n";
+
+    // Writes the ext4 filesystem of testdata/test.ext4 followed by the test APK, with the offset of
+    // its central directory moved along, so that the result is both an APK, as far as its digest is
+    // concerned, and a mountable filesystem. Returns its path and the path of an idsig file for it.
+    fn prepare_mountable_inputs(test_dir: &Path) -> (PathBuf, PathBuf) {
+        let fs_image = include_bytes!("../testdata/test.ext4");
+        let mut apk = include_bytes!("../testdata/test.apk").to_vec();
+        let eocd = apk.windows(4).rposition(|w| w == b"PK\x05\x06").unwrap();
+        let field = eocd + EOCD_CENTRAL_DIRECTORY_OFFSET_FIELD_OFFSET;
+        let central_directory_offset =
+            u32::from_le_bytes(apk[field..field + 4].try_into().unwrap());
+        let central_directory_offset = central_directory_offset + fs_image.len() as u32;
+        apk[field..field + 4].copy_from_slice(&central_directory_offset.to_le_bytes());
+
+        let apk_path = test_dir.join("test_ext4.apk");
+        create_block_aligned_file(&apk_path, &[fs_image.as_slice(), apk.as_slice()].concat());
+        let mut sig = V4Signature::create(
+            &mut File::open(&apk_path).unwrap(),
+            get_current_sdk().unwrap(),
+            BLOCK_SIZE as usize,
+            &[],
+            HashAlgorithm::SHA256,
+        )
+        .unwrap();
+        let idsig_path = test_dir.join("test_ext4.apk.idsig");
+        sig.write_into(&mut File::create(&idsig_path).unwrap()).unwrap();
+        (apk_path, idsig_path)
+    }
+
+    // The files of the mounted filesystem can be read through the dm-verity device.
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn mounted_content_is_readable() {
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let (apk_path, idsig_path) = prepare_mountable_inputs(test_dir.path());
+        let mount_point = test_dir.path().join("mnt");
+
+        let name = "mounted_content";
+        let ret = enable_verity_and_mount(
+            &apk_path,
+            ApkRange::default(),
+            &idsig_path,
+            None,
+            name,
+            None,
+            None,
+            &mount_point,
+            "ext4",
+        )
+        .unwrap();
+        let ret = scopeguard::guard(ret, |ret| disable_verity(ret, name).unwrap());
+
+        assert_eq!(Some(&mount_point), ret.mount_point.as_ref());
+        assert_eq!(TEST_EXT4_FILE_CONTENTS, fs::read(mount_point.join("hello.txt")).unwrap());
+    }
+
+    // Once the filesystem is mounted, changing the contents of a file in the backing APK makes
+    // reading the file fail, rather than returning the changed contents.
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn tampering_after_mount_fails_reads() {
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let (apk_path, idsig_path) = prepare_mountable_inputs(test_dir.path());
+        let mount_point = test_dir.path().join("mnt");
+
+        let name = "tampered_after_mount";
+        let ret = enable_verity_and_mount(
+            &apk_path,
+            ApkRange::default(),
+            &idsig_path,
+            None,
+            name,
+            None,
+            None,
+            &mount_point,
+            "ext4",
+        )
+        .unwrap();
+        let _ret = scopeguard::guard(ret, |ret| disable_verity(ret, name).unwrap());
+
+        let fs_image = include_bytes!("../testdata/test.ext4");
+        let offset = fs_image
+            .windows(TEST_EXT4_FILE_CONTENTS.len())
+            .position(|w| w == TEST_EXT4_FILE_CONTENTS)
+            .unwrap();
+        let f = OpenOptions::new().read(true).write(true).open(&apk_path).unwrap();
+        f.write_at(b"Goodbye", offset as u64).unwrap();
+
+        fs::read(mount_point.join("hello.txt")).expect_err("Should fail");
+    }
+
+    // Files which are already open can be used without any path, e.g. once they are unlinked.
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn open_files() {
+        let apk = include_bytes!("../testdata/test.apk");
+        let idsig = include_bytes!("../testdata/test.apk.idsig");
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let (apk_path, idsig_path) = prepare_inputs(test_dir.path(), apk, idsig);
+        let padded_apk = fs::read(&apk_path).unwrap();
+        let apk_file = File::open(&apk_path).unwrap();
+        let mut idsig_file = File::open(&idsig_path).unwrap();
+        // The idsig file is read from its start, wherever its offset is.
+        idsig_file.seek(SeekFrom::End(0)).unwrap();
+        let (apk_size, idsig_size) =
+            (apk_file.metadata().unwrap().len(), idsig_file.metadata().unwrap().len());
+        drop(test_dir);
+
+        let name = "open_files";
+        let ret = enable_verity_fd(
+            &apk_file,
+            apk_size,
+            ApkRange::default(),
+            &idsig_file,
+            idsig_size,
+            /* hash_file */ None,
+            name,
+            None,
+            None,
+        )
+        .unwrap();
+        let ret = scopeguard::guard(ret, |ret| disable_verity(ret, name).unwrap());
+
+        let verity = fs::read(&ret.mapper_device).unwrap();
+        assert_eq!(verity.len(), padded_apk.len()); // fail fast
+        assert_eq!(verity.as_slice(), padded_apk.as_slice());
+    }
+
+    // The merkle tree can be given in a file of its own rather than in the idsig file.
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn separate_hash_file() {
+        let apk = include_bytes!("../testdata/test.apk");
+        let idsig = include_bytes!("../testdata/test.apk.idsig");
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let (apk_path, idsig_path) = prepare_inputs(test_dir.path(), apk, idsig);
+        let padded_apk = fs::read(&apk_path).unwrap();
+
+        // Move the merkle tree to its own file, and corrupt the one left in the idsig file to make
+        // sure that it isn't used.
+        let sig = V4Signature::from_idsig_path(&idsig_path).unwrap();
+        let tree_range = sig.merkle_tree_offset as usize
+            ..sig.merkle_tree_offset as usize + sig.merkle_tree_size as usize;
+        let hash_file_path = test_dir.path().join("test.apk.hash");
+        fs::write(&hash_file_path, &idsig[tree_range.clone()]).unwrap();
+        let mut corrupted_idsig = fs::read(&idsig_path).unwrap();
+        corrupted_idsig[tree_range].fill(0);
+        fs::write(&idsig_path, corrupted_idsig).unwrap();
+
+        let name = "separate_hash_file";
+        let ret = enable_verity_with_hash_file(
+            &apk_path,
+            ApkRange::default(),
+            &idsig_path,
+            Some(&hash_file_path),
+            name,
+            None,
+            None,
+        )
+        .unwrap();
+        let ret = scopeguard::guard(ret, |ret| disable_verity(ret, name).unwrap());
+
+        let verity = fs::read(&ret.mapper_device).unwrap();
+        assert_eq!(verity.len(), padded_apk.len()); // fail fast
+        assert_eq!(verity.as_slice(), padded_apk.as_slice());
+    }
+
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn hash_file_of_wrong_size() {
+        let apk = include_bytes!("../testdata/test.apk");
+        let idsig = include_bytes!("../testdata/test.apk.idsig");
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let (apk_path, idsig_path) = prepare_inputs(test_dir.path(), apk, idsig);
+        let sig = V4Signature::from_idsig_path(&idsig_path).unwrap();
+        let hash_file_path = test_dir.path().join("test.apk.hash");
+        fs::write(&hash_file_path, vec![0; sig.merkle_tree_size as usize + 1]).unwrap();
+
+        let name = "hash_file_of_wrong_size";
+        let err = enable_verity_with_hash_file(
+            &apk_path,
+            ApkRange::default(),
+            &idsig_path,
+            Some(&hash_file_path),
+            name,
+            None,
+            None,
+        )
+        .expect_err("Should fail");
+        assert!(matches!(err, VerityError::HashFileSizeMismatch { .. }), "{err:?}");
+        assert!(!Path::new("/dev/mapper").join(name).exists());
+    }
+
+    // An APK packed in a larger container can be protected on its own.
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn apk_in_container() {
+        let apk = include_bytes!("../testdata/test.apk");
+        let idsig = include_bytes!("../testdata/test.apk.idsig");
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let (apk_path, idsig_path) = prepare_inputs(test_dir.path(), apk, idsig);
+        let padded_apk = fs::read(&apk_path).unwrap();
+
+        // Surround the APK with garbage.
+        let container_path = test_dir.path().join("container");
+        let mut container = vec![0xff; 2 * BLOCK_SIZE as usize];
+        container.extend_from_slice(&padded_apk);
+        container.extend_from_slice(&[0xff; BLOCK_SIZE as usize]);
+        fs::write(&container_path, container).unwrap();
+
+        let apk_range = ApkRange { offset: 2 * BLOCK_SIZE, size: Some(padded_apk.len() as u64) };
+        let name = "apk_in_container";
+        let ret = enable_verity(&container_path, apk_range, &idsig_path, name, None).unwrap();
+        let ret = scopeguard::guard(ret, |ret| disable_verity(ret, name).unwrap());
+
+        let verity = fs::read(&ret.mapper_device).unwrap();
+        assert_eq!(verity.len(), padded_apk.len()); // fail fast
+        assert_eq!(verity.as_slice(), padded_apk.as_slice());
+    }
+
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn misplaced_apk_in_container() {
+        let apk = include_bytes!("../testdata/test.apk");
+        let idsig = include_bytes!("../testdata/test.apk.idsig");
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let (apk_path, idsig_path) = prepare_inputs(test_dir.path(), apk, idsig);
+        let apk_size = fs::metadata(&apk_path).unwrap().len();
+
+        let misaligned = ApkRange { offset: 100, size: Some(BLOCK_SIZE) };
+        let err = enable_verity(&apk_path, misaligned, &idsig_path, "misaligned", None)
+            .expect_err("Should fail");
+        assert!(matches!(err, VerityError::NotBlockAligned { offset: 100, .. }), "{err:?}");
+
+        let out_of_file = ApkRange { offset: BLOCK_SIZE, size: Some(apk_size) };
+        let err = enable_verity(&apk_path, out_of_file, &idsig_path, "out_of_file", None)
+            .expect_err("Should fail");
+        assert!(matches!(err, VerityError::OutOfFile { what: "APK", .. }), "{err:?}");
+    }
+
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn unaligned_apk_size() {
+        let apk = include_bytes!("../testdata/test.apk");
+        let idsig = include_bytes!("../testdata/test.apk.idsig");
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let (apk_path, idsig_path) = prepare_inputs(test_dir.path(), apk, idsig);
+
+        let unaligned = ApkRange { offset: 0, size: Some(BLOCK_SIZE + 1) };
+        let err = enable_verity(&apk_path, unaligned, &idsig_path, "unaligned", None)
+            .expect_err("Should fail");
+        assert!(matches!(err, VerityError::NotBlockAligned { size, .. } if size == BLOCK_SIZE + 1));
+    }
+
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn invalid_idsig() {
+        let apk = include_bytes!("../testdata/test.apk");
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let (apk_path, idsig_path) = prepare_inputs(test_dir.path(), apk, &[0xff; 64]);
+
+        let name = "invalid_idsig";
+        let err = enable_verity(&apk_path, ApkRange::default(), &idsig_path, name, None)
+            .expect_err("Should fail");
+        assert!(matches!(&err, VerityError::InvalidIdsig(path, _) if path == &idsig_path));
+        assert!(!Path::new("/dev/mapper").join(name).exists());
+    }
+
+    // A compressed APK is only decompressed up to the size its merkle tree can cover.
+    #[rdroidtest]
+    fn decompressed_apk_size_is_bounded_by_idsig() {
+        let apk = include_bytes!("../testdata/test.apk");
+        let idsig_path = Path::new("testdata/test.apk.idsig");
+        let max_size = max_apk_size(Some(idsig_path)).unwrap();
+        assert!(max_size >= apk.len() as u64, "{max_size}");
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let compressed_apk_path = test_dir.path().join("test.apk.zst");
+        fs::write(&compressed_apk_path, zstd::encode_all(apk.as_slice(), 0).unwrap()).unwrap();
+        let decompressed = decompress_apk(&compressed_apk_path, max_size).unwrap();
+        let mut contents = vec![0; apk.len()];
+        decompressed.read_exact_at(&mut contents, 0).unwrap();
+        assert_eq!(apk.as_slice(), contents.as_slice());
+
+        // A file decompressing to more than the merkle tree covers is rejected.
+        let oversized = vec![0; max_size as usize + 1];
+        fs::write(&compressed_apk_path, zstd::encode_all(oversized.as_slice(), 0).unwrap())
+            .unwrap();
+        assert!(decompress_apk(&compressed_apk_path, max_size).is_err());
+        assert!(decompress_apk(&compressed_apk_path, max_size + 1).is_ok());
+    }
+
+    // A zstd-compressed APK gives the same dm-verity device as the uncompressed one.
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn compressed_apk() {
+        let apk = include_bytes!("../testdata/test.apk");
+        let idsig = include_bytes!("../testdata/test.apk.idsig");
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let (apk_path, idsig_path) = prepare_inputs(test_dir.path(), apk, idsig);
+        let compressed_apk_path = test_dir.path().join("test.apk.zst");
+        fs::write(&compressed_apk_path, zstd::encode_all(apk.as_slice(), 0).unwrap()).unwrap();
+
+        let name = "uncompressed";
+        let uncompressed =
+            enable_verity(&apk_path, ApkRange::default(), &idsig_path, name, None).unwrap();
+        let uncompressed =
+            scopeguard::guard(uncompressed, |ret| disable_verity(ret, name).unwrap());
+
+        let name = "compressed";
+        let args = ApkArgs {
+            apk: &compressed_apk_path,
+            apk_range: ApkRange::default(),
+            idsig: Some(&idsig_path),
+            hash_file: None,
+            name: name.to_owned(),
+            roothash: None,
+            salt: None,
+            mount_point: None,
+            overlay: None,
+        };
+        let compressed = enable_apk(&args, "ext4").unwrap();
+        let compressed = scopeguard::guard(compressed, |ret| disable_verity(ret, name).unwrap());
+
+        let expected = fs::read(&uncompressed.mapper_device).unwrap();
+        let verity = fs::read(&compressed.mapper_device).unwrap();
+        assert_eq!(verity.len(), expected.len()); // fail fast
+        assert_eq!(verity.as_slice(), expected.as_slice());
+    }
+
+    // When one APK of a batch fails, the devices of the APKs before it are removed again.
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn failed_batch_is_rolled_back() {
+        let apk = include_bytes!("../testdata/test.apk");
+        let idsig = include_bytes!("../testdata/test.apk.idsig");
+
+        let test_dirs: Vec<_> = (0..3).map(|_| tempfile::TempDir::new().unwrap()).collect();
+        let inputs: Vec<_> =
+            test_dirs.iter().map(|dir| prepare_inputs(dir.path(), apk, idsig)).collect();
+        let missing_apk = test_dirs[0].path().join("missing.apk");
+
+        let names = ["batch_0", "batch_1", "batch_2", "batch_3"];
+        let apk_args = |apk, idsig, name: &str| ApkArgs {
+            apk,
+            apk_range: ApkRange::default(),
+            idsig: Some(idsig),
+            hash_file: None,
+            name: name.to_owned(),
+            roothash: None,
+            salt: None,
+            mount_point: None,
+            overlay: None,
+        };
+        let apks = [
+            apk_args(&inputs[0].0, &inputs[0].1, names[0]),
+            apk_args(&inputs[1].0, &inputs[1].1, names[1]),
+            apk_args(&missing_apk, &inputs[2].1, names[2]),
+            apk_args(&inputs[2].0, &inputs[2].1, names[3]),
+        ];
+
+        enable_all(&apks, "ext4").expect_err("Should fail");
+
+        for name in names {
+            assert!(!Path::new("/dev/mapper").join(name).exists(), "{name} was left behind");
+        }
+    }
+
+    // A --mount-at for a block device that isn't set up is an error rather than being ignored.
+    #[rdroidtest]
+    fn mount_at_unknown_name_is_rejected() {
+        let command_line =
+            ["apkdmverity", "--extra-apk", "a.apk", "a.idsig", "none", "--mount-at", "extra-apk-1"];
+        let command_line = command_line.into_iter().chain(["/mnt/extra-apk-1"]);
+        let matches = clap_command().try_get_matches_from(command_line).unwrap();
+        assert!(get_apk_args(&matches).is_err());
+    }
+
+    // Extra APKs can be given without any --apk.
+    #[rdroidtest]
+    fn extra_apks_alone() {
+        let command_line =
+            ["apkdmverity", "--extra-apk", "a.apk", "a.idsig", "none", "--extra-apk", "b.apk"];
+        let command_line = command_line.into_iter().chain(["b.idsig", "none"]);
+        let matches = clap_command().try_get_matches_from(command_line).unwrap();
+        let apks = get_apk_args(&matches).unwrap();
+
+        let names: Vec<_> = apks.iter().map(|args| args.name.as_str()).collect();
+        assert_eq!(vec!["extra-apk-0", "extra-apk-1"], names);
+        assert_eq!(Path::new("b.apk"), apks[1].apk);
+    }
+
+    // The names of the block devices of the extra APKs can't be taken by an --apk.
+    #[rdroidtest]
+    fn apk_name_of_extra_apk_is_rejected() {
+        let command_line = [
+            "apkdmverity",
+            "--apk",
+            "a.apk",
+            "a.idsig",
+            "extra-apk-0",
+            "none",
+            "--extra-apk",
+            "b.apk",
+            "b.idsig",
+            "none",
+        ];
+        let matches = clap_command().try_get_matches_from(command_line).unwrap();
+        assert!(get_apk_args(&matches).is_err());
+    }
+
+    // Extra APKs are set up along with the main APK, under names following their order.
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn main_and_extra_apks() {
+        let apk = include_bytes!("../testdata/test.apk");
+        let idsig = include_bytes!("../testdata/test.apk.idsig");
+
+        let test_dirs: Vec<_> = (0..3).map(|_| tempfile::TempDir::new().unwrap()).collect();
+        let inputs: Vec<_> =
+            test_dirs.iter().map(|dir| prepare_inputs(dir.path(), apk, idsig)).collect();
+        let path = |path: &PathBuf| path.to_str().unwrap().to_owned();
+        let mut command_line = vec!["apkdmverity".to_owned(), "--apk".to_owned()];
+        command_line.extend([path(&inputs[0].0), path(&inputs[0].1), "main".into(), "none".into()]);
+        for (apk_path, idsig_path) in &inputs[1..] {
+            command_line.extend(["--extra-apk".into(), path(apk_path), path(idsig_path)]);
+            command_line.push("none".into());
+        }
+        let matches = clap_command().try_get_matches_from(command_line).unwrap();
+        let apks = get_apk_args(&matches).unwrap();
+
+        let names: Vec<_> = apks.iter().map(|args| args.name.as_str()).collect();
+        assert_eq!(vec!["main", "extra-apk-0", "extra-apk-1"], names);
+
+        let results = enable_all(&apks, "ext4").unwrap();
+        let results = scopeguard::guard(results, |results| {
+            for (ret, args) in results.into_iter().zip(&apks) {
+                disable_verity(ret, &args.name).unwrap();
+            }
+        });
+        assert_eq!(3, results.len());
+        for (ret, (apk_path, _)) in results.iter().zip(&inputs) {
+            assert_eq!(fs::read(apk_path).unwrap(), fs::read(&ret.mapper_device).unwrap());
+        }
+    }
+
+    // Writes through the overlay end up in the scratch file, not in the APK.
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn writes_go_to_overlay() {
+        let apk = include_bytes!("../testdata/test.apk");
+        let idsig = include_bytes!("../testdata/test.apk.idsig");
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let (apk_path, idsig_path) = prepare_inputs(test_dir.path(), apk, idsig);
+        let scratch_path = test_dir.path().join("scratch");
+        File::create(&scratch_path).unwrap().set_len(1024 * 1024).unwrap();
+
+        let name = "overlay";
+        let mut ret =
+            enable_verity(&apk_path, ApkRange::default(), &idsig_path, name, None).unwrap();
+        enable_overlay(&mut ret, name, &scratch_path).unwrap();
+        let ret = scopeguard::guard(ret, |ret| disable_verity(ret, name).unwrap());
+        let original = fs::read(&apk_path).unwrap();
+
+        let overlay_device = &ret.overlay.as_ref().unwrap().mapper_device;
+        let data = [0xaa; BLOCK_SIZE as usize];
+        let f = OpenOptions::new().read(true).write(true).open(overlay_device).unwrap();
+        f.write_all_at(&data, 0).unwrap();
+        f.sync_all().unwrap();
+
+        let mut buf = vec![0; data.len()];
+        f.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(data.as_slice(), buf.as_slice());
+        assert_eq!(original, fs::read(&ret.mapper_device).unwrap());
+        assert_eq!(original, fs::read(&apk_path).unwrap());
+    }
+
+    // APK signed only using APK signature scheme V3, and thus without an idsig file.
+    const V3_ONLY_APK: &str = "v3-only-with-rsa-pkcs1-sha256-2048.apk";
+
+    // The merkle tree of an APK signed only using APK signature scheme V3 is computed on the fly,
+    // and checked against the given root hash.
+    #[cfg(target_os = "android")]
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn idsig_computed_from_v3_only_apk() {
+        let apk = fs::read(V3_ONLY_APK).unwrap();
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let apk_path = test_dir.path().join("v3-only.apk");
+        create_block_aligned_file(&apk_path, &apk);
+        let idsig = create_idsig_from_apk(&apk_path, ApkRange::default()).unwrap();
+        let roothash =
+            V4Signature::from_idsig_path(fd_path(&idsig)).unwrap().hashing_info.raw_root_hash;
+
+        let name = "v3_only";
+        let args = ApkArgs {
+            apk: &apk_path,
+            apk_range: ApkRange::default(),
+            idsig: None,
+            hash_file: None,
+            name: name.to_owned(),
+            roothash: Some(roothash.to_vec()),
+            salt: None,
+            mount_point: None,
+            overlay: None,
+        };
+        let ret = enable_apk(&args, "ext4").unwrap();
+        let ret = scopeguard::guard(ret, |ret| disable_verity(ret, name).unwrap());
+
+        let verity = fs::read(&ret.mapper_device).unwrap();
+        let original = fs::read(&apk_path).unwrap();
+        assert_eq!(verity.len(), original.len()); // fail fast
+        assert_eq!(verity.as_slice(), original.as_slice());
+    }
+
+    // Nothing vouches for a merkle tree computed from the APK, so a root hash is required.
+    #[rdroidtest]
+    fn computed_idsig_needs_root_hash() {
+        let apk = fs::read(V3_ONLY_APK).unwrap();
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let apk_path = test_dir.path().join("v3-only.apk");
+        create_block_aligned_file(&apk_path, &apk);
+
+        let name = "computed_idsig_needs_root_hash";
+        let args = ApkArgs {
+            apk: &apk_path,
+            apk_range: ApkRange::default(),
+            idsig: None,
+            hash_file: None,
+            name: name.to_owned(),
+            roothash: None,
+            salt: None,
+            mount_point: None,
+            overlay: None,
+        };
+        let err = enable_apk(&args, "ext4").expect_err("Should fail");
+        assert!(err.to_string().contains("root hash"), "{err:?}");
+        assert!(!Path::new("/dev/mapper").join(name).exists());
+    }
+
+    // An idsig file of another APK is rejected before any device is created.
+    #[cfg(target_os = "android")]
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn idsig_of_another_apk() {
+        let apk = include_bytes!("../testdata/test.apk");
+        let other_apk = fs::read(V3_ONLY_APK).unwrap();
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let apk_path = test_dir.path().join("test.apk");
+        create_block_aligned_file(&apk_path, apk);
+        let other_apk_path = test_dir.path().join("v3-only.apk");
+        create_block_aligned_file(&other_apk_path, &other_apk);
+        let other_idsig = create_idsig_from_apk(&other_apk_path, ApkRange::default()).unwrap();
+
+        let name = "idsig_of_another_apk";
+        let err = enable_verity(
+            apk_path,
+            ApkRange::default(),
+            PathBuf::from(fd_path(&other_idsig)),
+            name,
+            None,
+        )
+        .expect_err("Should fail");
+        assert!(matches!(err, VerityError::IdsigMismatch { .. }), "{err:?}");
+        assert!(!Path::new("/dev/mapper").join(name).exists());
+    }
+
+    #[cfg(target_os = "android")]
+    #[rdroidtest]
+    fn logger_is_configured_for_logcat_on_android() {
+        init_logger();
+        // android_logger sets the maximum level of the `log` facade to the one it is configured
+        // with; it would stay at `Off` without a logger.
+        assert_eq!(log::LevelFilter::Info, log::max_level());
+    }
+
+    #[rdroidtest]
+    fn inspect_shows_root_hash_of_idsig() {
+        const ROOT_HASH: &str = "ce1194fdb3cb2537daf0ac8cdf4926754adcbce5abeece7945fe25d204a0df6a";
+        let idsig = Path::new("testdata/test.apk.idsig");
+
+        let text = inspect_idsig(idsig, false).unwrap();
+        assert!(text.lines().any(|line| line == format!("root_hash: {ROOT_HASH}")), "{text}");
+        assert!(text.lines().any(|line| line == "block_size: 4096"), "{text}");
+
+        let json: serde_json::Value =
+            serde_json::from_str(&inspect_idsig(idsig, true).unwrap()).unwrap();
+        assert_eq!(ROOT_HASH, json["root_hash"]);
+        assert_eq!(2, json["version"]);
     }
 
     #[rdroidtest]
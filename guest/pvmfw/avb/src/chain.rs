@@ -0,0 +1,141 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module handles the verification of a vbmeta image along with the vbmeta images chained
+//! from it by chain partition descriptors.
+
+use crate::ops::{Ops, Partitions};
+use crate::partition::PartitionName;
+use crate::verify::{copy_digest, report_unknown_descriptor, Digest};
+use crate::PvmfwVerifyError;
+use alloc::borrow::ToOwned;
+use alloc::ffi::CString;
+use alloc::string::String;
+use alloc::vec::Vec;
+use avb::{Descriptor, DescriptorError, IoError, IoResult};
+use core::ffi::CStr;
+
+/// Hash descriptor found in one of the vbmeta images of a chain.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChainedHashDescriptor {
+    /// Name of the partition hashed by the descriptor.
+    pub partition_name: String,
+    /// Name of the partition holding the vbmeta image which contains the descriptor.
+    pub vbmeta_partition_name: String,
+    /// SHA256 digest of the partition.
+    pub digest: Digest,
+}
+
+/// Hash descriptors of all the vbmeta images of a chain, in the order the images were verified.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChainedDescriptors {
+    /// Hash descriptors, at most one for each partition.
+    pub hash_descriptors: Vec<ChainedHashDescriptor>,
+}
+
+impl ChainedDescriptors {
+    /// Returns the hash descriptor of the given partition, if any.
+    pub fn find(&self, partition_name: &str) -> Option<&ChainedHashDescriptor> {
+        self.hash_descriptors.iter().find(|d| d.partition_name == partition_name)
+    }
+}
+
+/// The top-level partition, along with the partitions whose vbmeta image may be chained from it.
+struct VbmetaChain<'a> {
+    image: &'a [u8],
+    chained_partitions: Vec<(CString, &'a [u8])>,
+    trusted_public_key: &'a [u8],
+}
+
+impl<'a> Partitions<'a> for VbmetaChain<'a> {
+    fn get_partition(&self, partition_name: &CStr) -> IoResult<&'a [u8]> {
+        if partition_name == PartitionName::Kernel.as_cstr() {
+            return Ok(self.image);
+        }
+        self.chained_partitions
+            .iter()
+            .find(|(name, _)| name.as_c_str() == partition_name)
+            .map(|(_, image)| *image)
+            .ok_or(IoError::NoSuchPartition)
+    }
+
+    fn trusted_public_key(&self) -> &[u8] {
+        self.trusted_public_key
+    }
+}
+
+/// Verifies the vbmeta image in the footer of `image` against the trusted public key, then each
+/// vbmeta image chained from it against the public key embedded in the chain partition descriptor,
+/// and returns the hash descriptors of all of them.
+///
+/// As for the kernel, `image` is verified as the `boot` partition. `chained_partitions` holds the
+/// name and the image of the partitions which may be chained from it, each with its vbmeta image
+/// in its footer. libavb only follows the chain partition descriptors of the top-level vbmeta
+/// image, and rejects a chained vbmeta image with chain partition descriptors of its own, so that
+/// the chain is at most two levels deep and has no cycle.
+///
+/// The contents of the given partitions are verified against their hash descriptor, wherever it
+/// is in the chain. The contents of the other partitions are left to the caller, which can compare
+/// them with the returned digests.
+pub fn verify_vbmeta_chain(
+    image: &[u8],
+    chained_partitions: &[(&str, &[u8])],
+    trusted_public_key: &[u8],
+) -> Result<ChainedDescriptors, PvmfwVerifyError> {
+    let chained_partitions = chained_partitions
+        .iter()
+        .map(|(name, image)| {
+            let name = CString::new(*name).map_err(|_| DescriptorError::InvalidContents)?;
+            Ok((name, *image))
+        })
+        .collect::<Result<Vec<_>, DescriptorError>>()?;
+    let chain = VbmetaChain { image, chained_partitions, trusted_public_key };
+    let mut requested_partitions = Vec::from([PartitionName::Kernel.as_cstr()]);
+    requested_partitions.extend(chain.chained_partitions.iter().map(|(name, _)| name.as_c_str()));
+
+    let mut ops = Ops::new(&chain);
+    let verify_result = ops.verify_partitions(&requested_partitions)?;
+
+    let mut vbmeta_partition_names: Vec<&CStr> = Vec::new();
+    let mut chained_descriptors = ChainedDescriptors::default();
+    for vbmeta_image in verify_result.vbmeta_data() {
+        let vbmeta_partition_name = vbmeta_image.partition_name();
+        if vbmeta_partition_names.contains(&vbmeta_partition_name) {
+            return Err(PvmfwVerifyError::PartitionChainedTwice(
+                vbmeta_partition_name.to_string_lossy().into_owned(),
+            ));
+        }
+        vbmeta_partition_names.push(vbmeta_partition_name);
+
+        let descriptors = vbmeta_image.descriptors()?;
+        for descriptor in descriptors.iter().filter_map(|d| match d {
+            Descriptor::Hash(h) => Some(h),
+            _ => None,
+        }) {
+            if chained_descriptors.find(descriptor.partition_name).is_some() {
+                // A partition must be hashed by a single vbmeta image of the chain.
+                return Err(report_unknown_descriptor(
+                    &descriptors,
+                    DescriptorError::InvalidContents.into(),
+                ));
+            }
+            chained_descriptors.hash_descriptors.push(ChainedHashDescriptor {
+                partition_name: descriptor.partition_name.to_owned(),
+                vbmeta_partition_name: vbmeta_partition_name.to_string_lossy().into_owned(),
+                digest: copy_digest(descriptor)?,
+            });
+        }
+    }
+    Ok(chained_descriptors)
+}
@@ -15,6 +15,7 @@
 //! This module contains the error thrown by the payload verification API
 //! and other errors used in the library.
 
+use alloc::string::String;
 use avb::{DescriptorError, SlotVerifyError};
 use core::fmt;
 
@@ -28,6 +29,20 @@ pub enum PvmfwVerifyError {
     InvalidDescriptors(DescriptorError),
     /// Unknown vbmeta property.
     UnknownVbmetaProperty,
+    /// VBMeta has more hash descriptors than the number of partitions known by pvmfw.
+    TooManyHashDescriptors(usize),
+    /// VBMeta has no hash descriptor for the given required partition.
+    MissingHashDescriptor(&'static str),
+    /// VBMeta has invalid descriptors, likely because of a descriptor with an unknown tag, after
+    /// `index` valid descriptors.
+    UnknownDescriptor {
+        /// Index of the descriptor among all the descriptors of the VBMeta.
+        index: usize,
+        /// Tag of the descriptor.
+        tag: u64,
+    },
+    /// The vbmeta image of the partition is chained more than once.
+    PartitionChainedTwice(String),
 }
 
 impl From<SlotVerifyError<'_>> for PvmfwVerifyError {
@@ -51,6 +66,18 @@ impl fmt::Display for PvmfwVerifyError {
                 write!(f, "VBMeta has invalid descriptors. Error: {:?}", e)
             }
             Self::UnknownVbmetaProperty => write!(f, "Unknown vbmeta property"),
+            Self::TooManyHashDescriptors(count) => {
+                write!(f, "VBMeta has too many hash descriptors: {}", count)
+            }
+            Self::MissingHashDescriptor(partition_name) => {
+                write!(f, "VBMeta has no hash descriptor for partition {}", partition_name)
+            }
+            Self::UnknownDescriptor { index, tag } => {
+                write!(f, "VBMeta descriptor {} has unknown tag {:#x}", index, tag)
+            }
+            Self::PartitionChainedTwice(partition_name) => {
+                write!(f, "Partition {} is chained more than once", partition_name)
+            }
         }
     }
 }
@@ -18,10 +18,16 @@
 
 extern crate alloc;
 
+mod chain;
 mod error;
 mod ops;
 mod partition;
 mod verify;
 
+pub use chain::{verify_vbmeta_chain, ChainedDescriptors, ChainedHashDescriptor};
 pub use error::PvmfwVerifyError;
-pub use verify::{verify_payload, Capability, DebugLevel, Digest, VerifiedBootData};
+pub use partition::PartitionName;
+pub use verify::{
+    verify_initrd_against_kernel, verify_payload, verify_payload_any, Capability, DebugLevel,
+    Digest, HashDescriptors, InitrdMatch, VerifiedBootData,
+};
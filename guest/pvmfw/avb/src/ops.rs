@@ -21,6 +21,13 @@ use avb::{
 };
 use core::ffi::CStr;
 
+/// Partitions which can be verified, along with the public key their vbmeta must be signed with.
+pub(crate) trait Partitions<'a> {
+    fn get_partition(&self, partition_name: &CStr) -> IoResult<&'a [u8]>;
+
+    fn trusted_public_key(&self) -> &[u8];
+}
+
 pub(crate) struct Payload<'a> {
     kernel: &'a [u8],
     initrd: Option<&'a [u8]>,
@@ -35,8 +42,10 @@ impl<'a> Payload<'a> {
     ) -> Self {
         Self { kernel, initrd, trusted_public_key }
     }
+}
 
-    fn get_partition(&self, partition_name: &CStr) -> IoResult<&[u8]> {
+impl<'a> Partitions<'a> for Payload<'a> {
+    fn get_partition(&self, partition_name: &CStr) -> IoResult<&'a [u8]> {
         match partition_name.try_into()? {
             PartitionName::Kernel => Ok(self.kernel),
             PartitionName::InitrdNormal | PartitionName::InitrdDebug => {
@@ -44,25 +53,38 @@ impl<'a> Payload<'a> {
             }
         }
     }
+
+    fn trusted_public_key(&self) -> &[u8] {
+        self.trusted_public_key
+    }
 }
 
 /// Pvmfw customized operations used in the verification.
 pub(crate) struct Ops<'a> {
-    payload: &'a Payload<'a>,
+    partitions: &'a dyn Partitions<'a>,
 }
 
 impl<'a> Ops<'a> {
-    pub(crate) fn new(payload: &'a Payload<'a>) -> Self {
-        Self { payload }
+    pub(crate) fn new(partitions: &'a dyn Partitions<'a>) -> Self {
+        Self { partitions }
     }
 
     pub(crate) fn verify_partition(
         &mut self,
         partition_name: &CStr,
+    ) -> SlotVerifyResult<SlotVerifyData<'a>> {
+        self.verify_partitions(&[partition_name])
+    }
+
+    /// Verifies the top-level vbmeta image, read from the `boot` partition, the vbmeta images
+    /// chained from it and the contents of the given partitions.
+    pub(crate) fn verify_partitions(
+        &mut self,
+        partition_names: &[&CStr],
     ) -> SlotVerifyResult<SlotVerifyData<'a>> {
         slot_verify(
             self,
-            &[partition_name],
+            partition_names,
             None, // No partition slot suffix.
             SlotVerifyFlags::AVB_SLOT_VERIFY_FLAGS_NONE,
             HashtreeErrorMode::AVB_HASHTREE_ERROR_MODE_RESTART_AND_INVALIDATE,
@@ -77,13 +99,13 @@ impl<'a> avb::Ops<'a> for Ops<'a> {
         offset: i64,
         buffer: &mut [u8],
     ) -> IoResult<usize> {
-        let partition = self.payload.get_partition(partition)?;
+        let partition = self.partitions.get_partition(partition)?;
         copy_data_to_dst(partition, offset, buffer)?;
         Ok(buffer.len())
     }
 
     fn get_preloaded_partition(&mut self, partition: &CStr) -> IoResult<&'a [u8]> {
-        self.payload.get_partition(partition)
+        self.partitions.get_partition(partition)
     }
 
     fn validate_vbmeta_public_key(
@@ -92,7 +114,7 @@ impl<'a> avb::Ops<'a> for Ops<'a> {
         _public_key_metadata: Option<&[u8]>,
     ) -> IoResult<bool> {
         // The public key metadata is not used when we build the VBMeta.
-        Ok(self.payload.trusted_public_key == public_key)
+        Ok(self.partitions.trusted_public_key() == public_key)
     }
 
     fn read_rollback_index(&mut self, _rollback_index_location: usize) -> IoResult<u64> {
@@ -116,7 +138,7 @@ impl<'a> avb::Ops<'a> for Ops<'a> {
     }
 
     fn get_size_of_partition(&mut self, partition: &CStr) -> IoResult<u64> {
-        let partition = self.payload.get_partition(partition)?;
+        let partition = self.partitions.get_partition(partition)?;
         u64::try_from(partition.len()).map_err(|_| IoError::InvalidValueSize)
     }
 
@@ -17,30 +17,42 @@
 use avb::IoError;
 use core::ffi::CStr;
 
+/// A partition known by pvmfw.
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
-pub(crate) enum PartitionName {
-    /// The default `PartitionName` is needed to build the default `HashDescriptor`.
+pub enum PartitionName {
+    /// The kernel, in the `boot` partition. The default `PartitionName` is needed to build the
+    /// default `HashDescriptor`.
     #[default]
     Kernel,
+    /// The normal initrd, in the `initrd_normal` partition.
     InitrdNormal,
+    /// The debuggable initrd, in the `initrd_debug` partition.
     InitrdDebug,
 }
 
 impl PartitionName {
+    /// The number of partitions known by pvmfw.
+    pub(crate) const NUM_OF_KNOWN_PARTITIONS: usize = 3;
+
     const KERNEL_PARTITION_NAME: &'static [u8] = b"boot\0";
     const INITRD_NORMAL_PARTITION_NAME: &'static [u8] = b"initrd_normal\0";
     const INITRD_DEBUG_PARTITION_NAME: &'static [u8] = b"initrd_debug\0";
 
-    pub(crate) fn as_cstr(&self) -> &CStr {
+    pub(crate) fn as_cstr(&self) -> &'static CStr {
         CStr::from_bytes_with_nul(self.as_bytes()).unwrap()
     }
 
+    /// Returns the name of the partition.
+    pub fn as_str(&self) -> &'static str {
+        self.as_cstr().to_str().unwrap()
+    }
+
     fn as_non_null_terminated_bytes(&self) -> &[u8] {
         let partition_name = self.as_bytes();
         &partition_name[..partition_name.len() - 1]
     }
 
-    fn as_bytes(&self) -> &[u8] {
+    fn as_bytes(&self) -> &'static [u8] {
         match self {
             Self::Kernel => Self::KERNEL_PARTITION_NAME,
             Self::InitrdNormal => Self::INITRD_NORMAL_PARTITION_NAME,
@@ -17,11 +17,13 @@
 use crate::ops::{Ops, Payload};
 use crate::partition::PartitionName;
 use crate::PvmfwVerifyError;
+use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
 use avb::{
-    Descriptor, DescriptorError, DescriptorResult, HashDescriptor, PartitionData,
-    PropertyDescriptor, SlotVerifyError, SlotVerifyNoDataResult, VbmetaData,
+    ChainPartitionDescriptor, Descriptor, DescriptorError, DescriptorResult, HashDescriptor,
+    KernelCommandlineDescriptor, PartitionData, PropertyDescriptor, SlotVerifyError,
+    SlotVerifyNoDataResult, VbmetaData,
 };
 
 // We use this for the rollback_index field if SlotVerifyData has empty rollback_indexes
@@ -45,6 +47,20 @@ pub struct VerifiedBootData<'a> {
     pub capabilities: Vec<Capability>,
     /// Rollback index of kernel.
     pub rollback_index: u64,
+    /// Kernel command line from the kernel command line descriptors, if any, to be appended to
+    /// the bootargs of the payload.
+    pub kernel_cmdline: Option<String>,
+}
+
+/// The initrd hash descriptor of the kernel vbmeta which an initrd matches, if any.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InitrdMatch {
+    /// The initrd matches the `initrd_normal` hash descriptor.
+    Normal,
+    /// The initrd matches the `initrd_debug` hash descriptor.
+    Debug,
+    /// The initrd matches neither initrd hash descriptor.
+    Neither,
 }
 
 impl VerifiedBootData<'_> {
@@ -78,16 +94,12 @@ impl Capability {
     const SECRETKEEPER_PROTECTION: &'static [u8] = b"secretkeeper_protection";
     const SEPARATOR: u8 = b'|';
 
-    /// Returns the capabilities indicated in `descriptor`, or error if the descriptor has
-    /// unexpected contents.
-    fn get_capabilities(descriptor: &PropertyDescriptor) -> Result<Vec<Self>, PvmfwVerifyError> {
-        if descriptor.key != Self::KEY {
-            return Err(PvmfwVerifyError::UnknownVbmetaProperty);
-        }
-
+    /// Returns the capabilities indicated in `value`, or error if the value has unexpected
+    /// contents.
+    fn get_capabilities(value: &[u8]) -> Result<Vec<Self>, PvmfwVerifyError> {
         let mut res = Vec::new();
 
-        for v in descriptor.value.split(|b| *b == Self::SEPARATOR) {
+        for v in value.split(|b| *b == Self::SEPARATOR) {
             let cap = match v {
                 Self::REMOTE_ATTEST => Self::RemoteAttest,
                 Self::SECRETKEEPER_PROTECTION => Self::SecretkeeperProtection,
@@ -140,88 +152,267 @@ fn verify_loaded_partition_has_expected_length(
     }
 }
 
-/// Verifies that the vbmeta contains at most one property descriptor and it indicates the
-/// vm type is service VM.
+/// Property descriptors extracted from a vbmeta image, keyed by their key.
+struct PropertyDescriptors<'a>(Vec<&'a PropertyDescriptor<'a>>);
+
+impl<'a> PropertyDescriptors<'a> {
+    /// The keys of the properties that pvmfw understands.
+    const KNOWN_KEYS: [&'static str; 1] = [Capability::KEY];
+    /// Prefixes of the keys of the properties that only describe the build of the image, e.g.
+    /// `com.android.build.microdroid.fingerprint`, which pvmfw accepts without using them.
+    const INFORMATIONAL_KEY_PREFIXES: [&'static str; 1] = ["com.android.build."];
+
+    /// Extracts the property descriptors from all vbmeta descriptors. Multiple descriptors with
+    /// the same key is an error.
+    fn get(descriptors: &'a [Descriptor<'a>]) -> DescriptorResult<Self> {
+        let mut property_descriptors: Vec<&PropertyDescriptor> = Vec::new();
+
+        for descriptor in descriptors.iter().filter_map(|d| match d {
+            Descriptor::Property(p) => Some(p),
+            _ => None,
+        }) {
+            if property_descriptors.iter().any(|p| p.key == descriptor.key) {
+                // Duplicates of the same key is an error.
+                return Err(DescriptorError::InvalidContents);
+            }
+            property_descriptors.push(descriptor);
+        }
+        Ok(Self(property_descriptors))
+    }
+
+    /// Returns whether there is any property descriptor.
+    fn has_property_descriptor(&self) -> bool {
+        !self.0.is_empty()
+    }
+
+    /// Returns the value of the property with the given key, if any.
+    fn find_property_value(&self, key: &str) -> Option<&'a [u8]> {
+        self.0.iter().find(|p| p.key == key).map(|p| p.value)
+    }
+
+    /// Returns whether pvmfw either understands the property with the given key or can ignore it.
+    fn is_known_key(key: &str) -> bool {
+        Self::KNOWN_KEYS.contains(&key)
+            || Self::INFORMATIONAL_KEY_PREFIXES.iter().any(|prefix| key.starts_with(prefix))
+    }
+
+    /// Returns an error if any property is neither understood by pvmfw nor informational.
+    fn verify_all_known(&self) -> Result<(), PvmfwVerifyError> {
+        match self.0.iter().find(|p| !Self::is_known_key(p.key)) {
+            Some(_) => Err(PvmfwVerifyError::UnknownVbmetaProperty),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Verifies that all the property descriptors in the vbmeta are known and returns the
+/// capabilities they indicate.
 fn verify_property_and_get_capabilities(
     descriptors: &[Descriptor],
 ) -> Result<Vec<Capability>, PvmfwVerifyError> {
-    let mut iter = descriptors.iter().filter_map(|d| match d {
-        Descriptor::Property(p) => Some(p),
-        _ => None,
-    });
-
-    let descriptor = match iter.next() {
+    let property_descriptors = PropertyDescriptors::get(descriptors)?;
+    if !property_descriptors.has_property_descriptor() {
         // No property descriptors -> no capabilities.
-        None => return Ok(vec![]),
-        Some(d) => d,
-    };
-
-    // Multiple property descriptors -> error.
-    if iter.next().is_some() {
-        return Err(DescriptorError::InvalidContents.into());
+        return Ok(vec![]);
     }
+    property_descriptors.verify_all_known()?;
 
-    Capability::get_capabilities(descriptor)
+    match property_descriptors.find_property_value(Capability::KEY) {
+        Some(value) => Capability::get_capabilities(value),
+        None => Ok(vec![]),
+    }
 }
 
-/// Hash descriptors extracted from a vbmeta image.
+/// Hash descriptors extracted from a vbmeta image, in the order of the descriptors in the vbmeta.
 ///
 /// We always have a kernel hash descriptor and may have initrd normal or debug descriptors.
-struct HashDescriptors<'a> {
-    kernel: &'a HashDescriptor<'a>,
-    initrd_normal: Option<&'a HashDescriptor<'a>>,
-    initrd_debug: Option<&'a HashDescriptor<'a>>,
-}
+pub struct HashDescriptors<'a>(Vec<(PartitionName, &'a HashDescriptor<'a>)>);
 
 impl<'a> HashDescriptors<'a> {
     /// Extracts the hash descriptors from all vbmeta descriptors. Any unexpected hash descriptor
     /// is an error.
-    fn get(descriptors: &'a [Descriptor<'a>]) -> DescriptorResult<Self> {
-        let mut kernel = None;
-        let mut initrd_normal = None;
-        let mut initrd_debug = None;
+    pub fn get(descriptors: &'a [Descriptor<'a>]) -> Result<Self, PvmfwVerifyError> {
+        let count = descriptors.iter().filter(|d| matches!(d, Descriptor::Hash(_))).count();
+        if count > PartitionName::NUM_OF_KNOWN_PARTITIONS {
+            return Err(PvmfwVerifyError::TooManyHashDescriptors(count));
+        }
 
+        let mut hash_descriptors = Self(Vec::new());
         for descriptor in descriptors.iter().filter_map(|d| match d {
             Descriptor::Hash(h) => Some(h),
             _ => None,
         }) {
-            let target = match descriptor
+            let partition_name: PartitionName = descriptor
                 .partition_name
                 .as_bytes()
                 .try_into()
-                .map_err(|_| DescriptorError::InvalidContents)?
-            {
-                PartitionName::Kernel => &mut kernel,
-                PartitionName::InitrdNormal => &mut initrd_normal,
-                PartitionName::InitrdDebug => &mut initrd_debug,
-            };
-
-            if target.is_some() {
+                .map_err(|_| DescriptorError::InvalidContents)?;
+            if hash_descriptors.find(partition_name).is_some() {
                 // Duplicates of the same partition name is an error.
-                return Err(DescriptorError::InvalidContents);
+                return Err(DescriptorError::InvalidContents.into());
             }
-            target.replace(descriptor);
+            hash_descriptors.0.push((partition_name, descriptor));
         }
 
         // Kernel is required, the others are optional.
-        Ok(Self {
-            kernel: kernel.ok_or(DescriptorError::InvalidContents)?,
-            initrd_normal,
-            initrd_debug,
-        })
+        if hash_descriptors.find(PartitionName::Kernel).is_none() {
+            return Err(DescriptorError::InvalidContents.into());
+        }
+        Ok(hash_descriptors)
+    }
+
+    /// Returns the hash descriptor of the given partition, if any.
+    pub fn find(&self, partition_name: PartitionName) -> Option<&'a HashDescriptor<'a>> {
+        self.iter().find(|&(p, _)| p == partition_name).map(|(_, d)| d)
+    }
+
+    /// Returns the hash descriptor of the kernel, which `get` made sure exists.
+    fn kernel(&self) -> &'a HashDescriptor<'a> {
+        self.find(PartitionName::Kernel).unwrap()
+    }
+
+    /// Returns an iterator over all the hash descriptors, along with the partition each of them
+    /// hashes, in the order of the descriptors in the vbmeta.
+    pub fn iter(&self) -> impl Iterator<Item = (PartitionName, &'a HashDescriptor<'a>)> + '_ {
+        self.0.iter().copied()
+    }
+
+    /// Returns an iterator over the partitions with a hash descriptor, in the order of the
+    /// descriptors in the vbmeta.
+    pub fn partition_names(&self) -> impl Iterator<Item = PartitionName> + '_ {
+        self.iter().map(|(partition_name, _)| partition_name)
+    }
+
+    /// Returns an error naming the first of the given partitions without a hash descriptor, if
+    /// any.
+    fn verify_all(&self, partition_names: &[PartitionName]) -> Result<(), PvmfwVerifyError> {
+        match partition_names.iter().find(|&&p| self.find(p).is_none()) {
+            Some(missing) => Err(PvmfwVerifyError::MissingHashDescriptor(missing.as_str())),
+            None => Ok(()),
+        }
     }
 
     /// Returns an error if either initrd descriptor exists.
     fn verify_no_initrd(&self) -> Result<(), PvmfwVerifyError> {
-        match self.initrd_normal.or(self.initrd_debug) {
-            Some(_) => Err(SlotVerifyError::InvalidMetadata.into()),
-            None => Ok(()),
+        if self.partition_names().any(|partition_name| partition_name != PartitionName::Kernel) {
+            Err(SlotVerifyError::InvalidMetadata.into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Chain partition descriptors extracted from a vbmeta image.
+///
+/// Each descriptor delegates the verification of a partition to the vbmeta image stored in that
+/// partition, signed with the trusted public key embedded in the descriptor.
+struct ChainPartitionDescriptors<'a>(Vec<&'a ChainPartitionDescriptor<'a>>);
+
+impl<'a> ChainPartitionDescriptors<'a> {
+    /// Extracts the chain partition descriptors from all vbmeta descriptors. Multiple descriptors
+    /// for the same partition is an error.
+    fn get(descriptors: &'a [Descriptor<'a>]) -> DescriptorResult<Self> {
+        let mut chain_descriptors: Vec<&ChainPartitionDescriptor> = Vec::new();
+
+        for descriptor in descriptors.iter().filter_map(|d| match d {
+            Descriptor::ChainPartition(c) => Some(c),
+            _ => None,
+        }) {
+            if chain_descriptors.iter().any(|c| c.partition_name == descriptor.partition_name) {
+                // Duplicates of the same partition name is an error.
+                return Err(DescriptorError::InvalidContents);
+            }
+            chain_descriptors.push(descriptor);
+        }
+        Ok(Self(chain_descriptors))
+    }
+
+    /// Returns the chain partition descriptor of the given partition, if any.
+    fn find(&self, partition_name: &str) -> Option<&'a ChainPartitionDescriptor<'a>> {
+        self.0.iter().find(|c| c.partition_name == partition_name).copied()
+    }
+
+    /// Returns an error if any of the partitions verified by pvmfw is chained to another vbmeta
+    /// image, as those must be covered by the hash descriptors of the kernel vbmeta.
+    fn verify_no_known_partition(&self) -> DescriptorResult<()> {
+        let known_partitions =
+            [PartitionName::Kernel, PartitionName::InitrdNormal, PartitionName::InitrdDebug];
+        for partition_name in known_partitions {
+            if self.find(partition_name.as_str()).is_some() {
+                return Err(DescriptorError::InvalidContents);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Kernel command line descriptors extracted from a vbmeta image.
+struct KernelCommandlineDescriptors<'a>(Vec<&'a KernelCommandlineDescriptor<'a>>);
+
+impl<'a> KernelCommandlineDescriptors<'a> {
+    /// The command line is only used if the hashtree is not disabled.
+    const USE_ONLY_IF_HASHTREE_NOT_DISABLED: u32 = 1 << 0;
+    /// The command line is only used if the hashtree is disabled.
+    const USE_ONLY_IF_HASHTREE_DISABLED: u32 = 1 << 1;
+
+    /// Extracts the kernel command line descriptors from all vbmeta descriptors.
+    fn get(descriptors: &'a [Descriptor<'a>]) -> Self {
+        Self(
+            descriptors
+                .iter()
+                .filter_map(|d| match d {
+                    Descriptor::KernelCommandline(k) => Some(k),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+
+    /// Returns the command lines of the descriptors applicable in the given hashtree state,
+    /// joined with spaces in the order of the descriptors in the vbmeta, or `None` if there is
+    /// no applicable descriptor.
+    fn commandline(&self, hashtree_disabled: bool) -> Option<String> {
+        let excluded_flag = if hashtree_disabled {
+            Self::USE_ONLY_IF_HASHTREE_NOT_DISABLED
+        } else {
+            Self::USE_ONLY_IF_HASHTREE_DISABLED
+        };
+        let mut commandlines =
+            self.0.iter().filter(|k| k.flags.0 & excluded_flag == 0).map(|k| k.commandline);
+
+        let mut res = String::from(commandlines.next()?);
+        for commandline in commandlines {
+            res.push(' ');
+            res.push_str(commandline);
+        }
+        Some(res)
+    }
+}
+
+/// Replaces an invalid descriptors error with one reporting the index and the tag of the first
+/// vbmeta descriptor of an unknown type, if any, as it is the likely cause of the error.
+///
+/// Descriptors of an unknown type are otherwise ignored.
+pub(crate) fn report_unknown_descriptor(
+    descriptors: &[Descriptor],
+    error: PvmfwVerifyError,
+) -> PvmfwVerifyError {
+    if !matches!(error, PvmfwVerifyError::InvalidDescriptors(_)) {
+        return error;
+    }
+    for (index, descriptor) in descriptors.iter().enumerate() {
+        if let Descriptor::Unknown(data) = descriptor {
+            // The raw descriptor starts with its tag, as a big-endian u64.
+            let tag =
+                data.get(..8).and_then(|tag| tag.try_into().ok()).map_or(0, u64::from_be_bytes);
+            return PvmfwVerifyError::UnknownDescriptor { index, tag };
         }
     }
+    error
 }
 
 /// Returns a copy of the SHA256 digest in `descriptor`, or error if the sizes don't match.
-fn copy_digest(descriptor: &HashDescriptor) -> SlotVerifyNoDataResult<Digest> {
+pub(crate) fn copy_digest(descriptor: &HashDescriptor) -> SlotVerifyNoDataResult<Digest> {
     let mut digest = Digest::default();
     if descriptor.digest.len() != digest.len() {
         return Err(SlotVerifyError::InvalidMetadata);
@@ -245,6 +436,49 @@ fn verify_initrd(
     )
 }
 
+/// Returns the initrd partition whose hash descriptor `initrd` matches, trying the normal one
+/// first, or `None` if it matches neither.
+fn find_matching_initrd(ops: &mut Ops, initrd: &[u8]) -> Option<PartitionName> {
+    [PartitionName::InitrdNormal, PartitionName::InitrdDebug]
+        .into_iter()
+        .find(|&partition_name| verify_initrd(ops, partition_name, initrd).is_ok())
+}
+
+/// Verifies `initrd` on its own against the initrd hash descriptors of the vbmeta of the signed
+/// kernel, which is verified against the trusted public key, and reports which descriptor it
+/// matches.
+///
+/// Unlike `verify_payload`, this doesn't check the rest of the vbmeta, e.g. the capabilities, so
+/// that an initrd can be checked before being swapped for another one with the same kernel.
+pub fn verify_initrd_against_kernel(
+    kernel: &[u8],
+    initrd: &[u8],
+    trusted_public_key: &[u8],
+) -> Result<InitrdMatch, PvmfwVerifyError> {
+    let payload = Payload::new(kernel, Some(initrd), trusted_public_key);
+    let mut ops = Ops::new(&payload);
+    let kernel_verify_result = ops.verify_partition(PartitionName::Kernel.as_cstr())?;
+
+    let vbmeta_images = kernel_verify_result.vbmeta_data();
+    verify_only_one_vbmeta_exists(vbmeta_images)?;
+    let vbmeta_image = &vbmeta_images[0];
+    verify_vbmeta_is_from_kernel_partition(vbmeta_image)?;
+    let descriptors = vbmeta_image.descriptors()?;
+    let hash_descriptors = HashDescriptors::get(&descriptors)?;
+    ChainPartitionDescriptors::get(&descriptors)?.verify_no_known_partition()?;
+
+    match find_matching_initrd(&mut ops, initrd) {
+        Some(initrd_partition) => {
+            hash_descriptors.verify_all(&[initrd_partition])?;
+            Ok(match initrd_partition {
+                PartitionName::InitrdDebug => InitrdMatch::Debug,
+                _ => InitrdMatch::Normal,
+            })
+        }
+        None => Ok(InitrdMatch::Neither),
+    }
+}
+
 /// Verifies the payload (signed kernel + initrd) against the trusted public key.
 pub fn verify_payload<'a>(
     kernel: &[u8],
@@ -264,37 +498,349 @@ pub fn verify_payload<'a>(
     let vbmeta_image = &vbmeta_images[0];
     verify_vbmeta_is_from_kernel_partition(vbmeta_image)?;
     let descriptors = vbmeta_image.descriptors()?;
-    let hash_descriptors = HashDescriptors::get(&descriptors)?;
+    let hash_descriptors = HashDescriptors::get(&descriptors)
+        .map_err(|e| report_unknown_descriptor(&descriptors, e))?;
+    ChainPartitionDescriptors::get(&descriptors)
+        .map_err(|e| report_unknown_descriptor(&descriptors, e.into()))?
+        .verify_no_known_partition()?;
     let capabilities = verify_property_and_get_capabilities(&descriptors)?;
+    // pvmfw doesn't allow disabling the hashtree with the vbmeta flags.
+    let kernel_cmdline = KernelCommandlineDescriptors::get(&descriptors)
+        .commandline(/* hashtree_disabled= */ false);
 
     if initrd.is_none() {
         hash_descriptors.verify_no_initrd()?;
         return Ok(VerifiedBootData {
             debug_level: DebugLevel::None,
-            kernel_digest: copy_digest(hash_descriptors.kernel)?,
+            kernel_digest: copy_digest(hash_descriptors.kernel())?,
             initrd_digest: None,
             public_key: trusted_public_key,
             capabilities,
             rollback_index,
+            kernel_cmdline,
         });
     }
 
     let initrd = initrd.unwrap();
-    let (debug_level, initrd_descriptor) =
-        if verify_initrd(&mut ops, PartitionName::InitrdNormal, initrd).is_ok() {
-            (DebugLevel::None, hash_descriptors.initrd_normal)
-        } else if verify_initrd(&mut ops, PartitionName::InitrdDebug, initrd).is_ok() {
-            (DebugLevel::Full, hash_descriptors.initrd_debug)
-        } else {
-            return Err(SlotVerifyError::Verification(None).into());
-        };
-    let initrd_descriptor = initrd_descriptor.ok_or(DescriptorError::InvalidContents)?;
+    let (debug_level, initrd_partition) = match find_matching_initrd(&mut ops, initrd) {
+        Some(PartitionName::InitrdDebug) => (DebugLevel::Full, PartitionName::InitrdDebug),
+        Some(initrd_partition) => (DebugLevel::None, initrd_partition),
+        None => return Err(SlotVerifyError::Verification(None).into()),
+    };
+    hash_descriptors.verify_all(&[initrd_partition])?;
+    let initrd_descriptor = hash_descriptors.find(initrd_partition).unwrap();
     Ok(VerifiedBootData {
         debug_level,
-        kernel_digest: copy_digest(hash_descriptors.kernel)?,
+        kernel_digest: copy_digest(hash_descriptors.kernel())?,
         initrd_digest: Some(copy_digest(initrd_descriptor)?),
         public_key: trusted_public_key,
         capabilities,
         rollback_index,
+        kernel_cmdline,
     })
 }
+
+/// Verifies the payload (signed kernel + initrd) against each of the trusted public keys in turn,
+/// e.g. during a key rotation, and returns the index of the key it was signed with along with the
+/// verified data.
+///
+/// The payload is only rejected for a key if it was signed with another key. Once a key matches,
+/// any other verification failure is returned without trying the remaining keys.
+pub fn verify_payload_any<'a>(
+    kernel: &[u8],
+    initrd: Option<&[u8]>,
+    trusted_public_keys: &[&'a [u8]],
+) -> Result<(usize, VerifiedBootData<'a>), PvmfwVerifyError> {
+    for (index, trusted_public_key) in trusted_public_keys.iter().enumerate() {
+        match verify_payload(kernel, initrd, trusted_public_key) {
+            Ok(verified_boot_data) => return Ok((index, verified_boot_data)),
+            Err(PvmfwVerifyError::AvbError(SlotVerifyError::PublicKeyRejected)) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(SlotVerifyError::PublicKeyRejected.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use avb::{
+        ChainPartitionDescriptorFlags, HashDescriptorFlags, KernelCommandlineDescriptorFlags,
+    };
+
+    const TEST_PUBLIC_KEY: &[u8] = b"test_public_key";
+
+    fn hash_descriptor(partition_name: &str) -> Descriptor {
+        Descriptor::Hash(HashDescriptor {
+            image_size: 4096,
+            hash_algorithm: "sha256",
+            flags: HashDescriptorFlags(0),
+            partition_name,
+            salt: &[],
+            digest: &[0; 32],
+        })
+    }
+
+    fn kernel_commandline_descriptor(flags: u32, commandline: &str) -> Descriptor {
+        Descriptor::KernelCommandline(KernelCommandlineDescriptor {
+            flags: KernelCommandlineDescriptorFlags(flags),
+            commandline,
+        })
+    }
+
+    fn property_descriptor<'a>(key: &'a str, value: &'a [u8]) -> Descriptor<'a> {
+        Descriptor::Property(PropertyDescriptor { key, value })
+    }
+
+    fn chain_partition_descriptor(partition_name: &str) -> Descriptor {
+        Descriptor::ChainPartition(ChainPartitionDescriptor {
+            rollback_index_location: 1,
+            partition_name,
+            public_key: TEST_PUBLIC_KEY,
+            flags: ChainPartitionDescriptorFlags(0),
+        })
+    }
+
+    #[test]
+    fn unknown_descriptor_is_reported_with_its_index() {
+        // Tag 0x42, with 8 bytes following.
+        let raw_descriptor =
+            [0, 0, 0, 0, 0, 0, 0, 0x42, 0, 0, 0, 0, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 0];
+        // No kernel hash descriptor.
+        let descriptors = [
+            hash_descriptor("initrd_normal"),
+            Descriptor::Unknown(&raw_descriptor),
+            hash_descriptor("initrd_debug"),
+        ];
+
+        let error = HashDescriptors::get(&descriptors).err().unwrap();
+        assert_eq!(
+            PvmfwVerifyError::UnknownDescriptor { index: 1, tag: 0x42 },
+            report_unknown_descriptor(&descriptors, error)
+        );
+    }
+
+    #[test]
+    fn unknown_descriptor_is_ignored_on_success() {
+        let raw_descriptor =
+            [0, 0, 0, 0, 0, 0, 0, 0x42, 0, 0, 0, 0, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 0];
+        let descriptors = [hash_descriptor("boot"), Descriptor::Unknown(&raw_descriptor)];
+
+        assert!(HashDescriptors::get(&descriptors).is_ok());
+    }
+
+    #[test]
+    fn other_errors_are_not_blamed_on_unknown_descriptor() {
+        let raw_descriptor =
+            [0, 0, 0, 0, 0, 0, 0, 0x42, 0, 0, 0, 0, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 0];
+        let descriptors = [Descriptor::Unknown(&raw_descriptor)];
+
+        assert_eq!(
+            PvmfwVerifyError::UnknownVbmetaProperty,
+            report_unknown_descriptor(&descriptors, PvmfwVerifyError::UnknownVbmetaProperty)
+        );
+        assert_eq!(
+            PvmfwVerifyError::InvalidDescriptors(DescriptorError::InvalidContents),
+            report_unknown_descriptor(
+                &[hash_descriptor("boot")],
+                DescriptorError::InvalidContents.into()
+            )
+        );
+    }
+
+    #[test]
+    fn chain_partition_descriptors_are_parsed() {
+        let descriptors =
+            [chain_partition_descriptor("vendor"), chain_partition_descriptor("system")];
+
+        let chain_descriptors = ChainPartitionDescriptors::get(&descriptors).unwrap();
+
+        let vendor = chain_descriptors.find("vendor").unwrap();
+        assert_eq!("vendor", vendor.partition_name);
+        assert_eq!(TEST_PUBLIC_KEY, vendor.public_key);
+        assert!(chain_descriptors.find("system").is_some());
+        assert!(chain_descriptors.find("odm").is_none());
+        assert_eq!(Ok(()), chain_descriptors.verify_no_known_partition());
+    }
+
+    #[test]
+    fn duplicated_chain_partition_descriptors_are_rejected() {
+        let descriptors =
+            [chain_partition_descriptor("vendor"), chain_partition_descriptor("vendor")];
+
+        assert!(matches!(
+            ChainPartitionDescriptors::get(&descriptors),
+            Err(DescriptorError::InvalidContents)
+        ));
+    }
+
+    #[test]
+    fn chained_kernel_partition_is_rejected() {
+        let descriptors = [chain_partition_descriptor("boot")];
+
+        let chain_descriptors = ChainPartitionDescriptors::get(&descriptors).unwrap();
+
+        assert_eq!(
+            Err(DescriptorError::InvalidContents),
+            chain_descriptors.verify_no_known_partition()
+        );
+    }
+
+    #[test]
+    fn multiple_property_descriptors_are_found_by_key() {
+        let descriptors = [
+            property_descriptor("com.android.virt.cap", b"remote_attest"),
+            property_descriptor("com.android.build.microdroid.fingerprint", b"fingerprint"),
+        ];
+
+        let property_descriptors = PropertyDescriptors::get(&descriptors).unwrap();
+
+        assert!(property_descriptors.has_property_descriptor());
+        assert_eq!(
+            Some(b"remote_attest".as_slice()),
+            property_descriptors.find_property_value("com.android.virt.cap")
+        );
+        assert_eq!(
+            Some(b"fingerprint".as_slice()),
+            property_descriptors.find_property_value("com.android.build.microdroid.fingerprint")
+        );
+        assert_eq!(None, property_descriptors.find_property_value("mock_prop"));
+    }
+
+    #[test]
+    fn informational_properties_are_accepted() {
+        let descriptors = [
+            property_descriptor("com.android.virt.cap", b"remote_attest"),
+            property_descriptor("com.android.build.microdroid.fingerprint", b"fingerprint"),
+            property_descriptor("com.android.build.microdroid.os_version", b"1"),
+        ];
+
+        assert_eq!(
+            vec![Capability::RemoteAttest],
+            verify_property_and_get_capabilities(&descriptors).unwrap()
+        );
+    }
+
+    #[test]
+    fn unknown_properties_are_rejected() {
+        for key in ["mock_prop", "com.android.virt.foo", "com.android.buildfoo"] {
+            let descriptors = [property_descriptor(key, b"foo")];
+
+            assert_eq!(
+                Err(PvmfwVerifyError::UnknownVbmetaProperty),
+                verify_property_and_get_capabilities(&descriptors),
+                "{key}"
+            );
+        }
+    }
+
+    #[test]
+    fn property_descriptors_with_duplicated_key_are_rejected() {
+        let descriptors = [
+            property_descriptor("com.android.virt.cap", b"remote_attest"),
+            property_descriptor("com.android.virt.cap", b"secretkeeper_protection"),
+        ];
+
+        assert!(matches!(
+            PropertyDescriptors::get(&descriptors),
+            Err(DescriptorError::InvalidContents)
+        ));
+    }
+
+    #[test]
+    fn no_property_descriptor_gives_no_capabilities() {
+        let descriptors = [chain_partition_descriptor("vendor")];
+
+        assert!(!PropertyDescriptors::get(&descriptors).unwrap().has_property_descriptor());
+        assert_eq!(Ok(vec![]), verify_property_and_get_capabilities(&descriptors));
+    }
+
+    #[test]
+    fn hash_descriptors_are_iterated_in_insertion_order() {
+        let descriptors = [
+            hash_descriptor("initrd_debug"),
+            chain_partition_descriptor("vendor"),
+            hash_descriptor("boot"),
+            hash_descriptor("initrd_normal"),
+        ];
+
+        let hash_descriptors = HashDescriptors::get(&descriptors).unwrap();
+
+        let partition_names: Vec<_> = hash_descriptors.partition_names().collect();
+        assert_eq!(
+            vec![PartitionName::InitrdDebug, PartitionName::Kernel, PartitionName::InitrdNormal],
+            partition_names
+        );
+        let descriptor_names: Vec<_> =
+            hash_descriptors.iter().map(|(_, d)| d.partition_name).collect();
+        assert_eq!(vec!["initrd_debug", "boot", "initrd_normal"], descriptor_names);
+        assert!(hash_descriptors.verify_no_initrd().is_err());
+    }
+
+    #[test]
+    fn missing_required_hash_descriptor_is_reported() {
+        let descriptors = [hash_descriptor("boot"), hash_descriptor("initrd_normal")];
+
+        let hash_descriptors = HashDescriptors::get(&descriptors).unwrap();
+
+        assert_eq!(
+            Ok(()),
+            hash_descriptors.verify_all(&[PartitionName::Kernel, PartitionName::InitrdNormal])
+        );
+        assert_eq!(
+            Err(PvmfwVerifyError::MissingHashDescriptor("initrd_debug")),
+            hash_descriptors.verify_all(&[PartitionName::InitrdNormal, PartitionName::InitrdDebug])
+        );
+    }
+
+    #[test]
+    fn kernel_commandline_depends_on_hashtree_state() {
+        let descriptors = [
+            kernel_commandline_descriptor(0, "console=hvc0"),
+            kernel_commandline_descriptor(
+                KernelCommandlineDescriptors::USE_ONLY_IF_HASHTREE_NOT_DISABLED,
+                "dm=verity",
+            ),
+            hash_descriptor("boot"),
+            kernel_commandline_descriptor(
+                KernelCommandlineDescriptors::USE_ONLY_IF_HASHTREE_DISABLED,
+                "root=/dev/vda",
+            ),
+        ];
+
+        let cmdline_descriptors = KernelCommandlineDescriptors::get(&descriptors);
+
+        assert_eq!(
+            Some("console=hvc0 dm=verity"),
+            cmdline_descriptors.commandline(/* hashtree_disabled= */ false).as_deref()
+        );
+        assert_eq!(
+            Some("console=hvc0 root=/dev/vda"),
+            cmdline_descriptors.commandline(/* hashtree_disabled= */ true).as_deref()
+        );
+    }
+
+    #[test]
+    fn no_applicable_kernel_commandline_gives_none() {
+        let descriptors = [kernel_commandline_descriptor(
+            KernelCommandlineDescriptors::USE_ONLY_IF_HASHTREE_DISABLED,
+            "root=/dev/vda",
+        )];
+
+        let cmdline_descriptors = KernelCommandlineDescriptors::get(&descriptors);
+
+        assert_eq!(None, cmdline_descriptors.commandline(/* hashtree_disabled= */ false));
+        assert_eq!(None, KernelCommandlineDescriptors::get(&[]).commandline(false));
+    }
+
+    #[test]
+    fn too_many_hash_descriptors_are_rejected() {
+        let count = PartitionName::NUM_OF_KNOWN_PARTITIONS + 1;
+        let descriptors: Vec<_> = (0..count).map(|_| hash_descriptor("boot")).collect();
+
+        assert!(matches!(
+            HashDescriptors::get(&descriptors),
+            Err(PvmfwVerifyError::TooManyHashDescriptors(c)) if c == count
+        ));
+    }
+}
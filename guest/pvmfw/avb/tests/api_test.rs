@@ -19,7 +19,10 @@ mod utils;
 use anyhow::{anyhow, Result};
 use avb::{DescriptorError, SlotVerifyError};
 use avb_bindgen::{AvbFooter, AvbVBMetaImageHeader};
-use pvmfw_avb::{verify_payload, Capability, DebugLevel, PvmfwVerifyError, VerifiedBootData};
+use pvmfw_avb::{
+    verify_initrd_against_kernel, verify_payload, verify_payload_any, verify_vbmeta_chain,
+    Capability, ChainedHashDescriptor, DebugLevel, InitrdMatch, PvmfwVerifyError, VerifiedBootData,
+};
 use std::{
     fs,
     mem::{offset_of, size_of},
@@ -33,14 +36,21 @@ const TEST_IMG_WITH_PROP_DESC_PATH: &str = "test_image_with_prop_desc.img";
 const TEST_IMG_WITH_SERVICE_VM_PROP_PATH: &str = "test_image_with_service_vm_prop.img";
 const TEST_IMG_WITH_UNKNOWN_VM_TYPE_PROP_PATH: &str = "test_image_with_unknown_vm_type_prop.img";
 const TEST_IMG_WITH_MULTIPLE_PROPS_PATH: &str = "test_image_with_multiple_props.img";
+const TEST_IMG_WITH_BUILD_PROPS_PATH: &str = "test_image_with_build_props.img";
+const TEST_IMG_WITH_DUPLICATED_PROP_KEY_PATH: &str = "test_image_with_duplicated_prop_key.img";
 const TEST_IMG_WITH_DUPLICATED_CAP_PATH: &str = "test_image_with_duplicated_capability.img";
 const TEST_IMG_WITH_NON_INITRD_HASHDESC_PATH: &str = "test_image_with_non_initrd_hashdesc.img";
 const TEST_IMG_WITH_INITRD_AND_NON_INITRD_DESC_PATH: &str =
     "test_image_with_initrd_and_non_initrd_desc.img";
 const TEST_IMG_WITH_MULTIPLE_CAPABILITIES: &str = "test_image_with_multiple_capabilities.img";
+const TEST_IMG_WITH_CHAIN_PARTITION_PATH: &str = "test_image_with_chain_partition.img";
+const TEST_CHAINED_IMG_PATH: &str = "test_chained_image.img";
+const TEST_CHAINED_IMG_WITH_CHAIN_PARTITION_PATH: &str =
+    "test_chained_image_with_chain_partition.img";
 const UNSIGNED_TEST_IMG_PATH: &str = "unsigned_test.img";
 
 const RANDOM_FOOTER_POS: usize = 30;
+const RANDOM_AUXILIARY_DATA_POS: usize = 10;
 
 /// This test uses the Microdroid payload compiled on the fly to check that
 /// the latest payload can be verified successfully.
@@ -62,6 +72,58 @@ fn latest_debug_payload_passes_verification() -> Result<()> {
     )
 }
 
+#[test]
+fn latest_normal_initrd_matches_normal_descriptor() -> Result<()> {
+    assert_eq!(
+        Ok(InitrdMatch::Normal),
+        verify_initrd_against_kernel(
+            &load_latest_signed_kernel()?,
+            &load_latest_initrd_normal()?,
+            &load_trusted_public_key()?,
+        )
+    );
+    Ok(())
+}
+
+#[test]
+fn latest_debug_initrd_matches_debug_descriptor() -> Result<()> {
+    assert_eq!(
+        Ok(InitrdMatch::Debug),
+        verify_initrd_against_kernel(
+            &load_latest_signed_kernel()?,
+            &load_latest_initrd_debug()?,
+            &load_trusted_public_key()?,
+        )
+    );
+    Ok(())
+}
+
+#[test]
+fn invalid_initrd_matches_neither_descriptor() -> Result<()> {
+    assert_eq!(
+        Ok(InitrdMatch::Neither),
+        verify_initrd_against_kernel(
+            &load_latest_signed_kernel()?,
+            /* initrd= */ &fs::read(UNSIGNED_TEST_IMG_PATH)?,
+            &load_trusted_public_key()?,
+        )
+    );
+    Ok(())
+}
+
+#[test]
+fn initrd_is_not_matched_against_kernel_signed_with_another_key() -> Result<()> {
+    assert_eq!(
+        Err(SlotVerifyError::PublicKeyRejected.into()),
+        verify_initrd_against_kernel(
+            &load_latest_signed_kernel()?,
+            &load_latest_initrd_normal()?,
+            &fs::read(PUBLIC_KEY_RSA2048_PATH)?,
+        )
+    );
+    Ok(())
+}
+
 #[test]
 fn payload_expecting_no_initrd_passes_verification_with_no_initrd() -> Result<()> {
     let public_key = load_trusted_public_key()?;
@@ -80,6 +142,7 @@ fn payload_expecting_no_initrd_passes_verification_with_no_initrd() -> Result<()
         public_key: &public_key,
         capabilities: vec![],
         rollback_index: 0,
+        kernel_cmdline: None,
     };
     assert_eq!(expected_boot_data, verified_boot_data);
 
@@ -124,6 +187,7 @@ fn payload_expecting_no_initrd_passes_verification_with_service_vm_prop() -> Res
         public_key: &public_key,
         capabilities: vec![Capability::RemoteAttest],
         rollback_index: 0,
+        kernel_cmdline: None,
     };
     assert_eq!(expected_boot_data, verified_boot_data);
 
@@ -142,10 +206,47 @@ fn payload_with_unknown_vm_type_fails_verification_with_no_initrd() -> Result<()
 
 #[test]
 fn payload_with_multiple_props_fails_verification_with_no_initrd() -> Result<()> {
+    // One of the properties is not known by pvmfw.
     assert_payload_verification_fails(
         &fs::read(TEST_IMG_WITH_MULTIPLE_PROPS_PATH)?,
         /* initrd= */ None,
         &load_trusted_public_key()?,
+        PvmfwVerifyError::UnknownVbmetaProperty,
+    )
+}
+
+#[test]
+fn payload_with_build_props_passes_verification_with_no_initrd() -> Result<()> {
+    // The com.android.build.* properties only describe the build, so pvmfw ignores them.
+    let public_key = load_trusted_public_key()?;
+    let verified_boot_data = verify_payload(
+        &fs::read(TEST_IMG_WITH_BUILD_PROPS_PATH)?,
+        /* initrd= */ None,
+        &public_key,
+    )
+    .map_err(|e| anyhow!("Verification failed. Error: {}", e))?;
+
+    let kernel_digest = hash(&[&hex::decode("2136")?, &fs::read(UNSIGNED_TEST_IMG_PATH)?]);
+    let expected_boot_data = VerifiedBootData {
+        debug_level: DebugLevel::None,
+        kernel_digest,
+        initrd_digest: None,
+        public_key: &public_key,
+        capabilities: vec![Capability::RemoteAttest],
+        rollback_index: 0,
+        kernel_cmdline: None,
+    };
+    assert_eq!(expected_boot_data, verified_boot_data);
+
+    Ok(())
+}
+
+#[test]
+fn payload_with_duplicated_prop_key_fails_verification_with_no_initrd() -> Result<()> {
+    assert_payload_verification_fails(
+        &fs::read(TEST_IMG_WITH_DUPLICATED_PROP_KEY_PATH)?,
+        /* initrd= */ None,
+        &load_trusted_public_key()?,
         PvmfwVerifyError::InvalidDescriptors(DescriptorError::InvalidContents),
     )
 }
@@ -210,6 +311,175 @@ fn payload_with_a_different_valid_public_key_fails_verification() -> Result<()>
     )
 }
 
+#[test]
+fn payload_passes_verification_with_any_matching_key() -> Result<()> {
+    let kernel = load_latest_signed_kernel()?;
+    let initrd = load_latest_initrd_debug()?;
+    let rsa2048_public_key = fs::read(PUBLIC_KEY_RSA2048_PATH)?;
+    let rsa4096_public_key = load_trusted_public_key()?;
+
+    let (index, verified_boot_data) =
+        verify_payload_any(&kernel, Some(&initrd), &[&rsa2048_public_key, &rsa4096_public_key])
+            .map_err(|e| anyhow!("Verification failed. Error: {}", e))?;
+
+    assert_eq!(1, index);
+    assert_eq!(DebugLevel::Full, verified_boot_data.debug_level);
+    assert_eq!(rsa4096_public_key, verified_boot_data.public_key);
+    assert_eq!(Ok(verified_boot_data), verify_payload(&kernel, Some(&initrd), &rsa4096_public_key));
+    Ok(())
+}
+
+#[test]
+fn payload_fails_verification_with_no_matching_key() -> Result<()> {
+    let rsa2048_public_key = fs::read(PUBLIC_KEY_RSA2048_PATH)?;
+
+    assert_eq!(
+        Err(SlotVerifyError::PublicKeyRejected.into()),
+        verify_payload_any(
+            &load_latest_signed_kernel()?,
+            Some(&load_latest_initrd_normal()?),
+            &[&rsa2048_public_key, /* invalid key= */ &[0u8; 512]],
+        )
+    );
+    assert_eq!(
+        Err(SlotVerifyError::PublicKeyRejected.into()),
+        verify_payload_any(
+            &load_latest_signed_kernel()?,
+            None,
+            /* trusted_public_keys= */ &[]
+        )
+    );
+    Ok(())
+}
+
+#[test]
+fn payload_failing_verification_with_matching_key_fails_with_any_keys() -> Result<()> {
+    let rsa2048_public_key = fs::read(PUBLIC_KEY_RSA2048_PATH)?;
+    let rsa4096_public_key = load_trusted_public_key()?;
+
+    assert_eq!(
+        Err(SlotVerifyError::Verification(None).into()),
+        verify_payload_any(
+            &load_latest_signed_kernel()?,
+            /* initrd= */ Some(&fs::read(UNSIGNED_TEST_IMG_PATH)?),
+            &[&rsa2048_public_key, &rsa4096_public_key],
+        )
+    );
+    Ok(())
+}
+
+#[test]
+fn vbmeta_chain_of_kernel_gives_its_hash_descriptors() -> Result<()> {
+    let public_key = load_trusted_public_key()?;
+    let kernel = load_latest_signed_kernel()?;
+    let initrd = load_latest_initrd_normal()?;
+
+    let descriptors = verify_vbmeta_chain(&kernel, &[], &public_key)
+        .map_err(|e| anyhow!("Verification failed. Error: {}", e))?;
+
+    let verified_boot_data = verify_payload(&kernel, Some(&initrd), &public_key)
+        .map_err(|e| anyhow!("Verification failed. Error: {}", e))?;
+    let boot = descriptors.find("boot").ok_or(anyhow!("No boot hash descriptor"))?;
+    assert_eq!("boot", boot.vbmeta_partition_name);
+    assert_eq!(verified_boot_data.kernel_digest, boot.digest);
+    let initrd_normal =
+        descriptors.find("initrd_normal").ok_or(anyhow!("No initrd_normal hash descriptor"))?;
+    assert_eq!(verified_boot_data.initrd_digest, Some(initrd_normal.digest));
+    assert!(descriptors.find("initrd_debug").is_some());
+    Ok(())
+}
+
+#[test]
+fn vbmeta_chain_fails_verification_with_wrong_key() -> Result<()> {
+    let kernel = load_latest_signed_kernel()?;
+
+    assert_eq!(
+        Err(SlotVerifyError::PublicKeyRejected.into()),
+        verify_vbmeta_chain(&kernel, &[], &fs::read(PUBLIC_KEY_RSA2048_PATH)?)
+    );
+    Ok(())
+}
+
+#[test]
+fn vbmeta_chain_with_chained_partition_gives_hash_descriptors_of_both_images() -> Result<()> {
+    let image = fs::read(TEST_IMG_WITH_CHAIN_PARTITION_PATH)?;
+    let chained_image = fs::read(TEST_CHAINED_IMG_PATH)?;
+    let unsigned_image = fs::read(UNSIGNED_TEST_IMG_PATH)?;
+
+    let descriptors =
+        verify_vbmeta_chain(&image, &[("chained", &chained_image)], &load_trusted_public_key()?)
+            .map_err(|e| anyhow!("Verification failed. Error: {}", e))?;
+
+    let expected_boot = ChainedHashDescriptor {
+        partition_name: "boot".to_owned(),
+        vbmeta_partition_name: "boot".to_owned(),
+        digest: hash(&[&hex::decode("5111")?, &unsigned_image]),
+    };
+    let expected_chained = ChainedHashDescriptor {
+        partition_name: "chained".to_owned(),
+        vbmeta_partition_name: "chained".to_owned(),
+        digest: hash(&[&hex::decode("5112")?, &unsigned_image]),
+    };
+    assert_eq!(2, descriptors.hash_descriptors.len());
+    assert_eq!(Some(&expected_boot), descriptors.find("boot"));
+    assert_eq!(Some(&expected_chained), descriptors.find("chained"));
+    Ok(())
+}
+
+#[test]
+fn vbmeta_chain_fails_verification_without_chained_image() -> Result<()> {
+    let image = fs::read(TEST_IMG_WITH_CHAIN_PARTITION_PATH)?;
+
+    assert_eq!(
+        Err(SlotVerifyError::Io.into()),
+        verify_vbmeta_chain(&image, &[], &load_trusted_public_key()?)
+    );
+    Ok(())
+}
+
+#[test]
+fn vbmeta_chain_fails_verification_with_chained_image_signed_with_another_key() -> Result<()> {
+    let image = fs::read(TEST_IMG_WITH_CHAIN_PARTITION_PATH)?;
+    // Signed with the trusted key, rather than with the key in the chain partition descriptor.
+    let chained_image = fs::read(TEST_IMG_WITH_ONE_HASHDESC_PATH)?;
+
+    assert_eq!(
+        Err(SlotVerifyError::PublicKeyRejected.into()),
+        verify_vbmeta_chain(&image, &[("chained", &chained_image)], &load_trusted_public_key()?)
+    );
+    Ok(())
+}
+
+#[test]
+fn vbmeta_chain_fails_verification_with_tampered_chained_image() -> Result<()> {
+    let image = fs::read(TEST_IMG_WITH_CHAIN_PARTITION_PATH)?;
+    let mut chained_image = fs::read(TEST_CHAINED_IMG_PATH)?;
+    chained_image[1] = !chained_image[1]; // Flip the bits
+
+    assert_eq!(
+        Err(SlotVerifyError::Verification(None).into()),
+        verify_vbmeta_chain(&image, &[("chained", &chained_image)], &load_trusted_public_key()?)
+    );
+    Ok(())
+}
+
+#[test]
+fn vbmeta_chain_deeper_than_two_levels_fails_verification() -> Result<()> {
+    let image = fs::read(TEST_IMG_WITH_CHAIN_PARTITION_PATH)?;
+    let chained_image = fs::read(TEST_CHAINED_IMG_WITH_CHAIN_PARTITION_PATH)?;
+    let nested_image = fs::read(TEST_CHAINED_IMG_PATH)?;
+
+    assert_eq!(
+        Err(SlotVerifyError::InvalidMetadata.into()),
+        verify_vbmeta_chain(
+            &image,
+            &[("chained", &chained_image), ("nested", &nested_image)],
+            &load_trusted_public_key()?
+        )
+    );
+    Ok(())
+}
+
 #[test]
 fn payload_with_an_invalid_initrd_fails_verification() -> Result<()> {
     assert_payload_verification_with_initrd_fails(
@@ -314,6 +584,43 @@ fn tampered_vbmeta_fails_verification() -> Result<()> {
     )
 }
 
+#[test]
+fn tampered_vbmeta_auxiliary_data_fails_verification() -> Result<()> {
+    let kernel = load_latest_signed_kernel()?;
+    let initrd = load_latest_initrd_normal()?;
+    let public_key = load_trusted_public_key()?;
+    assert!(verify_payload(&kernel, Some(&initrd), &public_key).is_ok());
+
+    let kernel = tamper_vbmeta_auxiliary_data(&kernel, RANDOM_AUXILIARY_DATA_POS)?;
+
+    assert_payload_verification_with_initrd_fails(
+        &kernel,
+        &initrd,
+        &public_key,
+        SlotVerifyError::Verification(None).into(),
+    )
+}
+
+#[test]
+fn resigned_kernel_passes_verification_with_new_key_only() -> Result<()> {
+    let kernel = load_latest_signed_kernel()?;
+    let initrd = load_latest_initrd_normal()?;
+    let key = generate_test_key(&kernel)?;
+    let new_public_key = avb_public_key(&key)?;
+
+    let kernel = resign_vbmeta(&kernel, &key)?;
+
+    let verified_boot_data = verify_payload(&kernel, Some(&initrd), &new_public_key)
+        .map_err(|e| anyhow!("Verification failed. Error: {}", e))?;
+    assert_eq!(new_public_key, verified_boot_data.public_key);
+    assert_payload_verification_with_initrd_fails(
+        &kernel,
+        &initrd,
+        &load_trusted_public_key()?,
+        SlotVerifyError::PublicKeyRejected.into(),
+    )
+}
+
 #[test]
 fn vbmeta_with_public_key_overwritten_fails_verification() -> Result<()> {
     let mut kernel = load_latest_signed_kernel()?;
@@ -399,6 +706,7 @@ fn payload_with_rollback_index() -> Result<()> {
         public_key: &public_key,
         capabilities: vec![],
         rollback_index: 5,
+        kernel_cmdline: None,
     };
     assert_eq!(expected_boot_data, verified_boot_data);
     Ok(())
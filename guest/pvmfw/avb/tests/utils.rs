@@ -16,12 +16,19 @@
 
 //! Utility functions used by API tests.
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, ensure, Result};
 use avb_bindgen::{
-    avb_footer_validate_and_byteswap, avb_vbmeta_image_header_to_host_byte_order, AvbFooter,
-    AvbVBMetaImageHeader,
+    avb_footer_validate_and_byteswap, avb_vbmeta_image_header_to_host_byte_order, AvbAlgorithmType,
+    AvbFooter, AvbVBMetaImageHeader,
+};
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    hash::MessageDigest,
+    pkey::{PKey, Private},
+    rsa::Rsa,
+    sha,
+    sign::Signer,
 };
-use openssl::sha;
 use pvmfw_avb::{
     verify_payload, Capability, DebugLevel, Digest, PvmfwVerifyError, VerifiedBootData,
 };
@@ -105,6 +112,138 @@ pub fn extract_vbmeta_header(kernel: &[u8], footer: &AvbFooter) -> Result<AvbVBM
     Ok(vbmeta_header)
 }
 
+/// The offsets of the parts of a VBMeta image within the image it is appended to.
+struct VbmetaLayout {
+    header: AvbVBMetaImageHeader,
+    start: usize,
+    authentication_data: usize,
+    auxiliary_data: usize,
+    end: usize,
+}
+
+impl VbmetaLayout {
+    fn new(signed_image: &[u8]) -> Result<Self> {
+        let footer = extract_avb_footer(signed_image)?;
+        let header = extract_vbmeta_header(signed_image, &footer)?;
+        let start: usize = footer.vbmeta_offset.try_into()?;
+        let authentication_data = start + size_of::<AvbVBMetaImageHeader>();
+        let auxiliary_data =
+            authentication_data + usize::try_from(header.authentication_data_block_size)?;
+        let end = auxiliary_data + usize::try_from(header.auxiliary_data_block_size)?;
+        ensure!(end <= signed_image.len(), "VBMeta image extends beyond the signed image");
+        Ok(Self { header, start, authentication_data, auxiliary_data, end })
+    }
+}
+
+/// Returns a copy of `signed_image` with the bits of the byte at `offset` in the auxiliary data
+/// block of its VBMeta image flipped, e.g. to corrupt a descriptor.
+///
+/// The footer only records the position and size of the VBMeta image, which are unchanged, so it
+/// stays valid and only the signature of the VBMeta image no longer matches.
+pub fn tamper_vbmeta_auxiliary_data(signed_image: &[u8], offset: usize) -> Result<Vec<u8>> {
+    let layout = VbmetaLayout::new(signed_image)?;
+    let index = layout.auxiliary_data + offset;
+    ensure!(index < layout.end, "Offset {offset} is outside of the auxiliary data block");
+
+    let mut image = signed_image.to_vec();
+    image[index] = !image[index];
+    Ok(image)
+}
+
+/// Generates an RSA key which can sign the VBMeta image of `signed_image` in place of the one
+/// that signed it, i.e. one of the same size.
+pub fn generate_test_key(signed_image: &[u8]) -> Result<Rsa<Private>> {
+    let layout = VbmetaLayout::new(signed_image)?;
+    let bits = u32::try_from(layout.header.signature_size)? * 8;
+    Ok(Rsa::generate(bits)?)
+}
+
+/// Returns the public part of `key`, encoded as an `AvbRSAPublicKeyHeader` followed by the
+/// modulus and R^2 mod modulus, as expected by libavb.
+pub fn avb_public_key(key: &Rsa<Private>) -> Result<Vec<u8>> {
+    let mut ctx = BigNumContext::new()?;
+    let n = key.n();
+    let num_bits = n.num_bits();
+    let num_bytes = usize::try_from(num_bits)? / 8;
+
+    // n0inv = -1 / n[0] (mod 2^32)
+    let mut word = BigNum::new()?;
+    word.lshift(&BigNum::from_u32(1)?, 32)?;
+    let mut n0 = BigNum::new()?;
+    n0.nnmod(n, &word, &mut ctx)?;
+    let mut n0_inverse = BigNum::new()?;
+    n0_inverse.mod_inverse(&n0, &word, &mut ctx)?;
+    let n0inv = &word - &n0_inverse;
+
+    // rr = (2^num_bits)^2 (mod n)
+    let mut r_squared = BigNum::new()?;
+    r_squared.lshift(&BigNum::from_u32(1)?, num_bits * 2)?;
+    let mut rr = BigNum::new()?;
+    rr.nnmod(&r_squared, n, &mut ctx)?;
+
+    let mut public_key = Vec::new();
+    public_key.extend_from_slice(&u32::try_from(num_bits)?.to_be_bytes());
+    public_key.extend_from_slice(&n0inv.to_vec_padded(4)?);
+    public_key.extend_from_slice(&n.to_vec_padded(num_bytes.try_into()?)?);
+    public_key.extend_from_slice(&rr.to_vec_padded(num_bytes.try_into()?)?);
+    Ok(public_key)
+}
+
+/// Returns a copy of `signed_image` whose VBMeta image embeds the public part of `key` and is
+/// signed with it, using the same algorithm as the original signature.
+///
+/// `key` must be of the same size as the original key, e.g. from `generate_test_key`, so that
+/// the VBMeta image can be re-signed in place.
+pub fn resign_vbmeta(signed_image: &[u8], key: &Rsa<Private>) -> Result<Vec<u8>> {
+    let layout = VbmetaLayout::new(signed_image)?;
+    let header = &layout.header;
+    let digest = match header.algorithm_type {
+        t if t == AvbAlgorithmType::AVB_ALGORITHM_TYPE_SHA256_RSA2048 as u32
+            || t == AvbAlgorithmType::AVB_ALGORITHM_TYPE_SHA256_RSA4096 as u32
+            || t == AvbAlgorithmType::AVB_ALGORITHM_TYPE_SHA256_RSA8192 as u32 =>
+        {
+            MessageDigest::sha256()
+        }
+        t if t == AvbAlgorithmType::AVB_ALGORITHM_TYPE_SHA512_RSA2048 as u32
+            || t == AvbAlgorithmType::AVB_ALGORITHM_TYPE_SHA512_RSA4096 as u32
+            || t == AvbAlgorithmType::AVB_ALGORITHM_TYPE_SHA512_RSA8192 as u32 =>
+        {
+            MessageDigest::sha512()
+        }
+        t => bail!("Unsupported VBMeta algorithm type: {t}"),
+    };
+    let mut image = signed_image.to_vec();
+
+    let public_key = avb_public_key(key)?;
+    ensure!(
+        public_key.len() == usize::try_from(header.public_key_size)?,
+        "The key isn't of the same size as the one which signed the image"
+    );
+    let public_key_start = layout.auxiliary_data + usize::try_from(header.public_key_offset)?;
+    image[public_key_start..(public_key_start + public_key.len())].copy_from_slice(&public_key);
+
+    // The hash and the signature cover the header and the auxiliary data block.
+    let signed_data = [
+        &image[layout.start..layout.authentication_data],
+        &image[layout.auxiliary_data..layout.end],
+    ];
+    let hash = openssl::hash::hash(digest, &signed_data.concat())?;
+    let mut signer = Signer::new(digest, &PKey::from_rsa(key.clone())?)?;
+    signed_data.iter().try_for_each(|data| signer.update(data))?;
+    let signature = signer.sign_to_vec()?;
+
+    let hash_start = layout.authentication_data + usize::try_from(header.hash_offset)?;
+    ensure!(hash.len() == usize::try_from(header.hash_size)?, "Unexpected hash size");
+    image[hash_start..(hash_start + hash.len())].copy_from_slice(&hash);
+    let signature_start = layout.authentication_data + usize::try_from(header.signature_offset)?;
+    ensure!(
+        signature.len() == usize::try_from(header.signature_size)?,
+        "Unexpected signature size"
+    );
+    image[signature_start..(signature_start + signature.len())].copy_from_slice(&signature);
+    Ok(image)
+}
+
 pub fn assert_latest_payload_verification_passes(
     initrd: &[u8],
     initrd_salt: &[u8],
@@ -128,6 +267,7 @@ pub fn assert_latest_payload_verification_passes(
         public_key: &public_key,
         capabilities,
         rollback_index: if cfg!(llpvm_changes) { 1 } else { 0 },
+        kernel_cmdline: None,
     };
     assert_eq!(expected_boot_data, verified_boot_data);
 
@@ -219,6 +219,7 @@ mod tests {
         public_key: b"public key",
         capabilities: vec![],
         rollback_index: 42,
+        kernel_cmdline: None,
     };
     const HASH: Hash = *b"sixtyfourbyteslongsentencearerarebutletsgiveitatrycantbethathard";
 
@@ -1272,6 +1272,7 @@ pub fn modify_for_next_stage(
     debug_policy: Option<&[u8]>,
     debuggable: bool,
     kaslr_seed: u64,
+    kernel_cmdline: Option<&str>,
 ) -> libfdt::Result<()> {
     if let Some(debug_policy) = debug_policy {
         let backup = Vec::from(fdt.as_slice());
@@ -1300,12 +1301,32 @@ pub fn modify_for_next_stage(
             filter_out_dangerous_bootargs(fdt, &bootargs)?;
         }
     }
+    if let Some(kernel_cmdline) = kernel_cmdline {
+        append_verified_bootargs(fdt, kernel_cmdline)?;
+    }
 
     fdt.pack()?;
 
     Ok(())
 }
 
+/// Appends the kernel command line from the verified payload to the bootargs. Unlike the bootargs
+/// from the host, it is signed with the payload, so it is never filtered.
+fn append_verified_bootargs(fdt: &mut Fdt, kernel_cmdline: &str) -> libfdt::Result<()> {
+    let mut new_bootargs = Vec::new();
+    if let Some(bootargs) = read_bootargs_from(fdt)? {
+        if !bootargs.is_empty() {
+            new_bootargs.extend_from_slice(bootargs.as_bytes());
+            new_bootargs.push(b' '); // separator
+        }
+    }
+    new_bootargs.extend_from_slice(kernel_cmdline.as_bytes());
+    new_bootargs.push(b'\0');
+
+    let mut node = fdt.chosen_mut()?.ok_or(FdtError::NotFound)?;
+    node.setprop(cstr!("bootargs"), new_bootargs.as_slice())
+}
+
 /// Patch the "google,open-dice"-compatible reserved-memory node to point to the bcc range
 fn patch_dice_node(fdt: &mut Fdt, addr: usize, size: usize) -> libfdt::Result<()> {
     // We reject DTs with missing reserved-memory node as validation should have checked that the
@@ -26,9 +26,9 @@ use diced_open_dice::Hash;
 use diced_open_dice::Hidden;
 use log::trace;
 use uuid::Uuid;
-use virtio_drivers::transport::{pci::bus::PciRoot, DeviceType, Transport};
+use virtio_drivers::transport::pci::bus::PciRoot;
 use vmbase::util::ceiling_div;
-use vmbase::virtio::pci::{PciTransportIterator, VirtIOBlk};
+use vmbase::virtio::pci::VirtIOBlkIterator;
 use vmbase::virtio::HalImpl;
 use zerocopy::AsBytes;
 use zerocopy::FromBytes;
@@ -53,8 +53,6 @@ pub enum Error {
     RecordedDiceModeMismatch,
     /// Size of the instance.img entry being read or written is not supported.
     UnsupportedEntrySize(usize),
-    /// Failed to create VirtIO Block device.
-    VirtIOBlkCreationFailed(virtio_drivers::Error),
     /// An error happened during the interaction with BoringSSL.
     BoringSslFailed(bssl_avf::Error),
 }
@@ -71,9 +69,6 @@ impl fmt::Display for Error {
             Self::RecordedCodeHashMismatch => write!(f, "Recorded code hash doesn't match"),
             Self::RecordedDiceModeMismatch => write!(f, "Recorded DICE mode doesn't match"),
             Self::UnsupportedEntrySize(sz) => write!(f, "Invalid entry size: {sz}"),
-            Self::VirtIOBlkCreationFailed(e) => {
-                write!(f, "Failed to create VirtIO Block device: {e}")
-            }
             Self::BoringSslFailed(e) => {
                 write!(f, "An error happened during the interaction with BoringSSL: {e}")
             }
@@ -175,11 +170,7 @@ impl Header {
 }
 
 fn find_instance_img(pci_root: &mut PciRoot) -> Result<Partition> {
-    for transport in PciTransportIterator::<HalImpl>::new(pci_root)
-        .filter(|t| DeviceType::Block == t.device_type())
-    {
-        let device =
-            VirtIOBlk::<HalImpl>::new(transport).map_err(Error::VirtIOBlkCreationFailed)?;
+    for device in VirtIOBlkIterator::<HalImpl>::new(pci_root) {
         match Partition::get_by_name(device, "vm-instance") {
             Ok(Some(p)) => return Ok(p),
             Ok(None) => {}
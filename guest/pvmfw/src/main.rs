@@ -227,6 +227,7 @@ fn main(
         debug_policy,
         debuggable,
         kaslr_seed,
+        verified_boot_data.kernel_cmdline.as_deref(),
     )
     .map_err(|e| {
         error!("Failed to configure device tree: {e}");
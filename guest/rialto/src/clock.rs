@@ -0,0 +1,32 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Monotonic clock of the service VM.
+
+use service_vm_requests::MonotonicClock;
+use vmbase::{isb, read_sysreg};
+
+/// The virtual counter of the Arm generic timer, which KVM starts from zero when the VM is created.
+pub struct GenericTimer;
+
+impl MonotonicClock for GenericTimer {
+    fn uptime_ms(&self) -> u64 {
+        // Make sure that the counter isn't read ahead of the preceding instructions.
+        isb!();
+        let count = read_sysreg!("cntvct_el0") as u128;
+        let frequency = read_sysreg!("cntfrq_el0") as u128;
+        // Multiplying in u128 can't overflow, as both operands fit in a u64.
+        (count * 1000).checked_div(frequency).map_or(0, |ms| ms.try_into().unwrap_or(u64::MAX))
+    }
+}
@@ -20,7 +20,7 @@ use core::hint::spin_loop;
 use core::mem;
 use core::result;
 use log::info;
-use service_vm_comm::{Response, ServiceVmRequest};
+use service_vm_comm::{read_request, write_response, Response, ServiceVmRequest};
 use tinyvec::ArrayVec;
 use virtio_drivers::{
     self,
@@ -85,11 +85,11 @@ impl<H: Hal, T: Transport> VsockStream<H, T> {
     }
 
     pub fn read_request(&mut self) -> Result<ServiceVmRequest> {
-        Ok(ciborium::from_reader(self)?)
+        Ok(read_request(self)?)
     }
 
     pub fn write_response(&mut self, response: &Response) -> Result<()> {
-        Ok(ciborium::into_writer(response, self)?)
+        Ok(write_response(self, response)?)
     }
 
     /// Shuts down the data channel.
@@ -19,13 +19,12 @@ use core::{fmt, result};
 use diced_open_dice::DiceError;
 use fdtpci::PciError;
 use libfdt::FdtError;
-use service_vm_comm::RequestProcessingError;
+use service_vm_comm::{FramingError, RequestProcessingError};
 use vmbase::{hyp::Error as HypervisorError, memory::MemoryTrackerError, virtio::pci};
 
 pub type Result<T> = result::Result<T, Error>;
 
-type CiboriumSerError = ciborium::ser::Error<virtio_drivers::Error>;
-type CiboriumDeError = ciborium::de::Error<virtio_drivers::Error>;
+type VsockFramingError = FramingError<virtio_drivers::Error>;
 
 #[derive(Debug)]
 pub enum Error {
@@ -47,10 +46,8 @@ pub enum Error {
     MissingVirtIOSocketDevice,
     /// Failed VirtIO driver operation.
     VirtIODriverOperationFailed(virtio_drivers::Error),
-    /// Failed to serialize.
-    SerializationFailed(CiboriumSerError),
-    /// Failed to deserialize.
-    DeserializationFailed(CiboriumDeError),
+    /// Failed to read or write a message from or to the host.
+    MessageFramingFailed(VsockFramingError),
     /// Failed DICE operation.
     DiceOperationFailed(DiceError),
     /// Failed to process request.
@@ -75,8 +72,7 @@ impl fmt::Display for Error {
             Self::VirtIODriverOperationFailed(e) => {
                 write!(f, "Failed VirtIO driver operation: {e}")
             }
-            Self::SerializationFailed(e) => write!(f, "Failed to serialize: {e}"),
-            Self::DeserializationFailed(e) => write!(f, "Failed to deserialize: {e}"),
+            Self::MessageFramingFailed(e) => write!(f, "Failed to exchange a message: {e}"),
             Self::DiceOperationFailed(e) => write!(f, "Failed DICE operation: {e}"),
             Self::RequestProcessingFailed(e) => write!(f, "Failed to process request: {e}"),
         }
@@ -119,15 +115,9 @@ impl From<virtio_drivers::Error> for Error {
     }
 }
 
-impl From<CiboriumSerError> for Error {
-    fn from(e: CiboriumSerError) -> Self {
-        Self::SerializationFailed(e)
-    }
-}
-
-impl From<CiboriumDeError> for Error {
-    fn from(e: CiboriumDeError) -> Self {
-        Self::DeserializationFailed(e)
+impl From<VsockFramingError> for Error {
+    fn from(e: VsockFramingError) -> Self {
+        Self::MessageFramingFailed(e)
     }
 }
 
@@ -17,6 +17,7 @@
 #![no_main]
 #![no_std]
 
+mod clock;
 mod communication;
 mod error;
 mod exceptions;
@@ -24,10 +25,12 @@ mod fdt;
 
 extern crate alloc;
 
+use crate::clock::GenericTimer;
 use crate::communication::VsockStream;
 use crate::error::{Error, Result};
 use crate::fdt::{read_dice_range_from, read_is_strict_boot, read_vendor_hashtree_root_digest};
 use alloc::boxed::Box;
+use alloc::format;
 use ciborium_io::Write;
 use core::num::NonZeroUsize;
 use core::slice;
@@ -35,9 +38,9 @@ use diced_open_dice::{bcc_handover_parse, DiceArtifacts};
 use fdtpci::PciInfo;
 use libfdt::FdtError;
 use log::{debug, error, info};
-use service_vm_comm::{ServiceVmRequest, VmType};
+use service_vm_comm::{Response, ServiceVmRequest, VmType};
 use service_vm_fake_chain::service_vm;
-use service_vm_requests::{process_request, RequestContext};
+use service_vm_requests::{process_request, EventLog, RequestContext, SessionPolicy};
 use virtio_drivers::{
     device::socket::{VsockAddr, VMADDR_CID_HOST},
     transport::{pci::bus::PciRoot, DeviceType, Transport},
@@ -163,14 +166,25 @@ unsafe fn try_main(fdt_addr: usize) -> Result<()> {
     let socket_device = find_socket_device::<HalImpl>(&mut pci_root)?;
     debug!("Found socket device: guest cid = {:?}", socket_device.guest_cid());
     let vendor_hashtree_root_digest = read_vendor_hashtree_root_digest(fdt)?;
-    let request_context =
-        RequestContext { dice_artifacts: bcc_handover.as_ref(), vendor_hashtree_root_digest };
+    let mut event_log = EventLog::new();
+    let mut session_policy = SessionPolicy::new();
 
     let mut vsock_stream = VsockStream::new(socket_device, host_addr(fdt)?)?;
     while let ServiceVmRequest::Process(req) = vsock_stream.read_request()? {
         info!("Received request: {}", req.name());
-        let response = process_request(req, &request_context);
+        event_log.record(format!("Received request: {}", req.name()));
+        let mut request_context = RequestContext {
+            dice_artifacts: bcc_handover.as_ref(),
+            vendor_hashtree_root_digest,
+            event_log: &event_log,
+            session_policy: &mut session_policy,
+            clock: &GenericTimer,
+        };
+        let response = process_request(req, &mut request_context);
         info!("Sending response: {}", response.name());
+        if let Response::Err(e) = &response {
+            event_log.record(format!("Request failed: {e}"));
+        }
         vsock_stream.write_response(&response)?;
         vsock_stream.flush()?;
     }
@@ -29,7 +29,8 @@ use hwtrust::{rkp, session::Session};
 use log::{info, warn};
 use service_vm_comm::{
     ClientVmAttestationParams, Csr, CsrPayload, EcdsaP256KeyPair, GenerateCertificateRequestParams,
-    Request, RequestProcessingError, Response, VmType,
+    Request, RequestKind, RequestProcessingError, Response, VmType, MAX_PAYLOAD_TO_SIGN_SIZE,
+    PROTOCOL_VERSION,
 };
 use service_vm_fake_chain::client_vm::{
     fake_client_vm_dice_artifacts, fake_sub_components, SubComponent,
@@ -76,10 +77,150 @@ fn process_requests_in_non_protected_vm() -> Result<()> {
 fn check_processing_requests(vm_type: VmType, vm_memory_mb: Option<i32>) -> Result<()> {
     let mut vm = start_service_vm(vm_type, vm_memory_mb)?;
 
+    check_version_request(&mut vm)?;
     check_processing_reverse_request(&mut vm)?;
+    check_self_test_request(&mut vm)?;
+    check_uptime_request(&mut vm)?;
     let key_pair = check_processing_generating_key_pair_request(&mut vm)?;
     check_processing_generating_certificate_request(&mut vm, &key_pair.maced_public_key)?;
     check_attestation_request(&mut vm, &key_pair, vm_type)?;
+    check_event_log_request(&mut vm)?;
+    check_sign_with_attestation_key_request(&mut vm)?;
+    check_generate_and_certify_batch_request(&mut vm)?;
+    check_batch_request(&mut vm)?;
+    // This must be the last check, as it restricts the requests allowed in the session.
+    check_session_policy_request(&mut vm)?;
+    Ok(())
+}
+
+fn check_event_log_request(vm: &mut ServiceVm) -> Result<()> {
+    let request = Request::GetEventLog { max_entries: 2 };
+
+    let response = vm.process_request(request)?;
+    info!("Received response: {response:?}.");
+
+    match response {
+        Response::EventLog(events) => {
+            // The most recent event is the receipt of this request.
+            assert_eq!(2, events.len());
+            assert_eq!("Received request: GetEventLog", events[1]);
+            Ok(())
+        }
+        _ => bail!("Incorrect response type: {response:?}"),
+    }
+}
+
+fn check_sign_with_attestation_key_request(vm: &mut ServiceVm) -> Result<()> {
+    let request = Request::SignWithAttestationKey { payload: b"payload to sign".to_vec() };
+
+    let response = vm.process_request(request)?;
+    info!("Received response: {response:?}.");
+
+    match response {
+        Response::AttestationSignature { signature, cert_chain } => {
+            assert_array_has_nonzero(&signature);
+            assert_array_has_nonzero(&cert_chain);
+        }
+        _ => bail!("Incorrect response type: {response:?}"),
+    }
+
+    let request =
+        Request::SignWithAttestationKey { payload: vec![0; MAX_PAYLOAD_TO_SIGN_SIZE + 1] };
+    let response = vm.process_request(request)?;
+    info!("Received response: {response:?}.");
+
+    assert_eq!(Response::Err(RequestProcessingError::PayloadTooLarge), response);
+    Ok(())
+}
+
+fn check_generate_and_certify_batch_request(vm: &mut ServiceVm) -> Result<()> {
+    let request = Request::GenerateAndCertifyBatch { count: 2, challenge: vec![0x42; 16] };
+
+    let response = vm.process_request(request)?;
+    info!("Received response: {response:?}.");
+
+    match response {
+        Response::GenerateAndCertifyBatch { key_pairs, csr } => {
+            assert_eq!(2, key_pairs.len());
+            for key_pair in key_pairs {
+                assert_array_has_nonzero(&key_pair.maced_public_key);
+                assert_array_has_nonzero(&key_pair.key_blob);
+            }
+            check_csr(csr)
+        }
+        _ => bail!("Incorrect response type: {response:?}"),
+    }
+}
+
+fn check_version_request(vm: &mut ServiceVm) -> Result<()> {
+    let response = vm.process_request(Request::GetVersion)?;
+    info!("Received response: {response:?}.");
+
+    assert_eq!(Response::Version(PROTOCOL_VERSION), response);
+    Ok(())
+}
+
+fn check_session_policy_request(vm: &mut ServiceVm) -> Result<()> {
+    let allowed = vec![RequestKind::GetVersion, RequestKind::SetSessionPolicy];
+    let response = vm.process_request(Request::SetSessionPolicy { allowed })?;
+    info!("Received response: {response:?}.");
+    assert_eq!(Response::SetSessionPolicy, response);
+
+    let response = vm.process_request(Request::GetVersion)?;
+    assert_eq!(Response::Version(PROTOCOL_VERSION), response);
+
+    let response = vm.process_request(Request::Reverse(b"abc".to_vec()))?;
+    assert_eq!(Response::Err(RequestProcessingError::OperationNotPermitted), response);
+
+    let allowed = vec![RequestKind::GetVersion, RequestKind::Reverse];
+    let response = vm.process_request(Request::SetSessionPolicy { allowed })?;
+    assert_eq!(Response::Err(RequestProcessingError::SessionPolicyLoosened), response);
+    Ok(())
+}
+
+fn check_batch_request(vm: &mut ServiceVm) -> Result<()> {
+    let messages = ["abc", "defg", "hi"];
+    let request =
+        Request::Batch(messages.iter().map(|m| Request::Reverse(m.as_bytes().to_vec())).collect());
+
+    let response = vm.process_request(request)?;
+    info!("Received response: {response:?}.");
+
+    let expected_response = Response::Batch(
+        messages
+            .iter()
+            .map(|m| Response::Reverse(m.as_bytes().iter().rev().cloned().collect()))
+            .collect(),
+    );
+    assert_eq!(expected_response, response);
+    Ok(())
+}
+
+fn check_self_test_request(vm: &mut ServiceVm) -> Result<()> {
+    let response = vm.process_request(Request::SelfTest)?;
+    info!("Received response: {response:?}.");
+
+    match response {
+        Response::SelfTest { ok, details } => {
+            assert!(ok, "Self-test failed: {details:?}");
+            Ok(())
+        }
+        _ => bail!("Incorrect response type: {response:?}"),
+    }
+}
+
+fn check_uptime_request(vm: &mut ServiceVm) -> Result<()> {
+    let mut uptimes_ms = Vec::new();
+    for _ in 0..2 {
+        let response = vm.process_request(Request::GetUptime)?;
+        info!("Received response: {response:?}.");
+        match response {
+            Response::Uptime(uptime_ms) => uptimes_ms.push(uptime_ms),
+            _ => bail!("Incorrect response type: {response:?}"),
+        }
+    }
+    assert!(uptimes_ms[0] > 0, "The VM should have been running for some time");
+    assert!(uptimes_ms[0] <= uptimes_ms[1], "Uptime went backwards: {uptimes_ms:?}");
     Ok(())
 }
 
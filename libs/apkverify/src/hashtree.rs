@@ -15,7 +15,7 @@
  */
 
 use openssl::hash::{DigestBytes, Hasher, MessageDigest};
-use std::io::{Cursor, Read, Result, Write};
+use std::io::{Cursor, Read, Result, Seek, SeekFrom, Write};
 
 /// `HashTree` is a merkle tree (and its root hash) that is compatible with fs-verity.
 pub struct HashTree {
@@ -52,6 +52,71 @@ impl HashTree {
     }
 }
 
+/// Returns the size of the merkle tree of `input_size` bytes of input, as generated by
+/// `generate_hash_tree`.
+pub fn hash_tree_size(input_size: usize, block_size: usize, algorithm: MessageDigest) -> usize {
+    calc_hash_levels(input_size, block_size, algorithm.size()).iter().map(|r| r.len()).sum()
+}
+
+/// Same as `HashTree::from`, but writes the merkle tree to `output` at `tree_offset` instead of
+/// keeping it in memory, and only returns the root hash. Upper levels of the tree are computed by
+/// reading the lower ones back from `output`, so that memory use doesn't grow with `input_size`.
+pub fn write_hash_tree<R: Read, W: Read + Write + Seek>(
+    input: &mut R,
+    input_size: usize,
+    salt: &[u8],
+    block_size: usize,
+    algorithm: MessageDigest,
+    output: &mut W,
+    tree_offset: u64,
+) -> Result<Vec<u8>> {
+    let salt = zero_pad_salt(salt, algorithm);
+    let levels = calc_hash_levels(input_size, block_size, algorithm.size());
+    let mut a_block = vec![0; block_size];
+
+    if levels.is_empty() {
+        // No hash tree is generated when the input is smaller than a block, so the root hash is
+        // the one of the input data.
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        return Ok(hash_one_block(&data, &salt, block_size, algorithm)?.as_ref().to_vec());
+    }
+
+    for (n, cur) in levels.iter().enumerate() {
+        let mut hashes_size = 0;
+        if n == 0 {
+            // Level 0: the (zero-padded) input stream is hashed into level 0
+            output.seek(SeekFrom::Start(tree_offset + cur.start as u64))?;
+            let mut remaining = input_size;
+            while remaining > 0 {
+                let data = &mut a_block[..remaining.min(block_size)];
+                input.read_exact(data)?;
+                let h = hash_one_block(data, &salt, block_size, algorithm)?;
+                output.write_all(h.as_ref())?;
+                hashes_size += h.len();
+                remaining -= data.len();
+            }
+        } else {
+            // Intermediate levels: level n - 1, read back from `output`, is hashed into level n
+            for offset in levels[n - 1].clone().step_by(block_size) {
+                output.seek(SeekFrom::Start(tree_offset + offset as u64))?;
+                output.read_exact(&mut a_block)?;
+                let h = hash_one_block(&a_block, &salt, block_size, algorithm)?;
+                output.seek(SeekFrom::Start(tree_offset + (cur.start + hashes_size) as u64))?;
+                output.write_all(h.as_ref())?;
+                hashes_size += h.len();
+            }
+        }
+        // Zero-pad the level up to its end.
+        output.write_all(&vec![0; cur.len() - hashes_size])?;
+    }
+
+    // Root hash is from the first block of the hash tree.
+    output.seek(SeekFrom::Start(tree_offset))?;
+    output.read_exact(&mut a_block)?;
+    Ok(hash_one_block(&a_block, &salt, block_size, algorithm)?.as_ref().to_vec())
+}
+
 /// Calculate hash tree for the blocks in `input`.
 ///
 /// This function implements: https://www.kernel.org/doc/html/latest/filesystems/fsverity.html#merkle-tree
@@ -215,4 +280,34 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn write_hash_tree_matches_in_memory_tree() -> Result<()> {
+        let sizes = ["512", "4K", "1M", "10000000"];
+        for size in sizes.iter() {
+            let input_name = format!("tests/data/input.{}", size);
+            let size = std::fs::metadata(&input_name)?.len() as usize;
+            let salt = vec![1, 2, 3, 4, 5, 6];
+            let algorithm = MessageDigest::sha256();
+            let ht = HashTree::from(&mut File::open(&input_name)?, size, &salt, 4096, algorithm)?;
+
+            // Leave some room before the tree, as for the header of an idsig file.
+            let tree_offset = 100;
+            let mut output = Cursor::new(vec![0xff; tree_offset as usize]);
+            let root_hash = write_hash_tree(
+                &mut File::open(&input_name)?,
+                size,
+                &salt,
+                4096,
+                algorithm,
+                &mut output,
+                tree_offset,
+            )?;
+
+            assert_eq!(hash_tree_size(size, 4096, algorithm), ht.tree.len());
+            assert_eq!(ht.tree.as_slice(), &output.get_ref()[tree_offset as usize..]);
+            assert_eq!(ht.root_hash, root_hash);
+        }
+        Ok(())
+    }
 }
@@ -186,17 +186,82 @@ impl<R: Read + Seek> V4Signature<R> {
         Ok(ret)
     }
 
+    /// Same as `create` followed by `write_into`, except that the merkle tree is written to
+    /// `output` as it is computed instead of being kept in memory, so that the memory use doesn't
+    /// depend on the size of the APK. The returned `V4Signature` is backed by `output`.
+    /// The same care must be taken with |apk| as for `create`.
+    pub fn create_streaming<W: Read + Write + Seek>(
+        apk: &mut R,
+        current_sdk: u32,
+        block_size: usize,
+        salt: &[u8],
+        algorithm: HashAlgorithm,
+        mut output: W,
+    ) -> Result<V4Signature<W>> {
+        // Determine the size of the apk
+        let start = apk.stream_position()?;
+        let size = apk.seek(SeekFrom::End(0))? as usize;
+
+        apk.seek(SeekFrom::Start(start))?;
+        let (signature_algorithm_id, apk_digest) =
+            get_apk_digest(&mut *apk, current_sdk, /*verify=*/ false)?;
+
+        let algorithm = match algorithm {
+            HashAlgorithm::SHA256 => openssl::hash::MessageDigest::sha256(),
+        };
+        let mut header = V4Signature {
+            version: Version::default(),
+            hashing_info: HashingInfo::default(),
+            signing_info: SigningInfo::default(),
+            merkle_tree_size: hash_tree_size(size, block_size, algorithm) as u32,
+            merkle_tree_offset: 0, // known once the header is written
+            data: Cursor::new(Vec::new()),
+        };
+        // The root hash is only known once the merkle tree is written, so the header is written
+        // with a placeholder of the same size first, and then again with the actual root hash.
+        header.hashing_info.raw_root_hash = vec![0; algorithm.size()].into_boxed_slice();
+        header.hashing_info.log2_blocksize = log2(block_size);
+        header.signing_info.signature_algorithm_id = signature_algorithm_id;
+        header.signing_info.apk_digest = apk_digest;
+        // TODO(jiyong): add a signature to the signing_info struct
+
+        let header_start = output.stream_position()?;
+        header.write_header(&mut output)?;
+        let merkle_tree_offset = output.stream_position()?;
+
+        apk.seek(SeekFrom::Start(start))?;
+        let root_hash = write_hash_tree(
+            apk,
+            size,
+            salt,
+            block_size,
+            algorithm,
+            &mut output,
+            merkle_tree_offset,
+        )?;
+        header.hashing_info.raw_root_hash = root_hash.into_boxed_slice();
+
+        output.seek(SeekFrom::Start(header_start))?;
+        header.write_header(&mut output)?;
+        output.seek(SeekFrom::Start(merkle_tree_offset + header.merkle_tree_size as u64))?;
+
+        Ok(V4Signature {
+            version: header.version,
+            hashing_info: header.hashing_info,
+            signing_info: header.signing_info,
+            merkle_tree_size: header.merkle_tree_size,
+            merkle_tree_offset,
+            data: output,
+        })
+    }
+
     /// Writes the data into a writer
-    pub fn write_into<W: Write + Seek>(&mut self, mut w: &mut W) -> Result<()> {
-        // Writes the header part
-        w.write_u32::<LittleEndian>(self.version.to_u32().unwrap())?;
-        self.hashing_info.write_into(&mut w)?;
-        self.signing_info.write_into(&mut w)?;
-        w.write_u32::<LittleEndian>(self.merkle_tree_size)?;
+    pub fn write_into<W: Write + Seek>(&mut self, w: &mut W) -> Result<()> {
+        self.write_header(w)?;
 
         // Writes the merkle tree
         self.data.seek(SeekFrom::Start(self.merkle_tree_offset))?;
-        let copied_size = copy(&mut self.data, &mut w)?;
+        let copied_size = copy(&mut self.data, w)?;
         if copied_size != self.merkle_tree_size as u64 {
             bail!(
                 "merkle tree is {} bytes, but only {} bytes are written.",
@@ -207,6 +272,14 @@ impl<R: Read + Seek> V4Signature<R> {
         Ok(())
     }
 
+    fn write_header<W: Write + Seek>(&self, mut w: &mut W) -> Result<()> {
+        w.write_u32::<LittleEndian>(self.version.to_u32().unwrap())?;
+        self.hashing_info.write_into(&mut w)?;
+        self.signing_info.write_into(&mut w)?;
+        w.write_u32::<LittleEndian>(self.merkle_tree_size)?;
+        Ok(())
+    }
+
     /// Returns the bytes that represents the merkle tree
     pub fn merkle_tree(&mut self) -> Result<Vec<u8>> {
         self.data.seek(SeekFrom::Start(self.merkle_tree_offset))?;
@@ -360,6 +433,40 @@ mod tests {
         assert_eq!(fs::read(&idsig_path).unwrap(), output.get_ref().as_slice());
     }
 
+    /// Create an idsig file by streaming the merkle tree into it. The output must be the same as
+    /// the one of `create` followed by `write_into`.
+    #[test]
+    fn create_streaming_matches_create() {
+        let apk = include_bytes!("../tests/data/v4-digest-v3-Sha256withEC.apk");
+        let current_sdk = 31;
+        let mut created = V4Signature::create(
+            &mut Cursor::new(apk),
+            current_sdk,
+            4096,
+            &[],
+            HashAlgorithm::SHA256,
+        )
+        .unwrap();
+        let mut expected = Cursor::new(Vec::new());
+        created.write_into(&mut expected).unwrap();
+
+        let mut streamed = V4Signature::create_streaming(
+            &mut Cursor::new(apk),
+            current_sdk,
+            4096,
+            &[],
+            HashAlgorithm::SHA256,
+            Cursor::new(Vec::new()),
+        )
+        .unwrap();
+
+        assert_eq!(expected.get_ref(), streamed.data.get_ref());
+        assert_eq!(
+            created.merkle_tree().unwrap().as_slice(),
+            streamed.merkle_tree().unwrap().as_slice()
+        );
+    }
+
     /// Create V4Signature by hashing an APK. Merkle tree and the root hash should be the same
     /// as those in the idsig file created by the signapk tool.
     #[test]
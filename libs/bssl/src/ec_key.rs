@@ -43,6 +43,7 @@ use log::error;
 use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
 const ES256_ALGO: iana::Algorithm = iana::Algorithm::ES256;
+const ES384_ALGO: iana::Algorithm = iana::Algorithm::ES384;
 const P256_CURVE: iana::EllipticCurve = iana::EllipticCurve::P_256;
 const P384_CURVE: iana::EllipticCurve = iana::EllipticCurve::P_384;
 const P256_AFFINE_COORDINATE_SIZE: usize = 32;
@@ -239,8 +240,10 @@ impl EcKey {
     /// Returns the `CoseKey` for the public key.
     pub fn cose_public_key(&self) -> Result<CoseKey> {
         let (x, y) = self.public_key_coordinates()?;
-        let curve = self.ec_group()?.coset_curve()?;
-        let key = CoseKeyBuilder::new_ec2_pub_key(curve, x, y).algorithm(ES256_ALGO).build();
+        let ec_group = self.ec_group()?;
+        let curve = ec_group.coset_curve()?;
+        let algorithm = ec_group.coset_algorithm()?;
+        let key = CoseKeyBuilder::new_ec2_pub_key(curve, x, y).algorithm(algorithm).build();
         Ok(key)
     }
 
@@ -491,6 +494,19 @@ impl<'a> EcGroup<'a> {
         }
     }
 
+    /// Returns the ECDSA algorithm whose curve is the EC group of the key.
+    fn coset_algorithm(&self) -> Result<iana::Algorithm> {
+        #[allow(non_upper_case_globals)]
+        match self.curve_nid() {
+            NID_X9_62_prime256v1 => Ok(ES256_ALGO),
+            NID_secp384r1 => Ok(ES384_ALGO),
+            name => {
+                error!("Unsupported curve NID: {}", name);
+                Err(Error::Unimplemented)
+            }
+        }
+    }
+
     fn affine_coordinate_size(&self) -> Result<usize> {
         #[allow(non_upper_case_globals)]
         match self.curve_nid() {
@@ -15,7 +15,7 @@
 //! Wrappers of the HMAC functions in BoringSSL hmac.h.
 
 use crate::digest::Digester;
-use crate::sha::SHA256_DIGEST_LENGTH;
+use crate::sha::{SHA256_DIGEST_LENGTH, SHA512_DIGEST_LENGTH};
 use crate::util::to_call_failed_error;
 use bssl_avf_error::{ApiName, Result};
 use bssl_sys::HMAC;
@@ -25,6 +25,11 @@ pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<[u8; SHA256_DIGEST_LENGTH]
     hmac::<SHA256_DIGEST_LENGTH>(key, data, Digester::sha256())
 }
 
+/// Computes the HMAC using SHA-512 for the given `data` with the given `key`.
+pub fn hmac_sha512(key: &[u8], data: &[u8]) -> Result<[u8; SHA512_DIGEST_LENGTH]> {
+    hmac::<SHA512_DIGEST_LENGTH>(key, data, Digester::sha512())
+}
+
 /// Computes the HMAC for the given `data` with the given `key` and `digester`.
 ///
 /// The output size `HASH_LEN` should correspond to the length of the hash function's
@@ -29,6 +29,7 @@ mod err;
 mod evp;
 mod hkdf;
 mod hmac;
+mod mem;
 mod rand;
 mod sha;
 mod util;
@@ -43,6 +44,7 @@ pub use digest::Digester;
 pub use ec_key::{EcKey, ZVec};
 pub use evp::{PKey, PKeyType};
 pub use hkdf::hkdf;
-pub use hmac::hmac_sha256;
+pub use hmac::{hmac_sha256, hmac_sha512};
+pub use mem::constant_time_eq;
 pub use rand::rand_bytes;
 pub use sha::sha256;
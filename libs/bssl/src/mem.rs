@@ -0,0 +1,32 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Wrappers of the memory functions in BoringSSL mem.h.
+
+use bssl_sys::CRYPTO_memcmp;
+
+/// Returns whether `a` and `b` are equal, in time that depends only on their lengths and not on
+/// their contents.
+///
+/// This must be used instead of `==` to compare secrets such as MAC tags, as a variable-time
+/// comparison leaks how many leading bytes matched, letting an attacker forge a tag byte by byte.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    // SAFETY: Only reads `a.len()` bytes from each of the provided slices, which are both at
+    // least that long.
+    let ret = unsafe { CRYPTO_memcmp(a.as_ptr() as *const _, b.as_ptr() as *const _, a.len()) };
+    ret == 0
+}
@@ -13,7 +13,7 @@
 // limitations under the License.
 
 use bssl_avf::{sha256, ApiName, Digester, EcKey, EcdsaError, Error, PKey, Result};
-use coset::CborSerializable;
+use coset::{iana, CborSerializable, CoseKey, KeyType, Label};
 use spki::{
     der::{AnyRef, Decode, Encode},
     AlgorithmIdentifier, ObjectIdentifier, SubjectPublicKeyInfoRef,
@@ -69,6 +69,46 @@ fn p384_cose_public_key_serialization() -> Result<()> {
     check_cose_public_key_serialization(&mut ec_key)
 }
 
+#[test]
+fn p256_cose_public_key_has_es256_algorithm() -> Result<()> {
+    let mut ec_key = EcKey::new_p256()?;
+    ec_key.generate_key()?;
+
+    let cose_key = decode_cose_public_key(&ec_key)?;
+    check_cose_public_key_algorithm(&cose_key, iana::Algorithm::ES256, iana::EllipticCurve::P_256);
+    Ok(())
+}
+
+#[test]
+fn p384_cose_public_key_has_es384_algorithm() -> Result<()> {
+    let mut ec_key = EcKey::new_p384()?;
+    ec_key.generate_key()?;
+
+    let cose_key = decode_cose_public_key(&ec_key)?;
+    check_cose_public_key_algorithm(&cose_key, iana::Algorithm::ES384, iana::EllipticCurve::P_384);
+    Ok(())
+}
+
+fn decode_cose_public_key(ec_key: &EcKey) -> Result<CoseKey> {
+    let cose_key_data = ec_key.cose_public_key()?.to_vec().unwrap();
+    Ok(CoseKey::from_slice(&cose_key_data).unwrap())
+}
+
+fn check_cose_public_key_algorithm(
+    cose_key: &CoseKey,
+    algorithm: iana::Algorithm,
+    curve: iana::EllipticCurve,
+) {
+    assert_eq!(KeyType::Assigned(iana::KeyType::EC2), cose_key.kty);
+    assert_eq!(Some(coset::Algorithm::Assigned(algorithm)), cose_key.alg);
+    let crv = cose_key
+        .params
+        .iter()
+        .find(|(label, _)| *label == Label::Int(iana::Ec2KeyParameter::Crv as i64))
+        .map(|(_, value)| value.clone());
+    assert_eq!(Some((curve as i64).into()), crv);
+}
+
 fn check_cose_public_key_serialization(ec_key: &mut EcKey) -> Result<()> {
     ec_key.generate_key()?;
     let cose_key = ec_key.cose_public_key()?;
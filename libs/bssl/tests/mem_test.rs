@@ -0,0 +1,29 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use bssl_avf::constant_time_eq;
+
+#[test]
+fn equal_slices_are_equal() {
+    assert!(constant_time_eq(b"", b""));
+    assert!(constant_time_eq(b"tag", b"tag"));
+}
+
+#[test]
+fn different_slices_are_not_equal() {
+    assert!(!constant_time_eq(b"tag", b"tab"));
+    assert!(!constant_time_eq(b"tag", b"bag"));
+    assert!(!constant_time_eq(b"tag", b"tags"));
+    assert!(!constant_time_eq(b"", b"tag"));
+}
@@ -18,3 +18,4 @@ mod aead_test;
 mod eckey_test;
 mod hkdf_test;
 mod hmac_test;
+mod mem_test;
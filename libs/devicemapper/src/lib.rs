@@ -31,17 +31,22 @@
 #![allow(missing_docs)]
 #![cfg_attr(test, allow(unused))]
 
-use anyhow::{Context, Result};
+use anyhow::{ensure, Context, Result};
 use std::fs::{File, OpenOptions};
 use std::io::Write;
 use std::mem::size_of;
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use zerocopy::AsBytes;
+use zerocopy::FromBytes;
 use zerocopy::FromZeroes;
 
 /// Exposes DmCryptTarget & related builder
 pub mod crypt;
+/// Exposes the DmLinearTarget
+pub mod linear;
+/// Exposes the DmSnapshotTarget & related builder
+pub mod snapshot;
 /// Expose util functions
 pub mod util;
 /// Exposes the DmVerityTarget & related builder
@@ -51,14 +56,17 @@ pub mod loopdevice;
 
 mod sys;
 use crypt::DmCryptTarget;
+use linear::DmLinearTarget;
+use snapshot::DmSnapshotTarget;
 use sys::*;
 use util::*;
-use verity::DmVerityTarget;
+use verity::{DmVerityStatus, DmVerityTarget};
 
 nix::ioctl_readwrite!(_dm_dev_create, DM_IOCTL, Cmd::DM_DEV_CREATE, DmIoctl);
 nix::ioctl_readwrite!(_dm_dev_suspend, DM_IOCTL, Cmd::DM_DEV_SUSPEND, DmIoctl);
 nix::ioctl_readwrite!(_dm_table_load, DM_IOCTL, Cmd::DM_TABLE_LOAD, DmIoctl);
 nix::ioctl_readwrite!(_dm_dev_remove, DM_IOCTL, Cmd::DM_DEV_REMOVE, DmIoctl);
+nix::ioctl_readwrite!(_dm_table_status, DM_IOCTL, Cmd::DM_TABLE_STATUS, DmIoctl);
 
 /// Create a new (mapper) device
 fn dm_dev_create(dm: &DeviceMapper, ioctl: *mut DmIoctl) -> Result<i32> {
@@ -85,10 +93,16 @@ fn dm_dev_remove(dm: &DeviceMapper, ioctl: *mut DmIoctl) -> Result<i32> {
     Ok(unsafe { _dm_dev_remove(dm.0.as_raw_fd(), ioctl) }?)
 }
 
+fn dm_table_status(dm: &DeviceMapper, ioctl: *mut DmIoctl) -> Result<i32> {
+    // SAFETY: `ioctl` points to a buffer of `ioctl.data_size` bytes, which is the most the kernel
+    // writes to. It doesn't modify the state of this process in any other way.
+    Ok(unsafe { _dm_table_status(dm.0.as_raw_fd(), ioctl) }?)
+}
+
 // `DmTargetSpec` is the header of the data structure for a device-mapper target. When doing the
 // ioctl, one of more `DmTargetSpec` (and its body) are appened to the `DmIoctl` struct.
 #[repr(C)]
-#[derive(Copy, Clone, AsBytes, FromZeroes)]
+#[derive(Copy, Clone, AsBytes, FromZeroes, FromBytes)]
 struct DmTargetSpec {
     sector_start: u64,
     length: u64, // number of 512 sectors
@@ -154,13 +168,40 @@ impl DeviceMapper {
     /// Creates a (crypt) device and configure it according to the `target` specification.
     /// The path to the generated device is "/dev/mapper/<name>".
     pub fn create_crypt_device(&self, name: &str, target: &DmCryptTarget) -> Result<PathBuf> {
-        self.create_device(name, target.as_slice(), uuid("crypto".as_bytes())?, true)
+        self.create_device(name, target.as_slice(), 1, uuid("crypto".as_bytes())?, true)
     }
 
     /// Creates a (verity) device and configure it according to the `target` specification.
     /// The path to the generated device is "/dev/mapper/<name>".
     pub fn create_verity_device(&self, name: &str, target: &DmVerityTarget) -> Result<PathBuf> {
-        self.create_device(name, target.as_slice(), uuid("apkver".as_bytes())?, false)
+        self.create_device(name, target.as_slice(), 1, uuid("apkver".as_bytes())?, false)
+    }
+
+    /// Creates a (snapshot) device and configure it according to the `target` specification.
+    /// The path to the generated device is "/dev/mapper/<name>".
+    pub fn create_snapshot_device(&self, name: &str, target: &DmSnapshotTarget) -> Result<PathBuf> {
+        self.create_device(name, target.as_slice(), 1, uuid("snapst".as_bytes())?, true)
+    }
+
+    /// Creates a read-only (linear) device concatenating `segments`, each of which is the
+    /// `(device, offset, size)` of a range of a block device, in bytes. Regular files have to be
+    /// attached to loop devices first. The path to the generated device is "/dev/mapper/<name>".
+    pub fn create_linear_device(
+        &self,
+        name: &str,
+        segments: &[(&Path, u64, u64)],
+    ) -> Result<PathBuf> {
+        let target = DmLinearTarget::new(segments)?;
+        let uid = uuid("linear".as_bytes())?;
+        self.create_device(name, target.as_slice(), target.target_count(), uid, false)
+    }
+
+    /// Returns the health of the (verity) device with the given name.
+    pub fn verity_status(&self, name: &str) -> Result<DmVerityStatus> {
+        let info = self.target_status(name, /* table */ false)?;
+        let table = self.target_status(name, /* table */ true)?;
+        DmVerityStatus::parse(&info, &table)
+            .context(format!("failed to parse the status of device with name {}", &name))
     }
 
     /// Removes a mapper device.
@@ -172,10 +213,41 @@ impl DeviceMapper {
         Ok(())
     }
 
+    // Returns the status line of the only target of the device with the given name. If `table` is
+    // true, the line describes the table of the target rather than its current state.
+    fn target_status(&self, name: &str, table: bool) -> Result<String> {
+        const STATUS_BUFFER_SIZE: usize = 4096;
+        let payload_size = size_of::<DmIoctl>() + STATUS_BUFFER_SIZE;
+
+        let mut data = DmIoctl::new(name)?;
+        data.data_size = payload_size as u32;
+        data.data_start = size_of::<DmIoctl>() as u32;
+        if table {
+            data.flags |= Flag::DM_STATUS_TABLE_FLAG;
+        }
+
+        let mut payload = vec![0; payload_size];
+        payload[..size_of::<DmIoctl>()].copy_from_slice(data.as_bytes());
+        dm_table_status(self, payload.as_mut_ptr() as *mut DmIoctl)
+            .context(format!("failed to get the status of device with name {}", &name))?;
+
+        // The kernel fills in the header, followed by a `DmTargetSpec` and a null-terminated status
+        // line for each target, starting at `data_start`.
+        let data = DmIoctl::read_from_prefix(payload.as_slice()).context("truncated header")?;
+        ensure!(!data.flags.contains(Flag::DM_BUFFER_FULL_FLAG), "status buffer is too small");
+        ensure!(data.target_count == 1, "expected 1 target, found {}", data.target_count);
+        let spec_start = data.data_start as usize;
+        let status_start = spec_start + size_of::<DmTargetSpec>();
+        let status = payload.get(status_start..).context("truncated target spec")?;
+        let status = status.split(|b| *b == 0).next().unwrap_or_default();
+        Ok(String::from_utf8(status.to_vec())?)
+    }
+
     fn create_device(
         &self,
         name: &str,
         target: &[u8],
+        target_count: u32,
         uid: String,
         writable: bool,
     ) -> Result<PathBuf> {
@@ -191,7 +263,7 @@ impl DeviceMapper {
         let mut data = DmIoctl::new(name)?;
         data.data_size = payload_size as u32;
         data.data_start = size_of::<DmIoctl>() as u32;
-        data.target_count = 1;
+        data.target_count = target_count;
 
         if !writable {
             data.flags |= Flag::DM_READONLY_FLAG;
@@ -239,6 +311,7 @@ mod tests {
     use rustutils::system_properties;
     use std::fs::{read, File, OpenOptions};
     use std::io::Write;
+    use std::os::unix::fs::FileExt;
 
     // Just a logical set of keys to make testing easy. This has no real meaning.
     struct KeySet<'a> {
@@ -292,6 +365,108 @@ mod tests {
         }
     }
 
+    #[rdroidtest]
+    fn parse_verity_status() {
+        use verity::{DmVerityMode, DmVerityStatus};
+
+        let healthy =
+            DmVerityStatus::parse("V", "1 /dev/loop0 /dev/loop1 4096 4096 2 0 sha256 ab -")
+                .unwrap();
+        assert_eq!(
+            DmVerityStatus {
+                corruption_detected: false,
+                corrected_blocks: None,
+                mode: DmVerityMode::Eio
+            },
+            healthy
+        );
+
+        let corrupted = DmVerityStatus::parse(
+            "C 3",
+            "1 /dev/loop0 /dev/loop1 4096 4096 2 0 sha256 ab - 1 restart_on_corruption",
+        )
+        .unwrap();
+        assert_eq!(
+            DmVerityStatus {
+                corruption_detected: true,
+                corrected_blocks: Some(3),
+                mode: DmVerityMode::RestartOnCorruption
+            },
+            corrupted
+        );
+
+        DmVerityStatus::parse("X", "").expect_err("Should fail");
+    }
+
+    // Returns the body of the table of `target`, i.e. what follows its `DmTargetSpec`.
+    fn verity_table(target: &verity::DmVerityTarget) -> String {
+        let body = &target.as_slice()[size_of::<DmTargetSpec>()..];
+        let end = body.iter().position(|&b| b == 0).unwrap();
+        String::from_utf8(body[..end].to_vec()).unwrap()
+    }
+
+    #[rdroidtest]
+    fn verity_target_with_equal_block_sizes() {
+        use verity::DmVerityTargetBuilder;
+
+        // 256 data blocks of 4096 bytes, with 128 sha256 digests per hash block: 2 blocks for the
+        // first level and 1 for the root.
+        let target = DmVerityTargetBuilder::default()
+            .data_device(Path::new("/dev/loop0"), 256 * 4096)
+            .data_block_size(4096)
+            .hash_device(Path::new("/dev/loop1"))
+            .hash_block_size(4096)
+            .hash_tree_size(3 * 4096)
+            .root_digest(&[0xab])
+            .build()
+            .unwrap();
+
+        assert_eq!("1 /dev/loop0 /dev/loop1 4096 4096 256 0 sha256 ab -", verity_table(&target));
+    }
+
+    #[rdroidtest]
+    fn verity_target_with_different_block_sizes() {
+        use verity::DmVerityTargetBuilder;
+
+        // 256 data blocks of 4096 bytes, with 32 sha256 digests per hash block: 8 blocks for the
+        // first level and 1 for the root.
+        let target = DmVerityTargetBuilder::default()
+            .data_device(Path::new("/dev/loop0"), 256 * 4096)
+            .data_block_size(4096)
+            .hash_device(Path::new("/dev/loop1"))
+            .hash_block_size(1024)
+            .hash_tree_size(9 * 1024)
+            .root_digest(&[0xab])
+            .build()
+            .unwrap();
+
+        assert_eq!("1 /dev/loop0 /dev/loop1 4096 1024 256 0 sha256 ab -", verity_table(&target));
+    }
+
+    #[rdroidtest]
+    fn verity_target_with_invalid_sizes_is_rejected() {
+        use verity::DmVerityTargetBuilder;
+
+        let mut builder = DmVerityTargetBuilder::default();
+        builder
+            .data_device(Path::new("/dev/loop0"), 256 * 4096)
+            .data_block_size(4096)
+            .hash_device(Path::new("/dev/loop1"))
+            .hash_block_size(4096)
+            .root_digest(&[0xab]);
+        assert!(builder.build().is_ok());
+
+        // The merkle tree for hash blocks of 4096 bytes, with hash blocks of 1024 bytes.
+        builder.hash_block_size(1024).hash_tree_size(3 * 4096);
+        assert!(builder.build().is_err());
+
+        builder.hash_block_size(4096).data_block_size(3000);
+        assert!(builder.build().is_err());
+
+        builder.data_block_size(4096).hash_block_size(256);
+        assert!(builder.build().is_err());
+    }
+
     #[rdroidtest]
     fn mapping_again_keeps_data_xts() {
         mapping_again_keeps_data(&KEY_SET_XTS, "name1");
@@ -404,4 +579,56 @@ mod tests {
         let crypt = read(crypt_device).unwrap();
         assert_ne!(inputimg, crypt.as_slice());
     }
+
+    #[rdroidtest]
+    fn linear_device_concatenates_segments() {
+        let dm = DeviceMapper::new().unwrap();
+        let inputimg = include_bytes!("../testdata/rand8k");
+        let (first, second) = inputimg.split_at(inputimg.len() / 2);
+        let device = "linear1";
+
+        // Put the second half of the image at an offset in its file, as in a container.
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let first_file = test_dir.path().join("first");
+        std::fs::write(&first_file, first).unwrap();
+        let second_file = test_dir.path().join("second");
+        std::fs::write(&second_file, [&[0xff; 4096][..], second].concat()).unwrap();
+        let attach = |file: &Path, size| {
+            loopdevice::attach(file, 0, size, /* direct_io */ false, /* writable */ false).unwrap()
+        };
+        let first_device = attach(&first_file, first.len() as u64);
+        let second_device = attach(&second_file, 4096 + second.len() as u64);
+        scopeguard::defer! {
+            loopdevice::detach(&first_device).unwrap();
+            loopdevice::detach(&second_device).unwrap();
+            let _ignored = delete_device(&dm, device);
+        }
+
+        let segments = [
+            (first_device.as_path(), 0, first.len() as u64),
+            (second_device.as_path(), 4096, second.len() as u64),
+        ];
+        let linear_device = dm.create_linear_device(device, &segments).unwrap();
+
+        let linear = read(&linear_device).unwrap();
+        assert_eq!(inputimg.len(), linear.len()); // fail early if the size doesn't match
+        assert_eq!(inputimg, linear.as_slice());
+
+        // Read across the boundary between the segments.
+        let boundary = first.len();
+        let mut buf = [0; 1024];
+        File::open(&linear_device).unwrap().read_exact_at(&mut buf, boundary as u64 - 512).unwrap();
+        assert_eq!(&inputimg[boundary - 512..boundary + 512], buf.as_slice());
+    }
+
+    #[rdroidtest]
+    fn linear_target_rejects_unaligned_segments() {
+        let device = Path::new("/dev/loop0");
+        DmLinearTarget::new(&[]).expect_err("Should fail");
+        DmLinearTarget::new(&[(device, 100, 4096)]).expect_err("Should fail");
+        DmLinearTarget::new(&[(device, 0, 4000)]).expect_err("Should fail");
+
+        let target = DmLinearTarget::new(&[(device, 0, 4096), (device, 8192, 512)]).unwrap();
+        assert_eq!(2, target.target_count());
+    }
 }
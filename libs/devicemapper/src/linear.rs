@@ -0,0 +1,93 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// `dm::linear` module implements the "linear" target in the device mapper framework. It provides
+// `DmLinearTarget` struct which maps consecutive ranges of a mapper device to ranges of other block
+// devices, so that the latter can be presented as a single contiguous device.
+
+use anyhow::{ensure, Context, Result};
+use std::io::Write;
+use std::mem::size_of;
+use std::path::Path;
+use zerocopy::AsBytes;
+
+use crate::DmTargetSpec;
+
+// The UAPI for the linear target is here.
+// https://www.kernel.org/doc/Documentation/device-mapper/linear.txt
+
+const SECTOR_SIZE: u64 = 512;
+
+/// Device-Mapper's "linear" target, once for each segment of the mapper device. Unlike the other
+/// targets, it is made of as many target specs as there are segments.
+pub struct DmLinearTarget {
+    specs: Box<[u8]>,
+    count: u32,
+}
+
+impl DmLinearTarget {
+    /// Concatenates `segments`, each of which is the `(device, offset, size)` of a range of a block
+    /// device, e.g. a loop device a file is attached to. Offsets and sizes are in bytes, and must be
+    /// multiples of the sector size.
+    pub fn new(segments: &[(&Path, u64, u64)]) -> Result<Self> {
+        ensure!(!segments.is_empty(), "no segment to concatenate");
+
+        let mut buf = Vec::new();
+        let mut sector_start = 0;
+        for (device, offset, size) in segments {
+            let device_path = device.to_str().context("device path is not encoded in utf8")?;
+            ensure!(
+                offset % SECTOR_SIZE == 0 && size % SECTOR_SIZE == 0,
+                "segment at offset {} with size {} of {} is not aligned to the sector size",
+                offset,
+                size,
+                device_path
+            );
+            ensure!(*size > 0, "segment of {} is empty", device_path);
+
+            // Serialize the segment according to the spec, which is ...
+            // DmTargetSpec{...}
+            // <dev path> <offset>
+            let body = format!("{} {}\0", device_path, offset / SECTOR_SIZE);
+
+            let spec_size = size_of::<DmTargetSpec>() + body.len();
+            let aligned_size = (spec_size + 7) & !7; // align to 8 byte boundaries
+            let padding = aligned_size - spec_size;
+
+            let mut header = DmTargetSpec::new("linear")?;
+            header.sector_start = sector_start;
+            header.length = size / SECTOR_SIZE; // number of 512-byte sectors
+            header.next = aligned_size as u32;
+            sector_start += header.length;
+
+            buf.write_all(header.as_bytes())?;
+            buf.write_all(body.as_bytes())?;
+            buf.write_all(vec![0; padding].as_slice())?;
+        }
+
+        Ok(DmLinearTarget { specs: buf.into_boxed_slice(), count: segments.len().try_into()? })
+    }
+
+    /// Flatten into slice
+    pub fn as_slice(&self) -> &[u8] {
+        self.specs.as_ref()
+    }
+
+    /// Returns the number of target specs in `as_slice`.
+    pub fn target_count(&self) -> u32 {
+        self.count
+    }
+}
@@ -26,12 +26,14 @@ mod sys;
 use crate::util::*;
 use anyhow::{Context, Result};
 use libc::O_DIRECT;
-use std::fs::{File, OpenOptions};
+use nix::errno::Errno;
+use std::fs::{self, File, OpenOptions};
+use std::io;
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 use zerocopy::FromZeroes;
 
 use crate::loopdevice::sys::*;
@@ -59,6 +61,25 @@ pub fn loop_clr_fd(device_file: &File) -> Result<i32> {
     Ok(unsafe { _loop_clr_fd(device_file.as_raw_fd()) }?)
 }
 
+/// How `attach_with_retry` retries when attaching fails transiently.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first one.
+    pub attempts: u32,
+    /// The delay between two attempts.
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    // Note that the timing parameters are chosen rather arbitrarily. In practice (i.e. inside
+    // Microdroid) we can't experience the race condition because `apkverity` is the only user of
+    // /dev/loop-control at the moment. Retrying is mostly for testing where multiple tests run
+    // concurrently.
+    fn default() -> Self {
+        Self { attempts: 100, delay: Duration::from_millis(10) }
+    }
+}
+
 /// Creates a loop device and attach the given file at `path` as the backing store.
 pub fn attach<P: AsRef<Path>>(
     path: P,
@@ -66,38 +87,62 @@ pub fn attach<P: AsRef<Path>>(
     size_limit: u64,
     direct_io: bool,
     writable: bool,
+) -> Result<PathBuf> {
+    attach_with_retry(path, offset, size_limit, direct_io, writable, RetryPolicy::default())
+}
+
+/// Same as `attach`, but retries as configured by `retry` when attaching fails transiently.
+pub fn attach_with_retry<P: AsRef<Path>>(
+    path: P,
+    offset: u64,
+    size_limit: u64,
+    direct_io: bool,
+    writable: bool,
+    retry: RetryPolicy,
 ) -> Result<PathBuf> {
     // Attaching a file to a loop device can make a race condition; a loop device number obtained
     // from LOOP_CTL_GET_FREE might have been used by another thread or process. In that case the
-    // subsequent LOOP_CONFIGURE ioctl returns with EBUSY. Try until it succeeds.
-    //
-    // Note that the timing parameters below are chosen rather arbitrarily. In practice (i.e.
-    // inside Microdroid) we can't experience the race condition because `apkverity` is the only
-    // user of /dev/loop-control at the moment. This loop is mostly for testing where multiple
-    // tests run concurrently.
-    const TIMEOUT: Duration = Duration::from_secs(1);
-    const INTERVAL: Duration = Duration::from_millis(10);
-
-    let begin = Instant::now();
+    // subsequent LOOP_CONFIGURE ioctl returns with EBUSY. Try again with another loop device.
+    retry_transient_errors(retry, || try_attach(&path, offset, size_limit, direct_io, writable))
+}
+
+/// Calls `attempt` until it succeeds, fails with an error which isn't transient, or `retry` allows
+/// no more attempts. The error of the last attempt is returned if none succeeds.
+fn retry_transient_errors<T>(
+    retry: RetryPolicy,
+    mut attempt: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let mut attempts = 1;
     loop {
-        match try_attach(&path, offset, size_limit, direct_io, writable) {
-            Ok(loop_dev) => return Ok(loop_dev),
-            Err(e) => {
-                if begin.elapsed() > TIMEOUT {
-                    return Err(e);
-                }
-            }
-        };
-        thread::sleep(INTERVAL);
+        match attempt() {
+            Err(e) if is_transient(&e) && attempts < retry.attempts => attempts += 1,
+            result => return result,
+        }
+        thread::sleep(retry.delay);
     }
 }
 
+/// Returns whether `error` was caused by EBUSY or EAGAIN, i.e. whether attaching may succeed if
+/// tried again.
+fn is_transient(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        let errno = match cause.downcast_ref::<Errno>() {
+            Some(errno) => Some(*errno as i32),
+            None => cause.downcast_ref::<io::Error>().and_then(io::Error::raw_os_error),
+        };
+        matches!(errno, Some(libc::EBUSY | libc::EAGAIN))
+    })
+}
+
 #[cfg(not(target_os = "android"))]
 const LOOP_DEV_PREFIX: &str = "/dev/loop";
 
 #[cfg(target_os = "android")]
 const LOOP_DEV_PREFIX: &str = "/dev/block/loop";
 
+/// Where the kernel describes the block devices, including the backing files of loop devices.
+const SYS_BLOCK: &str = "/sys/block";
+
 fn try_attach<P: AsRef<Path>>(
     path: P,
     offset: u64,
@@ -156,11 +201,36 @@ pub fn detach<P: AsRef<Path>>(path: P) -> Result<()> {
     Ok(())
 }
 
+/// Detaches all the loop devices whose backing file is `path`, e.g. when the loop device paths
+/// returned by `attach` are lost. Returns the paths of the detached loop devices.
+pub fn detach_by_backing_file<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
+    let path = fs::canonicalize(&path).context(format!("Failed to resolve {:?}", path.as_ref()))?;
+    let mut detached = Vec::new();
+    for entry in fs::read_dir(SYS_BLOCK).context(format!("Failed to read {SYS_BLOCK}"))? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(num) = name.to_str().and_then(|name| name.strip_prefix("loop")) else {
+            continue;
+        };
+        // Only the loop devices which are attached have a backing file.
+        let Ok(backing_file) = fs::read_to_string(entry.path().join("loop/backing_file")) else {
+            continue;
+        };
+        if Path::new(backing_file.trim_end_matches('\n')) != path {
+            continue;
+        }
+        let device_path = PathBuf::from(format!("{}{}", LOOP_DEV_PREFIX, num));
+        detach(&device_path).context(format!("Failed to detach {:?}", &device_path))?;
+        detached.push(device_path);
+    }
+    Ok(detached)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs;
     use std::path::Path;
+    use std::time::Instant;
 
     fn create_empty_file(path: &Path, size: u64) {
         let f = File::create(path).unwrap();
@@ -178,6 +248,48 @@ mod tests {
         "0" == fs::read_to_string(ro).unwrap().trim()
     }
 
+    const NO_DELAY: RetryPolicy = RetryPolicy { attempts: 5, delay: Duration::ZERO };
+
+    #[test]
+    fn transient_errors_are_retried() {
+        let mut attempts = 0;
+        let result = retry_transient_errors(NO_DELAY, || {
+            attempts += 1;
+            match attempts {
+                1 => Err(Errno::EBUSY).context("Failed to configure /dev/loop0"),
+                2 => Err(io::Error::from_raw_os_error(libc::EAGAIN).into()),
+                _ => Ok(PathBuf::from("/dev/loop1")),
+            }
+        });
+
+        assert_eq!(PathBuf::from("/dev/loop1"), result.unwrap());
+        assert_eq!(3, attempts);
+    }
+
+    #[test]
+    fn other_errors_are_not_retried() {
+        let mut attempts = 0;
+        let result: Result<PathBuf> = retry_transient_errors(NO_DELAY, || {
+            attempts += 1;
+            Err(io::Error::from_raw_os_error(libc::ENOENT)).context("failed to open backing file")
+        });
+
+        assert!(result.is_err());
+        assert_eq!(1, attempts);
+    }
+
+    #[test]
+    fn retries_are_bounded() {
+        let mut attempts = 0;
+        let result: Result<PathBuf> = retry_transient_errors(NO_DELAY, || {
+            attempts += 1;
+            Err(Errno::EBUSY.into())
+        });
+
+        assert_eq!(Some(&Errno::EBUSY), result.unwrap_err().downcast_ref::<Errno>());
+        assert_eq!(NO_DELAY.attempts, attempts);
+    }
+
     #[test]
     fn attach_loop_device_with_dio() {
         let a_dir = tempfile::TempDir::new().unwrap();
@@ -204,6 +316,30 @@ mod tests {
         assert!(!is_direct_io(&dev));
     }
 
+    fn is_attached(dev: &Path) -> bool {
+        Path::new(SYS_BLOCK).join(dev.file_name().unwrap()).join("loop/backing_file").exists()
+    }
+
+    #[test]
+    fn detach_loop_device_by_backing_file() {
+        let a_dir = tempfile::TempDir::new().unwrap();
+        let a_file = a_dir.path().join("test");
+        let a_size = 4096u64;
+        create_empty_file(&a_file, a_size);
+        let dev = attach(&a_file, 0, a_size, /*direct_io*/ false, /*writable*/ false).unwrap();
+        assert!(is_attached(&dev));
+
+        assert_eq!(vec![dev.clone()], detach_by_backing_file(&a_file).unwrap());
+
+        // The loop device is released asynchronously if it is still open.
+        let begin = Instant::now();
+        while is_attached(&dev) && begin.elapsed() < Duration::from_secs(1) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!is_attached(&dev));
+        assert!(detach_by_backing_file(&a_file).unwrap().is_empty());
+    }
+
     #[test]
     fn attach_loop_device_with_dio_writable() {
         let a_dir = tempfile::TempDir::new().unwrap();
@@ -0,0 +1,144 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// `dm::snapshot` module implements the "snapshot" target in the device mapper framework. It
+// provides `DmSnapshotTargetBuilder` struct which is used to construct a `DmSnapshotTarget` struct
+// which is then given to `DeviceMapper` to create a writable mapper device on top of a read-only
+// origin device.
+
+use anyhow::{ensure, Context, Result};
+use std::io::Write;
+use std::mem::size_of;
+use std::path::Path;
+use zerocopy::AsBytes;
+
+use crate::DmTargetSpec;
+
+// The UAPI for the snapshot target is here.
+// https://www.kernel.org/doc/Documentation/device-mapper/snapshot.txt
+
+const SECTOR_SIZE: u64 = 512;
+
+/// Device-Mapper's "snapshot" target makes a block device writable by storing the chunks written to
+/// it in a separate copy-on-write (COW) device, leaving the origin device unchanged.
+pub struct DmSnapshotTarget(Box<[u8]>);
+
+impl DmSnapshotTarget {
+    /// Flatten into slice
+    pub fn as_slice(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+/// A builder that constructs `DmSnapshotTarget` struct.
+pub struct DmSnapshotTargetBuilder<'a> {
+    origin_device: Option<&'a Path>,
+    origin_size: u64,
+    cow_device: Option<&'a Path>,
+    persistent: bool,
+    chunk_size: u64,
+}
+
+impl<'a> Default for DmSnapshotTargetBuilder<'a> {
+    fn default() -> Self {
+        DmSnapshotTargetBuilder {
+            origin_device: None,
+            origin_size: 0,
+            cow_device: None,
+            persistent: false,
+            chunk_size: 4096,
+        }
+    }
+}
+
+impl<'a> DmSnapshotTargetBuilder<'a> {
+    /// Sets the device that provides the data as it was before any write.
+    pub fn origin_device(&mut self, p: &'a Path, size: u64) -> &mut Self {
+        self.origin_device = Some(p);
+        self.origin_size = size;
+        self
+    }
+
+    /// Sets the device where the chunks written to the snapshot are stored.
+    pub fn cow_device(&mut self, p: &'a Path) -> &mut Self {
+        self.cow_device = Some(p);
+        self
+    }
+
+    /// Sets whether the snapshot survives the removal of the device. Defaults to false, in which
+    /// case the COW device is only used as scratch space.
+    pub fn persistent(&mut self, persistent: bool) -> &mut Self {
+        self.persistent = persistent;
+        self
+    }
+
+    /// Sets the size in bytes of the chunks copied to the COW device. Defaults to 4096.
+    pub fn chunk_size(&mut self, chunk_size: u64) -> &mut Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Constructs a `DmSnapshotTarget`.
+    pub fn build(&self) -> Result<DmSnapshotTarget> {
+        // The `DmSnapshotTarget` struct actually is a flattened data consisting of a header and
+        // body. The format of the header is `dm_target_spec` as defined in
+        // include/uapi/linux/dm-ioctl.h.
+
+        // Step 1: check the validity of the inputs
+        let origin_device_path = self
+            .origin_device
+            .context("origin device is not set")?
+            .to_str()
+            .context("origin device path is not encoded in utf8")?;
+        let cow_device_path = self
+            .cow_device
+            .context("COW device is not set")?
+            .to_str()
+            .context("COW device path is not encoded in utf8")?;
+        ensure!(
+            self.chunk_size > 0 && self.chunk_size % SECTOR_SIZE == 0,
+            "chunk size {} is not a multiple of the sector size",
+            self.chunk_size
+        );
+
+        // Step 2: serialize the information according to the spec, which is ...
+        // DmTargetSpec{...}
+        // <origin> <COW device> <persistent?> <chunksize>
+        let mut body = String::new();
+        use std::fmt::Write;
+        write!(&mut body, "{} ", origin_device_path)?;
+        write!(&mut body, "{} ", cow_device_path)?;
+        write!(&mut body, "{} ", if self.persistent { "P" } else { "N" })?;
+        write!(&mut body, "{}", self.chunk_size / SECTOR_SIZE)?; // number of 512-byte sectors
+        write!(&mut body, "\0")?; // null terminator
+
+        let size = size_of::<DmTargetSpec>() + body.len();
+        let aligned_size = (size + 7) & !7; // align to 8 byte boundaries
+        let padding = aligned_size - size;
+
+        let mut header = DmTargetSpec::new("snapshot")?;
+        header.sector_start = 0;
+        header.length = self.origin_size / SECTOR_SIZE; // number of 512-byte sectors
+        header.next = aligned_size as u32;
+
+        let mut buf = Vec::with_capacity(aligned_size);
+        buf.write_all(header.as_bytes())?;
+        buf.write_all(body.as_bytes())?;
+        buf.write_all(vec![0; padding].as_slice())?;
+
+        Ok(DmSnapshotTarget(buf.into_boxed_slice()))
+    }
+}
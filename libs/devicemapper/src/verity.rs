@@ -18,7 +18,7 @@
 // it provides `DmVerityTargetBuilder` struct which is used to construct a `DmVerityTarget` struct
 // which is then given to `DeviceMapper` to create a mapper device.
 
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use std::io::Write;
 use std::mem::size_of;
 use std::path::Path;
@@ -49,12 +49,25 @@ pub enum DmVerityHashAlgorithm {
     SHA512,
 }
 
+impl DmVerityHashAlgorithm {
+    /// Size of the digests, in bytes.
+    fn digest_size(&self) -> u64 {
+        match self {
+            Self::SHA256 => 32,
+            Self::SHA512 => 64,
+        }
+    }
+}
+
 /// A builder that constructs `DmVerityTarget` struct.
 pub struct DmVerityTargetBuilder<'a> {
     version: DmVerityVersion,
     data_device: Option<&'a Path>,
     data_size: u64,
+    data_block_size: Option<u64>,
     hash_device: Option<&'a Path>,
+    hash_block_size: Option<u64>,
+    hash_tree_size: Option<u64>,
     hash_algorithm: DmVerityHashAlgorithm,
     root_digest: Option<&'a [u8]>,
     salt: Option<&'a [u8]>,
@@ -73,7 +86,10 @@ impl<'a> Default for DmVerityTargetBuilder<'a> {
             version: DmVerityVersion::V1,
             data_device: None,
             data_size: 0,
+            data_block_size: None,
             hash_device: None,
+            hash_block_size: None,
+            hash_tree_size: None,
             hash_algorithm: DmVerityHashAlgorithm::SHA256,
             root_digest: None,
             salt: None,
@@ -89,12 +105,33 @@ impl<'a> DmVerityTargetBuilder<'a> {
         self
     }
 
+    /// Sets the size of the blocks of the data device which are hashed, in bytes. It defaults to
+    /// the block size of the data device.
+    pub fn data_block_size(&mut self, size: u64) -> &mut Self {
+        self.data_block_size = Some(size);
+        self
+    }
+
     /// Sets the device that provides the merkle tree.
     pub fn hash_device(&mut self, p: &'a Path) -> &mut Self {
         self.hash_device = Some(p);
         self
     }
 
+    /// Sets the size of the blocks of the merkle tree, in bytes. It defaults to the block size of
+    /// the hash device, and doesn't need to be equal to the data block size.
+    pub fn hash_block_size(&mut self, size: u64) -> &mut Self {
+        self.hash_block_size = Some(size);
+        self
+    }
+
+    /// Sets the size of the merkle tree, in bytes. If set, it is checked to be the size implied by
+    /// the data size, the block sizes and the hash algorithm.
+    pub fn hash_tree_size(&mut self, size: u64) -> &mut Self {
+        self.hash_tree_size = Some(size);
+        self
+    }
+
     /// Sets the hash algorithm that the merkle tree is using.
     pub fn hash_algorithm(&mut self, algo: DmVerityHashAlgorithm) -> &mut Self {
         self.hash_algorithm = algo;
@@ -132,8 +169,11 @@ impl<'a> DmVerityTargetBuilder<'a> {
             .context("data device is not set")?
             .to_str()
             .context("data device path is not encoded in utf8")?;
-        let stat = fstat(self.data_device.unwrap())?; // safe; checked just above
-        let data_block_size = stat.st_blksize as u64;
+        let data_block_size = match self.data_block_size {
+            Some(size) => size,
+            None => fstat(self.data_device.unwrap())?.st_blksize as u64, // safe; checked just above
+        };
+        check_block_size("data", data_block_size)?;
         let data_size = self.data_size;
         let num_data_blocks = data_size / data_block_size;
 
@@ -142,8 +182,33 @@ impl<'a> DmVerityTargetBuilder<'a> {
             .context("hash device is not set")?
             .to_str()
             .context("hash device path is not encoded in utf8")?;
-        let stat = fstat(self.data_device.unwrap())?; // safe; checked just above
-        let hash_block_size = stat.st_blksize;
+        let hash_block_size = match self.hash_block_size {
+            Some(size) => size,
+            None => fstat(self.hash_device.unwrap())?.st_blksize as u64, // safe; checked just above
+        };
+        check_block_size("hash", hash_block_size)?;
+        ensure!(
+            hash_block_size >= self.hash_algorithm.digest_size(),
+            "hash block size {} is smaller than a digest",
+            hash_block_size
+        );
+        if let Some(hash_tree_size) = self.hash_tree_size {
+            let expected = expected_hash_tree_size(
+                num_data_blocks,
+                hash_block_size,
+                self.hash_algorithm.digest_size(),
+            );
+            ensure!(
+                hash_tree_size == expected,
+                "hash tree size {} doesn't match the {} bytes expected for {} data blocks of {} \
+                bytes and hash blocks of {} bytes",
+                hash_tree_size,
+                expected,
+                num_data_blocks,
+                data_block_size,
+                hash_block_size
+            );
+        }
 
         let hash_algorithm = match self.hash_algorithm {
             DmVerityHashAlgorithm::SHA256 => "sha256",
@@ -201,3 +266,84 @@ impl<'a> DmVerityTargetBuilder<'a> {
         Ok(DmVerityTarget(buf.into_boxed_slice()))
     }
 }
+
+/// Checks that `size`, the size of the `what` blocks, is one the kernel supports, i.e. a power of
+/// two no smaller than a sector.
+fn check_block_size(what: &str, size: u64) -> Result<()> {
+    ensure!(
+        size.is_power_of_two() && size >= 512,
+        "{} block size {} is not a power of two of at least 512 bytes",
+        what,
+        size
+    );
+    Ok(())
+}
+
+/// Returns the size of the merkle tree which dm-verity expects for `num_data_blocks`, with hash
+/// blocks of `hash_block_size` bytes and digests of `digest_size` bytes. Each hash block holds as
+/// many digests as the largest power of two which fits, and the top level is a single block,
+/// whose digest is the root digest.
+fn expected_hash_tree_size(num_data_blocks: u64, hash_block_size: u64, digest_size: u64) -> u64 {
+    let digests_per_block = 1 << (hash_block_size / digest_size).ilog2();
+    let mut num_digests = num_data_blocks;
+    let mut num_hash_blocks = 0;
+    while num_digests > 1 {
+        num_digests = num_digests.div_ceil(digests_per_block);
+        num_hash_blocks += num_digests;
+    }
+    num_hash_blocks * hash_block_size
+}
+
+/// What a dm-verity device does when a block fails verification.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DmVerityMode {
+    /// Fails the read with an I/O error. This is the default.
+    Eio,
+    /// Only logs the corruption and returns the block as it is.
+    IgnoreCorruption,
+    /// Restarts the system.
+    RestartOnCorruption,
+    /// Panics the system.
+    PanicOnCorruption,
+}
+
+/// Health of a dm-verity device, as reported by the kernel.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DmVerityStatus {
+    /// Whether a block failed verification since the device was created.
+    pub corruption_detected: bool,
+    /// Number of corrupted blocks corrected using forward error correction, if that is enabled.
+    pub corrected_blocks: Option<u64>,
+    /// What the device does when a block fails verification.
+    pub mode: DmVerityMode,
+}
+
+impl DmVerityStatus {
+    /// Parses the status of a verity target, given both as its state (`info`) and as its table
+    /// (`table`). The formats are described in
+    /// https://www.kernel.org/doc/Documentation/device-mapper/verity.txt
+    pub(crate) fn parse(info: &str, table: &str) -> Result<Self> {
+        let mut info = info.split_whitespace();
+        let corruption_detected = match info.next() {
+            Some("V") => false,
+            Some("C") => true,
+            other => bail!("unexpected verity state: {:?}", other),
+        };
+        let corrected_blocks = match info.next() {
+            None | Some("-") => None,
+            Some(count) => Some(count.parse().context("malformed corrected block count")?),
+        };
+
+        let mut mode = DmVerityMode::Eio;
+        for arg in table.split_whitespace() {
+            mode = match arg {
+                "ignore_corruption" => DmVerityMode::IgnoreCorruption,
+                "restart_on_corruption" => DmVerityMode::RestartOnCorruption,
+                "panic_on_corruption" => DmVerityMode::PanicOnCorruption,
+                _ => continue,
+            };
+        }
+
+        Ok(Self { corruption_detected, corrected_blocks, mode })
+    }
+}
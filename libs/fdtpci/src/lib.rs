@@ -110,6 +110,11 @@ impl PciInfo {
         Ok(Self { cam_range, bar_range })
     }
 
+    /// Returns the layout of the memory-mapped CAM found in the FDT.
+    pub fn cam(&self) -> Cam {
+        Cam::MmioCam
+    }
+
     /// Returns the `PciRoot` for the memory-mapped CAM found in the FDT. The CAM should be mapped
     /// before this is called, by calling [`PciInfo::map`].
     ///
@@ -121,7 +126,7 @@ impl PciInfo {
     pub unsafe fn make_pci_root(&self) -> PciRoot {
         // SAFETY: We trust that the FDT gave us a valid MMIO base address for the CAM. The caller
         // guarantees to only call us once, so there are no other references to it.
-        unsafe { PciRoot::new(self.cam_range.start as *mut u8, Cam::MmioCam) }
+        unsafe { PciRoot::new(self.cam_range.start as *mut u8, self.cam()) }
     }
 }
 
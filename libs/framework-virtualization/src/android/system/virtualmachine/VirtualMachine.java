@@ -579,7 +579,8 @@ public class VirtualMachine implements AutoCloseable {
                 service.initializeWritablePartition(
                         ParcelFileDescriptor.open(vm.mInstanceFilePath, MODE_READ_WRITE),
                         INSTANCE_FILE_SIZE,
-                        PartitionType.ANDROID_VM_INSTANCE);
+                        PartitionType.ANDROID_VM_INSTANCE,
+                        /* force= */ false);
             } catch (FileNotFoundException e) {
                 throw new VirtualMachineException("instance image missing", e);
             } catch (RemoteException e) {
@@ -593,7 +594,8 @@ public class VirtualMachine implements AutoCloseable {
                     service.initializeWritablePartition(
                             ParcelFileDescriptor.open(vm.mEncryptedStoreFilePath, MODE_READ_WRITE),
                             config.getEncryptedStorageBytes(),
-                            PartitionType.ENCRYPTEDSTORE);
+                            PartitionType.ENCRYPTEDSTORE,
+                            /* force= */ false);
                 } catch (FileNotFoundException e) {
                     throw new VirtualMachineException("encrypted storage image missing", e);
                 } catch (RemoteException e) {
@@ -2284,6 +2286,43 @@ public class VirtualMachine implements AutoCloseable {
             executeCallback((cb) -> cb.onError(VirtualMachine.this, translatedError, message));
         }
 
+        @Override
+        public void onResourceLimit(int cid, String resource, long limit, long observed) {
+            Log.w(TAG, "VM " + cid + " reached " + resource + " limit: " + observed + "/" + limit);
+        }
+
+        @Override
+        public void onConsoleClosed(int cid) {
+            Log.d(TAG, "Console of VM " + cid + " closed");
+        }
+
+        @Override
+        public void onConsoleResize(int cid, int rows, int cols) {
+            Log.d(TAG, "Console of VM " + cid + " resized to " + rows + "x" + cols);
+        }
+
+        @Override
+        public void onVsockConnection(int cid, int port, ParcelFileDescriptor stream) {
+            // Vsock ports can't be reserved through this API, so there is no one to take it.
+            Log.w(TAG, "Closing unexpected connection from VM " + cid + " to port " + port);
+            try {
+                stream.close();
+            } catch (IOException e) {
+                Log.w(TAG, "Failed to close connection from VM " + cid, e);
+            }
+        }
+
+        @Override
+        public void onGuestFileReceived(int cid, ParcelFileDescriptor file) {
+            // Files pushed by the VM aren't exposed through this API yet.
+            Log.d(TAG, "Ignoring file pushed by VM " + cid);
+            try {
+                file.close();
+            } catch (IOException e) {
+                Log.w(TAG, "Failed to close file pushed by VM " + cid, e);
+            }
+        }
+
         @Override
         public void onDied(int cid, int reason) {
             int translatedReason = getTranslatedReason(reason);
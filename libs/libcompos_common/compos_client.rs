@@ -29,19 +29,31 @@ use android_system_virtualizationservice::aidl::android::system::virtualizations
         VirtualMachineAppConfig,
     },
     VirtualMachineConfig::VirtualMachineConfig,
+    VirtualMachineState::VirtualMachineState,
 };
-use anyhow::{anyhow, bail, Context, Result};
-use binder::{ParcelFileDescriptor, Strong};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use binder::{Interface, ParcelFileDescriptor, Strong};
 use compos_aidl_interface::aidl::com::android::compos::ICompOsService::ICompOsService;
 use glob::glob;
 use log::{info, warn};
 use platformproperties::hypervisorproperties;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::path::{Path, PathBuf};
-use vmclient::{DeathReason, ErrorCode, VmInstance, VmWaitError};
+use std::thread;
+use std::time::Duration;
+use vmclient::{DeathReason, ErrorCode, VmInstance, VmPingError, VmWaitError};
+
+/// The number of times we try to connect to the CompOS service when reconnecting after its binder
+/// has died.
+const MAX_RECONNECT_ATTEMPTS: u32 = 3;
+/// Time to wait between attempts to reconnect to the CompOS service.
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
 
 /// This owns an instance of the CompOS VM.
-pub struct ComposClient(VmInstance);
+pub struct ComposClient {
+    instance: VmInstance,
+    auto_reconnect: bool,
+}
 
 /// CPU topology configuration for a virtual machine.
 #[derive(Default, Debug, Clone)]
@@ -53,12 +65,53 @@ pub enum VmCpuTopology {
     MatchHost,
 }
 
+/// How debuggable a virtual machine is, and which of its output is kept.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmDebugLevel {
+    /// The VM isn't debuggable and none of its output is kept.
+    #[default]
+    None,
+    /// The VM is debuggable, but only the logs of the payload are kept, not the console.
+    App,
+    /// The VM is debuggable and both the logs and the console are kept.
+    Full,
+}
+
+/// Where the console output of the VM goes, on top of the logs.
+#[derive(Debug, PartialEq, Eq)]
+enum ConsoleOutput {
+    /// The default of VirtualizationService, i.e. logcat.
+    Default,
+    /// Nowhere.
+    Discarded,
+}
+
+impl VmDebugLevel {
+    fn to_aidl(self) -> DebugLevel {
+        match self {
+            Self::None => DebugLevel::NONE,
+            // The payload output is only available in a debuggable VM.
+            Self::App | Self::Full => DebugLevel::FULL,
+        }
+    }
+
+    fn console_output(self) -> ConsoleOutput {
+        match self {
+            Self::App => ConsoleOutput::Discarded,
+            Self::None | Self::Full => ConsoleOutput::Default,
+        }
+    }
+}
+
 /// Parameters to be used when creating a virtual machine instance.
 #[derive(Default, Debug, Clone)]
 pub struct VmParameters {
     /// The name of VM for identifying.
     pub name: String,
-    /// Whether the VM should be debuggable.
+    /// How debuggable the VM should be.
+    pub debug_level: VmDebugLevel,
+    /// Deprecated: use `debug_level`. If set, the VM is fully debuggable regardless of
+    /// `debug_level`.
     pub debug_mode: bool,
     /// CPU topology of the VM. Defaults to 1 vCPU.
     pub cpu_topology: VmCpuTopology,
@@ -66,6 +119,21 @@ pub struct VmParameters {
     pub memory_mib: Option<i32>,
     /// Whether the VM prefers staged APEXes or activated ones (false; default)
     pub prefer_staged: bool,
+    /// Whether to reconnect to the CompOS service if its binder dies while the VM is still
+    /// running. This can't help if the VM itself has died (which includes the case where
+    /// VirtualizationService died, since the VM doesn't outlive it).
+    pub auto_reconnect: bool,
+}
+
+impl VmParameters {
+    /// Returns the debug level of the VM, taking the deprecated `debug_mode` into account.
+    fn effective_debug_level(&self) -> VmDebugLevel {
+        if self.debug_mode {
+            VmDebugLevel::Full
+        } else {
+            self.debug_level
+        }
+    }
 }
 
 impl ComposClient {
@@ -115,7 +183,8 @@ impl ComposClient {
             };
         let config_path = get_vm_config_path(has_system_ext, parameters.prefer_staged);
 
-        let debug_level = if parameters.debug_mode { DebugLevel::FULL } else { DebugLevel::NONE };
+        let vm_debug_level = parameters.effective_debug_level();
+        let debug_level = vm_debug_level.to_aidl();
 
         let cpu_topology = match parameters.cpu_topology {
             VmCpuTopology::OneCpu => CpuTopology::ONE_CPU,
@@ -143,8 +212,14 @@ impl ComposClient {
             ..Default::default()
         });
 
-        // Let logs go to logcat.
-        let (console_fd, log_fd) = (None, None);
+        // Let logs go to logcat, and the console too unless it isn't wanted.
+        let console_fd = match vm_debug_level.console_output() {
+            ConsoleOutput::Default => None,
+            ConsoleOutput::Discarded => Some(
+                OpenOptions::new().write(true).open("/dev/null").context("Failed to open null")?,
+            ),
+        };
+        let log_fd = None;
         let callback = Box::new(Callback {});
         let instance = VmInstance::create(
             service,
@@ -170,12 +245,30 @@ impl ComposClient {
         }
         ready?;
 
-        Ok(Self(instance))
+        Ok(Self { instance, auto_reconnect: parameters.auto_reconnect })
     }
 
     /// Create and return an RPC Binder connection to the Comp OS service in the VM.
     pub fn connect_service(&self) -> Result<Strong<dyn ICompOsService>> {
-        self.0.connect_service(COMPOS_VSOCK_PORT).context("Connecting to CompOS service")
+        self.instance.connect_service(COMPOS_VSOCK_PORT).context("Connecting to CompOS service")
+    }
+
+    /// Check that the VM is still alive and the given CompOS service is responsive, so that callers
+    /// can find out cheaply before submitting a long-running job.
+    ///
+    /// If the service's binder has died but the VM is still running, and the VM was started with
+    /// `auto_reconnect`, `service` is replaced with a new connection to the service.
+    pub fn ping(&self, service: &mut Strong<dyn ICompOsService>) -> Result<()> {
+        let timeout = TIMEOUTS.vm_max_time_to_respond;
+        match self.instance.ping(&service.as_binder(), timeout) {
+            Err(VmPingError::Binder(e)) if self.auto_reconnect => {
+                warn!("CompOS service binder failed ({e:?}), reconnecting");
+                *service = reconnect_service(self)?;
+                self.instance.ping(&service.as_binder(), timeout)
+            }
+            result => result,
+        }
+        .context("Pinging CompOS service")
     }
 
     /// Shut down the VM cleanly, by sending a quit request to the service, giving time for any
@@ -191,7 +284,7 @@ impl ComposClient {
     /// This should only be called when the instance has been requested to quit, or we believe that
     /// it is already in the process of exiting due to some failure.
     fn wait_for_shutdown(self) {
-        let death_reason = self.0.wait_for_death_with_timeout(TIMEOUTS.vm_max_time_to_exit);
+        let death_reason = self.instance.wait_for_death_with_timeout(TIMEOUTS.vm_max_time_to_exit);
         match death_reason {
             Some(DeathReason::Shutdown) => info!("VM has exited normally"),
             Some(reason) => warn!("VM died with reason {:?}", reason),
@@ -200,6 +293,60 @@ impl ComposClient {
     }
 }
 
+/// What reconnecting to a service in a VM needs from the VM.
+trait ServiceConnector<S> {
+    /// Returns why the VM died, if it did or VirtualizationService did, as reported by the death
+    /// recipient of the VM.
+    fn death_reason(&self) -> Option<DeathReason>;
+    /// Returns the state of the VM, as reported by VirtualizationService.
+    fn vm_state(&self) -> binder::Result<VirtualMachineState>;
+    /// Connects to the service.
+    fn connect(&self) -> Result<S>;
+}
+
+impl ServiceConnector<Strong<dyn ICompOsService>> for ComposClient {
+    fn death_reason(&self) -> Option<DeathReason> {
+        self.instance.wait_for_death_with_timeout(Duration::ZERO)
+    }
+
+    fn vm_state(&self) -> binder::Result<VirtualMachineState> {
+        self.instance.state()
+    }
+
+    fn connect(&self) -> Result<Strong<dyn ICompOsService>> {
+        self.connect_service()
+    }
+}
+
+/// Connects to the service of `vm` again, retrying up to `MAX_RECONNECT_ATTEMPTS` times, as long
+/// as the payload of the VM is still serving.
+fn reconnect_service<S>(vm: &dyn ServiceConnector<S>) -> Result<S> {
+    let mut attempts = 1;
+    loop {
+        check_vm_is_ready(vm)?;
+        match vm.connect() {
+            Ok(service) => return Ok(service),
+            Err(e) if attempts < MAX_RECONNECT_ATTEMPTS => {
+                warn!("Failed to reconnect to CompOS service (attempt {attempts}): {e:?}");
+                attempts += 1;
+                thread::sleep(RECONNECT_INTERVAL);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Returns an error if the VM is gone, along with VirtualizationService or not, or if its payload
+/// is no longer serving, in which case there is nothing to reconnect to.
+fn check_vm_is_ready<S>(vm: &dyn ServiceConnector<S>) -> Result<()> {
+    if let Some(reason) = vm.death_reason() {
+        bail!("VM died ({reason:?}), not reconnecting");
+    }
+    let state = vm.vm_state().context("Failed to get the VM state, not reconnecting")?;
+    ensure!(state == VirtualMachineState::READY, "VM is {state:?}, not reconnecting");
+    Ok(())
+}
+
 fn locate_config_apk(apex_dir: &Path) -> Result<PathBuf> {
     // Our config APK will be in a directory under app, but the name of the directory is at the
     // discretion of the build system. So just look in each sub-directory until we find it.
@@ -261,3 +408,130 @@ impl vmclient::VmCallback for Callback {
         log::warn!("VM died, cid = {}, reason = {:?}", cid, death_reason);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use binder::{Status, StatusCode};
+    use std::cell::Cell;
+
+    fn parameters(debug_level: VmDebugLevel, debug_mode: bool) -> VmParameters {
+        VmParameters { debug_level, debug_mode, ..Default::default() }
+    }
+
+    #[test]
+    fn no_debug_level_keeps_nothing() {
+        let level = parameters(VmDebugLevel::None, false).effective_debug_level();
+
+        assert_eq!(VmDebugLevel::None, level);
+        assert_eq!(DebugLevel::NONE, level.to_aidl());
+        assert_eq!(ConsoleOutput::Default, level.console_output());
+    }
+
+    #[test]
+    fn app_debug_level_discards_console() {
+        let level = parameters(VmDebugLevel::App, false).effective_debug_level();
+
+        assert_eq!(VmDebugLevel::App, level);
+        assert_eq!(DebugLevel::FULL, level.to_aidl());
+        assert_eq!(ConsoleOutput::Discarded, level.console_output());
+    }
+
+    #[test]
+    fn full_debug_level_keeps_console() {
+        let level = parameters(VmDebugLevel::Full, false).effective_debug_level();
+
+        assert_eq!(VmDebugLevel::Full, level);
+        assert_eq!(DebugLevel::FULL, level.to_aidl());
+        assert_eq!(ConsoleOutput::Default, level.console_output());
+    }
+
+    #[test]
+    fn debug_mode_means_full_debug_level() {
+        assert_eq!(
+            VmDebugLevel::Full,
+            parameters(VmDebugLevel::None, true).effective_debug_level()
+        );
+        assert_eq!(VmDebugLevel::Full, parameters(VmDebugLevel::App, true).effective_debug_level());
+    }
+
+    /// A VM whose service can be connected to after `failed_connections` attempts. Once `dies_after`
+    /// attempts have been made, if given, it reports its death as its death recipient would.
+    struct FakeVm {
+        state: Result<VirtualMachineState, StatusCode>,
+        failed_connections: u32,
+        dies_after: Option<u32>,
+        attempts: Cell<u32>,
+    }
+
+    impl FakeVm {
+        fn new(failed_connections: u32, dies_after: Option<u32>) -> Self {
+            Self {
+                state: Ok(VirtualMachineState::READY),
+                failed_connections,
+                dies_after,
+                attempts: Cell::new(0),
+            }
+        }
+    }
+
+    impl ServiceConnector<u32> for FakeVm {
+        fn death_reason(&self) -> Option<DeathReason> {
+            self.dies_after
+                .filter(|&n| self.attempts.get() >= n)
+                .map(|_| DeathReason::VirtualizationServiceDied)
+        }
+
+        fn vm_state(&self) -> binder::Result<VirtualMachineState> {
+            self.state.map_err(Status::from)
+        }
+
+        fn connect(&self) -> Result<u32> {
+            let attempts = self.attempts.get() + 1;
+            self.attempts.set(attempts);
+            ensure!(attempts > self.failed_connections, "Connection refused");
+            Ok(attempts)
+        }
+    }
+
+    #[test]
+    fn reconnects_to_ready_vm() {
+        let vm = FakeVm::new(1, None);
+
+        assert_eq!(2, reconnect_service(&vm).unwrap());
+    }
+
+    #[test]
+    fn gives_up_reconnecting_after_max_attempts() {
+        let vm = FakeVm::new(u32::MAX, None);
+
+        assert!(reconnect_service(&vm).is_err());
+        assert_eq!(MAX_RECONNECT_ATTEMPTS, vm.attempts.get());
+    }
+
+    #[test]
+    fn doesnt_reconnect_once_virtualization_service_died() {
+        let vm = FakeVm::new(0, Some(0));
+
+        assert!(reconnect_service(&vm).is_err());
+        assert_eq!(0, vm.attempts.get());
+    }
+
+    #[test]
+    fn stops_reconnecting_when_vm_dies() {
+        let vm = FakeVm::new(u32::MAX, Some(1));
+
+        assert!(reconnect_service(&vm).is_err());
+        assert_eq!(1, vm.attempts.get());
+    }
+
+    #[test]
+    fn doesnt_reconnect_to_vm_no_longer_ready() {
+        for state in [Ok(VirtualMachineState::FINISHED), Err(StatusCode::DEAD_OBJECT)] {
+            let vm = FakeVm { state, ..FakeVm::new(0, None) };
+
+            assert!(reconnect_service(&vm).is_err(), "{state:?}");
+            assert_eq!(0, vm.attempts.get());
+        }
+    }
+}
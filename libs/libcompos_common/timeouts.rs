@@ -29,6 +29,8 @@ pub struct Timeouts {
     pub vm_max_time_to_ready: Duration,
     /// Time we wait for a VM to exit once the payload has finished.
     pub vm_max_time_to_exit: Duration,
+    /// Time allowed for the CompOS service to respond to a ping.
+    pub vm_max_time_to_respond: Duration,
 }
 
 /// The timeouts that are appropriate on the current platform.
@@ -47,6 +49,7 @@ const NORMAL_TIMEOUTS: Timeouts = Timeouts {
     odrefresh_max_execution_time: Duration::from_secs(300),
     vm_max_time_to_ready: Duration::from_secs(15),
     vm_max_time_to_exit: Duration::from_secs(5),
+    vm_max_time_to_respond: Duration::from_secs(2),
 };
 
 /// The timeouts that we use when running under nested virtualization.
@@ -55,4 +58,5 @@ const EXTENDED_TIMEOUTS: Timeouts = Timeouts {
     odrefresh_max_execution_time: Duration::from_secs(480),
     vm_max_time_to_ready: Duration::from_secs(120),
     vm_max_time_to_exit: Duration::from_secs(20),
+    vm_max_time_to_respond: Duration::from_secs(10),
 };
@@ -0,0 +1,134 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module contains the framing of the messages exchanged between the host
+//! and the service VM over a stream.
+//!
+//! Each message is CBOR-encoded and preceded by its size in bytes, as a
+//! big-endian u32.
+
+use crate::message::{RequestProcessingError, Response, ServiceVmRequest};
+use alloc::vec;
+use alloc::vec::Vec;
+use ciborium_io::{Read, Write};
+use core::fmt;
+use coset::CoseError;
+use log::warn;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// The maximum size in bytes of a message, excluding its size prefix.
+///
+/// Larger frames are rejected before anything is allocated for them.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// The maximum size in bytes of an encoded response, so that the host can
+/// bound the buffer it reads responses into.
+///
+/// A response which would be larger, e.g. the CSR of a
+/// `Request::GenerateCertificateRequest`, is never sent: the service VM sends
+/// `RequestProcessingError::ResponseTooLarge` instead.
+pub const MAX_RESPONSE_SIZE: usize = MAX_FRAME_SIZE;
+
+/// Errors related to the framing of messages.
+#[derive(Debug)]
+pub enum FramingError<E> {
+    /// Failed to read from or write to the stream, e.g. because it ended in the
+    /// middle of a frame.
+    Io(E),
+
+    /// The message is larger than `MAX_FRAME_SIZE`.
+    FrameTooLarge(usize),
+
+    /// Failed to encode or decode the message.
+    Cbor(CoseError),
+}
+
+impl<E: fmt::Debug> fmt::Display for FramingError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "Failed to transfer the frame: {e:?}"),
+            Self::FrameTooLarge(size) => {
+                write!(f, "The frame of {size} bytes is larger than {MAX_FRAME_SIZE} bytes")
+            }
+            Self::Cbor(e) => write!(f, "Failed to encode or decode the message: {e}"),
+        }
+    }
+}
+
+/// Reads a request from the host.
+pub fn read_request<R: Read>(reader: &mut R) -> Result<ServiceVmRequest, FramingError<R::Error>> {
+    read_frame(reader)
+}
+
+/// Writes a request to the service VM.
+pub fn write_request<W: Write>(
+    writer: &mut W,
+    request: &ServiceVmRequest,
+) -> Result<(), FramingError<W::Error>> {
+    write_frame(writer, request)
+}
+
+/// Reads a response from the service VM.
+pub fn read_response<R: Read>(reader: &mut R) -> Result<Response, FramingError<R::Error>> {
+    read_frame(reader)
+}
+
+/// Writes a response to the host.
+///
+/// If the response is larger than `MAX_RESPONSE_SIZE`, a
+/// `RequestProcessingError::ResponseTooLarge` error is written instead.
+pub fn write_response<W: Write>(
+    writer: &mut W,
+    response: &Response,
+) -> Result<(), FramingError<W::Error>> {
+    let message = cbor_util::serialize(response).map_err(FramingError::Cbor)?;
+    if message.len() > MAX_RESPONSE_SIZE {
+        warn!("Response {} of {} bytes is too large to be sent", response.name(), message.len());
+        return write_frame(writer, &Response::Err(RequestProcessingError::ResponseTooLarge));
+    }
+    write_encoded_frame(writer, message)
+}
+
+fn read_frame<T: DeserializeOwned, R: Read>(reader: &mut R) -> Result<T, FramingError<R::Error>> {
+    let mut size = [0u8; 4];
+    reader.read_exact(&mut size).map_err(FramingError::Io)?;
+    let size = u32::from_be_bytes(size) as usize;
+    if size > MAX_FRAME_SIZE {
+        return Err(FramingError::FrameTooLarge(size));
+    }
+    let mut message = vec![0u8; size];
+    reader.read_exact(&mut message).map_err(FramingError::Io)?;
+    cbor_util::deserialize(&message).map_err(FramingError::Cbor)
+}
+
+fn write_frame<T: Serialize, W: Write>(
+    writer: &mut W,
+    message: &T,
+) -> Result<(), FramingError<W::Error>> {
+    let message = cbor_util::serialize(message).map_err(FramingError::Cbor)?;
+    write_encoded_frame(writer, message)
+}
+
+fn write_encoded_frame<W: Write>(
+    writer: &mut W,
+    message: Vec<u8>,
+) -> Result<(), FramingError<W::Error>> {
+    if message.len() > MAX_FRAME_SIZE {
+        return Err(FramingError::FrameTooLarge(message.len()));
+    }
+    // The size fits in a u32 as it is not larger than `MAX_FRAME_SIZE`.
+    let size = (message.len() as u32).to_be_bytes();
+    writer.write_all(&size).map_err(FramingError::Io)?;
+    writer.write_all(&message).map_err(FramingError::Io)
+}
@@ -20,12 +20,19 @@
 extern crate alloc;
 
 mod csr;
+mod framing;
 mod message;
 mod vsock;
 
 pub use csr::{Csr, CsrPayload};
+pub use framing::{
+    read_request, read_response, write_request, write_response, FramingError, MAX_FRAME_SIZE,
+    MAX_RESPONSE_SIZE,
+};
 pub use message::{
-    ClientVmAttestationParams, EcdsaP256KeyPair, GenerateCertificateRequestParams, Request,
-    RequestProcessingError, Response, ServiceVmRequest,
+    check_protocol_version, ClientVmAttestationParams, EcdsaP256KeyPair, EcdsaP384KeyPair,
+    GenerateCertificateRequestParams, Request, RequestKind, RequestProcessingError, Response,
+    ServiceVmRequest, MAX_BATCH_KEY_COUNT, MAX_CHALLENGE_SIZE, MAX_PAYLOAD_TO_SIGN_SIZE,
+    PROTOCOL_VERSION,
 };
 pub use vsock::VmType;
@@ -15,6 +15,8 @@
 //! This module contains the requests and responses definitions exchanged
 //! between the host and the service VM.
 
+use crate::framing::MAX_RESPONSE_SIZE;
+use alloc::string::String;
 use alloc::vec::Vec;
 use core::fmt;
 use log::error;
@@ -22,6 +24,29 @@ use serde::{Deserialize, Serialize};
 
 type MacedPublicKey = Vec<u8>;
 
+/// The version of the protocol defined in this module, reported by the service VM in
+/// `Response::Version`.
+///
+/// The compatibility rules are as follows:
+///
+/// - The version must be incremented whenever a request, response or error variant is added or
+///   the contents of an existing one change. Variants are serialized by name, so existing variants
+///   must never be renamed.
+/// - The host may talk to a service VM implementing the same or an older version, as long as it
+///   only sends requests that exist in that version.
+/// - The host must reject a service VM implementing a newer version, as the responses of that VM
+///   may not be understood. See `check_protocol_version`.
+pub const PROTOCOL_VERSION: u32 = 12;
+
+/// The maximum size in bytes of the payload in `Request::SignWithAttestationKey`.
+pub const MAX_PAYLOAD_TO_SIGN_SIZE: usize = 4096;
+
+/// The maximum size in bytes of the challenge included in a CSR.
+pub const MAX_CHALLENGE_SIZE: usize = 64;
+
+/// The maximum number of keys generated by `Request::GenerateAndCertifyBatch`.
+pub const MAX_BATCH_KEY_COUNT: u32 = 16;
+
 /// The main request type to be sent to the service VM.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ServiceVmRequest {
@@ -54,6 +79,106 @@ pub enum Request {
     /// Requests the service VM to attest the client VM and issue a certificate
     /// if the attestation succeeds.
     RequestClientVmAttestation(ClientVmAttestationParams),
+
+    /// Retrieves the most recent events logged by the service VM, to help
+    /// diagnose failures of previous requests.
+    ///
+    /// The service VM only keeps a bounded number of recent events, so fewer
+    /// than `max_entries` events may be returned.
+    GetEventLog {
+        /// The maximum number of events to return.
+        max_entries: u32,
+    },
+
+    /// Signs the provided payload with the attestation key of the service VM,
+    /// i.e. the CDI_Leaf_Priv of its DICE chain.
+    ///
+    /// The payload must not be larger than `MAX_PAYLOAD_TO_SIGN_SIZE` bytes.
+    SignWithAttestationKey {
+        /// The payload to sign.
+        payload: Vec<u8>,
+    },
+
+    /// Generates `count` new ECDSA P-256 key pairs and a single certificate
+    /// signing request covering all of them, saving a round-trip per key.
+    ///
+    /// `count` must be between 1 and `MAX_BATCH_KEY_COUNT` and the challenge
+    /// must not be larger than `MAX_CHALLENGE_SIZE` bytes.
+    GenerateAndCertifyBatch {
+        /// The number of key pairs to generate.
+        count: u32,
+
+        /// The challenge from the provisioning server to be included in the
+        /// signed data of the CSR.
+        challenge: Vec<u8>,
+    },
+
+    /// Generates a new ECDSA P-384 key pair that can be attested by the remote
+    /// server.
+    GenerateEcdsaP384KeyPair,
+
+    /// Retrieves the version of the protocol implemented by the service VM.
+    GetVersion,
+
+    /// Restricts the requests the service VM accepts for the remainder of the
+    /// session to the given kinds. Other requests are rejected with
+    /// `RequestProcessingError::OperationNotPermitted`.
+    ///
+    /// Once set, the policy can only be tightened: a subsequent policy must not
+    /// allow any kind that the current one doesn't. Further tightening is only
+    /// possible if `RequestKind::SetSessionPolicy` remains allowed.
+    SetSessionPolicy {
+        /// The kinds of requests allowed for the remainder of the session.
+        allowed: Vec<RequestKind>,
+    },
+
+    /// Processes the given requests in order, saving a round-trip per request.
+    ///
+    /// The response is a `Response::Batch` with one response per request, in
+    /// the same order. A failure of one request doesn't prevent the following
+    /// ones from being processed. Batches cannot be nested.
+    Batch(Vec<Request>),
+
+    /// Verifies a certificate chain inside the service VM, so that the result
+    /// doesn't depend on the integrity of the host.
+    ///
+    /// The chain is a CBOR-encoded DICE certificate chain as defined by
+    /// `DiceCertChain` in generateCertificateRequestV2.cddl, and the trust
+    /// anchor is the CBOR-encoded COSE_Key the chain must be rooted in.
+    VerifyCertChain {
+        /// The certificate chain to verify.
+        chain: Vec<u8>,
+
+        /// The public key the chain must be rooted in.
+        trust_anchor: Vec<u8>,
+    },
+
+    /// Deletes the key whose blob was returned by `Request::GenerateEcdsaP256KeyPair`,
+    /// `Request::GenerateEcdsaP384KeyPair` or `Request::GenerateAndCertifyBatch`.
+    ///
+    /// The service VM doesn't keep the keys it generates: the private key only
+    /// exists encrypted in the key blob, under a key derived from the sealing
+    /// CDI of the service VM. The request therefore only checks that the blob
+    /// was issued by this service VM, and the host must then destroy its copy
+    /// of the blob, which makes the key unusable.
+    ///
+    /// A blob that wasn't issued by this service VM is rejected with
+    /// `RequestProcessingError::NoSuchKey`. The request is idempotent: deleting
+    /// the same blob again succeeds.
+    DeleteKey(Vec<u8>),
+
+    /// Runs known-answer and sign/verify tests of the cryptographic primitives
+    /// used by the service VM, to check that they work without requiring any
+    /// provisioned key.
+    SelfTest,
+
+    /// Retrieves the time elapsed since the service VM booted, as measured by
+    /// its monotonic clock.
+    ///
+    /// This is not wall-clock time: it only orders and spaces events within
+    /// the same run of the service VM, and can't be compared with the time of
+    /// the host or of another VM.
+    GetUptime,
 }
 
 impl Request {
@@ -64,8 +189,76 @@ impl Request {
             Self::GenerateEcdsaP256KeyPair => "GenerateEcdsaP256KeyPair",
             Self::GenerateCertificateRequest(_) => "GenerateCertificateRequest",
             Self::RequestClientVmAttestation(_) => "RequestClientVmAttestation",
+            Self::GetEventLog { .. } => "GetEventLog",
+            Self::SignWithAttestationKey { .. } => "SignWithAttestationKey",
+            Self::GenerateAndCertifyBatch { .. } => "GenerateAndCertifyBatch",
+            Self::GenerateEcdsaP384KeyPair => "GenerateEcdsaP384KeyPair",
+            Self::GetVersion => "GetVersion",
+            Self::SetSessionPolicy { .. } => "SetSessionPolicy",
+            Self::Batch(_) => "Batch",
+            Self::VerifyCertChain { .. } => "VerifyCertChain",
+            Self::DeleteKey(_) => "DeleteKey",
+            Self::SelfTest => "SelfTest",
+            Self::GetUptime => "GetUptime",
         }
     }
+
+    /// Returns the kind of the request.
+    pub fn kind(&self) -> RequestKind {
+        match self {
+            Self::Reverse(_) => RequestKind::Reverse,
+            Self::GenerateEcdsaP256KeyPair => RequestKind::GenerateEcdsaP256KeyPair,
+            Self::GenerateCertificateRequest(_) => RequestKind::GenerateCertificateRequest,
+            Self::RequestClientVmAttestation(_) => RequestKind::RequestClientVmAttestation,
+            Self::GetEventLog { .. } => RequestKind::GetEventLog,
+            Self::SignWithAttestationKey { .. } => RequestKind::SignWithAttestationKey,
+            Self::GenerateAndCertifyBatch { .. } => RequestKind::GenerateAndCertifyBatch,
+            Self::GenerateEcdsaP384KeyPair => RequestKind::GenerateEcdsaP384KeyPair,
+            Self::GetVersion => RequestKind::GetVersion,
+            Self::SetSessionPolicy { .. } => RequestKind::SetSessionPolicy,
+            Self::Batch(_) => RequestKind::Batch,
+            Self::VerifyCertChain { .. } => RequestKind::VerifyCertChain,
+            Self::DeleteKey(_) => RequestKind::DeleteKey,
+            Self::SelfTest => RequestKind::SelfTest,
+            Self::GetUptime => RequestKind::GetUptime,
+        }
+    }
+}
+
+/// The kind of a `Request`, without its parameters. Used to express the
+/// requests allowed by `Request::SetSessionPolicy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RequestKind {
+    /// `Request::Reverse`.
+    Reverse,
+    /// `Request::GenerateEcdsaP256KeyPair`.
+    GenerateEcdsaP256KeyPair,
+    /// `Request::GenerateCertificateRequest`.
+    GenerateCertificateRequest,
+    /// `Request::RequestClientVmAttestation`.
+    RequestClientVmAttestation,
+    /// `Request::GetEventLog`.
+    GetEventLog,
+    /// `Request::SignWithAttestationKey`.
+    SignWithAttestationKey,
+    /// `Request::GenerateAndCertifyBatch`.
+    GenerateAndCertifyBatch,
+    /// `Request::GenerateEcdsaP384KeyPair`.
+    GenerateEcdsaP384KeyPair,
+    /// `Request::GetVersion`.
+    GetVersion,
+    /// `Request::SetSessionPolicy`.
+    SetSessionPolicy,
+    /// `Request::Batch`.
+    Batch,
+    /// `Request::VerifyCertChain`.
+    VerifyCertChain,
+    /// `Request::DeleteKey`.
+    DeleteKey,
+    /// `Request::SelfTest`.
+    SelfTest,
+    /// `Request::GetUptime`.
+    GetUptime,
 }
 
 /// Represents the params passed to `Request::RequestClientVmAttestation`.
@@ -106,6 +299,70 @@ pub enum Response {
     /// includes an extension that describes the attested client VM.
     RequestClientVmAttestation(Vec<u8>),
 
+    /// Returns the most recent events logged by the service VM, oldest first.
+    EventLog(Vec<String>),
+
+    /// Returns the signature over the payload in `Request::SignWithAttestationKey`.
+    AttestationSignature {
+        /// The serialized COSE_Sign1 of the payload, signed with the CDI_Leaf_Priv of the
+        /// service VM. Its protected header has the content type
+        /// "application/vnd.android.avf.host-payload" and the external AAD is
+        /// "AVF service VM host payload signature", so it can't be mistaken for a CSR.
+        signature: Vec<u8>,
+
+        /// The DICE chain of the service VM, whose leaf public key can be used to
+        /// verify the signature.
+        cert_chain: Vec<u8>,
+    },
+
+    /// Returns the new ECDSA P-256 key pairs and a CBOR Certificate Signing
+    /// Request (Csr) covering their public keys, in the same order.
+    GenerateAndCertifyBatch {
+        /// The new key pairs.
+        key_pairs: Vec<EcdsaP256KeyPair>,
+
+        /// The CSR serialized into a byte array.
+        csr: Vec<u8>,
+    },
+
+    /// Returns the new ECDSA P-384 key pair.
+    GenerateEcdsaP384KeyPair(EcdsaP384KeyPair),
+
+    /// Returns the version of the protocol implemented by the service VM.
+    Version(u32),
+
+    /// The session policy in `Request::SetSessionPolicy` is now in effect.
+    SetSessionPolicy,
+
+    /// Returns the responses to the requests in `Request::Batch`, in order.
+    Batch(Vec<Response>),
+
+    /// Returns the result of the verification in `Request::VerifyCertChain`.
+    VerifyCertChain {
+        /// Whether the chain is valid and rooted in the trust anchor.
+        valid: bool,
+
+        /// Why the chain is invalid, if it is.
+        reason: Option<String>,
+    },
+
+    /// The key blob in `Request::DeleteKey` was issued by the service VM and can
+    /// be destroyed.
+    DeleteKey,
+
+    /// Returns the result of the tests run for `Request::SelfTest`.
+    SelfTest {
+        /// Whether all the tests passed.
+        ok: bool,
+
+        /// The outcome of each test, in the order they were run.
+        details: Vec<String>,
+    },
+
+    /// Returns the milliseconds elapsed since the service VM booted, for
+    /// `Request::GetUptime`. This is not wall-clock time.
+    Uptime(u64),
+
     /// Encountered an error during the request processing.
     Err(RequestProcessingError),
 }
@@ -118,6 +375,17 @@ impl Response {
             Self::GenerateEcdsaP256KeyPair(_) => "GenerateEcdsaP256KeyPair",
             Self::GenerateCertificateRequest(_) => "GenerateCertificateRequest",
             Self::RequestClientVmAttestation(_) => "RequestClientVmAttestation",
+            Self::EventLog(_) => "EventLog",
+            Self::AttestationSignature { .. } => "AttestationSignature",
+            Self::GenerateAndCertifyBatch { .. } => "GenerateAndCertifyBatch",
+            Self::GenerateEcdsaP384KeyPair(_) => "GenerateEcdsaP384KeyPair",
+            Self::Version(_) => "Version",
+            Self::SetSessionPolicy => "SetSessionPolicy",
+            Self::Batch(_) => "Batch",
+            Self::VerifyCertChain { .. } => "VerifyCertChain",
+            Self::DeleteKey => "DeleteKey",
+            Self::SelfTest { .. } => "SelfTest",
+            Self::Uptime(_) => "Uptime",
             Self::Err(_) => "Err",
         }
     }
@@ -164,6 +432,40 @@ pub enum RequestProcessingError {
 
     /// The vendor partition loaded by the client VM is invalid.
     InvalidVendorPartition,
+
+    /// The payload to sign is larger than `MAX_PAYLOAD_TO_SIGN_SIZE`.
+    PayloadTooLarge,
+
+    /// The service VM has no attestation key to sign with.
+    MissingAttestationKey,
+
+    /// The challenge is larger than `MAX_CHALLENGE_SIZE`.
+    InvalidChallengeSize,
+
+    /// The number of keys to generate is zero or larger than `MAX_BATCH_KEY_COUNT`.
+    InvalidBatchKeyCount,
+
+    /// The peer implements a newer version of the protocol than `PROTOCOL_VERSION`.
+    IncompatibleProtocolVersion(u32),
+
+    /// The request is not allowed by the policy of the current session.
+    OperationNotPermitted,
+
+    /// The new session policy allows a request kind that the current one doesn't.
+    SessionPolicyLoosened,
+
+    /// A `Request::Batch` contains another batch.
+    NestedBatch,
+
+    /// A key to sign is neither an EC2 P-256 public key for ES256 nor an EC2 P-384 public key
+    /// for ES384.
+    InvalidPublicKey,
+
+    /// The key blob to delete wasn't issued by the service VM.
+    NoSuchKey,
+
+    /// The response is larger than `MAX_RESPONSE_SIZE`, so it wasn't sent.
+    ResponseTooLarge,
 }
 
 impl fmt::Display for RequestProcessingError {
@@ -198,10 +500,55 @@ impl fmt::Display for RequestProcessingError {
             Self::InvalidVendorPartition => {
                 write!(f, "The vendor partition loaded by the client VM is invalid")
             }
+            Self::PayloadTooLarge => {
+                write!(f, "The payload to sign is larger than {MAX_PAYLOAD_TO_SIGN_SIZE} bytes")
+            }
+            Self::MissingAttestationKey => {
+                write!(f, "The service VM has no attestation key to sign with")
+            }
+            Self::InvalidChallengeSize => {
+                write!(f, "The challenge is larger than {MAX_CHALLENGE_SIZE} bytes")
+            }
+            Self::InvalidBatchKeyCount => {
+                write!(
+                    f,
+                    "The number of keys to generate must be between 1 and {MAX_BATCH_KEY_COUNT}"
+                )
+            }
+            Self::IncompatibleProtocolVersion(version) => {
+                write!(
+                    f,
+                    "The peer implements protocol version {version}, which is newer than the \
+                     supported version {PROTOCOL_VERSION}"
+                )
+            }
+            Self::OperationNotPermitted => {
+                write!(f, "The request is not allowed by the policy of the current session")
+            }
+            Self::SessionPolicyLoosened => {
+                write!(f, "The session policy can only be tightened, not loosened")
+            }
+            Self::NestedBatch => write!(f, "Batches of requests cannot be nested"),
+            Self::InvalidPublicKey => {
+                write!(f, "A key to sign is not an EC2 P-256 or P-384 public key for ECDSA")
+            }
+            Self::NoSuchKey => write!(f, "The key blob wasn't issued by the service VM"),
+            Self::ResponseTooLarge => {
+                write!(f, "The response is larger than {MAX_RESPONSE_SIZE} bytes")
+            }
         }
     }
 }
 
+/// Checks that the protocol version reported by the peer can be understood, i.e. that it is not
+/// newer than `PROTOCOL_VERSION`.
+pub fn check_protocol_version(version: u32) -> Result<(), RequestProcessingError> {
+    if version > PROTOCOL_VERSION {
+        return Err(RequestProcessingError::IncompatibleProtocolVersion(version));
+    }
+    Ok(())
+}
+
 impl From<bssl_avf_error::Error> for RequestProcessingError {
     fn from(e: bssl_avf_error::Error) -> Self {
         Self::BoringSslError(e)
@@ -238,10 +585,22 @@ pub struct GenerateCertificateRequestParams {
 
     /// challenge contains a byte strong from the provisioning server which will be
     /// included in the signed data of the CSR structure.
-    /// The supported sizes is between 0 and 64 bytes, inclusive.
+    /// The supported sizes is between 0 and `MAX_CHALLENGE_SIZE` bytes, inclusive.
     pub challenge: Vec<u8>,
 }
 
+impl GenerateCertificateRequestParams {
+    /// Checks that the params are within the supported bounds, so that invalid
+    /// params are rejected before any CSR is built.
+    pub fn validate(&self) -> Result<(), RequestProcessingError> {
+        if self.challenge.len() > MAX_CHALLENGE_SIZE {
+            error!("The challenge has {} bytes", self.challenge.len());
+            return Err(RequestProcessingError::InvalidChallengeSize);
+        }
+        Ok(())
+    }
+}
+
 /// Represents an ECDSA P-256 key pair.
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct EcdsaP256KeyPair {
@@ -253,3 +612,15 @@ pub struct EcdsaP256KeyPair {
     /// Contains a handle to the private key.
     pub key_blob: Vec<u8>,
 }
+
+/// Represents an ECDSA P-384 key pair.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EcdsaP384KeyPair {
+    /// Contains a CBOR-encoded public key specified in:
+    ///
+    /// hardware/interfaces/security/rkp/aidl/android/hardware/security/keymint/MacedPublicKey.aidl
+    pub maced_public_key: MacedPublicKey,
+
+    /// Contains a handle to the private key.
+    pub key_blob: Vec<u8>,
+}
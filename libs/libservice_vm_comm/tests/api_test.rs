@@ -15,7 +15,12 @@
  */
 
 use diced_open_dice::DiceArtifacts;
-use service_vm_comm::{Csr, CsrPayload};
+use service_vm_comm::{
+    check_protocol_version, read_request, read_response, write_request, write_response, Csr,
+    CsrPayload, EcdsaP384KeyPair, FramingError, GenerateCertificateRequestParams, Request,
+    RequestProcessingError, Response, ServiceVmRequest, MAX_CHALLENGE_SIZE, MAX_FRAME_SIZE,
+    MAX_RESPONSE_SIZE, PROTOCOL_VERSION,
+};
 
 /// The following test data are generated with urandom
 const DATA1: [u8; 32] = [
@@ -47,3 +52,225 @@ fn csr_cbor_serialization() {
 
     assert_eq!(expected_csr, deserialized_csr);
 }
+
+#[test]
+fn sign_with_attestation_key_request_cbor_serialization() {
+    let request = Request::SignWithAttestationKey { payload: DATA1.to_vec() };
+    let mut cbor_vec = Vec::new();
+    ciborium::into_writer(&request, &mut cbor_vec).unwrap();
+    let deserialized_request: Request = ciborium::from_reader(cbor_vec.as_slice()).unwrap();
+
+    match deserialized_request {
+        Request::SignWithAttestationKey { payload } => assert_eq!(DATA1.to_vec(), payload),
+        _ => panic!("Unexpected request: {deserialized_request:?}"),
+    }
+}
+
+#[test]
+fn attestation_signature_response_cbor_serialization() {
+    let response =
+        Response::AttestationSignature { signature: DATA1.to_vec(), cert_chain: DATA2.to_vec() };
+    let expected_response = response.clone();
+    let mut cbor_vec = Vec::new();
+    ciborium::into_writer(&response, &mut cbor_vec).unwrap();
+    let deserialized_response: Response = ciborium::from_reader(cbor_vec.as_slice()).unwrap();
+
+    assert_eq!(expected_response, deserialized_response);
+}
+
+#[test]
+fn generate_ecdsa_p384_key_pair_cbor_serialization() {
+    let request = Request::GenerateEcdsaP384KeyPair;
+    let mut cbor_vec = Vec::new();
+    ciborium::into_writer(&request, &mut cbor_vec).unwrap();
+    let deserialized_request: Request = ciborium::from_reader(cbor_vec.as_slice()).unwrap();
+    assert!(matches!(deserialized_request, Request::GenerateEcdsaP384KeyPair));
+
+    let key_pair = EcdsaP384KeyPair { maced_public_key: DATA1.to_vec(), key_blob: DATA2.to_vec() };
+    let response = Response::GenerateEcdsaP384KeyPair(key_pair);
+    let expected_response = response.clone();
+    let mut cbor_vec = Vec::new();
+    ciborium::into_writer(&response, &mut cbor_vec).unwrap();
+    let deserialized_response: Response = ciborium::from_reader(cbor_vec.as_slice()).unwrap();
+    assert_eq!(expected_response, deserialized_response);
+}
+
+#[test]
+fn version_response_cbor_serialization() {
+    let response = Response::Version(PROTOCOL_VERSION);
+    let mut cbor_vec = Vec::new();
+    ciborium::into_writer(&response, &mut cbor_vec).unwrap();
+    let deserialized_response: Response = ciborium::from_reader(cbor_vec.as_slice()).unwrap();
+
+    assert_eq!(Response::Version(PROTOCOL_VERSION), deserialized_response);
+}
+
+#[test]
+fn same_or_older_protocol_version_is_accepted() {
+    assert_eq!(Ok(()), check_protocol_version(PROTOCOL_VERSION));
+    assert_eq!(Ok(()), check_protocol_version(PROTOCOL_VERSION - 1));
+}
+
+#[test]
+fn newer_protocol_version_is_rejected() {
+    let newer_version = PROTOCOL_VERSION + 1;
+
+    assert_eq!(
+        Err(RequestProcessingError::IncompatibleProtocolVersion(newer_version)),
+        check_protocol_version(newer_version)
+    );
+}
+
+#[test]
+fn verify_cert_chain_cbor_serialization() {
+    let request = Request::VerifyCertChain { chain: DATA1.to_vec(), trust_anchor: DATA2.to_vec() };
+    let mut cbor_vec = Vec::new();
+    ciborium::into_writer(&request, &mut cbor_vec).unwrap();
+    let deserialized_request: Request = ciborium::from_reader(cbor_vec.as_slice()).unwrap();
+
+    match deserialized_request {
+        Request::VerifyCertChain { chain, trust_anchor } => {
+            assert_eq!(DATA1.to_vec(), chain);
+            assert_eq!(DATA2.to_vec(), trust_anchor);
+        }
+        _ => panic!("Unexpected request: {deserialized_request:?}"),
+    }
+
+    for response in [
+        Response::VerifyCertChain { valid: true, reason: None },
+        Response::VerifyCertChain { valid: false, reason: Some("Invalid certificate 0".into()) },
+    ] {
+        let mut cbor_vec = Vec::new();
+        ciborium::into_writer(&response, &mut cbor_vec).unwrap();
+        let deserialized_response: Response = ciborium::from_reader(cbor_vec.as_slice()).unwrap();
+        assert_eq!(response, deserialized_response);
+    }
+}
+
+#[test]
+fn delete_key_cbor_serialization() {
+    let request = Request::DeleteKey(DATA1.to_vec());
+    let mut cbor_vec = Vec::new();
+    ciborium::into_writer(&request, &mut cbor_vec).unwrap();
+    let deserialized_request: Request = ciborium::from_reader(cbor_vec.as_slice()).unwrap();
+
+    match deserialized_request {
+        Request::DeleteKey(key_blob) => assert_eq!(DATA1.to_vec(), key_blob),
+        _ => panic!("Unexpected request: {deserialized_request:?}"),
+    }
+
+    for response in [Response::DeleteKey, Response::Err(RequestProcessingError::NoSuchKey)] {
+        let mut cbor_vec = Vec::new();
+        ciborium::into_writer(&response, &mut cbor_vec).unwrap();
+        let deserialized_response: Response = ciborium::from_reader(cbor_vec.as_slice()).unwrap();
+        assert_eq!(response, deserialized_response);
+    }
+}
+
+#[test]
+fn self_test_cbor_serialization() {
+    let mut cbor_vec = Vec::new();
+    ciborium::into_writer(&Request::SelfTest, &mut cbor_vec).unwrap();
+    let deserialized_request: Request = ciborium::from_reader(cbor_vec.as_slice()).unwrap();
+    assert!(matches!(deserialized_request, Request::SelfTest));
+
+    let response = Response::SelfTest {
+        ok: false,
+        details: vec!["HMAC-SHA256: passed".into(), "ECDSA P-256: failed".into()],
+    };
+    let mut cbor_vec = Vec::new();
+    ciborium::into_writer(&response, &mut cbor_vec).unwrap();
+    let deserialized_response: Response = ciborium::from_reader(cbor_vec.as_slice()).unwrap();
+    assert_eq!(response, deserialized_response);
+}
+
+#[test]
+fn uptime_cbor_serialization() {
+    let mut cbor_vec = Vec::new();
+    ciborium::into_writer(&Request::GetUptime, &mut cbor_vec).unwrap();
+    let deserialized_request: Request = ciborium::from_reader(cbor_vec.as_slice()).unwrap();
+    assert!(matches!(deserialized_request, Request::GetUptime));
+
+    for response in [Response::Uptime(0), Response::Uptime(123_456), Response::Uptime(u64::MAX)] {
+        let mut cbor_vec = Vec::new();
+        ciborium::into_writer(&response, &mut cbor_vec).unwrap();
+        let deserialized_response: Response = ciborium::from_reader(cbor_vec.as_slice()).unwrap();
+        assert_eq!(response, deserialized_response);
+    }
+}
+
+#[test]
+fn framed_messages_round_trip() {
+    let mut stream = Vec::new();
+    write_request(&mut stream, &ServiceVmRequest::Process(Request::Reverse(DATA1.to_vec())))
+        .unwrap();
+    write_request(&mut stream, &ServiceVmRequest::Shutdown).unwrap();
+    let response = Response::Reverse(DATA2.to_vec());
+    write_response(&mut stream, &response).unwrap();
+
+    let mut reader = stream.as_slice();
+    match read_request(&mut reader).unwrap() {
+        ServiceVmRequest::Process(Request::Reverse(data)) => assert_eq!(DATA1.to_vec(), data),
+        request => panic!("Unexpected request: {request:?}"),
+    }
+    assert!(matches!(read_request(&mut reader).unwrap(), ServiceVmRequest::Shutdown));
+    assert_eq!(response, read_response(&mut reader).unwrap());
+    assert!(reader.is_empty());
+}
+
+#[test]
+fn truncated_frame_is_rejected() {
+    let mut stream = Vec::new();
+    write_response(&mut stream, &Response::Reverse(DATA1.to_vec())).unwrap();
+
+    for len in [0, 2, stream.len() - 1] {
+        let mut reader = &stream[..len];
+        assert!(matches!(read_response(&mut reader), Err(FramingError::Io(_))), "len: {len}");
+    }
+}
+
+#[test]
+fn oversized_frame_is_rejected() {
+    let size = MAX_FRAME_SIZE + 1;
+    let mut stream = (size as u32).to_be_bytes().to_vec();
+    stream.resize(4 + size, 0);
+
+    let mut reader = stream.as_slice();
+    assert!(matches!(read_request(&mut reader), Err(FramingError::FrameTooLarge(s)) if s == size));
+
+    let request = ServiceVmRequest::Process(Request::Reverse(vec![0xab; MAX_FRAME_SIZE]));
+    assert!(matches!(
+        write_request(&mut Vec::new(), &request),
+        Err(FramingError::FrameTooLarge(_))
+    ));
+}
+
+#[test]
+fn oversized_response_is_replaced_with_error() {
+    let csr = vec![0xab; MAX_RESPONSE_SIZE];
+    let mut stream = Vec::new();
+    write_response(&mut stream, &Response::GenerateCertificateRequest(csr)).unwrap();
+
+    assert!(stream.len() - 4 <= MAX_RESPONSE_SIZE);
+    let mut reader = stream.as_slice();
+    assert_eq!(
+        Response::Err(RequestProcessingError::ResponseTooLarge),
+        read_response(&mut reader).unwrap()
+    );
+    assert!(reader.is_empty());
+}
+
+#[test]
+fn challenge_size_is_validated() {
+    let params = |size| GenerateCertificateRequestParams {
+        keys_to_sign: vec![DATA1.to_vec()],
+        challenge: vec![0x5a; size],
+    };
+
+    assert_eq!(Ok(()), params(0).validate());
+    assert_eq!(Ok(()), params(MAX_CHALLENGE_SIZE).validate());
+    assert_eq!(
+        Err(RequestProcessingError::InvalidChallengeSize),
+        params(MAX_CHALLENGE_SIZE + 1).validate()
+    );
+}
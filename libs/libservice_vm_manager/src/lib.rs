@@ -24,9 +24,12 @@ use android_system_virtualizationservice::{
     },
     binder::ParcelFileDescriptor,
 };
-use anyhow::{anyhow, ensure, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use log::{info, warn};
-use service_vm_comm::{Request, Response, ServiceVmRequest, VmType};
+use service_vm_comm::{
+    check_protocol_version, read_response, write_request, Request, Response, ServiceVmRequest,
+    VmType,
+};
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
@@ -168,7 +171,22 @@ impl ServiceVm {
         vsock_stream.set_read_timeout(Some(READ_TIMEOUT))?;
         vsock_stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
 
-        Ok(Self { vsock_stream, vm })
+        let mut service_vm = Self { vsock_stream, vm };
+        service_vm.check_protocol_version()?;
+        Ok(service_vm)
+    }
+
+    /// Checks that the service VM doesn't implement a newer version of the protocol than the
+    /// host, as its responses might otherwise be misinterpreted.
+    fn check_protocol_version(&mut self) -> Result<()> {
+        match self.process_request(Request::GetVersion)? {
+            Response::Version(version) => {
+                check_protocol_version(version).map_err(|e| anyhow!("{e}"))?;
+                info!("Service VM implements protocol version {version}");
+                Ok(())
+            }
+            response => bail!("Unexpected response to GetVersion: {}", response.name()),
+        }
     }
 
     /// Processes the request in the service VM.
@@ -180,7 +198,7 @@ impl ServiceVm {
     /// Sends the request to the service VM.
     fn write_request(&mut self, request: &ServiceVmRequest) -> Result<()> {
         let mut buffer = BufWriter::with_capacity(WRITE_BUFFER_CAPACITY, &mut self.vsock_stream);
-        ciborium::into_writer(request, &mut buffer)?;
+        write_request(&mut buffer, request).map_err(|e| anyhow!("{e}"))?;
         buffer.flush().context("Failed to flush the buffer")?;
         info!("Sent request to the service VM.");
         Ok(())
@@ -188,7 +206,8 @@ impl ServiceVm {
 
     /// Reads the response from the service VM.
     fn read_response(&mut self) -> Result<Response> {
-        let response: Response = ciborium::from_reader(&mut self.vsock_stream)
+        let response = read_response(&mut self.vsock_stream)
+            .map_err(|e| anyhow!("{e}"))
             .context("Failed to read the response from the service VM")?;
         info!("Received response from the service VM.");
         Ok(response)
@@ -290,6 +309,7 @@ fn instance_img(
         &instance_img,
         INSTANCE_IMG_SIZE_BYTES,
         PartitionType::ANDROID_VM_INSTANCE,
+        /* force= */ false,
     )?;
     Ok(instance_img)
 }
@@ -14,15 +14,22 @@
 
 //! This module contains the main API for the request processing module.
 
+use crate::cert_chain;
 use crate::client_vm;
+use crate::event_log::EventLog;
 use crate::rkp;
+use crate::self_test;
+use crate::session_policy::SessionPolicy;
 use alloc::vec::Vec;
 use diced_open_dice::DiceArtifacts;
-use service_vm_comm::{Request, Response};
+use service_vm_comm::{Request, RequestProcessingError, Response, PROTOCOL_VERSION};
 
 /// Processes a request and returns the corresponding response.
 /// This function serves as the entry point for the request processing module.
-pub fn process_request(request: Request, context: &RequestContext) -> Response {
+pub fn process_request(request: Request, context: &mut RequestContext) -> Response {
+    if let Err(e) = context.session_policy.check(request.kind()) {
+        return Response::Err(e);
+    }
     match request {
         Request::Reverse(v) => Response::Reverse(reverse(v)),
         Request::GenerateEcdsaP256KeyPair => {
@@ -39,7 +46,51 @@ pub fn process_request(request: Request, context: &RequestContext) -> Response {
             context.vendor_hashtree_root_digest,
         )
         .map_or_else(Response::Err, Response::RequestClientVmAttestation),
+        Request::GetEventLog { max_entries } => {
+            Response::EventLog(context.event_log.recent(max_entries))
+        }
+        Request::SignWithAttestationKey { payload } => {
+            rkp::sign_with_attestation_key(&payload, context.dice_artifacts)
+                .map_or_else(Response::Err, |(signature, cert_chain)| {
+                    Response::AttestationSignature { signature, cert_chain }
+                })
+        }
+        Request::GenerateAndCertifyBatch { count, challenge } => {
+            rkp::generate_and_certify_batch(count, challenge, context.dice_artifacts).map_or_else(
+                Response::Err,
+                |(key_pairs, csr)| Response::GenerateAndCertifyBatch { key_pairs, csr },
+            )
+        }
+        Request::GenerateEcdsaP384KeyPair => {
+            rkp::generate_ecdsa_p384_key_pair(context.dice_artifacts)
+                .map_or_else(Response::Err, Response::GenerateEcdsaP384KeyPair)
+        }
+        Request::GetVersion => Response::Version(PROTOCOL_VERSION),
+        Request::SetSessionPolicy { allowed } => context
+            .session_policy
+            .restrict(allowed)
+            .map_or_else(Response::Err, |()| Response::SetSessionPolicy),
+        Request::Batch(requests) => process_batch(requests, context),
+        Request::VerifyCertChain { chain, trust_anchor } => cert_chain::verify_cert_chain(
+            &chain,
+            &trust_anchor,
+        )
+        .map_or_else(Response::Err, |(valid, reason)| Response::VerifyCertChain { valid, reason }),
+        Request::DeleteKey(key_blob) => rkp::delete_key(&key_blob, context.dice_artifacts)
+            .map_or_else(Response::Err, |()| Response::DeleteKey),
+        Request::SelfTest => {
+            let (ok, details) = self_test::run();
+            Response::SelfTest { ok, details }
+        }
+        Request::GetUptime => Response::Uptime(context.clock.uptime_ms()),
+    }
+}
+
+fn process_batch(requests: Vec<Request>, context: &mut RequestContext) -> Response {
+    if requests.iter().any(|request| matches!(request, Request::Batch(_))) {
+        return Response::Err(RequestProcessingError::NestedBatch);
     }
+    Response::Batch(requests.into_iter().map(|request| process_request(request, context)).collect())
 }
 
 /// The context for the request processing.
@@ -51,8 +102,119 @@ pub struct RequestContext<'a> {
 
     /// The reference hash tree root digest of the vendor partition if exists.
     pub vendor_hashtree_root_digest: Option<&'a [u8]>,
+
+    /// The log of recent events in the service VM.
+    pub event_log: &'a EventLog,
+
+    /// The policy restricting the requests allowed in the current session.
+    pub session_policy: &'a mut SessionPolicy,
+
+    /// The monotonic clock of the service VM.
+    pub clock: &'a dyn MonotonicClock,
+}
+
+/// A monotonic clock, started when the service VM booted.
+pub trait MonotonicClock {
+    /// Returns the milliseconds elapsed since the service VM booted. This is not wall-clock time,
+    /// and never goes backwards.
+    fn uptime_ms(&self) -> u64;
 }
 
 fn reverse(payload: Vec<u8>) -> Vec<u8> {
     payload.into_iter().rev().collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use core::cell::Cell;
+    use service_vm_comm::RequestKind;
+
+    /// A clock which advances by one millisecond each time it is read.
+    #[derive(Default)]
+    struct FakeClock(Cell<u64>);
+
+    impl MonotonicClock for FakeClock {
+        fn uptime_ms(&self) -> u64 {
+            self.0.set(self.0.get() + 1);
+            self.0.get()
+        }
+    }
+
+    fn process_request_in_new_session(request: Request) -> Response {
+        let dice_artifacts = diced_sample_inputs::make_sample_bcc_and_cdis().unwrap();
+        let event_log = EventLog::new();
+        let mut session_policy = SessionPolicy::new();
+        let clock = FakeClock::default();
+        let mut context = RequestContext {
+            dice_artifacts: &dice_artifacts,
+            vendor_hashtree_root_digest: None,
+            event_log: &event_log,
+            session_policy: &mut session_policy,
+            clock: &clock,
+        };
+        process_request(request, &mut context)
+    }
+
+    #[test]
+    fn batch_responses_are_in_request_order() {
+        let request = Request::Batch(vec![
+            Request::Reverse(vec![1, 2, 3]),
+            Request::Reverse(vec![]),
+            Request::Reverse(vec![4, 5]),
+        ]);
+
+        let expected_response = Response::Batch(vec![
+            Response::Reverse(vec![3, 2, 1]),
+            Response::Reverse(vec![]),
+            Response::Reverse(vec![5, 4]),
+        ]);
+        assert_eq!(expected_response, process_request_in_new_session(request));
+    }
+
+    #[test]
+    fn batch_contains_per_item_errors() {
+        let request = Request::Batch(vec![
+            Request::SetSessionPolicy { allowed: vec![RequestKind::Batch] },
+            Request::Reverse(vec![1, 2]),
+        ]);
+
+        let expected_response = Response::Batch(vec![
+            Response::SetSessionPolicy,
+            Response::Err(RequestProcessingError::OperationNotPermitted),
+        ]);
+        assert_eq!(expected_response, process_request_in_new_session(request));
+    }
+
+    #[test]
+    fn nested_batch_is_rejected() {
+        let request = Request::Batch(vec![
+            Request::Reverse(vec![1]),
+            Request::Batch(vec![Request::Reverse(vec![2])]),
+        ]);
+
+        assert_eq!(
+            Response::Err(RequestProcessingError::NestedBatch),
+            process_request_in_new_session(request)
+        );
+    }
+
+    #[test]
+    fn uptime_is_read_from_the_clock() {
+        let request = Request::Batch(vec![Request::GetUptime, Request::GetUptime]);
+
+        let Response::Batch(responses) = process_request_in_new_session(request) else {
+            panic!("Expected a batch response");
+        };
+        let uptimes: Vec<u64> = responses
+            .iter()
+            .map(|response| match response {
+                Response::Uptime(uptime_ms) => *uptime_ms,
+                _ => panic!("Unexpected response: {response:?}"),
+            })
+            .collect();
+        assert_eq!(vec![1, 2], uptimes);
+        assert!(uptimes[0] <= uptimes[1], "Uptime went backwards: {uptimes:?}");
+    }
+}
@@ -0,0 +1,145 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module contains the verification of certificate chains requested by the host.
+
+use crate::dice::{DiceChainEntryPayload, PublicKey};
+use alloc::format;
+use alloc::string::String;
+use cbor_util::parse_value_array;
+use core::result;
+use coset::{AsCborValue, CborSerializable, CoseKey};
+use log::info;
+use service_vm_comm::RequestProcessingError;
+
+type Result<T> = result::Result<T, RequestProcessingError>;
+
+/// Verifies that `chain` is a well-formed DICE chain rooted in `trust_anchor`, in which each
+/// certificate is signed by the subject public key of the previous one.
+///
+/// DICE certificates carry no validity period and the service VM has no trusted source of time,
+/// so expiry is not checked.
+///
+/// Returns whether the chain is valid and, if it isn't, why. A malformed trust anchor is reported
+/// as an error instead, as no chain could be verified against it.
+pub(super) fn verify_cert_chain(
+    chain: &[u8],
+    trust_anchor: &[u8],
+) -> Result<(bool, Option<String>)> {
+    let trust_anchor = CoseKey::from_slice(trust_anchor)?;
+    match validate_dice_chain(chain, &trust_anchor) {
+        Ok(()) => Ok((true, None)),
+        Err(reason) => {
+            info!("The certificate chain is invalid: {reason}");
+            Ok((false, Some(reason)))
+        }
+    }
+}
+
+fn validate_dice_chain(chain: &[u8], trust_anchor: &CoseKey) -> result::Result<(), String> {
+    let mut chain = parse_value_array(chain, "DiceCertChain")
+        .map_err(|e| format!("Malformed certificate chain: {e}"))?;
+    if chain.len() < 2 {
+        return Err(String::from("The certificate chain has no certificates"));
+    }
+    let root_public_key = CoseKey::from_cbor_value(chain.remove(0))
+        .map_err(|e| format!("Malformed root public key: {e}"))?;
+    if &root_public_key != trust_anchor {
+        return Err(String::from("The certificate chain is not rooted in the trust anchor"));
+    }
+
+    let mut public_key = PublicKey::try_from(root_public_key)
+        .map_err(|e| format!("Invalid root public key: {e}"))?;
+    for (i, entry) in chain.into_iter().enumerate() {
+        let payload =
+            DiceChainEntryPayload::validate_cose_signature_and_extract_payload(entry, &public_key)
+                .map_err(|e| format!("Invalid certificate {i}: {e}"))?;
+        public_key = payload.subject_public_key;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use bssl_avf::EcKey;
+    use cbor_util::serialize;
+    use ciborium::value::Value;
+    use diced_open_dice::DiceArtifacts;
+
+    fn sample_dice_chain() -> Vec<Value> {
+        let dice_artifacts = diced_sample_inputs::make_sample_bcc_and_cdis().unwrap();
+        parse_value_array(dice_artifacts.bcc().unwrap(), "sample DICE chain").unwrap()
+    }
+
+    fn to_cbor(chain: Vec<Value>) -> Vec<u8> {
+        serialize(&Value::Array(chain)).unwrap()
+    }
+
+    #[test]
+    fn valid_chain_is_verified() {
+        let chain = sample_dice_chain();
+        let trust_anchor = serialize(&chain[0]).unwrap();
+
+        assert_eq!((true, None), verify_cert_chain(&to_cbor(chain), &trust_anchor).unwrap());
+    }
+
+    #[test]
+    fn broken_chain_is_rejected() {
+        let mut chain = sample_dice_chain();
+        let trust_anchor = serialize(&chain[0]).unwrap();
+        // The second certificate isn't signed by the root public key.
+        chain.remove(1);
+
+        let (valid, reason) = verify_cert_chain(&to_cbor(chain), &trust_anchor).unwrap();
+        assert!(!valid);
+        assert!(reason.unwrap().starts_with("Invalid certificate 0"));
+    }
+
+    #[test]
+    fn chain_not_rooted_in_trust_anchor_is_rejected() {
+        let chain = sample_dice_chain();
+        let mut ec_key = EcKey::new_p256().unwrap();
+        ec_key.generate_key().unwrap();
+        let trust_anchor = ec_key.cose_public_key().unwrap().to_vec().unwrap();
+
+        let (valid, reason) = verify_cert_chain(&to_cbor(chain), &trust_anchor).unwrap();
+        assert!(!valid);
+        assert_eq!(
+            Some("The certificate chain is not rooted in the trust anchor"),
+            reason.as_deref()
+        );
+    }
+
+    #[test]
+    fn malformed_chain_is_rejected() {
+        let chain = sample_dice_chain();
+        let trust_anchor = serialize(&chain[0]).unwrap();
+
+        let (valid, reason) = verify_cert_chain(&[0xff, 0x00], &trust_anchor).unwrap();
+        assert!(!valid);
+        assert!(reason.unwrap().starts_with("Malformed certificate chain"));
+    }
+
+    #[test]
+    fn malformed_trust_anchor_is_an_error() {
+        let chain = to_cbor(sample_dice_chain());
+
+        assert_eq!(
+            Err(RequestProcessingError::CosetError),
+            verify_cert_chain(&chain, &[0xff, 0x00])
+        );
+    }
+}
@@ -296,7 +296,7 @@ pub(crate) struct DiceChainEntryPayload {
 impl DiceChainEntryPayload {
     /// Validates the signature of the provided CBOR value with the provided public key and
     /// extracts payload from the value.
-    fn validate_cose_signature_and_extract_payload(
+    pub(crate) fn validate_cose_signature_and_extract_payload(
         value: Value,
         authority_public_key: &PublicKey,
     ) -> Result<Self> {
@@ -0,0 +1,82 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module contains a bounded log of recent events in the service VM, which
+//! the host can retrieve with `Request::GetEventLog` to diagnose failures.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// The maximum number of events kept in the log. Once the log is full, the
+/// oldest event is dropped whenever a new one is recorded.
+pub const EVENT_LOG_CAPACITY: usize = 64;
+
+/// A ring buffer of the most recent events in the service VM.
+#[derive(Debug, Default)]
+pub struct EventLog {
+    events: VecDeque<String>,
+}
+
+impl EventLog {
+    /// Creates an empty event log.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a new event, dropping the oldest one if the log is full.
+    pub fn record(&mut self, event: String) {
+        if self.events.len() == EVENT_LOG_CAPACITY {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// Returns up to `max_entries` of the most recent events, oldest first.
+    pub(crate) fn recent(&self, max_entries: u32) -> Vec<String> {
+        let count = usize::try_from(max_entries).unwrap_or(usize::MAX).min(self.events.len());
+        self.events.iter().skip(self.events.len() - count).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    #[test]
+    fn recent_events_are_returned_in_order() {
+        let mut event_log = EventLog::new();
+        for i in 0..5 {
+            event_log.record(format!("event {i}"));
+        }
+
+        assert_eq!(["event 2", "event 3", "event 4"], event_log.recent(3).as_slice());
+        assert_eq!(5, event_log.recent(u32::MAX).len());
+        assert!(event_log.recent(0).is_empty());
+    }
+
+    #[test]
+    fn event_log_is_bounded() {
+        let mut event_log = EventLog::new();
+        for i in 0..EVENT_LOG_CAPACITY + 10 {
+            event_log.record(format!("event {i}"));
+        }
+
+        let events = event_log.recent(u32::MAX);
+        assert_eq!(EVENT_LOG_CAPACITY, events.len());
+        assert_eq!("event 10", events[0]);
+        assert_eq!(format!("event {}", EVENT_LOG_CAPACITY + 9), events[EVENT_LOG_CAPACITY - 1]);
+    }
+}
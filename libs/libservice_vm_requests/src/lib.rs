@@ -20,10 +20,16 @@ extern crate alloc;
 
 mod api;
 mod cert;
+mod cert_chain;
 mod client_vm;
 mod dice;
+mod event_log;
 mod keyblob;
 mod pub_key;
 mod rkp;
+mod self_test;
+mod session_policy;
 
-pub use api::{process_request, RequestContext};
+pub use api::{process_request, MonotonicClock, RequestContext};
+pub use event_log::{EventLog, EVENT_LOG_CAPACITY};
+pub use session_policy::SessionPolicy;
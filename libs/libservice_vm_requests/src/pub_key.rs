@@ -15,40 +15,378 @@
 //! Handles the construction of the MACed public key.
 
 use alloc::vec::Vec;
-use bssl_avf::hmac_sha256;
+use bssl_avf::{constant_time_eq, hmac_sha256, hmac_sha512};
+use ciborium::value::Value;
 use core::result;
-use coset::{iana, CborSerializable, CoseKey, CoseMac0, CoseMac0Builder, HeaderBuilder};
+use coset::{
+    iana::{self, EnumI64},
+    Algorithm, CborSerializable, CoseKey, CoseMac0, CoseMac0Builder, Header, HeaderBuilder,
+    KeyType, Label,
+};
+use log::error;
 use service_vm_comm::RequestProcessingError;
 
 type Result<T> = result::Result<T, RequestProcessingError>;
 
-/// Verifies the MAC of the given public key.
-pub fn validate_public_key(maced_public_key: &[u8], hmac_key: &[u8]) -> Result<CoseKey> {
+/// The HMAC algorithms with which a public key can be MACed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MacAlgorithm {
+    /// HMAC with SHA-256, `HMAC 256/256` in COSE.
+    #[default]
+    HmacSha256,
+    /// HMAC with SHA-512, `HMAC 512/512` in COSE.
+    HmacSha512,
+}
+
+impl MacAlgorithm {
+    fn to_cose(self) -> iana::Algorithm {
+        match self {
+            Self::HmacSha256 => iana::Algorithm::HMAC_256_256,
+            Self::HmacSha512 => iana::Algorithm::HMAC_512_512,
+        }
+    }
+
+    /// Returns the algorithm set in the given COSE header, which defaults to HMAC-SHA256 if the
+    /// header doesn't set any.
+    fn from_cose_header(header: &Header) -> Result<Self> {
+        match &header.alg {
+            None | Some(Algorithm::Assigned(iana::Algorithm::HMAC_256_256)) => Ok(Self::HmacSha256),
+            Some(Algorithm::Assigned(iana::Algorithm::HMAC_512_512)) => Ok(Self::HmacSha512),
+            Some(alg) => {
+                error!("Unsupported MAC algorithm: {alg:?}");
+                Err(RequestProcessingError::InvalidMac)
+            }
+        }
+    }
+
+    fn compute_tag(self, key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let tag = match self {
+            Self::HmacSha256 => hmac_sha256(key, data)?.to_vec(),
+            Self::HmacSha512 => hmac_sha512(key, data)?.to_vec(),
+        };
+        Ok(tag)
+    }
+}
+
+/// A key with which public keys are MACed, and the identifier set as `kid` in the unprotected
+/// header of the public keys MACed with it, so that the key can be found again among the others in
+/// an `HmacKeyRing`.
+#[derive(Clone, Copy)]
+pub struct HmacKey<'a> {
+    /// The identifier of the key. It is empty for the keys without one, in which case no `kid` is
+    /// set in the header.
+    pub kid: &'a [u8],
+    /// The HMAC key itself.
+    pub key: &'a [u8],
+}
+
+impl<'a> HmacKey<'a> {
+    /// Returns the given HMAC key, without identifier.
+    pub fn new(key: &'a [u8]) -> Self {
+        Self { kid: &[], key }
+    }
+}
+
+/// The keys with which the public keys to validate may have been MACed, e.g. the current key and
+/// the ones it replaced, for as long as the public keys they MACed may still be in use.
+pub struct HmacKeyRing<'a>(&'a [HmacKey<'a>]);
+
+impl<'a> HmacKeyRing<'a> {
+    /// Returns the key ring made of the given keys, each of which should have a distinct `kid`.
+    pub fn new(keys: &'a [HmacKey<'a>]) -> Self {
+        Self(keys)
+    }
+
+    /// Returns the key identified by `kid`, which is empty for a public key MACed with a key
+    /// without identifier.
+    fn find(&self, kid: &[u8]) -> Result<&'a [u8]> {
+        match self.0.iter().find(|key| key.kid == kid) {
+            Some(key) => Ok(key.key),
+            None => {
+                error!("No HMAC key with kid {kid:02x?}");
+                Err(RequestProcessingError::InvalidMac)
+            }
+        }
+    }
+}
+
+/// Verifies the MAC of the given public key with the key of `hmac_keys` it names, and checks that
+/// it is an EC2 P-256 key for ES256 or an EC2 P-384 key for ES384, as generated by the service VM
+/// for the keys to sign in a CSR.
+pub fn validate_public_key(maced_public_key: &[u8], hmac_keys: &HmacKeyRing) -> Result<CoseKey> {
+    let public_key = verify_mac(maced_public_key, hmac_keys)?;
+    check_ecdsa_public_key(&public_key)?;
+    Ok(public_key)
+}
+
+fn check_ecdsa_public_key(public_key: &CoseKey) -> Result<()> {
+    if public_key.kty != KeyType::Assigned(iana::KeyType::EC2) {
+        error!("The public key to sign has key type {:?} instead of EC2", public_key.kty);
+        return Err(RequestProcessingError::InvalidPublicKey);
+    }
+    let crv = public_key
+        .params
+        .iter()
+        .find(|(label, _)| *label == Label::Int(iana::Ec2KeyParameter::Crv.to_i64()))
+        .map(|(_, value)| value);
+    let expected_alg = if crv == Some(&Value::from(iana::EllipticCurve::P_256.to_i64())) {
+        iana::Algorithm::ES256
+    } else if crv == Some(&Value::from(iana::EllipticCurve::P_384.to_i64())) {
+        iana::Algorithm::ES384
+    } else {
+        error!("The public key to sign has curve {crv:?} instead of P-256 or P-384");
+        return Err(RequestProcessingError::InvalidPublicKey);
+    };
+    if public_key.alg != Some(Algorithm::Assigned(expected_alg)) {
+        error!(
+            "The public key to sign has algorithm {:?} instead of {expected_alg:?}",
+            public_key.alg
+        );
+        return Err(RequestProcessingError::InvalidPublicKey);
+    }
+    Ok(())
+}
+
+/// Verifies the MAC of the given public key, with the algorithm set in its protected header and the
+/// key of `hmac_keys` identified in its unprotected header. The identifier isn't authenticated, but
+/// naming another key than the one the public key was MACed with only makes the verification fail.
+fn verify_mac(maced_public_key: &[u8], hmac_keys: &HmacKeyRing) -> Result<CoseKey> {
     let cose_mac = CoseMac0::from_slice(maced_public_key)?;
-    cose_mac.verify_tag(&[], |tag, data| verify_tag(tag, data, hmac_key))?;
+    let algorithm = MacAlgorithm::from_cose_header(&cose_mac.protected.header)?;
+    let hmac_key = hmac_keys.find(&cose_mac.unprotected.key_id)?;
+    cose_mac.verify_tag(&[], |tag, data| verify_tag(tag, data, hmac_key, algorithm))?;
     let payload = cose_mac.payload.ok_or(RequestProcessingError::KeyToSignHasEmptyPayload)?;
     Ok(CoseKey::from_slice(&payload)?)
 }
 
-fn verify_tag(tag: &[u8], data: &[u8], hmac_key: &[u8]) -> Result<()> {
-    let computed_tag = hmac_sha256(hmac_key, data)?;
-    if tag == computed_tag {
+fn verify_tag(tag: &[u8], data: &[u8], hmac_key: &[u8], algorithm: MacAlgorithm) -> Result<()> {
+    let computed_tag = algorithm.compute_tag(hmac_key, data)?;
+    // The tag must be compared in constant time. Otherwise the time taken to reject a tag would
+    // reveal how many of its leading bytes are correct, allowing it to be forged byte by byte.
+    if constant_time_eq(tag, &computed_tag) {
         Ok(())
     } else {
         Err(RequestProcessingError::InvalidMac)
     }
 }
 
-/// Returns the MACed public key.
-pub fn build_maced_public_key(public_key: CoseKey, hmac_key: &[u8]) -> Result<Vec<u8>> {
-    const ALGO: iana::Algorithm = iana::Algorithm::HMAC_256_256;
-
+/// Returns the public key MACed with the given key and algorithm. The identifier of the key, if
+/// any, is set in the unprotected header.
+pub fn build_maced_public_key(
+    public_key: CoseKey,
+    hmac_key: HmacKey,
+    algorithm: MacAlgorithm,
+) -> Result<Vec<u8>> {
     let external_aad = &[];
-    let protected = HeaderBuilder::new().algorithm(ALGO).build();
+    let protected = HeaderBuilder::new().algorithm(algorithm.to_cose()).build();
+    let mut unprotected = HeaderBuilder::new();
+    if !hmac_key.kid.is_empty() {
+        unprotected = unprotected.key_id(hmac_key.kid.to_vec());
+    }
     let cose_mac = CoseMac0Builder::new()
         .protected(protected)
+        .unprotected(unprotected.build())
         .payload(public_key.to_vec()?)
-        .try_create_tag(external_aad, |data| hmac_sha256(hmac_key, data).map(|v| v.to_vec()))?
+        .try_create_tag(external_aad, |data| algorithm.compute_tag(hmac_key.key, data))?
         .build();
     Ok(cose_mac.to_vec()?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const HMAC_KEY: [u8; 32] = [0x5a; 32];
+    const HMAC_KEYS: HmacKeyRing = HmacKeyRing(&[HmacKey { kid: &[], key: &HMAC_KEY }]);
+    const DATA: &[u8] = b"data to authenticate";
+
+    #[test]
+    fn correct_tag_is_verified() {
+        let tag = hmac_sha256(&HMAC_KEY, DATA).unwrap();
+
+        assert_eq!(Ok(()), verify_tag(&tag, DATA, &HMAC_KEY, MacAlgorithm::HmacSha256));
+    }
+
+    #[test]
+    fn incorrect_tag_is_rejected() {
+        let mut tag = hmac_sha256(&HMAC_KEY, DATA).unwrap();
+        tag[tag.len() - 1] ^= 1;
+
+        assert_eq!(
+            Err(RequestProcessingError::InvalidMac),
+            verify_tag(&tag, DATA, &HMAC_KEY, MacAlgorithm::HmacSha256)
+        );
+        assert_eq!(
+            Err(RequestProcessingError::InvalidMac),
+            verify_tag(&tag[..tag.len() - 1], DATA, &HMAC_KEY, MacAlgorithm::HmacSha256)
+        );
+    }
+
+    #[test]
+    fn public_key_maced_with_hmac_sha512_round_trips() {
+        let mut ec_key = bssl_avf::EcKey::new_p256().unwrap();
+        ec_key.generate_key().unwrap();
+        let public_key = ec_key.cose_public_key().unwrap();
+
+        let maced_public_key = build_maced_public_key(
+            public_key.clone(),
+            HmacKey::new(&HMAC_KEY),
+            MacAlgorithm::HmacSha512,
+        )
+        .unwrap();
+
+        let cose_mac = CoseMac0::from_slice(&maced_public_key).unwrap();
+        assert_eq!(
+            Some(Algorithm::Assigned(iana::Algorithm::HMAC_512_512)),
+            cose_mac.protected.header.alg
+        );
+        assert_eq!(64, cose_mac.tag.len());
+        assert_eq!(public_key, validate_public_key(&maced_public_key, &HMAC_KEYS).unwrap());
+        assert_eq!(
+            Err(RequestProcessingError::InvalidMac),
+            validate_public_key(&maced_public_key, &HmacKeyRing::new(&[HmacKey::new(&[0xa5; 32])]))
+        );
+    }
+
+    fn maced_public_key(public_key: CoseKey) -> Vec<u8> {
+        build_maced_public_key(public_key, HmacKey::new(&HMAC_KEY), MacAlgorithm::default())
+            .unwrap()
+    }
+
+    const OLD_HMAC_KEY: HmacKey = HmacKey { kid: b"v1", key: &[0x11; 32] };
+    const CURRENT_HMAC_KEY: HmacKey = HmacKey { kid: b"v2", key: &[0x22; 32] };
+
+    fn p256_public_key() -> CoseKey {
+        let mut ec_key = bssl_avf::EcKey::new_p256().unwrap();
+        ec_key.generate_key().unwrap();
+        ec_key.cose_public_key().unwrap()
+    }
+
+    #[test]
+    fn public_key_is_verified_with_key_of_matching_kid() {
+        let public_key = p256_public_key();
+        let hmac_keys = HmacKeyRing::new(&[OLD_HMAC_KEY, CURRENT_HMAC_KEY]);
+
+        for hmac_key in [OLD_HMAC_KEY, CURRENT_HMAC_KEY] {
+            let maced_public_key =
+                build_maced_public_key(public_key.clone(), hmac_key, MacAlgorithm::default())
+                    .unwrap();
+
+            let cose_mac = CoseMac0::from_slice(&maced_public_key).unwrap();
+            assert_eq!(hmac_key.kid, cose_mac.unprotected.key_id);
+            assert_eq!(Ok(public_key.clone()), validate_public_key(&maced_public_key, &hmac_keys));
+        }
+    }
+
+    #[test]
+    fn public_key_with_unknown_kid_is_rejected() {
+        let maced_public_key =
+            build_maced_public_key(p256_public_key(), OLD_HMAC_KEY, MacAlgorithm::default())
+                .unwrap();
+
+        assert_eq!(
+            Err(RequestProcessingError::InvalidMac),
+            validate_public_key(&maced_public_key, &HmacKeyRing::new(&[CURRENT_HMAC_KEY]))
+        );
+        // A public key MACed with a key without identifier doesn't match any key with one either.
+        assert_eq!(
+            Err(RequestProcessingError::InvalidMac),
+            validate_public_key(
+                &maced_public_key(p256_public_key()),
+                &HmacKeyRing::new(&[OLD_HMAC_KEY])
+            )
+        );
+    }
+
+    #[test]
+    fn public_key_with_kid_of_other_key_is_rejected() {
+        let maced_public_key =
+            build_maced_public_key(p256_public_key(), OLD_HMAC_KEY, MacAlgorithm::default())
+                .unwrap();
+        let mut cose_mac = CoseMac0::from_slice(&maced_public_key).unwrap();
+        cose_mac.unprotected.key_id = CURRENT_HMAC_KEY.kid.to_vec();
+        let maced_public_key = cose_mac.to_vec().unwrap();
+
+        assert_eq!(
+            Err(RequestProcessingError::InvalidMac),
+            validate_public_key(
+                &maced_public_key,
+                &HmacKeyRing::new(&[OLD_HMAC_KEY, CURRENT_HMAC_KEY])
+            )
+        );
+    }
+
+    #[test]
+    fn p256_public_key_is_valid() {
+        let mut ec_key = bssl_avf::EcKey::new_p256().unwrap();
+        ec_key.generate_key().unwrap();
+        let public_key = ec_key.cose_public_key().unwrap();
+
+        let maced_public_key = maced_public_key(public_key.clone());
+
+        assert_eq!(Ok(public_key), validate_public_key(&maced_public_key, &HMAC_KEYS));
+    }
+
+    #[test]
+    fn p384_public_key_is_valid() {
+        let mut ec_key = bssl_avf::EcKey::new_p384().unwrap();
+        ec_key.generate_key().unwrap();
+        let public_key = ec_key.cose_public_key().unwrap();
+
+        let maced_public_key = maced_public_key(public_key.clone());
+
+        assert_eq!(Ok(public_key), validate_public_key(&maced_public_key, &HMAC_KEYS));
+    }
+
+    #[test]
+    fn public_key_with_algorithm_of_other_curve_is_invalid() {
+        for (mut ec_key, wrong_alg) in [
+            (bssl_avf::EcKey::new_p256().unwrap(), iana::Algorithm::ES384),
+            (bssl_avf::EcKey::new_p384().unwrap(), iana::Algorithm::ES256),
+        ] {
+            ec_key.generate_key().unwrap();
+            let mut public_key = ec_key.cose_public_key().unwrap();
+            public_key.alg = Some(Algorithm::Assigned(wrong_alg));
+
+            let maced_public_key = maced_public_key(public_key);
+
+            assert_eq!(
+                Err(RequestProcessingError::InvalidPublicKey),
+                validate_public_key(&maced_public_key, &HMAC_KEYS)
+            );
+        }
+    }
+
+    #[test]
+    fn public_key_on_unsupported_curve_is_invalid() {
+        let key = coset::CoseKeyBuilder::new_ec2_pub_key(
+            iana::EllipticCurve::P_521,
+            vec![0x42; 66],
+            vec![0x42; 66],
+        )
+        .algorithm(iana::Algorithm::ES512)
+        .build();
+
+        let maced_public_key = maced_public_key(key);
+
+        assert_eq!(
+            Err(RequestProcessingError::InvalidPublicKey),
+            validate_public_key(&maced_public_key, &HMAC_KEYS)
+        );
+    }
+
+    #[test]
+    fn symmetric_key_is_invalid() {
+        let key = coset::CoseKeyBuilder::new_symmetric_key(vec![0x42; 32])
+            .algorithm(iana::Algorithm::ES256)
+            .build();
+
+        let maced_public_key = maced_public_key(key);
+
+        assert_eq!(
+            Err(RequestProcessingError::InvalidPublicKey),
+            validate_public_key(&maced_public_key, &HMAC_KEYS)
+        );
+    }
+}
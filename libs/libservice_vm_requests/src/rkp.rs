@@ -15,8 +15,10 @@
 //! This module contains functions related to the attestation of the
 //! service VM via the RKP (Remote Key Provisioning) server.
 
-use crate::keyblob::EncryptedKeyBlob;
-use crate::pub_key::{build_maced_public_key, validate_public_key};
+use crate::keyblob::{decrypt_private_key, EncryptedKeyBlob};
+use crate::pub_key::{
+    build_maced_public_key, validate_public_key, HmacKey, HmacKeyRing, MacAlgorithm,
+};
 use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
@@ -26,12 +28,15 @@ use ciborium::{
     value::{CanonicalValue, Value},
 };
 use core::result;
-use coset::{AsCborValue, CoseSign1, CoseSign1Builder, HeaderBuilder};
+use coset::{AsCborValue, CborSerializable, CoseSign1, CoseSign1Builder, Header, HeaderBuilder};
 use diced_open_dice::{
     derive_cdi_leaf_priv, kdf, sign, DiceArtifacts, PrivateKey, DICE_COSE_KEY_ALG_VALUE,
 };
 use log::{debug, error};
-use service_vm_comm::{EcdsaP256KeyPair, GenerateCertificateRequestParams, RequestProcessingError};
+use service_vm_comm::{
+    EcdsaP256KeyPair, EcdsaP384KeyPair, GenerateCertificateRequestParams, RequestProcessingError,
+    MAX_BATCH_KEY_COUNT, MAX_PAYLOAD_TO_SIGN_SIZE,
+};
 use zeroize::Zeroizing;
 
 type Result<T> = result::Result<T, RequestProcessingError>;
@@ -45,20 +50,55 @@ const HMAC_KEY_SALT: [u8; 32] = [
 const HMAC_KEY_INFO: &[u8] = b"rialto hmac wkey";
 const HMAC_KEY_LENGTH: usize = 32;
 
+/// Content type of the COSE_Sign1 returned by `sign_with_attestation_key`.
+const HOST_PAYLOAD_CONTENT_TYPE: &str = "application/vnd.android.avf.host-payload";
+/// External AAD of the COSE_Sign1 returned by `sign_with_attestation_key`.
+///
+/// The `SignedData` of a CSR is signed with the same key but with an empty external AAD, so the
+/// signature over a host payload can never be a valid CSR signature, whatever the payload is.
+const HOST_PAYLOAD_SIGNATURE_AAD: &[u8] = b"AVF service VM host payload signature";
+
 pub(super) fn generate_ecdsa_p256_key_pair(
     dice_artifacts: &dyn DiceArtifacts,
 ) -> Result<EcdsaP256KeyPair> {
+    let (maced_public_key, key_blob) = generate_ecdsa_key_pair(EcKey::new_p256()?, dice_artifacts)?;
+    Ok(EcdsaP256KeyPair { maced_public_key, key_blob })
+}
+
+pub(super) fn generate_ecdsa_p384_key_pair(
+    dice_artifacts: &dyn DiceArtifacts,
+) -> Result<EcdsaP384KeyPair> {
+    let (maced_public_key, key_blob) = generate_ecdsa_key_pair(EcKey::new_p384()?, dice_artifacts)?;
+    Ok(EcdsaP384KeyPair { maced_public_key, key_blob })
+}
+
+/// Generates a key pair on the curve of the given `ec_key` and returns the MACed public key and
+/// the serialized encrypted key blob of the private key.
+fn generate_ecdsa_key_pair(
+    mut ec_key: EcKey,
+    dice_artifacts: &dyn DiceArtifacts,
+) -> Result<(Vec<u8>, Vec<u8>)> {
     let hmac_key = derive_hmac_key(dice_artifacts)?;
-    let mut ec_key = EcKey::new_p256()?;
     ec_key.generate_key()?;
 
-    let maced_public_key = build_maced_public_key(ec_key.cose_public_key()?, hmac_key.as_ref())?;
+    let maced_public_key = build_maced_public_key(
+        ec_key.cose_public_key()?,
+        HmacKey::new(hmac_key.as_ref()),
+        MacAlgorithm::default(),
+    )?;
     let key_blob =
         EncryptedKeyBlob::new(ec_key.ec_private_key()?.as_slice(), dice_artifacts.cdi_seal())?;
+    Ok((maced_public_key, cbor_util::serialize(&key_blob)?))
+}
 
-    let key_pair =
-        EcdsaP256KeyPair { maced_public_key, key_blob: cbor_util::serialize(&key_blob)? };
-    Ok(key_pair)
+/// Checks that `key_blob` was issued by `generate_ecdsa_key_pair` in this service VM, so that the
+/// host can destroy it. The service VM keeps no copy of the key, so there is nothing else to delete.
+pub(super) fn delete_key(key_blob: &[u8], dice_artifacts: &dyn DiceArtifacts) -> Result<()> {
+    decrypt_private_key(key_blob, dice_artifacts.cdi_seal()).map_err(|e| {
+        error!("Failed to decrypt the key blob to delete: {e}");
+        RequestProcessingError::NoSuchKey
+    })?;
+    Ok(())
 }
 
 const CSR_PAYLOAD_SCHEMA_V3: u8 = 3;
@@ -74,10 +114,13 @@ pub(super) fn generate_certificate_request(
     params: GenerateCertificateRequestParams,
     dice_artifacts: &dyn DiceArtifacts,
 ) -> Result<Vec<u8>> {
+    params.validate()?;
     let hmac_key = derive_hmac_key(dice_artifacts)?;
+    let hmac_keys = [HmacKey::new(hmac_key.as_ref())];
+    let hmac_keys = HmacKeyRing::new(&hmac_keys);
     let mut public_keys: Vec<Value> = Vec::new();
     for key_to_sign in params.keys_to_sign {
-        let public_key = validate_public_key(&key_to_sign, hmac_key.as_ref())?;
+        let public_key = validate_public_key(&key_to_sign, &hmac_keys)?;
         public_keys.push(public_key.to_cbor_value()?);
     }
     debug!("Successfully validated all '{}' public keys.", public_keys.len());
@@ -113,6 +156,58 @@ pub(super) fn generate_certificate_request(
     Ok(cbor_util::serialize(&auth_req)?)
 }
 
+/// Generates `count` new ECDSA P-256 key pairs and a CSR covering their public keys.
+///
+/// The public keys appear in the CSR in the same order as the returned key pairs.
+pub(super) fn generate_and_certify_batch(
+    count: u32,
+    challenge: Vec<u8>,
+    dice_artifacts: &dyn DiceArtifacts,
+) -> Result<(Vec<EcdsaP256KeyPair>, Vec<u8>)> {
+    if count == 0 || count > MAX_BATCH_KEY_COUNT {
+        error!("Invalid number of keys to generate: {count}");
+        return Err(RequestProcessingError::InvalidBatchKeyCount);
+    }
+    // Rejects invalid params before generating any key pair.
+    let mut params = GenerateCertificateRequestParams { keys_to_sign: Vec::new(), challenge };
+    params.validate()?;
+    let key_pairs = (0..count)
+        .map(|_| generate_ecdsa_p256_key_pair(dice_artifacts))
+        .collect::<Result<Vec<_>>>()?;
+    debug!("Successfully generated '{count}' key pairs.");
+
+    params.keys_to_sign = key_pairs.iter().map(|k| k.maced_public_key.clone()).collect();
+    let csr = generate_certificate_request(params, dice_artifacts)?;
+    Ok((key_pairs, csr))
+}
+
+/// Signs the given payload with the CDI_Leaf_Priv of the service VM and returns the serialized
+/// COSE_Sign1 along with the DICE chain certifying the corresponding public key.
+///
+/// The payload is never signed as is: it is wrapped in a COSE_Sign1 whose protected header and
+/// external AAD differ from those of the CSR `SignedData`, so that the host cannot use this request
+/// to forge a CSR.
+pub(super) fn sign_with_attestation_key(
+    payload: &[u8],
+    dice_artifacts: &dyn DiceArtifacts,
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    if payload.len() > MAX_PAYLOAD_TO_SIGN_SIZE {
+        error!("The payload to sign has {} bytes", payload.len());
+        return Err(RequestProcessingError::PayloadTooLarge);
+    }
+    let dice_cert_chain =
+        dice_artifacts.bcc().ok_or(RequestProcessingError::MissingAttestationKey)?;
+    let dice_key_alg = cbor_util::dice_cose_key_alg(DICE_COSE_KEY_ALG_VALUE)?;
+    let protected = HeaderBuilder::new()
+        .algorithm(dice_key_alg)
+        .content_type(String::from(HOST_PAYLOAD_CONTENT_TYPE))
+        .build();
+    let signature =
+        build_cose_sign1(protected, payload.to_vec(), HOST_PAYLOAD_SIGNATURE_AAD, dice_artifacts)?;
+    debug!("Successfully signed the payload with the attestation key.");
+    Ok((signature.to_vec()?, dice_cert_chain.to_vec()))
+}
+
 /// Generates the device info required by the RKP server as a temporary placeholder.
 /// More details in b/301592917.
 ///
@@ -149,24 +244,34 @@ fn derive_hmac_key(dice_artifacts: &dyn DiceArtifacts) -> Result<Zeroizing<[u8;
 
 /// Builds the `SignedData` for the given payload.
 fn build_signed_data(payload: &Value, dice_artifacts: &dyn DiceArtifacts) -> Result<CoseSign1> {
+    let dice_key_alg = cbor_util::dice_cose_key_alg(DICE_COSE_KEY_ALG_VALUE)?;
+    let protected = HeaderBuilder::new().algorithm(dice_key_alg).build();
+    build_cose_sign1(protected, cbor_util::serialize(payload)?, &[], dice_artifacts)
+}
+
+/// Builds a COSE_Sign1 of the given payload signed with the CDI_Leaf_Priv.
+fn build_cose_sign1(
+    protected: Header,
+    payload: Vec<u8>,
+    external_aad: &[u8],
+    dice_artifacts: &dyn DiceArtifacts,
+) -> Result<CoseSign1> {
     let cdi_leaf_priv = derive_cdi_leaf_priv(dice_artifacts).map_err(|e| {
         error!("Failed to derive the CDI_Leaf_Priv: {e}");
         RequestProcessingError::InternalError
     })?;
-    let dice_key_alg = cbor_util::dice_cose_key_alg(DICE_COSE_KEY_ALG_VALUE)?;
-    let protected = HeaderBuilder::new().algorithm(dice_key_alg).build();
-    let signed_data = CoseSign1Builder::new()
+    let cose_sign1 = CoseSign1Builder::new()
         .protected(protected)
-        .payload(cbor_util::serialize(payload)?)
-        .try_create_signature(&[], |message| sign_message(message, &cdi_leaf_priv))?
+        .payload(payload)
+        .try_create_signature(external_aad, |message| sign_message(message, &cdi_leaf_priv))?
         .build();
-    Ok(signed_data)
+    Ok(cose_sign1)
 }
 
 fn sign_message(message: &[u8], private_key: &PrivateKey) -> Result<Vec<u8>> {
     Ok(sign(message, private_key.as_array())
         .map_err(|e| {
-            error!("Failed to sign the CSR: {e}");
+            error!("Failed to sign the message: {e}");
             RequestProcessingError::InternalError
         })?
         .to_vec())
@@ -175,6 +280,11 @@ fn sign_message(message: &[u8], private_key: &PrivateKey) -> Result<Vec<u8>> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use coset::{iana, iana::EnumI64, Label};
+    use diced_open_dice::{
+        derive_cdi_private_key_seed, keypair_from_seed, verify, DiceError, PublicKey,
+    };
+    use service_vm_comm::MAX_CHALLENGE_SIZE;
 
     /// The keys of device info map should be in the length-first core deterministic encoding
     /// order as per RFC8949.
@@ -192,4 +302,197 @@ mod tests {
         sorted_keys.sort_by(|a, b| a.len().cmp(&b.len()).then(a.cmp(b)));
         assert_eq!(device_info_keys, sorted_keys);
     }
+
+    #[test]
+    fn ecdsa_p384_key_pair_has_valid_p384_public_key() {
+        let dice_artifacts = diced_sample_inputs::make_sample_bcc_and_cdis().unwrap();
+        let hmac_key = derive_hmac_key(&dice_artifacts).unwrap();
+
+        let key_pair = generate_ecdsa_p384_key_pair(&dice_artifacts).unwrap();
+
+        let hmac_keys = [HmacKey::new(hmac_key.as_ref())];
+        let public_key =
+            validate_public_key(&key_pair.maced_public_key, &HmacKeyRing::new(&hmac_keys)).unwrap();
+        let crv = public_key
+            .params
+            .iter()
+            .find(|(label, _)| *label == Label::Int(iana::Ec2KeyParameter::Crv.to_i64()))
+            .map(|(_, value)| value.clone());
+        assert_eq!(Some(Value::from(iana::EllipticCurve::P_384.to_i64())), crv);
+        assert_eq!(Some(coset::Algorithm::Assigned(iana::Algorithm::ES384)), public_key.alg);
+        assert!(!key_pair.key_blob.is_empty());
+    }
+
+    /// Returns the challenge and the public keys from the given CSR.
+    fn parse_csr(csr: &[u8]) -> (Vec<u8>, Vec<Value>) {
+        let auth_req: Value = cbor_util::deserialize(csr).unwrap();
+        let auth_req = auth_req.into_array().unwrap();
+        let signed_data = CoseSign1::from_cbor_value(auth_req[3].clone()).unwrap();
+        let signed_data_payload: Value =
+            cbor_util::deserialize(&signed_data.payload.unwrap()).unwrap();
+        let signed_data_payload = signed_data_payload.into_array().unwrap();
+        let challenge = signed_data_payload[0].as_bytes().unwrap().clone();
+        let csr_payload: Value =
+            cbor_util::deserialize(signed_data_payload[1].as_bytes().unwrap()).unwrap();
+        let public_keys = csr_payload.into_array().unwrap()[3].clone().into_array().unwrap();
+        (challenge, public_keys)
+    }
+
+    #[test]
+    fn generate_and_certify_batch_certifies_keys_in_order() {
+        let dice_artifacts = diced_sample_inputs::make_sample_bcc_and_cdis().unwrap();
+        let challenge = vec![0x5a; MAX_CHALLENGE_SIZE];
+        let hmac_key = derive_hmac_key(&dice_artifacts).unwrap();
+        let hmac_keys = [HmacKey::new(hmac_key.as_ref())];
+
+        let (key_pairs, csr) =
+            generate_and_certify_batch(3, challenge.clone(), &dice_artifacts).unwrap();
+
+        assert_eq!(3, key_pairs.len());
+        let (csr_challenge, public_keys) = parse_csr(&csr);
+        assert_eq!(challenge, csr_challenge);
+        assert_eq!(key_pairs.len(), public_keys.len());
+        for (key_pair, public_key) in key_pairs.iter().zip(public_keys) {
+            let expected_public_key =
+                validate_public_key(&key_pair.maced_public_key, &HmacKeyRing::new(&hmac_keys))
+                    .unwrap();
+            assert_eq!(expected_public_key.to_cbor_value().unwrap(), public_key);
+        }
+    }
+
+    #[test]
+    fn generate_and_certify_batch_rejects_invalid_count() {
+        let dice_artifacts = diced_sample_inputs::make_sample_bcc_and_cdis().unwrap();
+
+        for count in [0, MAX_BATCH_KEY_COUNT + 1] {
+            assert_eq!(
+                Err(RequestProcessingError::InvalidBatchKeyCount),
+                generate_and_certify_batch(count, vec![], &dice_artifacts)
+            );
+        }
+    }
+
+    #[test]
+    fn generate_and_certify_batch_rejects_oversized_challenge() {
+        let dice_artifacts = diced_sample_inputs::make_sample_bcc_and_cdis().unwrap();
+        let challenge = vec![0x5a; MAX_CHALLENGE_SIZE + 1];
+
+        assert_eq!(
+            Err(RequestProcessingError::InvalidChallengeSize),
+            generate_and_certify_batch(1, challenge, &dice_artifacts)
+        );
+    }
+
+    #[test]
+    fn generate_certificate_request_rejects_oversized_challenge() {
+        let dice_artifacts = diced_sample_inputs::make_sample_bcc_and_cdis().unwrap();
+        let params = GenerateCertificateRequestParams {
+            keys_to_sign: vec![],
+            challenge: vec![0x5a; MAX_CHALLENGE_SIZE + 1],
+        };
+
+        assert_eq!(
+            Err(RequestProcessingError::InvalidChallengeSize),
+            generate_certificate_request(params, &dice_artifacts)
+        );
+    }
+
+    #[test]
+    fn delete_key_is_idempotent() {
+        let dice_artifacts = diced_sample_inputs::make_sample_bcc_and_cdis().unwrap();
+        let key_pair = generate_ecdsa_p256_key_pair(&dice_artifacts).unwrap();
+
+        assert_eq!(Ok(()), delete_key(&key_pair.key_blob, &dice_artifacts));
+        assert_eq!(Ok(()), delete_key(&key_pair.key_blob, &dice_artifacts));
+    }
+
+    #[test]
+    fn delete_key_rejects_unknown_key_blob() {
+        let dice_artifacts = diced_sample_inputs::make_sample_bcc_and_cdis().unwrap();
+        let mut key_blob = generate_ecdsa_p256_key_pair(&dice_artifacts).unwrap().key_blob;
+        *key_blob.last_mut().unwrap() ^= 1;
+
+        assert_eq!(Err(RequestProcessingError::NoSuchKey), delete_key(&key_blob, &dice_artifacts));
+        assert_eq!(Err(RequestProcessingError::NoSuchKey), delete_key(&[], &dice_artifacts));
+    }
+
+    fn cdi_leaf_pub(dice_artifacts: &dyn DiceArtifacts) -> PublicKey {
+        let seed = derive_cdi_private_key_seed(dice_artifacts.cdi_attest()).unwrap();
+        keypair_from_seed(seed.as_array()).unwrap().0
+    }
+
+    fn verify_with(
+        public_key: &PublicKey,
+    ) -> impl Fn(&[u8], &[u8]) -> diced_open_dice::Result<()> + '_ {
+        |signature, message| {
+            let signature = signature.try_into().map_err(|_| DiceError::InvalidInput)?;
+            verify(message, signature, public_key)
+        }
+    }
+
+    #[test]
+    fn sign_with_attestation_key_returns_dice_chain() {
+        let dice_artifacts = diced_sample_inputs::make_sample_bcc_and_cdis().unwrap();
+        let payload = [0xab; MAX_PAYLOAD_TO_SIGN_SIZE];
+
+        let (signature, cert_chain) = sign_with_attestation_key(&payload, &dice_artifacts).unwrap();
+
+        let signature = CoseSign1::from_slice(&signature).unwrap();
+        assert_eq!(Some(payload.to_vec()), signature.payload);
+        assert_eq!(
+            Some(coset::ContentType::Text(String::from(HOST_PAYLOAD_CONTENT_TYPE))),
+            signature.protected.header.content_type
+        );
+        let public_key = cdi_leaf_pub(&dice_artifacts);
+        signature.verify_signature(HOST_PAYLOAD_SIGNATURE_AAD, verify_with(&public_key)).unwrap();
+        assert_eq!(dice_artifacts.bcc(), Some(cert_chain.as_slice()));
+    }
+
+    #[test]
+    fn attestation_signature_is_not_a_valid_csr_signature() {
+        let dice_artifacts = diced_sample_inputs::make_sample_bcc_and_cdis().unwrap();
+        let public_key = cdi_leaf_pub(&dice_artifacts);
+        // A payload shaped like the `SignedData` payload of a CSR.
+        let signed_data_payload =
+            cbor!([Value::Bytes(vec![0x5a; MAX_CHALLENGE_SIZE]), Value::Bytes(vec![0xab; 32])])
+                .unwrap();
+        let signed_data_payload = cbor_util::serialize(&signed_data_payload).unwrap();
+        let csr_protected = HeaderBuilder::new()
+            .algorithm(cbor_util::dice_cose_key_alg(DICE_COSE_KEY_ALG_VALUE).unwrap())
+            .build();
+
+        // The signature over the payload doesn't verify as the `SignedData` of a CSR, which has a
+        // different protected header and an empty external AAD.
+        let (signature, _) =
+            sign_with_attestation_key(&signed_data_payload, &dice_artifacts).unwrap();
+        let signature = CoseSign1::from_slice(&signature).unwrap();
+        let forged_signed_data = CoseSign1Builder::new()
+            .protected(csr_protected.clone())
+            .payload(signed_data_payload.clone())
+            .signature(signature.signature.clone())
+            .build();
+        assert!(forged_signed_data.verify_signature(&[], verify_with(&public_key)).is_err());
+        assert!(signature.verify_signature(&[], verify_with(&public_key)).is_err());
+
+        // Nor does the signature over the data to be signed of the `SignedData` of a CSR.
+        let tbs_data = CoseSign1Builder::new()
+            .protected(csr_protected)
+            .payload(signed_data_payload)
+            .build()
+            .tbs_data(&[]);
+        let (signature, _) = sign_with_attestation_key(&tbs_data, &dice_artifacts).unwrap();
+        let signature = CoseSign1::from_slice(&signature).unwrap();
+        assert!(verify_with(&public_key)(&signature.signature, &tbs_data).is_err());
+    }
+
+    #[test]
+    fn sign_with_attestation_key_rejects_oversized_payload() {
+        let dice_artifacts = diced_sample_inputs::make_sample_bcc_and_cdis().unwrap();
+        let payload = [0xab; MAX_PAYLOAD_TO_SIGN_SIZE + 1];
+
+        assert_eq!(
+            Err(RequestProcessingError::PayloadTooLarge),
+            sign_with_attestation_key(&payload, &dice_artifacts)
+        );
+    }
 }
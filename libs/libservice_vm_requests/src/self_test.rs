@@ -0,0 +1,107 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module contains the self-test of the cryptographic primitives used by the service VM.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use bssl_avf::{hmac_sha256, sha256, EcKey};
+use log::error;
+
+/// HMAC-SHA256 test case 2 of RFC 4231.
+const HMAC_KEY: &[u8] = b"Jefe";
+const HMAC_DATA: &[u8] = b"what do ya want for nothing?";
+const HMAC_SHA256_EXPECTED: [u8; 32] = [
+    0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e, 0x6a, 0x04, 0x24, 0x26, 0x08, 0x95, 0x75, 0xc7,
+    0x5a, 0x00, 0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83, 0x9d, 0xec, 0x58, 0xb9, 0x64, 0xec, 0x38, 0x43,
+];
+
+const MESSAGE_TO_SIGN: &[u8] = b"service VM self-test";
+
+type TestResult = Result<(), String>;
+
+/// Runs all the tests and returns whether they all passed, along with the outcome of each test.
+pub(super) fn run() -> (bool, Vec<String>) {
+    let tests: [(&str, fn() -> TestResult); 2] =
+        [("HMAC-SHA256 known answer", test_hmac_sha256), ("ECDSA P-256 sign/verify", test_ecdsa)];
+    let mut ok = true;
+    let details = tests
+        .iter()
+        .map(|(name, test)| match test() {
+            Ok(()) => format!("{name}: passed"),
+            Err(reason) => {
+                error!("Self-test {name} failed: {reason}");
+                ok = false;
+                format!("{name}: failed: {reason}")
+            }
+        })
+        .collect();
+    (ok, details)
+}
+
+fn test_hmac_sha256() -> TestResult {
+    let mac = hmac_sha256(HMAC_KEY, HMAC_DATA).map_err(|e| format!("{e}"))?;
+    check_hmac_sha256(&mac)
+}
+
+fn check_hmac_sha256(mac: &[u8]) -> TestResult {
+    if mac == HMAC_SHA256_EXPECTED {
+        Ok(())
+    } else {
+        Err(String::from("Unexpected MAC"))
+    }
+}
+
+fn test_ecdsa() -> TestResult {
+    let mut ec_key = EcKey::new_p256().map_err(|e| format!("{e}"))?;
+    ec_key.generate_key().map_err(|e| format!("{e}"))?;
+    let digest = sha256(MESSAGE_TO_SIGN).map_err(|e| format!("{e}"))?;
+    let signature = ec_key.ecdsa_sign_der(&digest).map_err(|e| format!("{e}"))?;
+    ec_key
+        .ecdsa_verify_der(&signature, &digest)
+        .map_err(|e| format!("Valid signature rejected: {e}"))?;
+
+    let mut tampered_digest = digest;
+    tampered_digest[0] ^= 1;
+    if ec_key.ecdsa_verify_der(&signature, &tampered_digest).is_ok() {
+        return Err(String::from("Signature of a different digest accepted"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn all_tests_pass() {
+        let (ok, details) = run();
+
+        assert!(ok, "{details:?}");
+        assert_eq!(
+            ["HMAC-SHA256 known answer: passed", "ECDSA P-256 sign/verify: passed"],
+            details.as_slice()
+        );
+    }
+
+    #[test]
+    fn wrong_mac_is_detected() {
+        let mut mac = HMAC_SHA256_EXPECTED;
+        mac[31] ^= 1;
+
+        assert_eq!(Ok(()), check_hmac_sha256(&HMAC_SHA256_EXPECTED));
+        assert!(check_hmac_sha256(&mac).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module contains the policy restricting the requests the service VM
+//! accepts for the remainder of a session, set with `Request::SetSessionPolicy`.
+
+use alloc::vec::Vec;
+use core::result;
+use service_vm_comm::{RequestKind, RequestProcessingError};
+
+type Result<T> = result::Result<T, RequestProcessingError>;
+
+/// The kinds of requests allowed in the current session.
+#[derive(Debug, Default)]
+pub struct SessionPolicy {
+    /// `None` until a policy is set, in which case all requests are allowed.
+    allowed: Option<Vec<RequestKind>>,
+}
+
+impl SessionPolicy {
+    /// Creates a policy allowing all requests.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks that requests of the given kind are allowed.
+    pub(crate) fn check(&self, kind: RequestKind) -> Result<()> {
+        match &self.allowed {
+            Some(allowed) if !allowed.contains(&kind) => {
+                Err(RequestProcessingError::OperationNotPermitted)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Restricts the allowed requests to the given kinds, which must all be
+    /// allowed by the current policy.
+    pub(crate) fn restrict(&mut self, allowed: Vec<RequestKind>) -> Result<()> {
+        if allowed.iter().any(|kind| self.check(*kind).is_err()) {
+            return Err(RequestProcessingError::SessionPolicyLoosened);
+        }
+        self.allowed = Some(allowed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn all_requests_are_allowed_without_policy() {
+        let policy = SessionPolicy::new();
+
+        assert_eq!(Ok(()), policy.check(RequestKind::SignWithAttestationKey));
+        assert_eq!(Ok(()), policy.check(RequestKind::SetSessionPolicy));
+    }
+
+    #[test]
+    fn disallowed_request_is_rejected_after_policy_is_set() {
+        let mut policy = SessionPolicy::new();
+        policy
+            .restrict(vec![RequestKind::GenerateEcdsaP256KeyPair, RequestKind::SetSessionPolicy])
+            .unwrap();
+
+        assert_eq!(Ok(()), policy.check(RequestKind::GenerateEcdsaP256KeyPair));
+        assert_eq!(
+            Err(RequestProcessingError::OperationNotPermitted),
+            policy.check(RequestKind::SignWithAttestationKey)
+        );
+    }
+
+    #[test]
+    fn policy_can_be_tightened() {
+        let mut policy = SessionPolicy::new();
+        policy
+            .restrict(vec![RequestKind::GenerateEcdsaP256KeyPair, RequestKind::SetSessionPolicy])
+            .unwrap();
+        policy.restrict(vec![RequestKind::GenerateEcdsaP256KeyPair]).unwrap();
+
+        assert_eq!(Ok(()), policy.check(RequestKind::GenerateEcdsaP256KeyPair));
+        assert_eq!(
+            Err(RequestProcessingError::OperationNotPermitted),
+            policy.check(RequestKind::SetSessionPolicy)
+        );
+    }
+
+    #[test]
+    fn policy_cannot_be_loosened() {
+        let mut policy = SessionPolicy::new();
+        policy.restrict(vec![RequestKind::SetSessionPolicy]).unwrap();
+
+        assert_eq!(
+            Err(RequestProcessingError::SessionPolicyLoosened),
+            policy.restrict(vec![RequestKind::SetSessionPolicy, RequestKind::Reverse])
+        );
+        assert_eq!(
+            Err(RequestProcessingError::OperationNotPermitted),
+            policy.check(RequestKind::Reverse)
+        );
+    }
+}
@@ -14,18 +14,29 @@
 
 //! Functions to scan the PCI bus for VirtIO devices.
 
+mod cam;
+mod devices;
+mod topology;
+
 use crate::memory::{MemoryTracker, MemoryTrackerError};
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::fmt;
 use core::marker::PhantomData;
+use core::ops::Range;
+use devices::{fill_from, set_up_next_device, transport_or_skip};
 use fdtpci::PciInfo;
 use log::debug;
 use once_cell::race::OnceBox;
+use topology::{reachable_buses, PciTopology, ROOT_BUS};
 use virtio_drivers::{
-    device::{blk, socket},
-    transport::pci::{
-        bus::{BusDeviceIterator, PciRoot},
-        virtio_device_type, PciTransport,
+    device::{blk, console, rng, socket},
+    transport::{
+        pci::{
+            bus::{BusDeviceIterator, DeviceFunction, DeviceFunctionInfo, HeaderType, PciRoot},
+            virtio_device_type, PciTransport,
+        },
+        DeviceType, Transport,
     },
     Hal,
 };
@@ -37,10 +48,16 @@ pub(super) static PCI_INFO: OnceBox<PciInfo> = OnceBox::new();
 pub enum PciError {
     /// Attempted to initialize the PCI more than once.
     DuplicateInitialization,
-    /// Failed to map PCI CAM.
-    CamMapFailed(MemoryTrackerError),
-    /// Failed to map PCI BAR.
-    BarMapFailed(MemoryTrackerError),
+    /// Failed to map the given PCI CAM range.
+    CamMapFailed(Range<usize>, MemoryTrackerError),
+    /// Failed to map the given PCI BAR range.
+    BarMapFailed(Range<usize>, MemoryTrackerError),
+    /// No VirtIO entropy device was found.
+    MissingEntropyDevice,
+    /// Failed to obtain entropy from the VirtIO entropy device.
+    EntropyDeviceFailed(virtio_drivers::Error),
+    /// The VirtIO entropy device stopped providing entropy.
+    EntropyDeviceExhausted,
 }
 
 impl fmt::Display for PciError {
@@ -49,8 +66,19 @@ impl fmt::Display for PciError {
             Self::DuplicateInitialization => {
                 write!(f, "Attempted to initialize the PCI more than once.")
             }
-            Self::CamMapFailed(e) => write!(f, "Failed to map PCI CAM: {e}"),
-            Self::BarMapFailed(e) => write!(f, "Failed to map PCI BAR: {e}"),
+            Self::CamMapFailed(range, e) => {
+                write!(f, "Failed to map PCI CAM {:#x}-{:#x}: {e}", range.start, range.end)
+            }
+            Self::BarMapFailed(range, e) => {
+                write!(f, "Failed to map PCI BAR {:#x}-{:#x}: {e}", range.start, range.end)
+            }
+            Self::MissingEntropyDevice => write!(f, "No VirtIO entropy device found"),
+            Self::EntropyDeviceFailed(e) => {
+                write!(f, "Failed to obtain entropy from VirtIO entropy device: {e}")
+            }
+            Self::EntropyDeviceExhausted => {
+                write!(f, "The VirtIO entropy device stopped providing entropy")
+            }
         }
     }
 }
@@ -67,9 +95,10 @@ impl fmt::Display for PciError {
 pub fn initialize(pci_info: PciInfo, memory: &mut MemoryTracker) -> Result<PciRoot, PciError> {
     PCI_INFO.set(Box::new(pci_info.clone())).map_err(|_| PciError::DuplicateInitialization)?;
 
-    memory.map_mmio_range(pci_info.cam_range.clone()).map_err(PciError::CamMapFailed)?;
+    let cam_range = pci_info.cam_range.clone();
+    memory.map_mmio_range(cam_range.clone()).map_err(|e| PciError::CamMapFailed(cam_range, e))?;
     let bar_range = pci_info.bar_range.start as usize..pci_info.bar_range.end as usize;
-    memory.map_mmio_range(bar_range).map_err(PciError::BarMapFailed)?;
+    memory.map_mmio_range(bar_range.clone()).map_err(|e| PciError::BarMapFailed(bar_range, e))?;
 
     // Safety: This is the only place where we call make_pci_root, and `PCI_INFO.set` above will
     // panic if it is called a second time.
@@ -84,9 +113,67 @@ pub type VirtIOBlk<T> = blk::VirtIOBlk<T, PciTransport>;
 /// Spec: https://docs.oasis-open.org/virtio/virtio/v1.2/csd01/virtio-v1.2-csd01.html 5.10
 pub type VirtIOSocket<T> = socket::VirtIOSocket<T, PciTransport>;
 
+/// Virtio Console device.
+///
+/// Spec: https://docs.oasis-open.org/virtio/virtio/v1.2/csd01/virtio-v1.2-csd01.html 5.3
+pub type VirtIOConsole<T> = console::VirtIOConsole<T, PciTransport>;
+
+/// Virtio Entropy device.
+///
+/// Spec: https://docs.oasis-open.org/virtio/virtio/v1.2/csd01/virtio-v1.2-csd01.html 5.4
+pub type VirtIORng<T> = rng::VirtIORng<T, PciTransport>;
+
+/// Offset in the configuration space of a PCI-to-PCI bridge of the register holding its primary,
+/// secondary and subordinate bus numbers.
+const BRIDGE_BUS_NUMBERS_OFFSET: u8 = 0x18;
+
+/// The PCI topology as seen through the CAM of a `PciRoot`.
+struct PciRootTopology<'a> {
+    pci_root: &'a PciRoot,
+    pci_info: &'a PciInfo,
+}
+
+impl<'a> PciRootTopology<'a> {
+    /// Returns the topology behind `pci_root`, or `None` if the PCI hasn't been initialized.
+    fn new(pci_root: &'a PciRoot) -> Option<Self> {
+        Some(Self { pci_root, pci_info: PCI_INFO.get()? })
+    }
+
+    fn secondary_bus(&self, bridge: DeviceFunction) -> Option<u8> {
+        let cam_range = &self.pci_info.cam_range;
+        // SAFETY: The whole CAM was mapped by `initialize`, and reading the bus numbers of a
+        // bridge has no side effect.
+        let bus_numbers = unsafe {
+            cam::read_register(
+                cam_range.start as *const u8,
+                cam_range.len(),
+                self.pci_info.cam(),
+                bridge,
+                BRIDGE_BUS_NUMBERS_OFFSET,
+            )
+        };
+        Some(bus_numbers?.to_le_bytes()[1])
+    }
+}
+
+impl PciTopology for PciRootTopology<'_> {
+    fn secondary_buses(&mut self, bus: u8) -> Vec<u8> {
+        self.pci_root
+            .enumerate_bus(bus)
+            .filter(|(_, info)| info.header_type == HeaderType::PciPciBridge)
+            .filter_map(|(bridge, _)| self.secondary_bus(bridge))
+            .collect()
+    }
+}
+
 /// An iterator that iterates over the PCI transport for each device.
+///
+/// Devices are discovered on the root bus and on all the buses reachable from it through
+/// PCI-to-PCI bridges.
 pub struct PciTransportIterator<'a, T: Hal> {
     pci_root: &'a mut PciRoot,
+    /// The buses left to scan after the current one, last to be scanned first.
+    buses: Vec<u8>,
     bus: BusDeviceIterator,
     _hal: PhantomData<T>,
 }
@@ -94,8 +181,27 @@ pub struct PciTransportIterator<'a, T: Hal> {
 impl<'a, T: Hal> PciTransportIterator<'a, T> {
     /// Creates a new iterator.
     pub fn new(pci_root: &'a mut PciRoot) -> Self {
-        let bus = pci_root.enumerate_bus(0);
-        Self { pci_root, bus, _hal: PhantomData }
+        let mut buses = match PciRootTopology::new(pci_root) {
+            Some(mut topology) => reachable_buses(&mut topology),
+            None => Vec::from([ROOT_BUS]),
+        };
+        buses.reverse();
+        let bus = Self::scan_bus(pci_root, buses.pop().unwrap());
+        Self { pci_root, buses, bus, _hal: PhantomData }
+    }
+
+    fn scan_bus(pci_root: &PciRoot, bus: u8) -> BusDeviceIterator {
+        debug!("Scanning PCI bus {bus}");
+        pci_root.enumerate_bus(bus)
+    }
+
+    fn next_device(&mut self) -> Option<(DeviceFunction, DeviceFunctionInfo)> {
+        loop {
+            if let Some(device) = self.bus.next() {
+                return Some(device);
+            }
+            self.bus = Self::scan_bus(self.pci_root, self.buses.pop()?);
+        }
     }
 }
 
@@ -104,7 +210,7 @@ impl<'a, T: Hal> Iterator for PciTransportIterator<'a, T> {
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            let (device_function, info) = self.bus.next()?;
+            let (device_function, info) = self.next_device()?;
             let (status, command) = self.pci_root.get_status_command(device_function);
             debug!(
                 "Found PCI device {} at {}, status {:?} command {:?}",
@@ -116,7 +222,82 @@ impl<'a, T: Hal> Iterator for PciTransportIterator<'a, T> {
             };
             debug!("  VirtIO {:?}", virtio_type);
 
-            return PciTransport::new::<T>(self.pci_root, device_function).ok();
+            let transport = PciTransport::new::<T>(self.pci_root, device_function);
+            if let Some(transport) = transport_or_skip(device_function, transport) {
+                return Some(transport);
+            }
+        }
+    }
+}
+
+/// An iterator over the VirtIO block devices found on the PCI buses.
+///
+/// Devices whose driver fails to be set up are logged and skipped.
+pub struct VirtIOBlkIterator<'a, T: Hal>(PciTransportIterator<'a, T>);
+
+impl<'a, T: Hal> VirtIOBlkIterator<'a, T> {
+    /// Creates a new iterator.
+    pub fn new(pci_root: &'a mut PciRoot) -> Self {
+        Self(PciTransportIterator::new(pci_root))
+    }
+}
+
+impl<'a, T: Hal> Iterator for VirtIOBlkIterator<'a, T> {
+    type Item = VirtIOBlk<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut transports = self.0.by_ref().filter(|t| t.device_type() == DeviceType::Block);
+        set_up_next_device(&mut transports, VirtIOBlk::<T>::new)
+    }
+}
+
+/// An iterator over the VirtIO console devices found on the PCI buses.
+///
+/// Devices whose driver fails to be set up are logged and skipped.
+pub struct VirtIOConsoleIterator<'a, T: Hal>(PciTransportIterator<'a, T>);
+
+impl<'a, T: Hal> VirtIOConsoleIterator<'a, T> {
+    /// Creates a new iterator.
+    pub fn new(pci_root: &'a mut PciRoot) -> Self {
+        Self(PciTransportIterator::new(pci_root))
+    }
+}
+
+impl<'a, T: Hal> Iterator for VirtIOConsoleIterator<'a, T> {
+    type Item = VirtIOConsoleWriter<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut transports = self.0.by_ref().filter(|t| t.device_type() == DeviceType::Console);
+        set_up_next_device(&mut transports, VirtIOConsole::<T>::new).map(VirtIOConsoleWriter)
+    }
+}
+
+/// Writes text to a VirtIO console device.
+pub struct VirtIOConsoleWriter<T: Hal>(VirtIOConsole<T>);
+
+impl<T: Hal> fmt::Write for VirtIOConsoleWriter<T> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.0.send(byte).map_err(|_| fmt::Error)?;
         }
+        Ok(())
+    }
+}
+
+/// Fills `dst` with entropy from the first VirtIO entropy device found on the PCI buses.
+pub fn fill_with_virtio_entropy<T: Hal>(
+    pci_root: &mut PciRoot,
+    dst: &mut [u8],
+) -> Result<(), PciError> {
+    let mut transports = PciTransportIterator::<T>::new(pci_root)
+        .filter(|t| t.device_type() == DeviceType::EntropySource);
+    let mut rng = set_up_next_device(&mut transports, VirtIORng::<T>::new)
+        .ok_or(PciError::MissingEntropyDevice)?;
+
+    let filled =
+        fill_from(dst, |buf| rng.request_entropy(buf)).map_err(PciError::EntropyDeviceFailed)?;
+    if filled < dst.len() {
+        return Err(PciError::EntropyDeviceExhausted);
     }
+    Ok(())
 }
@@ -0,0 +1,117 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reading the configuration space of PCI functions through a memory-mapped CAM.
+
+use core::mem::size_of;
+use core::ptr;
+use virtio_drivers::transport::pci::bus::{Cam, DeviceFunction};
+
+/// Returns the offset in `cam` of the register at `register_offset` in the configuration space of
+/// `device_function`.
+pub fn cam_offset(cam: Cam, device_function: DeviceFunction, register_offset: u8) -> usize {
+    let bdf = (usize::from(device_function.bus) << 8)
+        | (usize::from(device_function.device) << 3)
+        | usize::from(device_function.function);
+    let function_shift = match cam {
+        Cam::MmioCam => 8,
+        Cam::Ecam => 12,
+    };
+    (bdf << function_shift) | usize::from(register_offset)
+}
+
+/// Reads the 32-bit register at `register_offset` in the configuration space of `device_function`,
+/// or returns `None` if it lies beyond the `cam_size` bytes of `cam` mapped at `cam_base`.
+///
+/// # Safety
+///
+/// `cam_size` bytes must be mapped at `cam_base`, and reading the register must have no side
+/// effect.
+pub unsafe fn read_register(
+    cam_base: *const u8,
+    cam_size: usize,
+    cam: Cam,
+    device_function: DeviceFunction,
+    register_offset: u8,
+) -> Option<u32> {
+    let offset = cam_offset(cam, device_function, register_offset);
+    if offset.checked_add(size_of::<u32>())? > cam_size || offset % size_of::<u32>() != 0 {
+        return None;
+    }
+    // SAFETY: The register is aligned and within the mapped CAM, and the caller guarantees that
+    // reading it has no side effect.
+    Some(unsafe { ptr::read_volatile(cam_base.add(offset) as *const u32) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MMIO_CAM_SIZE: usize = 0x100_0000;
+
+    fn device_function(bus: u8, device: u8, function: u8) -> DeviceFunction {
+        DeviceFunction { bus, device, function }
+    }
+
+    /// A fake CAM, with the registers of all the functions initialized to zero.
+    fn fake_cam(size: usize) -> Vec<u32> {
+        vec![0; size / size_of::<u32>()]
+    }
+
+    fn read(cam: &[u32], layout: Cam, device_function: DeviceFunction, offset: u8) -> Option<u32> {
+        let size = cam.len() * size_of::<u32>();
+        // SAFETY: The whole fake CAM is allocated and reading it has no side effect.
+        unsafe { read_register(cam.as_ptr() as *const u8, size, layout, device_function, offset) }
+    }
+
+    #[test]
+    fn mmio_cam_offset() {
+        assert_eq!(0x18, cam_offset(Cam::MmioCam, device_function(0, 0, 0), 0x18));
+        assert_eq!(0x11_1918, cam_offset(Cam::MmioCam, device_function(0x11, 0x3, 0x1), 0x18));
+        assert_eq!(0xff_fffc, cam_offset(Cam::MmioCam, device_function(0xff, 0x1f, 0x7), 0xfc));
+    }
+
+    #[test]
+    fn ecam_offset() {
+        assert_eq!(0x111_9018, cam_offset(Cam::Ecam, device_function(0x11, 0x3, 0x1), 0x18));
+    }
+
+    #[test]
+    fn register_is_read_from_mmio_cam() {
+        let mut cam = fake_cam(MMIO_CAM_SIZE);
+        let bridge = device_function(17, 3, 1);
+        cam[cam_offset(Cam::MmioCam, bridge, 0x18) / size_of::<u32>()] = 0x00_12_11_00;
+
+        assert_eq!(Some(0x00_12_11_00), read(&cam, Cam::MmioCam, bridge, 0x18));
+        assert_eq!(Some(0), read(&cam, Cam::MmioCam, device_function(1, 3, 1), 0x18));
+        assert_eq!(Some(0), read(&cam, Cam::MmioCam, device_function(17, 3, 0), 0x18));
+    }
+
+    #[test]
+    fn register_beyond_cam_is_not_read() {
+        let cam = fake_cam(MMIO_CAM_SIZE);
+
+        // With the ECAM layout, bus 16 and above are beyond a CAM of the MMIO CAM size.
+        assert_eq!(None, read(&cam, Cam::Ecam, device_function(16, 0, 0), 0x18));
+        assert_eq!(Some(0), read(&cam, Cam::MmioCam, device_function(255, 31, 7), 0xfc));
+        assert_eq!(None, read(&fake_cam(0x100), Cam::MmioCam, device_function(0, 0, 1), 0));
+    }
+
+    #[test]
+    fn unaligned_register_is_not_read() {
+        let cam = fake_cam(MMIO_CAM_SIZE);
+
+        assert_eq!(None, read(&cam, Cam::MmioCam, device_function(0, 0, 0), 0x19));
+    }
+}
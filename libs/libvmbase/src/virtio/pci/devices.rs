@@ -0,0 +1,152 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Setting up VirtIO device drivers without letting a faulty device abort the enumeration.
+
+use core::fmt::{Debug, Display};
+use log::{debug, warn};
+
+/// Returns the transport created for `device`, or `None` if its creation failed.
+///
+/// Failures are only logged, as they may well concern devices which aren't going to be used.
+pub fn transport_or_skip<T, E: Debug>(device: impl Display, transport: Result<T, E>) -> Option<T> {
+    match transport {
+        Ok(transport) => Some(transport),
+        Err(e) => {
+            debug!("Failed to create transport for PCI device {device}, skipping it: {e:?}");
+            None
+        }
+    }
+}
+
+/// Sets up a driver for the next transport of `transports` for which that succeeds.
+///
+/// Transports for which `set_up` fails are logged and skipped, so that a single faulty device
+/// doesn't prevent the others from being used.
+pub fn set_up_next_device<T, D, E: Debug>(
+    transports: &mut impl Iterator<Item = T>,
+    mut set_up: impl FnMut(T) -> Result<D, E>,
+) -> Option<D> {
+    transports.find_map(|transport| match set_up(transport) {
+        Ok(device) => Some(device),
+        Err(e) => {
+            warn!("Failed to set up VirtIO device, skipping it: {e:?}");
+            None
+        }
+    })
+}
+
+/// Fills `dst` with the bytes returned by successive calls to `read`, each of which writes to the
+/// start of the buffer it is given and returns the number of bytes written.
+///
+/// Returns the number of bytes filled, which is less than the length of `dst` only if `read` ran
+/// dry.
+pub fn fill_from<E>(
+    dst: &mut [u8],
+    mut read: impl FnMut(&mut [u8]) -> Result<usize, E>,
+) -> Result<usize, E> {
+    let mut filled = 0;
+    while filled < dst.len() {
+        match read(&mut dst[filled..])? {
+            0 => break,
+            size => filled += size,
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enumeration_continues_after_failed_transport() {
+        let transports = [("00:01.0", Ok(1)), ("00:02.0", Err("bad BAR")), ("00:03.0", Ok(3))];
+        let transports: Vec<u8> = transports
+            .into_iter()
+            .filter_map(|(device, transport)| transport_or_skip(device, transport))
+            .collect();
+
+        assert_eq!(vec![1, 3], transports);
+    }
+
+    /// A fake block device, only recording the transport it was set up from.
+    #[derive(Debug, PartialEq)]
+    struct FakeBlk(u8);
+
+    fn set_up_blk(transport: (u8, bool)) -> Result<FakeBlk, &'static str> {
+        match transport {
+            (id, true) => Ok(FakeBlk(id)),
+            (_, false) => Err("failed to negotiate features"),
+        }
+    }
+
+    #[test]
+    fn failed_device_is_skipped() {
+        let mut transports = [(0, false), (1, true)].into_iter();
+
+        assert_eq!(Some(FakeBlk(1)), set_up_next_device(&mut transports, set_up_blk));
+        assert_eq!(None, set_up_next_device(&mut transports, set_up_blk));
+    }
+
+    #[test]
+    fn every_device_is_set_up() {
+        let mut transports = [(0, true), (1, false), (2, true)].into_iter();
+        let devices: Vec<_> =
+            core::iter::from_fn(|| set_up_next_device(&mut transports, set_up_blk)).collect();
+
+        assert_eq!(vec![FakeBlk(0), FakeBlk(2)], devices);
+    }
+
+    /// A fake entropy device, returning at most `chunk_size` bytes per request.
+    struct FakeRng {
+        entropy: Vec<u8>,
+        chunk_size: usize,
+    }
+
+    impl FakeRng {
+        fn request_entropy(&mut self, dst: &mut [u8]) -> Result<usize, &'static str> {
+            let size = dst.len().min(self.chunk_size).min(self.entropy.len());
+            dst[..size].copy_from_slice(&self.entropy[..size]);
+            self.entropy.drain(..size);
+            Ok(size)
+        }
+    }
+
+    #[test]
+    fn bytes_are_read_from_rng() {
+        let entropy: Vec<u8> = (1..=10).collect();
+        let mut rng = FakeRng { entropy: entropy.clone(), chunk_size: 4 };
+        let mut dst = [0; 10];
+
+        assert_eq!(Ok(10), fill_from(&mut dst, |buf| rng.request_entropy(buf)));
+        assert_eq!(entropy, dst);
+    }
+
+    #[test]
+    fn exhausted_rng_is_reported() {
+        let mut rng = FakeRng { entropy: vec![0xaa; 6], chunk_size: 4 };
+        let mut dst = [0; 10];
+
+        assert_eq!(Ok(6), fill_from(&mut dst, |buf| rng.request_entropy(buf)));
+        assert_eq!([0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0, 0, 0, 0], dst);
+    }
+
+    #[test]
+    fn rng_error_is_propagated() {
+        let mut dst = [0; 10];
+
+        assert_eq!(Err("device error"), fill_from(&mut dst, |_| Err("device error")));
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Discovery of the PCI buses reachable from the root bus.
+
+#[cfg(not(test))]
+use alloc::vec::Vec;
+
+/// The number of the PCI bus behind the root complex.
+pub const ROOT_BUS: u8 = 0;
+
+/// The PCI-to-PCI bridges connecting the buses of a PCI hierarchy.
+pub trait PciTopology {
+    /// Returns the secondary bus numbers of the PCI-to-PCI bridges found on `bus`.
+    fn secondary_buses(&mut self, bus: u8) -> Vec<u8>;
+}
+
+/// Returns the buses reachable from the root bus by following PCI-to-PCI bridges, in breadth-first
+/// order starting with the root bus.
+///
+/// Each bus is returned once, even if misconfigured bridges form a cycle.
+pub fn reachable_buses(topology: &mut impl PciTopology) -> Vec<u8> {
+    let mut visited = [false; 256];
+    visited[usize::from(ROOT_BUS)] = true;
+    let mut buses = Vec::from([ROOT_BUS]);
+    let mut next = 0;
+    while let Some(&bus) = buses.get(next) {
+        for secondary in topology.secondary_buses(bus) {
+            if !visited[usize::from(secondary)] {
+                visited[usize::from(secondary)] = true;
+                buses.push(secondary);
+            }
+        }
+        next += 1;
+    }
+    buses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// A fake PCI root complex, made of the bridges found on each bus.
+    #[derive(Default)]
+    struct FakePciRoot {
+        bridges: HashMap<u8, Vec<u8>>,
+        scanned: Vec<u8>,
+    }
+
+    impl FakePciRoot {
+        fn with_bridges(bridges: &[(u8, &[u8])]) -> Self {
+            let bridges = bridges.iter().map(|(bus, secondary)| (*bus, secondary.to_vec()));
+            Self { bridges: bridges.collect(), ..Default::default() }
+        }
+    }
+
+    impl PciTopology for FakePciRoot {
+        fn secondary_buses(&mut self, bus: u8) -> Vec<u8> {
+            self.scanned.push(bus);
+            self.bridges.get(&bus).cloned().unwrap_or_default()
+        }
+    }
+
+    #[test]
+    fn root_bus_only_without_bridges() {
+        let mut root = FakePciRoot::default();
+
+        assert_eq!(vec![ROOT_BUS], reachable_buses(&mut root));
+        assert_eq!(vec![ROOT_BUS], root.scanned);
+    }
+
+    #[test]
+    fn buses_behind_bridges_are_reachable() {
+        let mut root = FakePciRoot::with_bridges(&[(0, &[1, 4]), (1, &[2, 3]), (4, &[5])]);
+
+        assert_eq!(vec![0, 1, 4, 2, 3, 5], reachable_buses(&mut root));
+        assert_eq!(vec![0, 1, 4, 2, 3, 5], root.scanned);
+    }
+
+    #[test]
+    fn unconnected_buses_are_not_reachable() {
+        let mut root = FakePciRoot::with_bridges(&[(0, &[1]), (2, &[3])]);
+
+        assert_eq!(vec![0, 1], reachable_buses(&mut root));
+    }
+
+    #[test]
+    fn bridge_cycles_are_scanned_once() {
+        let mut root = FakePciRoot::with_bridges(&[(0, &[1]), (1, &[0, 2]), (2, &[1, 2])]);
+
+        assert_eq!(vec![0, 1, 2], reachable_buses(&mut root));
+        assert_eq!(vec![0, 1, 2], root.scanned);
+    }
+}
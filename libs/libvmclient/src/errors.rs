@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use super::DeathReason;
+use android_system_virtualizationservice::binder::StatusCode;
 use thiserror::Error;
 
 /// An error while waiting for a VM to do something.
@@ -31,3 +32,20 @@ pub enum VmWaitError {
     #[error("VM payload finished.")]
     Finished,
 }
+
+/// An error while checking whether a service in the VM is responsive.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum VmPingError {
+    /// The service didn't respond within the timeout.
+    #[error("Timed out waiting for VM service to respond.")]
+    TimedOut,
+    /// The VM died before the service responded.
+    #[error("VM died. ({reason:?})")]
+    Died {
+        /// The reason why the VM died.
+        reason: DeathReason,
+    },
+    /// The service responded with an error.
+    #[error("VM service ping failed: {0:?}")]
+    Binder(StatusCode),
+}
@@ -21,7 +21,7 @@ mod sync;
 
 pub use crate::death_reason::DeathReason;
 pub use crate::error_code::ErrorCode;
-pub use crate::errors::VmWaitError;
+pub use crate::errors::{VmPingError, VmWaitError};
 use crate::sync::Monitor;
 use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::{
     DeathReason::DeathReason as AidlDeathReason, ErrorCode::ErrorCode as AidlErrorCode,
@@ -35,8 +35,8 @@ use android_system_virtualizationservice::{
         VirtualMachineState::VirtualMachineState,
     },
     binder::{
-        BinderFeatures, DeathRecipient, FromIBinder, IBinder, Interface, ParcelFileDescriptor,
-        Result as BinderResult, StatusCode, Strong,
+        BinderFeatures, DeathRecipient, FromIBinder, IBinder, IBinderInternal, Interface,
+        ParcelFileDescriptor, Result as BinderResult, SpIBinder, StatusCode, Strong,
     },
 };
 use command_fds::CommandFdExt;
@@ -51,7 +51,8 @@ use std::{
     fmt::{self, Debug, Formatter},
     fs::File,
     os::unix::io::{AsFd, AsRawFd, IntoRawFd, OwnedFd},
-    sync::Arc,
+    sync::{mpsc, Arc},
+    thread,
     time::Duration,
 };
 
@@ -195,6 +196,25 @@ pub trait VmCallback {
     /// further details.
     fn on_error(&self, cid: i32, error_code: ErrorCode, message: &str) {}
 
+    /// Called when the VM is approaching or has exceeded the configured `limit` on `resource`.
+    /// `observed` is the usage that crossed the threshold.
+    fn on_resource_limit(&self, cid: i32, resource: &str, limit: i64, observed: i64) {}
+
+    /// Called when the console output of the VM, forwarded to the console fd given when creating
+    /// it, has ended. Nothing is written to that fd afterwards.
+    fn on_console_closed(&self, cid: i32) {}
+
+    /// Called when the guest has resized its console window to `rows` by `cols` characters.
+    fn on_console_resize(&self, cid: i32, rows: i32, cols: i32) {}
+
+    /// Called when the VM has connected to `port`, one of the vsock ports reserved in its config.
+    /// `stream` is the accepted connection.
+    fn on_vsock_connection(&self, cid: i32, port: i32, stream: OwnedFd) {}
+
+    /// Called when the VM has pushed a file to the host, e.g. a crash minidump. `file` is open for
+    /// reading.
+    fn on_guest_file_received(&self, cid: i32, file: OwnedFd) {}
+
     /// Called when the VM has exited, all resources have been freed, and any logs have been
     /// written. `death_reason` gives an indication why the VM exited.
     fn on_died(&self, cid: i32, death_reason: DeathReason) {}
@@ -282,6 +302,13 @@ impl VmInstance {
         }
     }
 
+    /// Waits until the payload in the VM finishes, and returns its exit code.
+    ///
+    /// Returns an error if the VM dies before the payload finishes, or the `timeout` elapses.
+    pub fn wait_until_finished(&self, timeout: Duration) -> Result<i32, VmWaitError> {
+        self.state.wait_until_finished(timeout)
+    }
+
     /// Tries to connect to an RPC Binder service provided by the VM on the given vsock port.
     pub fn connect_service<T: FromIBinder + ?Sized>(
         &self,
@@ -300,6 +327,39 @@ impl VmInstance {
             }
         })
     }
+
+    /// Checks that the VM is still alive and that `service`, a Binder object previously obtained
+    /// from the VM (e.g. via `connect_service`), responds to a ping within `timeout`.
+    pub fn ping(&self, service: &SpIBinder, timeout: Duration) -> Result<(), VmPingError> {
+        if let Some(reason) = self.state.state.lock().unwrap().death_reason {
+            return Err(VmPingError::Died { reason });
+        }
+        let mut service = service.clone();
+        ping_with_timeout(timeout, move || service.ping_binder()).map_err(|e| {
+            // If the VM died while we were waiting, report that rather than the ping failure.
+            match self.state.state.lock().unwrap().death_reason {
+                Some(reason) => VmPingError::Died { reason },
+                None => e,
+            }
+        })
+    }
+}
+
+/// Runs `ping` on a separate thread, so that a hung service can't block the caller for longer
+/// than `timeout`. If the ping does time out the thread is left to finish (or not) on its own.
+fn ping_with_timeout(
+    timeout: Duration,
+    ping: impl FnOnce() -> Result<(), StatusCode> + Send + 'static,
+) -> Result<(), VmPingError> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        // The receiver may have given up already, in which case there is nobody to tell.
+        let _ = sender.send(ping());
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result.map_err(VmPingError::Binder),
+        Err(_) => Err(VmPingError::TimedOut),
+    }
 }
 
 impl Debug for VmInstance {
@@ -327,6 +387,7 @@ fn wait_for_binder_death(
 struct VmState {
     death_reason: Option<DeathReason>,
     reported_state: VirtualMachineState,
+    exit_code: Option<i32>,
 }
 
 impl Monitor<VmState> {
@@ -343,6 +404,30 @@ impl Monitor<VmState> {
         self.state.lock().unwrap().reported_state = state;
         self.cv.notify_all();
     }
+
+    fn notify_finished(&self, exit_code: i32) {
+        let state = &mut *self.state.lock().unwrap();
+        state.reported_state = VirtualMachineState::FINISHED;
+        state.exit_code = Some(exit_code);
+        self.cv.notify_all();
+    }
+
+    fn wait_until_finished(&self, timeout: Duration) -> Result<i32, VmWaitError> {
+        let (state, timeout_result) = self
+            .wait_timeout_while(timeout, |state| {
+                state.exit_code.is_none() && state.death_reason.is_none()
+            })
+            .unwrap();
+        // The payload may have finished just before the VM died; report the exit code if so.
+        if let Some(exit_code) = state.exit_code {
+            Ok(exit_code)
+        } else if let Some(reason) = state.death_reason {
+            Err(VmWaitError::Died { reason })
+        } else {
+            assert!(timeout_result.timed_out());
+            Err(VmWaitError::TimedOut)
+        }
+    }
 }
 
 struct VirtualMachineCallback {
@@ -382,7 +467,7 @@ impl IVirtualMachineCallback for VirtualMachineCallback {
     }
 
     fn onPayloadFinished(&self, cid: i32, exit_code: i32) -> BinderResult<()> {
-        self.state.notify_state(VirtualMachineState::FINISHED);
+        self.state.notify_finished(exit_code);
         if let Some(ref callback) = self.client_callback {
             callback.on_payload_finished(cid, exit_code);
         }
@@ -398,6 +483,54 @@ impl IVirtualMachineCallback for VirtualMachineCallback {
         Ok(())
     }
 
+    fn onResourceLimit(
+        &self,
+        cid: i32,
+        resource: &str,
+        limit: i64,
+        observed: i64,
+    ) -> BinderResult<()> {
+        if let Some(ref callback) = self.client_callback {
+            callback.on_resource_limit(cid, resource, limit, observed);
+        }
+        Ok(())
+    }
+
+    fn onConsoleClosed(&self, cid: i32) -> BinderResult<()> {
+        if let Some(ref callback) = self.client_callback {
+            callback.on_console_closed(cid);
+        }
+        Ok(())
+    }
+
+    fn onConsoleResize(&self, cid: i32, rows: i32, cols: i32) -> BinderResult<()> {
+        if let Some(ref callback) = self.client_callback {
+            callback.on_console_resize(cid, rows, cols);
+        }
+        Ok(())
+    }
+
+    fn onVsockConnection(
+        &self,
+        cid: i32,
+        port: i32,
+        stream: &ParcelFileDescriptor,
+    ) -> BinderResult<()> {
+        if let Some(ref callback) = self.client_callback {
+            let stream = stream.as_ref().try_clone().map_err(|_| StatusCode::BAD_VALUE)?;
+            callback.on_vsock_connection(cid, port, stream.into());
+        }
+        Ok(())
+    }
+
+    fn onGuestFileReceived(&self, cid: i32, file: &ParcelFileDescriptor) -> BinderResult<()> {
+        if let Some(ref callback) = self.client_callback {
+            let file = file.as_ref().try_clone().map_err(|_| StatusCode::BAD_VALUE)?;
+            callback.on_guest_file_received(cid, file.into());
+        }
+        Ok(())
+    }
+
     fn onDied(&self, cid: i32, reason: AidlDeathReason) -> BinderResult<()> {
         let reason = reason.into();
         self.state.notify_death(reason);
@@ -407,3 +540,74 @@ impl IVirtualMachineCallback for VirtualMachineCallback {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    fn new_callback() -> (Arc<Monitor<VmState>>, VirtualMachineCallback) {
+        let state = Arc::new(Monitor::new(VmState::default()));
+        let callback = VirtualMachineCallback { state: state.clone(), client_callback: None };
+        (state, callback)
+    }
+
+    #[test]
+    fn wait_until_finished_returns_exit_code() {
+        let (state, callback) = new_callback();
+        callback.onPayloadStarted(3).unwrap();
+        callback.onPayloadReady(3).unwrap();
+        assert_eq!(state.wait_until_finished(TIMEOUT), Err(VmWaitError::TimedOut));
+
+        callback.onPayloadFinished(3, 42).unwrap();
+        assert_eq!(state.wait_until_finished(TIMEOUT), Ok(42));
+
+        // The exit code is still available after the VM has shut down.
+        callback.onDied(3, AidlDeathReason::SHUTDOWN).unwrap();
+        assert_eq!(state.wait_until_finished(TIMEOUT), Ok(42));
+    }
+
+    #[test]
+    fn wait_until_finished_reports_death_before_finish() {
+        let (state, callback) = new_callback();
+        callback.onPayloadStarted(3).unwrap();
+        callback.onDied(3, AidlDeathReason::KILLED).unwrap();
+        assert_eq!(
+            state.wait_until_finished(TIMEOUT),
+            Err(VmWaitError::Died { reason: DeathReason::Killed })
+        );
+    }
+
+    #[test]
+    fn wait_until_finished_wakes_up_on_finish() {
+        let (state, callback) = new_callback();
+        let waiter = thread::spawn(move || state.wait_until_finished(Duration::from_secs(10)));
+        callback.onPayloadStarted(3).unwrap();
+        callback.onPayloadFinished(3, 0).unwrap();
+        assert_eq!(waiter.join().unwrap(), Ok(0));
+    }
+
+    #[test]
+    fn ping_responding_service() {
+        assert_eq!(ping_with_timeout(TIMEOUT, || Ok(())), Ok(()));
+    }
+
+    #[test]
+    fn ping_failing_service() {
+        assert_eq!(
+            ping_with_timeout(TIMEOUT, || Err(StatusCode::DEAD_OBJECT)),
+            Err(VmPingError::Binder(StatusCode::DEAD_OBJECT))
+        );
+    }
+
+    #[test]
+    fn ping_hanging_service() {
+        let (_keep_hanging, hang) = mpsc::channel::<()>();
+        let result = ping_with_timeout(TIMEOUT, move || {
+            let _ = hang.recv();
+            Ok(())
+        });
+        assert_eq!(result, Err(VmPingError::TimedOut));
+    }
+}
@@ -91,6 +91,7 @@ pub fn run_vm() -> Result<VmInstance, Error> {
         &ParcelFileDescriptor::new(instance_img),
         INSTANCE_FILE_SIZE.try_into()?,
         PartitionType::ANDROID_VM_INSTANCE,
+        /* force= */ false,
     )?;
     info!("created instance image at: {instance_img_path:?}");
 